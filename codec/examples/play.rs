@@ -0,0 +1,29 @@
+//! Plays a replay on a libretro core, saving the screen every EVERY frames
+//! (default 60) as PPM images in DIR:
+//!
+//! cargo run -p rply-codec --example play --features examples-support,retro -- \
+//!     examples/bobl.replay CORE ROM DIR [EVERY]
+use retro_rs::Emulator;
+use rply_codec::{
+    decode,
+    examples_support::{Player, PpmFrames},
+};
+use std::path::Path;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, replay, core, rom, dir, rest @ ..] = args.as_slice() else {
+        eprintln!("usage: play REPLAY CORE ROM DIR [EVERY]");
+        std::process::exit(2);
+    };
+    let every = rest.first().map_or(60, |e| e.parse().unwrap());
+    let file = std::io::BufReader::new(std::fs::File::open(replay).unwrap());
+    let rply = decode(file).unwrap();
+    let emu = Emulator::create(Path::new(core), Path::new(rom));
+    std::fs::create_dir_all(dir).unwrap();
+    let mut player = Player::new(rply, emu).unwrap();
+    // Keep the picture right even if this build of the core drifts
+    player.set_resync(true);
+    let frames = player.play(&mut PpmFrames::new(dir, every)).unwrap();
+    println!("Played {frames} frames into {dir}");
+}
@@ -0,0 +1,46 @@
+//! Records a replay of a libretro core holding right and pressing B every
+//! half second, with a checkpoint every second:
+//!
+//! cargo run -p rply-codec --example record --features examples-support,retro -- \
+//!     CORE ROM out.replay [FRAMES]
+use retro_rs::Emulator;
+use rply_codec::{Header, HeaderBase, examples_support::record};
+use std::path::Path;
+
+/* libretro joypad button ids */
+const B: u32 = 0;
+const RIGHT: u32 = 7;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, core, rom, out, rest @ ..] = args.as_slice() else {
+        eprintln!("usage: record CORE ROM OUT.replay [FRAMES]");
+        std::process::exit(2);
+    };
+    let frames = rest.first().map_or(600, |f| f.parse().unwrap());
+    let emu = Emulator::create(Path::new(core), Path::new(rom));
+    let identifier = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut header = Header::V0V1(HeaderBase {
+        version: 2,
+        content_crc: 0,
+        initial_state_size: 0,
+        identifier,
+    });
+    header.upgrade();
+    let mut file = std::fs::File::create(out).unwrap();
+    record(
+        emu,
+        header,
+        &mut file,
+        frames,
+        60,
+        |frame, port, _device, _idx, id| {
+            let pressed = port == 0 && (id == RIGHT || (id == B && frame % 30 < 5));
+            i16::from(pressed)
+        },
+    )
+    .unwrap();
+    println!("Recorded {frames} frames to {out}");
+}
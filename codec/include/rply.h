@@ -0,0 +1,81 @@
+/* C interface to rply-codec, built with its capi feature:
+ *   cargo rustc -p rply-codec --release --features capi --crate-type cdylib
+ * See codec/src/capi.rs for details. */
+#ifndef RPLY_H
+#define RPLY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RPLY_OK 0
+/* rply_read_frame found no more frames */
+#define RPLY_END 1
+/* Reading or writing the file failed */
+#define RPLY_ERR_IO (-1)
+/* The replay is malformed or uses something this build doesn't support */
+#define RPLY_ERR_FORMAT (-2)
+/* A null pointer, a path that isn't UTF-8, or a read on a handle opened for writing (or the reverse) */
+#define RPLY_ERR_ARGUMENT (-3)
+/* A bug in rply-codec */
+#define RPLY_ERR_INTERNAL (-4)
+
+typedef struct Rply rply_t;
+
+/* One input event, as retro_input_state_t was called */
+typedef struct rply_input {
+   uint8_t port;
+   uint8_t device;
+   uint8_t idx;
+   uint16_t id;
+   int16_t val;
+} rply_input_t;
+
+/* One key event, as retro_keyboard_event_t was called */
+typedef struct rply_key {
+   uint8_t down;
+   uint16_t modifiers;
+   uint32_t code;
+   uint32_t character;
+} rply_key_t;
+
+/* A frame's events and checkpoint.  Frames read with rply_read_frame point
+ * into the handle and stay valid until its next call. */
+typedef struct rply_frame {
+   /* Number of this frame, counting from 1; ignored when writing */
+   uint64_t frame_number;
+   const rply_key_t *keys;
+   size_t key_count;
+   const rply_input_t *inputs;
+   size_t input_count;
+   /* The core's state at the end of the frame, if checkpoint_len isn't 0 */
+   const uint8_t *checkpoint;
+   size_t checkpoint_len;
+} rply_frame_t;
+
+/* Opens a replay for reading; null on failure, with the reason in *error if
+ * error isn't null */
+rply_t *rply_open(const char *path, int *error);
+/* Creates a replay for writing in the current format version with
+ * uncompressed checkpoints; initial_state may be null if its length is 0 */
+rply_t *rply_create(const char *path, uint64_t identifier, uint32_t content_crc,
+      const uint8_t *initial_state, size_t initial_state_len, int *error);
+/* The initial state of a replay opened for reading; null if there is none */
+const uint8_t *rply_initial_state(const rply_t *rply, size_t *len);
+uint64_t rply_identifier(const rply_t *rply);
+/* RPLY_OK, RPLY_END after the last frame, or an error */
+int rply_read_frame(rply_t *rply, rply_frame_t *frame);
+int rply_write_frame(rply_t *rply, const rply_frame_t *frame);
+/* The handle's last error, valid until its next call; "" if none */
+const char *rply_last_error(const rply_t *rply);
+/* Finishes a replay opened for writing and frees the handle either way */
+int rply_close(rply_t *rply);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,180 @@
+use crate::{Core, Frame, Header, InputData, ReplayDecoder, ReplayError, decode};
+use retro_rs::Emulator;
+use std::cell::RefCell;
+use std::io::BufRead;
+use std::rc::Rc;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+enum Source<R: BufRead> {
+    Decoder(Box<ReplayDecoder<R>>),
+    /* Version 0 frames only exist as the inputs a running core polls */
+    Polled {
+        rply: Rc<RefCell<ReplayDecoder<R>>>,
+        frame: Rc<RefCell<Frame>>,
+    },
+}
+
+/// A replay of any format version read as version 2 style frames, from
+/// [`decode_any`].  With an emulator, every frame yielded has also been run
+/// on it, so the emulator's state follows the replay; checkpoints are not
+/// loaded into it.
+pub struct AnyDecoder<'e, R: BufRead> {
+    /// The replay's header, upgraded to version 2.  Version 0 replays don't
+    /// record their length, so their frame count is zero.
+    pub header: Header,
+    pub initial_state: Vec<u8>,
+    /// Frames read so far
+    pub frame_number: u64,
+    emu: Option<&'e mut Emulator>,
+    source: Source<R>,
+    failed: bool,
+}
+
+/// Decodes a replay of any format version, loading its initial state into
+/// `emu` if given.  Version 0 replays store only the values of the inputs
+/// the core polled, so reading their frames means running them in `emu`.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: The replay is version 0 and no emulator was given
+/// [`ReplayError::CoreState`]: The emulator could not load the initial state
+/// Otherwise as [`decode`]
+pub fn decode_any<R: BufRead + 'static>(
+    reader: R,
+    mut emu: Option<&mut Emulator>,
+) -> Result<AnyDecoder<'_, R>> {
+    let mut rply = decode(reader)?;
+    let mut header = rply.header.clone();
+    header.upgrade();
+    let initial_state = rply.initial_state.clone();
+    if let Some(emu) = &mut emu
+        && !initial_state.is_empty()
+        && !emu.load(&initial_state)
+    {
+        return Err(ReplayError::CoreState(0));
+    }
+    let source = if !rply.header.capabilities().coreless_frames {
+        if emu.is_none() {
+            return Err(ReplayError::NoCoreRead());
+        }
+        /* The first frame's key events come before any polled inputs */
+        let mut frame = Frame::default();
+        rply.read_key_events(&mut frame)?;
+        rply.read_end_of_frame(&mut frame)?;
+        Source::Polled {
+            rply: Rc::new(RefCell::new(rply)),
+            frame: Rc::new(RefCell::new(frame)),
+        }
+    } else {
+        Source::Decoder(Box::new(rply))
+    };
+    Ok(AnyDecoder {
+        header,
+        initial_state,
+        frame_number: 0,
+        emu,
+        source,
+        failed: false,
+    })
+}
+
+impl<R: BufRead + 'static> AnyDecoder<'_, R> {
+    /// The emulator frames are being run on, if there is one
+    pub fn emulator(&mut self) -> Option<&mut Emulator> {
+        self.emu.as_deref_mut()
+    }
+
+    fn read_frame(&mut self) -> Option<Result<Frame>> {
+        match &mut self.source {
+            Source::Decoder(rply) => {
+                let frame = rply.frames().next()?;
+                if let (Ok(frame), Some(emu)) = (&frame, &mut self.emu) {
+                    emu.run_frame(frame);
+                }
+                Some(frame)
+            }
+            Source::Polled { rply, frame } => {
+                let emu = self.emu.as_mut()?;
+                frame.borrow_mut().clear();
+                emu.run_with_button_callback(Box::new(poll(Rc::clone(rply), Rc::clone(frame))));
+                let mut rply = rply.borrow_mut();
+                match rply.read_key_events(&mut frame.borrow_mut()) {
+                    Ok(()) => {}
+                    Err(ReplayError::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return None;
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+                Some(
+                    rply.read_end_of_frame(&mut frame.borrow_mut())
+                        .map(|()| std::mem::take(&mut *frame.borrow_mut())),
+                )
+            }
+        }
+    }
+}
+
+/* Answers each input the core polls from the version 0 stream, recording it in `frame` */
+fn poll<R: BufRead>(
+    rply: Rc<RefCell<ReplayDecoder<R>>>,
+    frame: Rc<RefCell<Frame>>,
+) -> impl FnMut(u32, u32, u32, u32) -> i16 {
+    move |port, device, idx, id| {
+        // A truncated stream reads as released buttons; the next frame then ends it
+        let val = rply.borrow_mut().read_v0_button().unwrap_or_default();
+        if let (Ok(port), Ok(device), Ok(idx), Ok(id)) = (
+            u8::try_from(port),
+            u8::try_from(device),
+            u8::try_from(idx),
+            u16::try_from(id),
+        ) {
+            frame.borrow_mut().input_events.push(InputData {
+                port,
+                device,
+                idx,
+                id,
+                val,
+            });
+        }
+        val
+    }
+}
+
+/// Yields each frame in turn.  After an error is yielded, the iterator ends.
+impl<R: BufRead + 'static> Iterator for AnyDecoder<'_, R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let frame = self.read_frame()?;
+        match &frame {
+            Ok(_) => self.frame_number += 1,
+            Err(_) => self.failed = true,
+        }
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn decodes_any_version() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(bytes.clone())).unwrap();
+        let any = decode_any(std::io::Cursor::new(bytes), None).unwrap();
+        assert_eq!(any.header, rply.header);
+        let frames: Vec<_> = any.collect::<Result<_>>().unwrap();
+        assert_eq!(frames, rply.frames().collect::<Result<Vec<_>>>().unwrap());
+
+        let mut v0 = 0x4253_5632_u32.to_le_bytes().to_vec();
+        v0.extend([0; 20]);
+        assert!(matches!(
+            decode_any(std::io::Cursor::new(v0), None),
+            Err(ReplayError::NoCoreRead())
+        ));
+    }
+}
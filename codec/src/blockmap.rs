@@ -0,0 +1,465 @@
+use crate::{Frame, ReplayDecoder, ReplayError};
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::ops::Range;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/* Block size for replays whose header has none (version 0 and 1) */
+const DEFAULT_BLOCK_SIZE: usize = 256;
+
+/// How statestream would store one block of a checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlockUse {
+    /// Past the end of a checkpoint smaller than the largest one
+    Absent = 0,
+    /// All zeros, which statestream never sends
+    Zero = 1,
+    /// Seen before, at any position of any earlier checkpoint
+    Reused = 2,
+    /// Never seen before, so sent in full
+    New = 3,
+}
+
+/// The use of every block of every checkpoint of a replay, from which
+/// [`BlockMap::write_png`] draws a picture of how well statestream works
+/// for its core: a column per checkpoint, a row per block position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockMap {
+    pub block_size: usize,
+    /// The frame of each checkpoint, starting with 0 for the initial state
+    pub frames: Vec<u64>,
+    /// Each checkpoint's blocks, in the same order as `frames`
+    pub checkpoints: Vec<Vec<BlockUse>>,
+}
+
+impl BlockMap {
+    /// Block positions in the largest checkpoint, the image's height
+    #[must_use]
+    pub fn positions(&self) -> usize {
+        self.checkpoints.iter().map(Vec::len).max().unwrap_or(0)
+    }
+    /// How many blocks of all checkpoints were used each way
+    #[must_use]
+    pub fn count(&self, kind: BlockUse) -> usize {
+        self.checkpoints
+            .iter()
+            .map(|blocks| blocks.iter().filter(|&&b| b == kind).count())
+            .sum()
+    }
+    /// Writes the map as a PNG with a pixel per block: new blocks red,
+    /// reused ones green, zero blocks dark grey, and absent ones black.
+    /// Only available with the `zlib` feature.
+    /// # Errors
+    /// Any error writing to `out`
+    #[cfg(feature = "zlib")]
+    pub fn write_png(&self, mut out: impl std::io::Write) -> std::io::Result<()> {
+        use std::io::Write;
+        const PALETTE: [u8; 12] = [0, 0, 0, 48, 48, 48, 60, 170, 80, 230, 60, 50];
+        let too_big = |_| std::io::Error::other("block map too big for a PNG");
+        let (width, height) = (self.checkpoints.len(), self.positions());
+        let mut header = vec![];
+        header.extend(u32::try_from(width).map_err(too_big)?.to_be_bytes());
+        header.extend(u32::try_from(height).map_err(too_big)?.to_be_bytes());
+        // Two bits a pixel, indexing the palette
+        header.extend([2, 3, 0, 0, 0]);
+        let mut pixels = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        let mut row = vec![0; 1 + width.div_ceil(4)];
+        for position in 0..height {
+            row.fill(0);
+            for (x, blocks) in self.checkpoints.iter().enumerate() {
+                let kind = blocks.get(position).copied().unwrap_or(BlockUse::Absent);
+                row[1 + x / 4] |= (kind as u8) << (6 - 2 * (x % 4));
+            }
+            pixels.write_all(&row)?;
+        }
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+        for (kind, data) in [
+            (b"IHDR", header),
+            (b"PLTE", PALETTE.to_vec()),
+            (b"IDAT", pixels.finish()?),
+            (b"IEND", vec![]),
+        ] {
+            let mut crc = flate2::Crc::new();
+            crc.update(kind);
+            crc.update(&data);
+            out.write_all(&u32::try_from(data.len()).map_err(too_big)?.to_be_bytes())?;
+            out.write_all(kind)?;
+            out.write_all(&data)?;
+            out.write_all(&crc.sum().to_be_bytes())?;
+        }
+        out.flush()
+    }
+}
+
+/// Reads the rest of `rply` and sorts every block of its initial state and
+/// checkpoints into zero, new, and reused, as statestream would send them
+/// with no memory budget, whatever encoding the replay actually uses.
+/// Blocks are `block_size` bytes, or the header's block size if `None`.
+/// Blocks are compared by hash, so a collision could count a new block as
+/// reused, but practically never will.
+/// # Errors
+/// Any error from decoding the replay's frames
+pub fn block_map<R: BufRead>(
+    rply: &mut ReplayDecoder<R>,
+    block_size: Option<usize>,
+) -> Result<BlockMap> {
+    let block_size = block_size
+        .or(usize::try_from(rply.header.block_size()).ok())
+        .filter(|&size| size > 0)
+        .unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut seen = HashSet::new();
+    let mut sort = |state: &[u8]| {
+        state
+            .chunks(block_size)
+            .map(|block| {
+                if block.iter().all(|&b| b == 0) {
+                    BlockUse::Zero
+                } else if seen.insert(xxhash_rust::xxh3::xxh3_64(block)) {
+                    BlockUse::New
+                } else {
+                    BlockUse::Reused
+                }
+            })
+            .collect()
+    };
+    let mut map = BlockMap {
+        block_size,
+        frames: vec![0],
+        checkpoints: vec![sort(&rply.initial_state)],
+    };
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        if !frame.checkpoint_bytes.is_empty() {
+            map.frames.push(rply.frame_number);
+            map.checkpoints.push(sort(&frame.checkpoint_bytes));
+        }
+    }
+    Ok(map)
+}
+
+/// What changed in one checkpoint since the state before it, from
+/// [`block_changes`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockChanges {
+    /// Frames preceding the checkpoint
+    pub frame: u64,
+    /// Byte ranges that differ from the state before, including any bytes
+    /// only one of them has
+    pub bytes: Vec<Range<usize>>,
+    /// Ranges of block indices holding any of the changed bytes
+    pub blocks: Vec<Range<usize>>,
+}
+
+impl BlockChanges {
+    /// How many bytes changed
+    #[must_use]
+    pub fn changed_bytes(&self) -> usize {
+        self.bytes.iter().map(ExactSizeIterator::len).sum()
+    }
+    /// How many blocks changed
+    #[must_use]
+    pub fn changed_blocks(&self) -> usize {
+        self.blocks.iter().map(ExactSizeIterator::len).sum()
+    }
+}
+
+/* Ranges of the indices of `block_size` blocks holding any of `bytes`, which must be sorted */
+pub(crate) fn block_ranges(bytes: &[Range<usize>], block_size: usize) -> Vec<Range<usize>> {
+    let mut blocks: Vec<Range<usize>> = vec![];
+    for range in bytes.iter().filter(|r| !r.is_empty()) {
+        let (start, end) = (range.start / block_size, (range.end - 1) / block_size + 1);
+        match blocks.last_mut() {
+            Some(last) if last.end >= start => last.end = last.end.max(end),
+            _ => blocks.push(start..end),
+        }
+    }
+    blocks
+}
+
+/// Reads the rest of `rply` and compares each checkpoint with the one
+/// before it (the initial state, for the first), giving a profile of the
+/// core's memory activity over the run.  Blocks are `block_size` bytes, or
+/// the header's block size if `None`, and are compared in place, so unlike
+/// [`block_map`] a block moved from elsewhere counts as changed.
+/// # Errors
+/// Any error from decoding the replay's frames
+pub fn block_changes<R: BufRead>(
+    rply: &mut ReplayDecoder<R>,
+    block_size: Option<usize>,
+) -> Result<Vec<BlockChanges>> {
+    let block_size = block_size
+        .or(usize::try_from(rply.header.block_size()).ok())
+        .filter(|&size| size > 0)
+        .unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut previous = rply.initial_state.clone();
+    let mut changes = vec![];
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        if frame.checkpoint_bytes.is_empty() {
+            continue;
+        }
+        let bytes = crate::verify::differences(&previous, &frame.checkpoint_bytes);
+        changes.push(BlockChanges {
+            frame: rply.frame_number,
+            blocks: block_ranges(&bytes, block_size),
+            bytes,
+        });
+        std::mem::swap(&mut previous, &mut frame.checkpoint_bytes);
+    }
+    Ok(changes)
+}
+
+/// A named piece of the savestate, e.g. a console's work RAM, for
+/// [`region_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: String,
+    /// Where the region starts in the savestate
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// How statestream would store one [`MemoryRegion`]'s blocks over a
+/// replay, from [`region_stats`].  A block straddling two regions counts
+/// for both.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegionStats {
+    pub name: String,
+    /// The region's blocks in every checkpoint after the initial state,
+    /// each compared with the same position in the state before it
+    pub blocks: u64,
+    /// How many of those differed from the state before
+    pub changed: u64,
+    /// Blocks of the initial state, or changed ones, that were all zeros
+    /// or seen before, so statestream sent only their index
+    pub deduplicated: u64,
+    /// Blocks of the initial state, or changed ones, never seen before,
+    /// so sent in full
+    pub new: u64,
+    /// Bytes the new blocks add to the replay before compression
+    pub bytes: u64,
+}
+
+impl RegionStats {
+    /// The fraction of the region's blocks that changed between checkpoints
+    #[must_use]
+    pub fn change_rate(&self) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.changed as f64 / self.blocks.max(1) as f64;
+        rate
+    }
+    /// The fraction of the region's stored blocks that statestream
+    /// deduplicated rather than sent
+    #[must_use]
+    pub fn dedup_rate(&self) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.deduplicated as f64 / (self.deduplicated + self.new).max(1) as f64;
+        rate
+    }
+}
+
+/// Reads the rest of `rply` and, for each of `regions`, counts how often
+/// its blocks change from one checkpoint to the next, how many statestream
+/// would deduplicate, and how many bytes its new blocks would add, with no
+/// memory budget and whatever encoding the replay actually uses.  Blocks
+/// are `block_size` bytes, or the header's block size if `None`, cut from
+/// the start of the state, and are compared by hash as in [`block_map`].
+/// # Errors
+/// Any error from decoding the replay's frames
+pub fn region_stats<R: BufRead>(
+    rply: &mut ReplayDecoder<R>,
+    regions: &[MemoryRegion],
+    block_size: Option<usize>,
+) -> Result<Vec<RegionStats>> {
+    let block_size = block_size
+        .or(usize::try_from(rply.header.block_size()).ok())
+        .filter(|&size| size > 0)
+        .unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut stats: Vec<RegionStats> = regions
+        .iter()
+        .map(|region| RegionStats {
+            name: region.name.clone(),
+            ..RegionStats::default()
+        })
+        .collect();
+    let mut seen = HashSet::new();
+    let mut tally = |state: &[u8], previous: Option<&[u8]>| {
+        /* Each block's use, or None if it is the same as the state before */
+        let uses: Vec<Option<BlockUse>> = state
+            .chunks(block_size)
+            .enumerate()
+            .map(|(i, block)| {
+                let start = i * block_size;
+                let before = previous.map(|p| p.get(start..start + block.len()));
+                if before.is_some_and(|before| before == Some(block)) {
+                    None
+                } else if block.iter().all(|&b| b == 0) {
+                    Some(BlockUse::Zero)
+                } else if seen.insert(xxhash_rust::xxh3::xxh3_64(block)) {
+                    Some(BlockUse::New)
+                } else {
+                    Some(BlockUse::Reused)
+                }
+            })
+            .collect();
+        for (region, stats) in regions.iter().zip(&mut stats) {
+            let first = region.offset / block_size;
+            let end = (region.offset + region.len)
+                .div_ceil(block_size)
+                .min(uses.len());
+            for block in uses.get(first..end).unwrap_or_default() {
+                if previous.is_some() {
+                    stats.blocks += 1;
+                    stats.changed += u64::from(block.is_some());
+                }
+                match block {
+                    Some(BlockUse::New) => {
+                        stats.new += 1;
+                        stats.bytes += block_size as u64;
+                    }
+                    Some(_) => stats.deduplicated += 1,
+                    None => {}
+                }
+            }
+        }
+    };
+    tally(&rply.initial_state, None);
+    let mut previous = rply.initial_state.clone();
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        if !frame.checkpoint_bytes.is_empty() {
+            tally(&frame.checkpoint_bytes, Some(&previous));
+            std::mem::swap(&mut previous, &mut frame.checkpoint_bytes);
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn block_uses() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(bytes.as_slice()).unwrap();
+        let map = block_map(&mut rply, None).unwrap();
+        assert_eq!((map.block_size, map.positions()), (16, 7));
+        assert_eq!(map.frames.len(), 26);
+        assert_eq!((map.frames[1], map.frames[25]), (40, 1000));
+        // The initial state is all zeros, and later ones fill in slowly
+        assert!(map.checkpoints[0].iter().all(|&b| b == BlockUse::Zero));
+        assert_eq!(map.checkpoints[1][0], BlockUse::New);
+        assert!(map.count(BlockUse::New) > 25);
+        assert_eq!(
+            map.count(BlockUse::New) + map.count(BlockUse::Reused) + map.count(BlockUse::Zero),
+            26 * 7
+        );
+
+        #[cfg(feature = "zlib")]
+        {
+            let mut png = vec![];
+            map.write_png(&mut png).unwrap();
+            assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+            assert_eq!(&png[12..16], b"IHDR");
+            assert_eq!(&png[16..24], &[0, 0, 0, 26, 0, 0, 0, 7]);
+            assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+        }
+    }
+
+    #[test]
+    fn changes() {
+        let header = crate::HeaderV2::builder(1, 0)
+            .block_size(16)
+            .build()
+            .unwrap();
+        let states = [vec![0; 64], vec![0; 64], vec![0; 80]];
+        let mut states = states.map(|mut state| {
+            state[5] = 1;
+            state[40] = 2;
+            state
+        });
+        states[1][17] = 3;
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(header, &[0; 64], &mut out).unwrap();
+        for state in states {
+            enc.write_frame(&Frame::default()).unwrap();
+            enc.write_frame(&Frame {
+                checkpoint_bytes: state,
+                ..Frame::default()
+            })
+            .unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        let mut rply = crate::decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        let changes = block_changes(&mut rply, None).unwrap();
+        let frames: Vec<_> = changes.iter().map(|c| c.frame).collect();
+        assert_eq!(frames, [2, 4, 6]);
+        assert_eq!(changes[0].bytes, [5..6, 40..41]);
+        assert_eq!(changes[0].blocks, [0..1, 2..3]);
+        // Byte 17 is set, then set back along with the state growing
+        assert_eq!(changes[1].bytes, vec![17..18]);
+        assert_eq!(changes[2].bytes, [17..18, 64..80]);
+        assert_eq!(changes[2].blocks, [1..2, 4..5]);
+        assert_eq!(
+            (changes[2].changed_bytes(), changes[2].changed_blocks()),
+            (17, 2)
+        );
+    }
+
+    #[test]
+    fn regions() {
+        let header = crate::HeaderV2::builder(1, 0)
+            .block_size(16)
+            .build()
+            .unwrap();
+        let mut state = vec![0; 64];
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(header, &state, &mut out).unwrap();
+        // Block 0 gets new contents, then block 2 does and block 1 gets a copy of block 0
+        for (at, val) in [(5, 1), (40, 2), (21, 1)] {
+            state[at] = val;
+            if at == 40 {
+                continue;
+            }
+            enc.write_frame(&Frame {
+                checkpoint_bytes: state.clone(),
+                ..Frame::default()
+            })
+            .unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        let mut rply = crate::decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        let region = |name: &str, offset, len| MemoryRegion {
+            name: name.into(),
+            offset,
+            len,
+        };
+        let regions = [
+            region("a", 0, 16),
+            region("b", 16, 32),
+            region("c", 48, 100),
+        ];
+        let stats = region_stats(&mut rply, &regions, None).unwrap();
+        let counts: Vec<_> = stats
+            .iter()
+            .map(|s| (s.blocks, s.changed, s.deduplicated, s.new, s.bytes))
+            .collect();
+        // The initial state's zero blocks are all deduplicated
+        assert_eq!(
+            counts,
+            [(2, 1, 1, 1, 16), (4, 2, 3, 1, 16), (2, 0, 1, 0, 0)]
+        );
+        assert!((stats[1].change_rate() - 0.5).abs() < f64::EPSILON);
+        assert!((stats[1].dedup_rate() - 0.75).abs() < f64::EPSILON);
+        assert!(stats[2].change_rate().abs() < f64::EPSILON);
+    }
+}
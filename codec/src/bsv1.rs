@@ -0,0 +1,95 @@
+use crate::{Header, ReplayError, ReplayRecorder};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use retro_rs::Emulator;
+use std::cell::RefCell;
+use std::io::{BufRead, Read, Seek, Write};
+use std::rc::Rc;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/* "BSV1", stored big-endian unlike the rest of the header */
+const BSV1_MAGIC: u32 = 0x4253_5631;
+
+/* Reads the 16-byte movie header and serialized state, returning the content CRC and state */
+fn read_header<R: BufRead>(bsv: &mut R) -> Result<(u32, Vec<u8>)> {
+    let magic = bsv.read_u32::<BigEndian>()?;
+    if magic != BSV1_MAGIC {
+        return Err(ReplayError::Magic(magic));
+    }
+    let _serializer = bsv.read_u32::<LittleEndian>()?;
+    let content_crc = bsv.read_u32::<LittleEndian>()?;
+    let state_size = bsv.read_u32::<LittleEndian>()?;
+    // Grown as the state is read, so a bad size can't claim 4 GiB up front
+    let mut state = vec![];
+    bsv.take(u64::from(state_size)).read_to_end(&mut state)?;
+    if state.len() != state_size as usize {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok((content_crc, state))
+}
+
+/// Converts an old RetroArch BSV1 movie (`.bsv`) into a replay with
+/// `header`'s settings and the movie's content CRC.  BSV1 movies store
+/// only the value of each input the core polled, without frame
+/// boundaries, so the movie is run in `emu` and recorded with a
+/// [`ReplayRecorder`], checkpoints included.  The movie's serialized state
+/// becomes the replay's initial state; movies without one start from the
+/// emulator's current state.  A last frame cut short by the end of the
+/// movie reads its missing inputs as zero.
+///
+/// Returns the number of frames written and the emulator.
+/// # Errors
+/// [`ReplayError::Magic`]: Not a BSV1 movie
+/// [`ReplayError::CoreState`]: The core could not load the movie's state or save a checkpoint
+/// Otherwise as [`ReplayRecorder::new`] and [`ReplayRecorder::run_frame`]
+pub fn import_bsv1<R: BufRead + 'static, W: Write + Seek>(
+    mut bsv: R,
+    mut emu: Emulator,
+    mut header: Header,
+    out: &mut W,
+) -> Result<(u64, Emulator)> {
+    let (content_crc, state) = read_header(&mut bsv)?;
+    if !state.is_empty() && !emu.load(&state) {
+        return Err(ReplayError::CoreState(0));
+    }
+    header.set_content_crc(content_crc);
+    let mut recorder = ReplayRecorder::new(emu, header, out)?;
+    let bsv = Rc::new(RefCell::new(bsv));
+    while !bsv.borrow_mut().fill_buf()?.is_empty() {
+        let bsv = Rc::clone(&bsv);
+        recorder.run_frame(move |_, _, _, _| {
+            bsv.borrow_mut()
+                .read_i16::<LittleEndian>()
+                .unwrap_or_default()
+        })?;
+    }
+    let frames = recorder.frame_number();
+    Ok((frames, recorder.finish()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bsv1_header() {
+        let mut movie = b"BSV1".to_vec();
+        for n in [0_u32, 0xdead_beef, 3] {
+            movie.extend(n.to_le_bytes());
+        }
+        movie.extend([1, 2, 3, 4, 0]);
+        let mut bsv = movie.as_slice();
+        assert_eq!(read_header(&mut bsv).unwrap(), (0xdead_beef, vec![1, 2, 3]));
+        assert_eq!(bsv, [4, 0]);
+        assert!(matches!(
+            read_header(&mut &b"2VSB"[..]),
+            Err(ReplayError::Magic(0x3256_5342))
+        ));
+        // A state claiming far more than the movie holds
+        movie[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            read_header(&mut movie.as_slice()),
+            Err(ReplayError::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -0,0 +1,530 @@
+//! C ABI for reading and writing replays, declared in `include/rply.h`.
+//! Build it as a C library with
+//! `cargo rustc -p rply-codec --release --features capi --crate-type cdylib`
+//! (or `staticlib`).
+//!
+//! Handles are opaque; functions returning `int` return [`RPLY_OK`] or a
+//! negative error code, and [`rply_last_error`] describes the handle's
+//! last error.  Panics are caught and reported as [`RPLY_ERR_INTERNAL`].
+#![allow(unsafe_code)]
+use crate::{
+    Frame, Header, HeaderBase, InputData, KeyData, ReplayDecoder, ReplayEncoder, ReplayError,
+};
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::panic::AssertUnwindSafe;
+
+pub const RPLY_OK: c_int = 0;
+/// [`rply_read_frame`] found no more frames
+pub const RPLY_END: c_int = 1;
+/// Reading or writing the file failed
+pub const RPLY_ERR_IO: c_int = -1;
+/// The replay is malformed or uses something this build doesn't support
+pub const RPLY_ERR_FORMAT: c_int = -2;
+/// A null pointer, a path that isn't UTF-8, or a read on a handle opened for writing (or the reverse)
+pub const RPLY_ERR_ARGUMENT: c_int = -3;
+/// A bug in this crate
+pub const RPLY_ERR_INTERNAL: c_int = -4;
+
+/// One input event, as `retro_input_state_t` was called
+#[repr(C)]
+pub struct RplyInput {
+    pub port: u8,
+    pub device: u8,
+    pub idx: u8,
+    pub id: u16,
+    pub val: i16,
+}
+
+/// One key event, as `retro_keyboard_event_t` was called
+#[repr(C)]
+pub struct RplyKey {
+    pub down: u8,
+    pub modifiers: u16,
+    pub code: u32,
+    pub character: u32,
+}
+
+/// A frame's events and checkpoint.  Frames read with [`rply_read_frame`]
+/// point into the handle and stay valid until its next call.
+#[repr(C)]
+pub struct RplyFrame {
+    /// Number of this frame, counting from 1
+    pub frame_number: u64,
+    pub keys: *const RplyKey,
+    pub key_count: usize,
+    pub inputs: *const RplyInput,
+    pub input_count: usize,
+    /// The core's state at the end of the frame, if any
+    pub checkpoint: *const u8,
+    pub checkpoint_len: usize,
+}
+
+enum Mode {
+    Read {
+        rply: Box<ReplayDecoder<BufReader<File>>>,
+        frame: Frame,
+        keys: Vec<RplyKey>,
+        inputs: Vec<RplyInput>,
+    },
+    Write {
+        /* Borrows `file`, which is only freed once the encoder is gone */
+        rply: Option<Box<ReplayEncoder<'static, BufWriter<File>>>>,
+        file: *mut BufWriter<File>,
+        frame: Frame,
+    },
+}
+
+/// An open replay
+pub struct Rply {
+    mode: Mode,
+    error: CString,
+}
+
+impl Rply {
+    fn fail(&mut self, code: c_int, message: &str) -> c_int {
+        self.error = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        code
+    }
+    fn fail_with(&mut self, e: &ReplayError) -> c_int {
+        self.fail(code(e), &e.to_string())
+    }
+}
+
+fn code(e: &ReplayError) -> c_int {
+    match e.root() {
+        ReplayError::IO(_) => RPLY_ERR_IO,
+        _ => RPLY_ERR_FORMAT,
+    }
+}
+
+/* Runs `f`, turning a panic into RPLY_ERR_INTERNAL */
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(RPLY_ERR_INTERNAL)
+}
+
+/* Stores `code` through `error` if the caller wants it */
+unsafe fn report(error: *mut c_int, code: c_int) {
+    if !error.is_null() {
+        // SAFETY: the caller passes null or a valid pointer
+        unsafe { *error = code };
+    }
+}
+
+unsafe fn path<'p>(path: *const c_char) -> Option<&'p str> {
+    if path.is_null() {
+        return None;
+    }
+    // SAFETY: the caller passes a NUL-terminated string
+    unsafe { CStr::from_ptr(path) }.to_str().ok()
+}
+
+/* `len` items at `ptr`, which may be null if `len` is zero */
+unsafe fn slice<'s, T>(ptr: *const T, len: usize) -> Option<&'s [T]> {
+    if len == 0 {
+        Some(&[])
+    } else if ptr.is_null() {
+        None
+    } else {
+        // SAFETY: the caller passes `len` valid items
+        Some(unsafe { std::slice::from_raw_parts(ptr, len) })
+    }
+}
+
+/// Opens the replay at `path` for reading, returning null on failure with
+/// the reason in `*error` if `error` isn't null.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string, and `error` null or valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_open(path: *const c_char, error: *mut c_int) -> *mut Rply {
+    let mut handle = std::ptr::null_mut();
+    let code = guard(|| {
+        // SAFETY: as this function's contract
+        let Some(path) = (unsafe { self::path(path) }) else {
+            return RPLY_ERR_ARGUMENT;
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return RPLY_ERR_IO,
+        };
+        match crate::decode(BufReader::new(file)) {
+            Ok(rply) => {
+                handle = Box::into_raw(Box::new(Rply {
+                    mode: Mode::Read {
+                        rply: Box::new(rply),
+                        frame: Frame::default(),
+                        keys: vec![],
+                        inputs: vec![],
+                    },
+                    error: CString::default(),
+                }));
+                RPLY_OK
+            }
+            Err(e) => code(&e),
+        }
+    });
+    // SAFETY: as this function's contract
+    unsafe { report(error, code) };
+    handle
+}
+
+/// Creates a replay at `path` for writing in the current format version,
+/// with uncompressed checkpoints and `initial_state` (which may be null if
+/// `initial_state_len` is zero).  Returns null on failure with the reason
+/// in `*error` if `error` isn't null.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string, `initial_state` must point to
+/// `initial_state_len` bytes, and `error` must be null or valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_create(
+    path: *const c_char,
+    identifier: u64,
+    content_crc: u32,
+    initial_state: *const u8,
+    initial_state_len: usize,
+    error: *mut c_int,
+) -> *mut Rply {
+    let mut handle = std::ptr::null_mut();
+    let code = guard(|| {
+        // SAFETY: as this function's contract
+        let (Some(path), Some(initial_state)) = (unsafe { self::path(path) }, unsafe {
+            slice(initial_state, initial_state_len)
+        }) else {
+            return RPLY_ERR_ARGUMENT;
+        };
+        let file = match File::create(path) {
+            Ok(file) => Box::into_raw(Box::new(BufWriter::new(file))),
+            Err(_) => return RPLY_ERR_IO,
+        };
+        let mut header = Header::V0V1(HeaderBase {
+            version: 2,
+            content_crc,
+            initial_state_size: 0,
+            identifier,
+        });
+        header.upgrade();
+        // SAFETY: `file` stays allocated until rply_close drops the encoder
+        match ReplayEncoder::new(header, initial_state, unsafe { &mut *file }) {
+            Ok(rply) => {
+                handle = Box::into_raw(Box::new(Rply {
+                    mode: Mode::Write {
+                        rply: Some(Box::new(rply)),
+                        file,
+                        frame: Frame::default(),
+                    },
+                    error: CString::default(),
+                }));
+                RPLY_OK
+            }
+            Err(e) => {
+                // SAFETY: the failed encoder no longer borrows `file`
+                drop(unsafe { Box::from_raw(file) });
+                code(&e)
+            }
+        }
+    });
+    // SAFETY: as this function's contract
+    unsafe { report(error, code) };
+    handle
+}
+
+/// The initial state of a replay opened for reading, with its length in
+/// `*len`; null with `*len` zero if there is none.
+///
+/// # Safety
+/// `rply` must be a handle from [`rply_open`] or [`rply_create`], and
+/// `len` must be valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_initial_state(rply: *const Rply, len: *mut usize) -> *const u8 {
+    // SAFETY: as this function's contract
+    let state = match unsafe { rply.as_ref() } {
+        Some(Rply {
+            mode: Mode::Read { rply, .. },
+            ..
+        }) if !rply.initial_state.is_empty() => rply.initial_state.as_slice(),
+        _ => &[],
+    };
+    if !len.is_null() {
+        // SAFETY: as this function's contract
+        unsafe { *len = state.len() };
+    }
+    if state.is_empty() {
+        std::ptr::null()
+    } else {
+        state.as_ptr()
+    }
+}
+
+/// The replay's identifier, or zero for a null handle
+///
+/// # Safety
+/// `rply` must be null or a handle from [`rply_open`] or [`rply_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_identifier(rply: *const Rply) -> u64 {
+    // SAFETY: as this function's contract
+    match unsafe { rply.as_ref() } {
+        Some(Rply {
+            mode: Mode::Read { rply, .. },
+            ..
+        }) => rply.header.identifier(),
+        Some(Rply {
+            mode: Mode::Write {
+                rply: Some(rply), ..
+            },
+            ..
+        }) => rply.header.identifier(),
+        _ => 0,
+    }
+}
+
+/// Reads the next frame into `*frame`, returning [`RPLY_END`] after the
+/// last one.
+///
+/// # Safety
+/// `rply` must be a handle from [`rply_open`], and `frame` must be valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_read_frame(rply: *mut Rply, frame: *mut RplyFrame) -> c_int {
+    // SAFETY: as this function's contract
+    let (Some(handle), Some(out)) = (unsafe { rply.as_mut() }, unsafe { frame.as_mut() }) else {
+        return RPLY_ERR_ARGUMENT;
+    };
+    guard(|| {
+        let Mode::Read {
+            rply,
+            frame,
+            keys,
+            inputs,
+        } = &mut handle.mode
+        else {
+            return handle.fail(RPLY_ERR_ARGUMENT, "replay was opened for writing");
+        };
+        let read = rply.at_end().and_then(|end| {
+            if !end {
+                rply.read_frame(frame)?;
+            }
+            Ok(end)
+        });
+        match read {
+            Ok(true) => return RPLY_END,
+            Ok(false) => {}
+            Err(e) => return handle.fail_with(&e),
+        }
+        keys.clear();
+        keys.extend(frame.key_events.iter().map(|k| RplyKey {
+            down: k.down,
+            modifiers: k.modf,
+            code: k.code,
+            character: k.chr,
+        }));
+        inputs.clear();
+        inputs.extend(frame.input_events.iter().map(|i| RplyInput {
+            port: i.port,
+            device: i.device,
+            idx: i.idx,
+            id: i.id,
+            val: i.val,
+        }));
+        *out = RplyFrame {
+            frame_number: rply.frame_number,
+            keys: keys.as_ptr(),
+            key_count: keys.len(),
+            inputs: inputs.as_ptr(),
+            input_count: inputs.len(),
+            checkpoint: frame.checkpoint_bytes.as_ptr(),
+            checkpoint_len: frame.checkpoint_bytes.len(),
+        };
+        RPLY_OK
+    })
+}
+
+/// Appends `*frame` to a replay opened for writing; its `frame_number` is
+/// ignored.
+///
+/// # Safety
+/// `rply` must be a handle from [`rply_create`], and `frame` must be valid
+/// with each pointer null or pointing to as many items as its count.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_write_frame(rply: *mut Rply, frame: *const RplyFrame) -> c_int {
+    // SAFETY: as this function's contract
+    let (Some(handle), Some(input)) = (unsafe { rply.as_mut() }, unsafe { frame.as_ref() }) else {
+        return RPLY_ERR_ARGUMENT;
+    };
+    // SAFETY: as this function's contract
+    let (Some(keys), Some(inputs), Some(checkpoint)) = (
+        unsafe { slice(input.keys, input.key_count) },
+        unsafe { slice(input.inputs, input.input_count) },
+        unsafe { slice(input.checkpoint, input.checkpoint_len) },
+    ) else {
+        return RPLY_ERR_ARGUMENT;
+    };
+    guard(|| {
+        let Mode::Write {
+            rply: Some(rply),
+            frame,
+            ..
+        } = &mut handle.mode
+        else {
+            return handle.fail(RPLY_ERR_ARGUMENT, "replay was opened for reading");
+        };
+        frame.clear();
+        frame.key_events.extend(keys.iter().map(|k| KeyData {
+            down: k.down,
+            modf: k.modifiers,
+            code: k.code,
+            chr: k.character,
+        }));
+        frame.input_events.extend(inputs.iter().map(|i| InputData {
+            port: i.port,
+            device: i.device,
+            idx: i.idx,
+            id: i.id,
+            val: i.val,
+        }));
+        frame.checkpoint_bytes.extend_from_slice(checkpoint);
+        match rply.write_frame(frame) {
+            Ok(()) => RPLY_OK,
+            Err(e) => handle.fail_with(&e),
+        }
+    })
+}
+
+/// A description of the handle's last error, valid until its next call;
+/// empty if there was none.
+///
+/// # Safety
+/// `rply` must be a handle from [`rply_open`] or [`rply_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_last_error(rply: *const Rply) -> *const c_char {
+    // SAFETY: as this function's contract
+    unsafe { rply.as_ref() }.map_or(c"".as_ptr(), |handle| handle.error.as_ptr())
+}
+
+/// Closes the handle, finishing the replay if it was opened for writing.
+/// The handle is freed even if finishing fails.
+///
+/// # Safety
+/// `rply` must be null or a handle from [`rply_open`] or [`rply_create`]
+/// that hasn't been closed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rply_close(rply: *mut Rply) -> c_int {
+    if rply.is_null() {
+        return RPLY_OK;
+    }
+    // SAFETY: as this function's contract
+    let mut handle = unsafe { Box::from_raw(rply) };
+    guard(move || {
+        let Mode::Write { rply, file, .. } = &mut handle.mode else {
+            return RPLY_OK;
+        };
+        let finished = rply.take().map_or(Ok(()), |mut rply| {
+            let finished = rply.finish();
+            // Dropping it would otherwise try again, and panic
+            rply.abandon();
+            finished
+        });
+        // SAFETY: the encoder borrowing `file` is gone
+        let mut file = unsafe { Box::from_raw(*file) };
+        match finished {
+            Ok(()) => match std::io::Write::flush(&mut file) {
+                Ok(()) => RPLY_OK,
+                Err(_) => RPLY_ERR_IO,
+            },
+            Err(e) => code(&e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capi_roundtrip() {
+        let path = std::env::temp_dir().join(format!("capi-{}.replay", std::process::id()));
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let mut error = RPLY_ERR_INTERNAL;
+        let state = [1_u8, 2, 3];
+        unsafe {
+            let rply = rply_create(
+                path.as_ptr(),
+                42,
+                7,
+                state.as_ptr(),
+                state.len(),
+                &raw mut error,
+            );
+            assert_eq!(error, RPLY_OK);
+            for i in 0..3_u8 {
+                let input = RplyInput {
+                    port: 0,
+                    device: 1,
+                    idx: 0,
+                    id: 8,
+                    val: i16::from(i),
+                };
+                let checkpoint = [i; 3];
+                let frame = RplyFrame {
+                    frame_number: 0,
+                    keys: std::ptr::null(),
+                    key_count: 0,
+                    inputs: &raw const input,
+                    input_count: 1,
+                    checkpoint: checkpoint.as_ptr(),
+                    checkpoint_len: if i == 2 { 3 } else { 0 },
+                };
+                assert_eq!(rply_write_frame(rply, &raw const frame), RPLY_OK);
+            }
+            let mut frame = std::mem::zeroed::<RplyFrame>();
+            assert_eq!(rply_read_frame(rply, &raw mut frame), RPLY_ERR_ARGUMENT);
+            assert!(!CStr::from_ptr(rply_last_error(rply)).is_empty());
+            assert_eq!(rply_close(rply), RPLY_OK);
+
+            let rply = rply_open(path.as_ptr(), &raw mut error);
+            assert_eq!(error, RPLY_OK);
+            assert_eq!(rply_identifier(rply), 42);
+            let mut len = 0;
+            let initial = rply_initial_state(rply, &raw mut len);
+            assert_eq!(std::slice::from_raw_parts(initial, len), state);
+            let mut vals = vec![];
+            while rply_read_frame(rply, &raw mut frame) == RPLY_OK {
+                vals.push((*frame.inputs).val);
+            }
+            assert_eq!(vals, [0, 1, 2]);
+            assert_eq!(frame.frame_number, 3);
+            assert_eq!(
+                std::slice::from_raw_parts(frame.checkpoint, frame.checkpoint_len),
+                [2, 2, 2]
+            );
+            assert_eq!(rply_close(rply), RPLY_OK);
+        }
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+        assert!(unsafe { rply_open(path.as_ptr(), &raw mut error) }.is_null());
+        assert_eq!(error, RPLY_ERR_IO);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn close_failing() {
+        // Writes to /dev/full fail for want of space
+        let mut error = RPLY_ERR_INTERNAL;
+        let full = c"/dev/full";
+        let path = std::env::temp_dir().join(format!("capi-close-{}.replay", std::process::id()));
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let rply = rply_create(full.as_ptr(), 1, 2, std::ptr::null(), 0, &raw mut error);
+            assert!(rply.is_null());
+            assert_eq!(error, RPLY_ERR_IO);
+            let rply = rply_create(path.as_ptr(), 1, 2, std::ptr::null(), 0, &raw mut error);
+            assert_eq!(error, RPLY_OK);
+            let Mode::Write { file, .. } = &mut (*rply).mode else {
+                unreachable!()
+            };
+            *(**file).get_mut() = File::options().write(true).open("/dev/full").unwrap();
+            assert_eq!(rply_close(rply), RPLY_ERR_IO);
+        }
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use crate::{
+    Compression, Encoding, InvalidDeterminant, ReplayError,
+    compression::{CompressionOptions, Compressor, Compressors},
+    delta,
+    regions::RegionsCodec,
+    statestream,
+};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// What a [`CheckpointCodec`] knows about the checkpoint being encoded or decoded.
+pub struct CheckpointContext<'a> {
+    /// Frame on which the checkpoint occurs (0 for the initial state)
+    pub frame: u64,
+    /// Uncompressed, unencoded bytes of the previous checkpoint in the stream (empty for the initial state)
+    pub previous: &'a [u8],
+}
+
+/// A checkpoint encoding scheme, identified in the stream by its encoding byte.
+/// Codecs see uncompressed data; compression is applied outside of them.
+/// They must be [`Send`] so that encoders and decoders can move between threads.
+pub trait CheckpointCodec: Send {
+    /// Writes `checkpoint` in this encoding, returning the number of encoded bytes written.
+    /// # Errors
+    /// Any I/O error from the writer, or an encoding-specific error wrapped in [`std::io::Error`].
+    fn encode(
+        &mut self,
+        writer: &mut dyn Write,
+        checkpoint: &[u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<u32>;
+    /// Reads one encoded checkpoint into `checkpoint`, which is already sized to the checkpoint's unencoded size.
+    /// # Errors
+    /// Any I/O error from the reader, or an encoding-specific error wrapped in [`std::io::Error`].
+    fn decode(
+        &mut self,
+        reader: &mut dyn Read,
+        checkpoint: &mut [u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<()>;
+}
+
+/// User-supplied [`CheckpointCodec`]s and [`Compressor`]s, keyed by encoding
+/// or compression byte.  Identifiers below [`CodecRegistry::FIRST_CUSTOM_ID`]
+/// are reserved for built-in encodings and compression schemes.
+#[derive(Default)]
+pub struct CodecRegistry {
+    codecs: HashMap<u8, Box<dyn CheckpointCodec>>,
+    compressors: Compressors,
+    options: CompressionOptions,
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<crate::EncryptionKey>,
+}
+
+impl CodecRegistry {
+    pub const FIRST_CUSTOM_ID: u8 = 128;
+    /// Registers `codec` under [`Encoding::Custom`]`(id)`, returning any codec previously registered there.
+    /// # Panics
+    /// If `id` is reserved for a built-in encoding.
+    pub fn register(
+        &mut self,
+        id: u8,
+        codec: Box<dyn CheckpointCodec>,
+    ) -> Option<Box<dyn CheckpointCodec>> {
+        assert!(
+            id >= Self::FIRST_CUSTOM_ID,
+            "Encoding {id} is reserved for built-in encodings"
+        );
+        self.codecs.insert(id, codec)
+    }
+    /// Registers `compressor` under [`Compression::Custom`]`(id)`, returning any compressor previously registered there.
+    /// # Panics
+    /// If `id` is reserved for a built-in compression scheme.
+    pub fn register_compressor(
+        &mut self,
+        id: u8,
+        compressor: Box<dyn Compressor>,
+    ) -> Option<Box<dyn Compressor>> {
+        self.compressors.register(id, compressor)
+    }
+    /// Sets the options the built-in compressors use, including for the
+    /// initial state of an encoder created with this registry.
+    pub fn set_compression_options(&mut self, options: CompressionOptions) {
+        self.compressors.set_options(options);
+        self.options = options;
+    }
+    /// Sets the key used to encrypt or decrypt the [`crate::EncryptedSections`] of a replay.
+    #[cfg(feature = "encryption")]
+    pub fn set_encryption_key(&mut self, key: crate::EncryptionKey) {
+        self.encryption_key = Some(key);
+    }
+}
+
+struct RawCodec;
+
+impl CheckpointCodec for RawCodec {
+    fn encode(
+        &mut self,
+        writer: &mut dyn Write,
+        checkpoint: &[u8],
+        _cx: &CheckpointContext,
+    ) -> std::io::Result<u32> {
+        writer.write_all(checkpoint)?;
+        u32::try_from(checkpoint.len())
+            .map_err(|e| std::io::Error::other(crate::ReplayError::CheckpointTooBig(e)))
+    }
+    fn decode(
+        &mut self,
+        reader: &mut dyn Read,
+        checkpoint: &mut [u8],
+        _cx: &CheckpointContext,
+    ) -> std::io::Result<()> {
+        reader.read_exact(checkpoint)
+    }
+}
+
+pub(crate) struct StatestreamCodec {
+    pub(crate) ctx: statestream::Ctx,
+    /* Checkpoints with their regions aligned to blocks, if the state has regions */
+    aligned: Vec<u8>,
+}
+
+impl CheckpointCodec for StatestreamCodec {
+    fn encode(
+        &mut self,
+        mut writer: &mut dyn Write,
+        checkpoint: &[u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<u32> {
+        // Regions are aligned differently from the previous checkpoint, so
+        // they aren't XORed with it
+        if self.ctx.has_regions() {
+            self.ctx.align(checkpoint, &mut self.aligned);
+            return statestream::Encoder::new(&mut writer, &mut self.ctx)
+                .encode_checkpoint(&self.aligned, cx.frame);
+        }
+        statestream::Encoder::new(&mut writer, &mut self.ctx).encode_checkpoint_against(
+            checkpoint,
+            cx.previous,
+            cx.frame,
+        )
+    }
+    fn decode(
+        &mut self,
+        mut reader: &mut dyn Read,
+        checkpoint: &mut [u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<()> {
+        if !self.ctx.has_regions() {
+            let mut ss_decoder =
+                statestream::Decoder::new(&mut reader, &mut self.ctx, checkpoint.len());
+            std::io::copy(&mut ss_decoder, &mut std::io::Cursor::new(&mut *checkpoint))?;
+            if let Some(basis) = ss_decoder.basis() {
+                if xxhash_rust::xxh3::xxh3_64(cx.previous) != basis {
+                    return Err(std::io::Error::other(ReplayError::SkippedCheckpoints()));
+                }
+                statestream::xor_into(checkpoint, cx.previous);
+            }
+            return Ok(());
+        }
+        self.aligned
+            .resize(self.ctx.aligned_len(checkpoint.len()), 0);
+        let mut ss_decoder =
+            statestream::Decoder::new(&mut reader, &mut self.ctx, self.aligned.len());
+        std::io::copy(
+            &mut ss_decoder,
+            &mut std::io::Cursor::new(&mut self.aligned[..]),
+        )?;
+        self.ctx.unalign(&self.aligned, checkpoint);
+        Ok(())
+    }
+}
+
+struct DeltaCodec;
+
+impl CheckpointCodec for DeltaCodec {
+    fn encode(
+        &mut self,
+        mut writer: &mut dyn Write,
+        checkpoint: &[u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<u32> {
+        delta::encode(&mut writer, cx.previous, checkpoint)
+    }
+    fn decode(
+        &mut self,
+        mut reader: &mut dyn Read,
+        checkpoint: &mut [u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<()> {
+        delta::decode(&mut reader, cx.previous, checkpoint)
+    }
+}
+
+/// The built-in codecs and compressors plus any registered ones, as owned by an encoder or decoder.
+pub(crate) struct Codecs {
+    raw: RawCodec,
+    pub(crate) statestream: StatestreamCodec,
+    delta: DeltaCodec,
+    pub(crate) regions: RegionsCodec,
+    custom: CodecRegistry,
+    pub(crate) stats: crate::clock::Stats,
+}
+
+impl Codecs {
+    pub(crate) fn new(block_size: u32, superblock_size: u32, custom: CodecRegistry) -> Self {
+        let ctx = statestream::Ctx::new(block_size, superblock_size);
+        let stats = ctx.stats.share();
+        let mut regions = RegionsCodec::new(block_size, superblock_size);
+        regions.ctx.stats = ctx.stats.share();
+        regions.compressors.set_options(custom.options);
+        Self {
+            raw: RawCodec,
+            statestream: StatestreamCodec {
+                ctx,
+                aligned: vec![],
+            },
+            delta: DeltaCodec,
+            regions,
+            custom,
+            stats,
+        }
+    }
+    /// Finds the codec and compressor for a checkpoint.
+    pub(crate) fn get_mut(
+        &mut self,
+        encoding: Encoding,
+        compression: Compression,
+    ) -> Result<(&mut dyn CheckpointCodec, &dyn Compressor), ReplayError> {
+        let compressor =
+            self.custom
+                .compressors
+                .get(compression)
+                .ok_or(ReplayError::Compression(InvalidDeterminant(u8::from(
+                    compression,
+                ))))?;
+        let codec: &mut dyn CheckpointCodec = match encoding {
+            Encoding::Raw => &mut self.raw,
+            Encoding::Statestream => &mut self.statestream,
+            Encoding::Delta => &mut self.delta,
+            Encoding::Regions => &mut self.regions,
+            Encoding::Custom(id) => match self.custom.codecs.get_mut(&id) {
+                Some(codec) => codec.as_mut(),
+                None => return Err(ReplayError::Encoding(InvalidDeterminant(id))),
+            },
+        };
+        Ok((codec, compressor))
+    }
+    pub(crate) fn register(
+        &mut self,
+        id: u8,
+        codec: Box<dyn CheckpointCodec>,
+    ) -> Option<Box<dyn CheckpointCodec>> {
+        self.custom.register(id, codec)
+    }
+    pub(crate) fn register_compressor(
+        &mut self,
+        id: u8,
+        compressor: Box<dyn Compressor>,
+    ) -> Option<Box<dyn Compressor>> {
+        self.custom.register_compressor(id, compressor)
+    }
+    pub(crate) fn set_compression_options(&mut self, options: CompressionOptions) {
+        self.custom.set_compression_options(options);
+        self.regions.compressors.set_options(options);
+    }
+    /// Loads the header's zstd dictionary, if it has one.
+    pub(crate) fn load_zstd_dictionary(&mut self, metadata: &crate::Metadata) {
+        if let Some(dictionary) = metadata.zstd_dictionary() {
+            self.custom.compressors.set_zstd_dictionary(dictionary);
+            self.regions.compressors.set_zstd_dictionary(dictionary);
+        }
+    }
+    /// Aligns statestream blocks to the header's state regions, if it has
+    /// any, and splits [`Encoding::Regions`] checkpoints along them.
+    pub(crate) fn load_state_regions(&mut self, metadata: &crate::Metadata) {
+        if let Some(regions) = metadata.state_regions() {
+            self.statestream.ctx.set_regions(&regions);
+            self.regions.set_regions(&regions);
+        }
+    }
+    /* The statestream settings below apply to both sets of statestream tables */
+    pub(crate) fn set_commit_settings(&mut self, interval: u8, threshold: u8) {
+        self.statestream
+            .ctx
+            .set_commit_settings(interval, threshold);
+        self.regions.ctx.set_commit_settings(interval, threshold);
+    }
+    pub(crate) fn set_memory_budget(&mut self, bytes: Option<usize>) {
+        self.statestream.ctx.set_memory_budget(bytes);
+        self.regions.ctx.set_memory_budget(bytes);
+    }
+    pub(crate) fn set_runs(&mut self, runs: bool) {
+        self.statestream.ctx.set_runs(runs);
+        self.regions.ctx.set_runs(runs);
+    }
+    pub(crate) fn set_block_hash(&mut self, hash: crate::BlockHash, trust: bool) {
+        self.statestream.ctx.set_block_hash(hash, trust);
+        self.regions.ctx.set_block_hash(hash, trust);
+    }
+    /* Regions checkpoints split states differently, so only plain
+    statestream checkpoints look ahead */
+    pub(crate) fn set_lookahead<'s>(&mut self, states: impl IntoIterator<Item = &'s [u8]>) {
+        self.statestream.ctx.set_lookahead(states);
+    }
+    /* Only whole-state statestream tables are seeded: a regions decoder
+    doesn't know how the regions are grouped until it reads a checkpoint */
+    pub(crate) fn seed(&mut self, state: &[u8]) {
+        let ss = &mut self.statestream;
+        if ss.ctx.has_regions() {
+            ss.ctx.align(state, &mut ss.aligned);
+            ss.ctx.seed(&ss.aligned);
+        } else {
+            ss.ctx.seed(state);
+        }
+    }
+    /* Only plain statestream checkpoints are XORed, not regions */
+    pub(crate) fn set_xor(&mut self, xor: bool) {
+        self.statestream.ctx.set_xor(xor);
+    }
+    pub(crate) fn has_xor(&self) -> bool {
+        self.statestream.ctx.has_xor()
+    }
+    pub(crate) fn set_max_index_entries(&mut self, max: usize) {
+        self.statestream.ctx.set_max_index_entries(max);
+        self.regions.ctx.set_max_index_entries(max);
+    }
+    pub(crate) fn table_bytes(&self) -> usize {
+        self.statestream.ctx.table_bytes() + self.regions.ctx.table_bytes()
+    }
+    pub(crate) fn has_evicted(&self) -> bool {
+        self.statestream.ctx.has_evicted() || self.regions.ctx.has_evicted()
+    }
+    pub(crate) fn stop_evicting(&mut self) {
+        self.statestream.ctx.stop_evicting();
+        self.regions.ctx.stop_evicting();
+    }
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encryption_key(&self) -> Option<&crate::EncryptionKey> {
+        self.custom.encryption_key.as_ref()
+    }
+}
+
+#[cfg(all(test, feature = "zlib"))]
+mod tests {
+    use super::*;
+    use crate::{Compression, Frame, Header, HeaderBase, HeaderV2, ReplayError};
+
+    /* Stores each byte inverted */
+    struct Invert;
+    impl CheckpointCodec for Invert {
+        fn encode(
+            &mut self,
+            writer: &mut dyn Write,
+            checkpoint: &[u8],
+            _cx: &CheckpointContext,
+        ) -> std::io::Result<u32> {
+            let inverted: Vec<u8> = checkpoint.iter().map(|b| !b).collect();
+            writer.write_all(&inverted)?;
+            Ok(u32::try_from(inverted.len()).unwrap())
+        }
+        fn decode(
+            &mut self,
+            reader: &mut dyn Read,
+            checkpoint: &mut [u8],
+            _cx: &CheckpointContext,
+        ) -> std::io::Result<()> {
+            reader.read_exact(checkpoint)?;
+            for b in checkpoint {
+                *b = !*b;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_codec() {
+        let header = Header::V2(HeaderV2 {
+            base: HeaderBase {
+                version: 2,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 0,
+            },
+            frame_count: 0,
+            block_size: 16,
+            superblock_size: 4,
+            checkpoint_commit_interval: 4,
+            checkpoint_commit_threshold: 2,
+            checkpoint_compression: Compression::Zlib,
+            encrypted_sections: crate::EncryptedSections::default(),
+            metadata: crate::Metadata::default(),
+        });
+        let state: Vec<u8> = (0..200_u8).collect();
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = crate::encode(header, &state, &mut out).unwrap();
+            assert!(enc.register_codec(200, Box::new(Invert)).is_none());
+            enc.set_checkpoint_encoding(Encoding::Custom(200));
+            let mut frame = Frame::default();
+            enc.write_frame(&frame).unwrap();
+            frame.checkpoint_bytes = state.iter().rev().copied().collect();
+            enc.write_frame(&frame).unwrap();
+            enc.finish().unwrap();
+        }
+        let bytes = out.into_inner();
+        let mut frame = Frame::default();
+        let mut dec = crate::decode(bytes.as_slice()).unwrap();
+        dec.read_frame(&mut frame).unwrap();
+        assert!(matches!(
+            dec.read_frame(&mut frame).map_err(ReplayError::into_root),
+            Err(ReplayError::Encoding(_))
+        ));
+        let mut registry = CodecRegistry::default();
+        registry.register(200, Box::new(Invert));
+        let mut dec = crate::ReplayDecoder::with_codecs(bytes.as_slice(), registry).unwrap();
+        assert_eq!(dec.initial_state, state);
+        dec.read_frame(&mut frame).unwrap();
+        dec.read_frame(&mut frame).unwrap();
+        assert_eq!(frame.checkpoint_encoding, Encoding::Custom(200));
+        assert_eq!(
+            frame.checkpoint_bytes,
+            state.iter().rev().copied().collect::<Vec<_>>()
+        );
+    }
+}
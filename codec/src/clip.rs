@@ -0,0 +1,333 @@
+use crate::{Core, Frame, ReplayDecoder, ReplayEncoder, ReplayError};
+use std::io::{BufRead, Seek, Write};
+use std::ops::Range;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// Writes frames `from..to` of `rply` to `out` as a standalone replay with
+/// the same header settings and metadata.  A replay can only start from a
+/// known state, so the clip's initial state is the last checkpoint at or
+/// before frame `from` (or the replay's initial state).  With a `core`,
+/// the frames from that checkpoint up to `from` are re-simulated so the
+/// clip starts exactly at `from`; without one, the clip starts at the
+/// checkpoint instead.  `to` is clamped to the end of the replay.
+///
+/// Returns the frame of `rply` the clip starts at.
+/// # Errors
+/// [`ReplayError::ClipRange`]: `from` is not before `to` or the end of the replay
+/// [`ReplayError::CoreState`]: The core failed to save or load a state
+/// Any error from decoding `rply` or encoding the clip
+pub fn clip<R: BufRead + Seek, W: Write + Seek>(
+    rply: &mut ReplayDecoder<R>,
+    from: u64,
+    to: u64,
+    out: &mut W,
+    core: Option<&mut dyn Core>,
+) -> Result<u64> {
+    if from >= to {
+        return Err(ReplayError::ClipRange(from, to));
+    }
+    rply.seek_to_frame(0)?;
+    let mut start = 0;
+    let mut start_state = rply.initial_state.clone();
+    /* Frames since the last checkpoint before `from` */
+    let mut lead_in = vec![];
+    while rply.frame_number < from {
+        if rply.at_end()? {
+            return Err(ReplayError::ClipRange(from, to));
+        }
+        let mut frame = Frame::default();
+        rply.read_frame(&mut frame)?;
+        if frame.checkpoint_bytes.is_empty() {
+            lead_in.push(frame);
+        } else {
+            start = rply.frame_number;
+            start_state = std::mem::take(&mut frame.checkpoint_bytes);
+            lead_in.clear();
+        }
+    }
+    if rply.at_end()? {
+        return Err(ReplayError::ClipRange(from, to));
+    }
+    if let Some(core) = core
+        && start < from
+    {
+        if !core.load_state(&start_state) {
+            return Err(ReplayError::CoreState(start));
+        }
+        for frame in &lead_in {
+            core.run_frame(frame);
+        }
+        if !core.save_state(&mut start_state) {
+            return Err(ReplayError::CoreState(from));
+        }
+        start = from;
+        lead_in.clear();
+    }
+    let mut header = rply.header.clone();
+    header.set_initial_state_size(0);
+    let mut enc = ReplayEncoder::new(header, &start_state, out)?;
+    for frame in &lead_in {
+        enc.write_frame(frame)?;
+    }
+    let mut frame = Frame::default();
+    while rply.frame_number < to && !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        enc.write_frame(&frame)?;
+    }
+    enc.finish()?;
+    Ok(start)
+}
+
+/// Extracts `range` of `rply` into a new replay written to `out`, starting
+/// from the nearest checkpoint at or before `range.start`.  This is
+/// [`clip`] without a core.
+///
+/// Returns the frame of `rply` the new replay starts at.
+/// # Errors
+/// As [`clip`]
+pub fn trim<R: BufRead + Seek, W: Write + Seek>(
+    rply: &mut ReplayDecoder<R>,
+    range: Range<u64>,
+    out: &mut W,
+) -> Result<u64> {
+    clip(rply, range.start, range.end, out, None)
+}
+
+/// Writes the rest of `rply` to `out`, keeping only every `n`th of its
+/// checkpoints, starting with the first (none if `n` is zero), e.g. for a
+/// replay checkpointed far more often than anyone seeks in it.  `out`
+/// encodes the kept checkpoints afresh, so their statestream tokens and
+/// sizes only refer to each other.  To add checkpoints instead, see
+/// [`crate::Verifier::regenerate`].
+///
+/// Returns how many checkpoints were kept.
+/// # Errors
+/// Any error from decoding `rply` or writing `out`
+pub fn thin_checkpoints<R: BufRead, W: Write + Seek>(
+    rply: &mut ReplayDecoder<R>,
+    n: u64,
+    out: &mut ReplayEncoder<'_, W>,
+) -> Result<u64> {
+    let mut seen = 0;
+    let mut kept = 0;
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        if !frame.checkpoint_bytes.is_empty() {
+            if n > 0 && seen % n == 0 {
+                kept += 1;
+            } else {
+                frame.checkpoint_bytes.clear();
+            }
+            seen += 1;
+        }
+        out.write_frame(&frame)?;
+    }
+    Ok(kept)
+}
+
+/// Writes the frames of `first` followed by those of `second` to `out` as
+/// one replay, with `first`'s header settings, metadata, and initial state.
+/// `second` is assumed to start where `first` ends, so its initial state
+/// is dropped.
+///
+/// With a `core`, the junction is checked before anything is written: the
+/// core runs from `first`'s last checkpoint across the junction to
+/// `second`'s first checkpoint, and the splice fails if its state doesn't
+/// match that checkpoint.  Returns the frame of the spliced replay whose
+/// checkpoint confirmed the junction, or `None` if there was no core or
+/// `second` has no checkpoints.
+/// # Errors
+/// [`ReplayError::SpliceDesync`]: The checkpoint after the junction doesn't
+/// match the core's state; carries its frame and the differing byte ranges
+/// [`ReplayError::CoreState`]: The core failed to save or load a state
+/// Any error from decoding either replay or encoding the splice
+pub fn splice<A: BufRead + Seek, B: BufRead + Seek, W: Write + Seek>(
+    first: &mut ReplayDecoder<A>,
+    second: &mut ReplayDecoder<B>,
+    out: &mut W,
+    core: Option<&mut dyn Core>,
+) -> Result<Option<u64>> {
+    let checked = match core {
+        Some(core) => check_junction(first, second, core)?,
+        None => None,
+    };
+    first.seek_to_frame(0)?;
+    second.seek_to_frame(0)?;
+    let mut header = first.header.clone();
+    header.set_initial_state_size(0);
+    let mut enc = ReplayEncoder::new(header, &first.initial_state, out)?;
+    let mut frame = Frame::default();
+    while !first.at_end()? {
+        first.read_frame(&mut frame)?;
+        enc.write_frame(&frame)?;
+    }
+    while !second.at_end()? {
+        second.read_frame(&mut frame)?;
+        enc.write_frame(&frame)?;
+    }
+    enc.finish()?;
+    Ok(checked)
+}
+
+/* Runs `core` from the last checkpoint of `first` to the first checkpoint of `second` */
+fn check_junction<A: BufRead + Seek, B: BufRead + Seek>(
+    first: &mut ReplayDecoder<A>,
+    second: &mut ReplayDecoder<B>,
+    core: &mut dyn Core,
+) -> Result<Option<u64>> {
+    first.seek_to_frame(0)?;
+    let mut start = 0;
+    let mut state = first.initial_state.clone();
+    /* Frames since the last checkpoint of `first` */
+    let mut lead_in = vec![];
+    while !first.at_end()? {
+        let mut frame = Frame::default();
+        first.read_frame(&mut frame)?;
+        if frame.checkpoint_bytes.is_empty() {
+            lead_in.push(frame);
+        } else {
+            start = first.frame_number;
+            state = std::mem::take(&mut frame.checkpoint_bytes);
+            lead_in.clear();
+        }
+    }
+    let junction = first.frame_number;
+    if !state.is_empty() && !core.load_state(&state) {
+        return Err(ReplayError::CoreState(start));
+    }
+    for frame in &lead_in {
+        core.run_frame(frame);
+    }
+    second.seek_to_frame(0)?;
+    let mut frame = Frame::default();
+    while !second.at_end()? {
+        second.read_frame(&mut frame)?;
+        core.run_frame(&frame);
+        if !frame.checkpoint_bytes.is_empty() {
+            let checkpoint = junction + second.frame_number;
+            if !core.save_state(&mut state) {
+                return Err(ReplayError::CoreState(checkpoint));
+            }
+            if state != frame.checkpoint_bytes {
+                return Err(ReplayError::SpliceDesync(
+                    checkpoint,
+                    crate::verify::differences(&state, &frame.checkpoint_bytes),
+                ));
+            }
+            return Ok(Some(checkpoint));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::{Counter, replay};
+
+    #[test]
+    fn clips() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+
+        // Without a core the clip starts at the checkpoint before frame 130
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(clip(&mut rply, 130, 200, &mut out, None).unwrap(), 120);
+        let mut clipped = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        assert_eq!(clipped.header.frame_count(), Some(80));
+        let mut core = Counter(vec![]);
+        core.load_state(&clipped.initial_state);
+        assert_eq!(core.0[0], 120);
+        let frames = clipped.frames().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(frames[0].input_events[0].val, 121 * 7 % 300);
+
+        // With a core it starts exactly there, and still syncs
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(
+            clip(&mut rply, 130, 2000, &mut out, Some(&mut Counter(vec![]))).unwrap(),
+            130
+        );
+        let mut clipped = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        assert_eq!(clipped.header.frame_count(), Some(870));
+        let verification = crate::Verifier::new(Counter(vec![]))
+            .verify(&mut clipped, None, |_| Ok(()))
+            .unwrap();
+        assert_eq!(verification.desync, None);
+        assert_eq!(verification.checkpoints, 22);
+
+        let mut trimmed = std::io::Cursor::new(vec![]);
+        assert_eq!(trim(&mut rply, 130..200, &mut trimmed).unwrap(), 120);
+        let mut out = std::io::Cursor::new(vec![]);
+        clip(&mut rply, 130, 200, &mut out, None).unwrap();
+        assert_eq!(trimmed.into_inner(), out.get_ref().clone());
+
+        assert!(matches!(
+            clip(&mut rply, 1000, 1100, &mut out, None),
+            Err(ReplayError::ClipRange(1000, 1100))
+        ));
+    }
+
+    #[test]
+    fn splices() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut head = std::io::Cursor::new(vec![]);
+        clip(&mut rply, 0, 130, &mut head, None).unwrap();
+        let mut tail = std::io::Cursor::new(vec![]);
+        clip(&mut rply, 130, 1000, &mut tail, Some(&mut Counter(vec![]))).unwrap();
+        let mut head = crate::decode(std::io::Cursor::new(head.into_inner())).unwrap();
+        let mut tail = crate::decode(std::io::Cursor::new(tail.into_inner())).unwrap();
+
+        // The tail's first checkpoint is frame 160 of the original
+        let mut out = std::io::Cursor::new(vec![]);
+        let checked = splice(&mut head, &mut tail, &mut out, Some(&mut Counter(vec![])));
+        assert_eq!(checked.unwrap(), Some(160));
+        let mut spliced = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        assert_eq!(spliced.header.frame_count(), Some(1000));
+        let verification = crate::verify(&mut spliced, &mut Counter(vec![])).unwrap();
+        assert_eq!((verification.desync, verification.checkpoints), (None, 25));
+
+        // The whole replay starts at frame 0, not where the head ends
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut whole = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let err = splice(&mut head, &mut whole, &mut out, Some(&mut Counter(vec![])));
+        assert!(matches!(err, Err(ReplayError::SpliceDesync(170, _))));
+        assert!(out.get_ref().is_empty());
+
+        // Without a core the junction isn't checked
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(splice(&mut head, &mut whole, &mut out, None).unwrap(), None);
+        let spliced = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        assert_eq!(spliced.header.frame_count(), Some(1130));
+    }
+
+    #[test]
+    fn thins_checkpoints() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc =
+                crate::encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+            assert_eq!(thin_checkpoints(&mut rply, 5, &mut enc).unwrap(), 5);
+        }
+        let thinned = out.into_inner();
+        assert!(thinned.len() < bytes.len());
+        let mut original = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut rply = crate::decode(std::io::Cursor::new(&thinned)).unwrap();
+        let mut kept = vec![];
+        for (i, (frame, original)) in rply.frames().zip(original.frames()).enumerate() {
+            let (frame, original) = (frame.unwrap(), original.unwrap());
+            assert_eq!(frame.input_events, original.input_events);
+            if !frame.checkpoint_bytes.is_empty() {
+                assert_eq!(frame.checkpoint_bytes, original.checkpoint_bytes);
+                kept.push(i + 1);
+            }
+        }
+        assert_eq!(kept, [40, 240, 440, 640, 840]);
+        let verification = crate::verify(&mut rply, &mut Counter(vec![])).unwrap();
+        assert_eq!((verification.frames, verification.checkpoints), (1000, 5));
+    }
+}
@@ -1,4 +1,6 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 #[repr(usize)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -25,6 +27,22 @@ pub enum Counter {
     EncTotalKBsOut,
     DecSkippedSuperblocks,
     DecSkippedBlocks,
+    /// Zero bytes appended to fill a state's partial last block
+    EncPaddingBytes,
+    /// Zero-block entries filling a state's partial last superblock
+    EncPaddingBlocks,
+    DecPaddingBytes,
+    DecPaddingBlocks,
+    /// Padding that broke the rules: padding entries other than the zero
+    /// block, or superblocks past the end of the state
+    DecBadPadding,
+    /// Blocks and superblocks evicted by checkpoint commits
+    EncEvictedBlocks,
+    EncEvictedSuperblocks,
+    DecEvictedBlocks,
+    DecEvictedSuperblocks,
+    /// Superblock sequence entries sent as runs rather than one by one
+    EncRunSuperblocks,
     Count,
 }
 static TIME_ACC: [AtomicU64; Timer::Count as usize] = [
@@ -55,30 +73,151 @@ static COUNTS: [AtomicU64; Counter::Count as usize] = [
     AtomicU64::new(0),
     AtomicU64::new(0),
     AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
 ];
 
-pub struct Stopwatch(Timer, std::time::Instant);
-impl Stopwatch {
-    fn new(t: Timer) -> Self {
-        Self(t, std::time::Instant::now())
+/// Timer and counter totals for one encoder or decoder, as returned by
+/// [`crate::ReplayDecoder::stats`] and [`crate::ReplayEncoder::stats`].
+/// The process-wide totals from [`stats`] and [`counts`] add up every
+/// encoder and decoder's.
+#[derive(Debug, Default)]
+pub struct Stats(std::sync::Arc<Accumulators>);
+
+#[derive(Debug)]
+struct Accumulators {
+    time_acc: [AtomicU64; Timer::Count as usize],
+    time_counts: [AtomicU64; Timer::Count as usize],
+    counts: [AtomicU64; Counter::Count as usize],
+    tracing: AtomicBool,
+    spans: Mutex<Vec<Span>>,
+}
+
+impl Default for Accumulators {
+    fn default() -> Self {
+        Self {
+            time_acc: std::array::from_fn(|_| AtomicU64::new(0)),
+            time_counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            tracing: AtomicBool::new(false),
+            spans: Mutex::new(vec![]),
+        }
     }
 }
-impl Drop for Stopwatch {
-    fn drop(&mut self) {
-        TIME_ACC[self.0 as usize].fetch_add(
-            u64::try_from(self.1.elapsed().as_micros()).unwrap_or(u64::MAX),
-            Ordering::Relaxed,
-        );
-        TIME_COUNTS[self.0 as usize].fetch_add(1, Ordering::Relaxed);
+
+/// One timing taken while tracing (see [`Stats::set_tracing`]).  Spans of
+/// a frame contain those of its checkpoint, which contain its statestream
+/// encoding or decoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub timer: Timer,
+    /// Microseconds from the first span any [`Stats`] recorded in this
+    /// process, so spans of different encoders and decoders line up
+    pub start_micros: u64,
+    pub micros: u64,
+}
+
+/* What span starts count from */
+static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+impl Stats {
+    /* Another handle onto the same totals, for the parts of a codec that
+    count separately */
+    pub(crate) fn share(&self) -> Self {
+        Self(self.0.clone())
+    }
+    pub(crate) fn time(&self, t: Timer) -> Stopwatch {
+        Stopwatch(t, std::time::Instant::now(), self.share())
+    }
+    pub(crate) fn count(&self, c: Counter, amt: u64) -> u64 {
+        COUNTS[c as usize].fetch_add(amt, Ordering::Relaxed);
+        self.0.counts[c as usize].fetch_add(amt, Ordering::Relaxed) + amt
+    }
+    #[must_use]
+    pub fn stats(&self, t: Timer) -> Times {
+        Times {
+            count: self.0.time_counts[t as usize].load(Ordering::Relaxed),
+            micros: self.0.time_acc[t as usize].load(Ordering::Relaxed),
+        }
+    }
+    #[must_use]
+    pub fn counts(&self, c: Counter) -> u64 {
+        self.0.counts[c as usize].load(Ordering::Relaxed)
+    }
+    /// Starts or stops keeping a [`Span`] for every timing, on top of the
+    /// totals; they take 24 bytes each, a few per frame.
+    pub fn set_tracing(&self, tracing: bool) {
+        EPOCH.get_or_init(std::time::Instant::now);
+        self.0.tracing.store(tracing, Ordering::Relaxed);
+    }
+    /// The spans kept so far, in the order they ended.
+    #[must_use]
+    pub fn spans(&self) -> Vec<Span> {
+        self.0.spans.lock().unwrap().clone()
     }
 }
 
-pub fn time(t: Timer) -> Stopwatch {
-    Stopwatch::new(t)
+/// Writes the spans of each named [`Stats`] as a Chrome tracing JSON file,
+/// which Perfetto and `chrome://tracing` open, with a track per name.
+/// # Errors
+/// If writing to `out` fails
+pub fn write_trace(mut out: impl Write, tracks: &[(&str, &Stats)]) -> std::io::Result<()> {
+    write!(out, "{{\"traceEvents\":[")?;
+    for (tid, (name, stats)) in tracks.iter().enumerate() {
+        let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+        let sep = if tid == 0 { "" } else { "," };
+        write!(
+            out,
+            "{sep}\n{{\"ph\":\"M\",\"name\":\"thread_name\",\"pid\":1,\"tid\":{tid},\"args\":{{\"name\":\"{name}\"}}}}"
+        )?;
+        for span in stats.0.spans.lock().unwrap().iter() {
+            write!(
+                out,
+                ",\n{{\"ph\":\"X\",\"name\":\"{:?}\",\"pid\":1,\"tid\":{tid},\"ts\":{},\"dur\":{}}}",
+                span.timer, span.start_micros, span.micros
+            )?;
+        }
+    }
+    writeln!(out, "\n]}}")
 }
-pub fn count(c: Counter, amt: u64) -> u64 {
-    COUNTS[c as usize].fetch_add(amt, Ordering::Relaxed) + amt
+
+pub struct Stopwatch(Timer, std::time::Instant, Stats);
+impl Drop for Stopwatch {
+    fn drop(&mut self) {
+        let micros = u64::try_from(self.1.elapsed().as_micros()).unwrap_or(u64::MAX);
+        let accs = &self.2.0;
+        if accs.tracing.load(Ordering::Relaxed) {
+            let epoch = *EPOCH.get_or_init(std::time::Instant::now);
+            let since = |instant: std::time::Instant| {
+                let micros = instant.saturating_duration_since(epoch).as_micros();
+                u64::try_from(micros).unwrap_or(u64::MAX)
+            };
+            // Rounding the ends rather than the length keeps spans nested
+            let start = since(self.1);
+            accs.spans.lock().unwrap().push(Span {
+                timer: self.0,
+                start_micros: start,
+                micros: since(std::time::Instant::now()) - start,
+            });
+        }
+        for (acc, count) in [
+            (&TIME_ACC, &TIME_COUNTS),
+            (&accs.time_acc, &accs.time_counts),
+        ] {
+            acc[self.0 as usize].fetch_add(micros, Ordering::Relaxed);
+            count[self.0 as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
 }
+
 pub struct Times {
     pub count: u64,
     pub micros: u64,
@@ -92,3 +231,47 @@ pub fn stats(t: Timer) -> Times {
 pub fn counts(c: Counter) -> u64 {
     COUNTS[c as usize].load(Ordering::Relaxed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Frame;
+
+    #[test]
+    fn trace() {
+        let bytes = crate::verify::tests::replay(None);
+        let mut rply = crate::decode(bytes.as_slice()).unwrap();
+        rply.stats().set_tracing(true);
+        let mut frame = Frame::default();
+        for _ in 0..1000 {
+            rply.read_frame(&mut frame).unwrap();
+        }
+        let spans = rply.stats().spans();
+        let frames: Vec<_> = spans
+            .iter()
+            .filter(|span| span.timer == Timer::DecodeFrame)
+            .collect();
+        assert_eq!(frames.len(), 1000);
+        // Checkpoints and their statestream decoding happen inside frames
+        for span in &spans {
+            if span.timer != Timer::DecodeFrame {
+                assert!(frames.iter().any(|frame| {
+                    frame.start_micros <= span.start_micros
+                        && span.start_micros + span.micros <= frame.start_micros + frame.micros
+                }));
+            }
+        }
+        assert!(
+            spans
+                .iter()
+                .any(|span| span.timer == Timer::DecodeStatestream)
+        );
+        let mut out = vec![];
+        write_trace(&mut out, &[("Decoder \"1\"", rply.stats())]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("{\"traceEvents\":[\n{\"ph\":\"M\""));
+        assert!(out.contains("\"name\":\"Decoder \\\"1\\\"\""));
+        assert_eq!(out.matches("\"name\":\"DecodeFrame\"").count(), 1000);
+        assert!(out.ends_with("\n]}\n"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{ReplayError, Verification, metadata::Metadata};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// Metadata chunk holding a replay's [`CompatMatrix`]
+pub const COMPAT: crate::ChunkTag = *b"COMP";
+
+/// The outcome of verifying a replay with one build of one core.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompatEntry {
+    /// Core name, e.g. its libretro library name
+    pub core: String,
+    pub version: String,
+    /// Core options that affect emulation, in whatever form the caller uses consistently
+    pub options: String,
+    /// Verification outcome; [`Verification::differences`] is not stored
+    pub result: Verification,
+}
+
+impl CompatEntry {
+    #[must_use]
+    pub fn synced(&self) -> bool {
+        self.result.desync.is_none()
+    }
+}
+
+/// Which core builds a replay has been verified with, and whether each
+/// still syncs.  Kept in a sidecar or in the replay's [`Metadata`] so that
+/// archives can notice when a core update breaks old replays.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompatMatrix {
+    entries: Vec<CompatEntry>,
+}
+
+impl CompatMatrix {
+    pub fn entries(&self) -> &[CompatEntry] {
+        &self.entries
+    }
+    /// The recorded result for this core, version, and options, if any.
+    #[must_use]
+    pub fn get(&self, core: &str, version: &str, options: &str) -> Option<&CompatEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.core == core && e.version == version && e.options == options)
+    }
+    /// Records a result, replacing any earlier one for the same core, version, and options.
+    pub fn record(&mut self, entry: CompatEntry) {
+        match self.entries.iter_mut().find(|e| {
+            e.core == entry.core && e.version == entry.version && e.options == entry.options
+        }) {
+            Some(old) => *old = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// # Errors
+    /// [`ReplayError::Metadata`]: Malformed matrix
+    /// [`ReplayError::IO`]: Error reading the matrix
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+            let len = reader.read_u32::<LittleEndian>()?;
+            // Grown as the string is read, so a bad length can't claim 4 GiB up front
+            let mut bytes = vec![];
+            reader.take(u64::from(len)).read_to_end(&mut bytes)?;
+            if bytes.len() != len as usize {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            String::from_utf8(bytes).map_err(|_| ReplayError::Metadata())
+        }
+        let count = reader.read_u32::<LittleEndian>()?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let core = read_string(reader)?;
+            let version = read_string(reader)?;
+            let options = read_string(reader)?;
+            let frames = reader.read_u64::<LittleEndian>()?;
+            let checkpoints = reader.read_u64::<LittleEndian>()?;
+            let desync = match reader.read_u8()? {
+                0 => None,
+                1 => Some(reader.read_u64::<LittleEndian>()?),
+                _ => return Err(ReplayError::Metadata()),
+            };
+            entries.push(CompatEntry {
+                core,
+                version,
+                options,
+                result: Verification {
+                    frames,
+                    checkpoints,
+                    desync,
+                    differences: vec![],
+                },
+            });
+        }
+        Ok(Self { entries })
+    }
+    /// # Errors
+    /// [`ReplayError::Metadata`]: Too many entries or strings too long
+    /// [`ReplayError::IO`]: Error writing the matrix
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        fn write_string<W: Write>(writer: &mut W, s: &str) -> Result<()> {
+            writer.write_u32::<LittleEndian>(
+                u32::try_from(s.len()).map_err(|_| ReplayError::Metadata())?,
+            )?;
+            writer.write_all(s.as_bytes())?;
+            Ok(())
+        }
+        writer.write_u32::<LittleEndian>(
+            u32::try_from(self.entries.len()).map_err(|_| ReplayError::Metadata())?,
+        )?;
+        for entry in &self.entries {
+            write_string(writer, &entry.core)?;
+            write_string(writer, &entry.version)?;
+            write_string(writer, &entry.options)?;
+            writer.write_u64::<LittleEndian>(entry.result.frames)?;
+            writer.write_u64::<LittleEndian>(entry.result.checkpoints)?;
+            match entry.result.desync {
+                None => writer.write_u8(0)?,
+                Some(frame) => {
+                    writer.write_u8(1)?;
+                    writer.write_u64::<LittleEndian>(frame)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Loads a matrix sidecar, or an empty matrix if there isn't one.
+    /// # Errors
+    /// As [`CompatMatrix::read`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::read(&mut std::io::BufReader::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// # Errors
+    /// As [`CompatMatrix::write`]
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        crate::verify::save_sidecar(path.as_ref(), |file| self.write(file))
+    }
+}
+
+impl Metadata {
+    /// The [`COMPAT`] chunk, if present.
+    /// # Errors
+    /// [`ReplayError::Metadata`]: The chunk is malformed
+    pub fn compat(&self) -> Result<Option<CompatMatrix>> {
+        self.get(COMPAT)
+            .map(|mut bytes| {
+                let matrix = CompatMatrix::read(&mut bytes).map_err(|_| ReplayError::Metadata())?;
+                if bytes.is_empty() {
+                    Ok(matrix)
+                } else {
+                    Err(ReplayError::Metadata())
+                }
+            })
+            .transpose()
+    }
+    /// # Errors
+    /// As [`CompatMatrix::write`]
+    pub fn set_compat(&mut self, matrix: &CompatMatrix) -> Result<()> {
+        let mut bytes = vec![];
+        matrix.write(&mut bytes)?;
+        self.set(COMPAT, bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_roundtrip() {
+        let mut matrix = CompatMatrix::default();
+        let entry = |version: &str, desync| CompatEntry {
+            core: "FCEUmm".into(),
+            version: version.into(),
+            options: "fceumm_region=NTSC".into(),
+            result: Verification {
+                frames: 6383,
+                checkpoints: 106,
+                desync,
+                differences: vec![],
+            },
+        };
+        matrix.record(entry("1.0", None));
+        matrix.record(entry("1.1", Some(3000)));
+        matrix.record(entry("1.0", Some(10)));
+        assert_eq!(matrix.entries().len(), 2);
+        assert!(
+            !matrix
+                .get("FCEUmm", "1.0", "fceumm_region=NTSC")
+                .unwrap()
+                .synced()
+        );
+
+        let mut metadata = Metadata::default();
+        assert_eq!(metadata.compat().unwrap(), None);
+        metadata.set_compat(&matrix).unwrap();
+        assert_eq!(metadata.compat().unwrap(), Some(matrix.clone()));
+        metadata.set(COMPAT, vec![1, 0, 0, 0, 2]);
+        assert!(matches!(metadata.compat(), Err(ReplayError::Metadata())));
+        // A core name claiming far more than the chunk holds
+        metadata.set(COMPAT, vec![1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, b'F']);
+        assert!(matches!(metadata.compat(), Err(ReplayError::Metadata())));
+    }
+}
@@ -0,0 +1,613 @@
+use crate::Compression;
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+
+/// A compressing writer that must be explicitly finished to flush its trailer.
+pub trait CompressWrite: Write {
+    /// Completes the compressed stream.
+    /// # Errors
+    /// Any I/O error from the underlying writer.
+    fn finish(self: Box<Self>) -> std::io::Result<()>;
+}
+
+/// A checkpoint compression scheme, identified in the stream by its compression byte.
+/// Compressors wrap the output of a [`crate::CheckpointCodec`].
+pub trait Compressor: Send {
+    /// Wraps `writer` so that bytes written through the result are compressed.
+    /// # Errors
+    /// Any error from setting up the compressor.
+    fn compress<'w>(
+        &self,
+        writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>>;
+    /// Wraps `reader`, which yields exactly the compressed bytes of one checkpoint.
+    /// # Errors
+    /// Any error from setting up the decompressor.
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>>;
+}
+
+/// Settings for the built-in compression schemes, used for every
+/// checkpoint an encoder compresses.  Decoders don't need them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionOptions {
+    /// Compression level in the scheme's own range: zlib 0 to 9 (default
+    /// 6), zstd -7 to 22 (default 16), brotli 0 to 11 (default 5).  LZ4
+    /// has no levels.
+    pub level: Option<i32>,
+    /// Base 2 log of the window size: zstd 10 to 31 (default chosen by
+    /// level), brotli 10 to 24 (default 22).
+    pub window_log: Option<u32>,
+    /// Threads zstd compresses on besides the caller's; zero (the
+    /// default) compresses on the caller's thread only.  Ignored without
+    /// the `zstd-mt` feature.
+    pub workers: u32,
+}
+
+/* Checks that an option falls in `range` */
+#[cfg(any(feature = "zlib", feature = "zstd", feature = "brotli"))]
+fn in_range<T: Copy + PartialOrd + std::fmt::Display, U: TryFrom<T>>(
+    value: T,
+    range: std::ops::RangeInclusive<T>,
+    what: &str,
+) -> std::io::Result<U> {
+    range
+        .contains(&value)
+        .then(|| U::try_from(value).ok())
+        .flatten()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{what} {value} is outside {}..={}",
+                    range.start(),
+                    range.end()
+                ),
+            )
+        })
+}
+
+/// Registered custom [`Compressor`]s, keyed by compression byte; built-in
+/// schemes are available when their feature is enabled.
+#[derive(Default)]
+pub(crate) struct Compressors {
+    custom: HashMap<u8, Box<dyn Compressor>>,
+    #[cfg(feature = "zlib")]
+    zlib: Zlib,
+    #[cfg(feature = "zstd")]
+    zstd: Zstd,
+    #[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+    zstd: Ruzstd,
+    #[cfg(feature = "brotli")]
+    brotli: Brotli,
+}
+
+impl Compressors {
+    pub(crate) fn register(
+        &mut self,
+        id: u8,
+        compressor: Box<dyn Compressor>,
+    ) -> Option<Box<dyn Compressor>> {
+        assert!(
+            id >= crate::CodecRegistry::FIRST_CUSTOM_ID,
+            "Compression {id} is reserved for built-in compression schemes"
+        );
+        self.custom.insert(id, compressor)
+    }
+    /// Sets the dictionary zstd compresses and decompresses with; empty
+    /// for none.
+    #[allow(unused_variables)]
+    pub(crate) fn set_zstd_dictionary(&mut self, dictionary: &[u8]) {
+        #[cfg(feature = "zstd")]
+        {
+            self.zstd.1 = dictionary.to_vec();
+        }
+        #[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+        {
+            self.zstd.0 = dictionary.to_vec();
+        }
+    }
+    #[allow(unused_variables)]
+    pub(crate) fn set_options(&mut self, options: CompressionOptions) {
+        #[cfg(feature = "zlib")]
+        {
+            self.zlib.0 = options;
+        }
+        #[cfg(feature = "zstd")]
+        {
+            self.zstd.0 = options;
+        }
+        #[cfg(feature = "brotli")]
+        {
+            self.brotli.0 = options;
+        }
+    }
+    /// Looks up the compressor for `compression`, which is `None` if it
+    /// is a built-in scheme whose feature is disabled or an unregistered
+    /// custom scheme.
+    pub(crate) fn get(&self, compression: Compression) -> Option<&dyn Compressor> {
+        match compression {
+            Compression::None => Some(&Uncompressed),
+            #[cfg(feature = "zlib")]
+            Compression::Zlib => Some(&self.zlib),
+            #[cfg(any(feature = "zstd", feature = "ruzstd"))]
+            Compression::Zstd => Some(&self.zstd),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(&Lz4),
+            #[cfg(feature = "brotli")]
+            Compression::Brotli => Some(&self.brotli),
+            Compression::Custom(id) => self.custom.get(&id).map(AsRef::as_ref),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+struct Uncompressed;
+
+struct Passthrough<'w>(&'w mut dyn Write);
+
+impl Write for Passthrough<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl CompressWrite for Passthrough<'_> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Compressor for Uncompressed {
+    fn compress<'w>(
+        &self,
+        writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+        Ok(Box::new(Passthrough(writer)))
+    }
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>> {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(feature = "zlib")]
+#[derive(Default)]
+struct Zlib(CompressionOptions);
+
+#[cfg(feature = "zlib")]
+impl<W: Write> CompressWrite for flate2::write::ZlibEncoder<W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        flate2::write::ZlibEncoder::finish(*self).map(|_| ())
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl Compressor for Zlib {
+    fn compress<'w>(
+        &self,
+        writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+        let level = match self.0.level {
+            Some(level) => flate2::Compression::new(in_range(level, 0..=9, "zlib level")?),
+            None => flate2::Compression::default(),
+        };
+        Ok(Box::new(flate2::write::ZlibEncoder::new(writer, level)))
+    }
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>> {
+        Ok(Box::new(flate2::bufread::ZlibDecoder::new(reader)))
+    }
+}
+
+/* Options and dictionary, which zstd treats as absent when empty */
+#[cfg(feature = "zstd")]
+#[derive(Default)]
+struct Zstd(CompressionOptions, Vec<u8>);
+
+#[cfg(feature = "zstd")]
+impl<W: Write> CompressWrite for zstd::Encoder<'_, W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        zstd::Encoder::finish(*self).map(|_| ())
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for Zstd {
+    fn compress<'w>(
+        &self,
+        writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+        let level = in_range(self.0.level.unwrap_or(16), -7..=22, "zstd level")?;
+        let mut encoder = zstd::Encoder::with_dictionary(writer, level, &self.1)?;
+        if let Some(window_log) = self.0.window_log {
+            encoder.window_log(in_range(window_log, 10..=31, "zstd window log")?)?;
+        }
+        #[cfg(feature = "zstd-mt")]
+        if self.0.workers > 0 {
+            encoder.multithread(self.0.workers)?;
+        }
+        Ok(Box::new(encoder))
+    }
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>> {
+        Ok(Box::new(
+            zstd::Decoder::with_dictionary(reader, &self.1)?.single_frame(),
+        ))
+    }
+}
+
+/* Decompression only, with the dictionary if any */
+#[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+#[derive(Default)]
+struct Ruzstd(Vec<u8>);
+
+#[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+impl Compressor for Ruzstd {
+    fn compress<'w>(
+        &self,
+        _writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "zstd compression needs the zstd feature; ruzstd only decompresses",
+        ))
+    }
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>> {
+        let mut decoder = ruzstd::decoding::FrameDecoder::new();
+        if !self.0.is_empty() {
+            let dictionary = ruzstd::decoding::Dictionary::decode_dict(&self.0)
+                .map_err(std::io::Error::other)?;
+            decoder
+                .add_dict(dictionary)
+                .map_err(std::io::Error::other)?;
+        }
+        Ok(Box::new(
+            ruzstd::decoding::StreamingDecoder::new_with_decoder(reader, decoder)
+                .map_err(std::io::Error::other)?,
+        ))
+    }
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes on `samples`,
+/// typically some of a replay's checkpoints, for storing with
+/// [`crate::Metadata::set_zstd_dictionary`].  Savestates repeat a lot
+/// from one to the next, so a few dozen samples and 16 to 64 KiB of
+/// dictionary are usually enough.
+/// # Errors
+/// If zstd can't build a dictionary, e.g. because there are too few samples.
+#[cfg(feature = "zstd")]
+pub fn train_zstd_dictionary<S: AsRef<[u8]>>(
+    samples: &[S],
+    max_size: usize,
+) -> std::io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+#[cfg(feature = "lz4")]
+struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl<W: Write> CompressWrite for lz4_flex::frame::FrameEncoder<W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        lz4_flex::frame::FrameEncoder::finish(*self)
+            .map(|_| ())
+            .map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn compress<'w>(
+        &self,
+        writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+        Ok(Box::new(lz4_flex::frame::FrameEncoder::new(writer)))
+    }
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>> {
+        Ok(Box::new(lz4_flex::frame::FrameDecoder::new(reader)))
+    }
+}
+
+#[cfg(feature = "brotli")]
+#[derive(Default)]
+struct Brotli(CompressionOptions);
+
+#[cfg(feature = "brotli")]
+impl<W: Write> CompressWrite for brotli::CompressorWriter<W> {
+    fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+        // into_inner ends the stream but swallows errors, so flush first to surface them
+        self.flush()?;
+        self.into_inner();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl Compressor for Brotli {
+    fn compress<'w>(
+        &self,
+        writer: &'w mut dyn Write,
+    ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+        let level = in_range(self.0.level.unwrap_or(5), 0..=11, "brotli level")?;
+        let window_log = in_range(
+            self.0.window_log.unwrap_or(22),
+            10..=24,
+            "brotli window log",
+        )?;
+        Ok(Box::new(brotli::CompressorWriter::new(
+            writer, 4096, level, window_log,
+        )))
+    }
+    fn decompress<'r>(&self, reader: &'r mut dyn BufRead) -> std::io::Result<Box<dyn Read + 'r>> {
+        Ok(Box::new(brotli::Decompressor::new(reader, 4096)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CodecRegistry, Frame, Header, HeaderBase, HeaderV2, ReplayError};
+
+    /* Flips every bit of the stream */
+    struct Invert;
+    struct InvertWriter<'w>(&'w mut dyn Write);
+    impl Write for InvertWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let inverted: Vec<u8> = buf.iter().map(|b| !b).collect();
+            self.0.write_all(&inverted)?;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+    impl CompressWrite for InvertWriter<'_> {
+        fn finish(self: Box<Self>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    struct InvertReader<'r>(&'r mut dyn BufRead);
+    impl Read for InvertReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.0.read(buf)?;
+            for b in &mut buf[..n] {
+                *b = !*b;
+            }
+            Ok(n)
+        }
+    }
+    impl Compressor for Invert {
+        fn compress<'w>(
+            &self,
+            writer: &'w mut dyn Write,
+        ) -> std::io::Result<Box<dyn CompressWrite + 'w>> {
+            Ok(Box::new(InvertWriter(writer)))
+        }
+        fn decompress<'r>(
+            &self,
+            reader: &'r mut dyn BufRead,
+        ) -> std::io::Result<Box<dyn Read + 'r>> {
+            Ok(Box::new(InvertReader(reader)))
+        }
+    }
+
+    #[test]
+    fn custom_compressor() {
+        let header = Header::V2(HeaderV2 {
+            base: HeaderBase {
+                version: 2,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 0,
+            },
+            frame_count: 0,
+            block_size: 16,
+            superblock_size: 4,
+            checkpoint_commit_interval: 4,
+            checkpoint_commit_threshold: 2,
+            checkpoint_compression: Compression::Custom(130),
+            encrypted_sections: crate::EncryptedSections::default(),
+            metadata: crate::Metadata::default(),
+        });
+        let state: Vec<u8> = (0..200_u8).collect();
+        let mut registry = CodecRegistry::default();
+        registry.register_compressor(130, Box::new(Invert));
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc =
+                crate::ReplayEncoder::with_codecs(header, &state, &mut out, registry).unwrap();
+            let frame = Frame {
+                checkpoint_bytes: state.iter().rev().copied().collect(),
+                ..Frame::default()
+            };
+            enc.write_frame(&frame).unwrap();
+            enc.finish().unwrap();
+        }
+        let bytes = out.into_inner();
+        assert!(matches!(
+            crate::decode(bytes.as_slice()),
+            Err(ReplayError::Compression(_))
+        ));
+        let mut registry = CodecRegistry::default();
+        registry.register_compressor(130, Box::new(Invert));
+        let mut dec = crate::ReplayDecoder::with_codecs(bytes.as_slice(), registry).unwrap();
+        let mut frame = Frame::default();
+        dec.read_frame(&mut frame).unwrap();
+        assert_eq!(frame.checkpoint_compression, Compression::Custom(130));
+        assert_eq!(
+            frame.checkpoint_bytes,
+            state.iter().rev().copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+    fn ruzstd_decompress() {
+        // A zstd frame holding one raw block, since ruzstd can't compress
+        let frame = [
+            0x28,
+            0xb5,
+            0x2f,
+            0xfd,
+            0x20,
+            5,
+            1 | 5 << 3,
+            0,
+            0,
+            b'h',
+            b'e',
+            b'l',
+            b'l',
+            b'o',
+        ];
+        let compressors = Compressors::default();
+        let zstd = compressors.get(Compression::Zstd).unwrap();
+        let mut reader = &frame[..];
+        let mut out = vec![];
+        zstd.decompress(&mut reader)
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"hello");
+        assert!(zstd.compress(&mut vec![]).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "zlib", feature = "zstd"))]
+    fn compression_options() {
+        let mut header = Header::V2(HeaderV2 {
+            base: HeaderBase {
+                version: 2,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 0,
+            },
+            frame_count: 0,
+            block_size: 16,
+            superblock_size: 4,
+            checkpoint_commit_interval: 4,
+            checkpoint_commit_threshold: 2,
+            checkpoint_compression: Compression::Zstd,
+            encrypted_sections: crate::EncryptedSections::default(),
+            metadata: crate::Metadata::default(),
+        });
+        let state: Vec<u8> = (0..4000_u32).map(|i| (i % 251) as u8).collect();
+        let frame = Frame {
+            checkpoint_bytes: state.iter().rev().copied().collect(),
+            ..Frame::default()
+        };
+        let encode = |header: &Header, options| {
+            let mut registry = CodecRegistry::default();
+            registry.set_compression_options(options);
+            let mut out = std::io::Cursor::new(vec![]);
+            {
+                let mut enc =
+                    crate::ReplayEncoder::with_codecs(header.clone(), &state, &mut out, registry)?;
+                enc.write_frame(&frame)?;
+                enc.finish()?;
+            }
+            Ok::<_, ReplayError>(out.into_inner())
+        };
+        for options in [
+            CompressionOptions::default(),
+            CompressionOptions {
+                level: Some(1),
+                window_log: Some(12),
+                workers: 2,
+            },
+        ] {
+            let bytes = encode(&header, options).unwrap();
+            let mut dec = crate::decode(bytes.as_slice()).unwrap();
+            assert_eq!(dec.initial_state, state);
+            let mut decoded = Frame::default();
+            dec.read_frame(&mut decoded).unwrap();
+            assert_eq!(decoded.checkpoint_bytes, frame.checkpoint_bytes);
+        }
+        header.set_checkpoint_compression(Compression::Zlib);
+        let bad = CompressionOptions {
+            level: Some(10),
+            ..CompressionOptions::default()
+        };
+        assert!(matches!(encode(&header, bad), Err(ReplayError::IO(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn zstd_dictionary() {
+        /* Pseudo-random states that share most of their bytes */
+        let mut seed = 1_u32;
+        let mut noise = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        let base: Vec<u8> = (0..2000).map(|_| noise()).collect();
+        let states: Vec<Vec<u8>> = (0..64)
+            .map(|_| {
+                let mut state = base.clone();
+                for _ in 0..50 {
+                    let at = usize::from(noise()) * 7;
+                    state[at] = noise();
+                }
+                state
+            })
+            .collect();
+        let dictionary = train_zstd_dictionary(&states, 4096).unwrap();
+        let encode = |metadata: crate::Metadata| {
+            let mut header = Header::V2(HeaderV2 {
+                base: HeaderBase {
+                    version: 3,
+                    content_crc: 0,
+                    initial_state_size: 0,
+                    identifier: 0,
+                },
+                frame_count: 0,
+                block_size: 16,
+                superblock_size: 4,
+                checkpoint_commit_interval: 4,
+                checkpoint_commit_threshold: 2,
+                checkpoint_compression: Compression::Zstd,
+                encrypted_sections: crate::EncryptedSections::default(),
+                metadata: crate::Metadata::default(),
+            });
+            header.set_metadata(metadata);
+            let mut out = std::io::Cursor::new(vec![]);
+            {
+                let mut enc =
+                    crate::ReplayEncoder::with_version(header, &states[0], &mut out, 3).unwrap();
+                enc.set_checkpoint_encoding(crate::Encoding::Raw);
+                for state in &states[1..] {
+                    let frame = Frame {
+                        checkpoint_bytes: state.clone(),
+                        ..Frame::default()
+                    };
+                    enc.write_frame(&frame).unwrap();
+                }
+                enc.finish().unwrap();
+            }
+            out.into_inner()
+        };
+        let plain = encode(crate::Metadata::default());
+        let mut metadata = crate::Metadata::default();
+        metadata.set_zstd_dictionary(dictionary.clone());
+        let bytes = encode(metadata);
+        assert!(bytes.len() + dictionary.len() < plain.len());
+        let mut dec = crate::decode(bytes.as_slice()).unwrap();
+        assert_eq!(
+            dec.header.metadata().unwrap().zstd_dictionary(),
+            Some(&dictionary[..])
+        );
+        assert_eq!(dec.initial_state, states[0]);
+        for (frame, state) in dec.frames().zip(&states[1..]) {
+            assert_eq!(&frame.unwrap().checkpoint_bytes, state);
+        }
+        /* Without the dictionary the checkpoints can't be decompressed */
+        let mut stripped = bytes.clone();
+        let at = stripped
+            .windows(4)
+            .position(|w| w == crate::ZSTD_DICTIONARY)
+            .unwrap();
+        stripped[at..at + 4].copy_from_slice(b"ZDIX");
+        assert!(crate::decode(stripped.as_slice()).is_err());
+    }
+}
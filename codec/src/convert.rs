@@ -0,0 +1,396 @@
+use crate::rply::{JOYPAD, JOYPAD_MASK};
+use crate::{
+    Frame, Header, HeaderBase, InputData, Metadata, Movie, MovieFormat, ReplayDecoder, ReplayError,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use std::io::{BufRead, Seek, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/* FCEUX's gamepad columns, "RLDUTSBA", as RetroPad button ids */
+const BUTTONS: [(char, u16); 8] = [
+    ('R', 7),
+    ('L', 6),
+    ('D', 5),
+    ('U', 4),
+    ('T', 3),
+    ('S', 2),
+    ('B', 0),
+    ('A', 8),
+];
+
+/* Power and reset on the first frame are what a replay starts from anyway */
+const FIRST_FRAME_COMMANDS: u8 = 0b11;
+
+/* The fm2 header lines this crate understands */
+struct Settings {
+    header: Header,
+    fourscore: bool,
+    /* Whether port 0 and port 1 have gamepads */
+    gamepads: [bool; 2],
+}
+
+impl Settings {
+    fn new() -> Self {
+        let mut header = Header::V0V1(HeaderBase {
+            version: 2,
+            content_crc: 0,
+            initial_state_size: 0,
+            identifier: 0,
+        });
+        header.upgrade();
+        Self {
+            header,
+            fourscore: false,
+            gamepads: [true; 2],
+        }
+    }
+    fn parse(&mut self, number: usize, line: &str) -> Result<()> {
+        let unsupported = |what: &str| Err(ReplayError::Fm2(number, what.to_string()));
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let metadata = self.header.metadata_mut();
+        match (key, value) {
+            ("binary" | "savestate" | "port2" | "FDS", "0" | "") => {}
+            ("binary", _) => return unsupported("binary input logs aren't supported"),
+            ("savestate", _) => {
+                return unsupported("movies starting from a savestate aren't supported");
+            }
+            ("port2" | "FDS", _) => return unsupported("only gamepads are supported"),
+            ("fourscore", value) => self.fourscore = value == "1",
+            ("port0" | "port1", "0" | "1") => {
+                self.gamepads[usize::from(key == "port1")] = value == "1";
+            }
+            ("port0" | "port1", _) => return unsupported("only gamepads are supported"),
+            ("guid", guid) => self.header.set_identifier(guid_identifier(guid)),
+            ("romFilename", title) => metadata.set_title(title),
+            ("romChecksum", checksum) => {
+                let Some(hash) = checksum
+                    .strip_prefix("base64:")
+                    .and_then(|hash| BASE64.decode(hash).ok())
+                else {
+                    return unsupported("romChecksum isn't base64");
+                };
+                metadata.set_rom_hash(&hash);
+            }
+            ("comment", comment) => {
+                if let Some(author) = comment.strip_prefix("author ") {
+                    metadata.set_author(author);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    /* Ports whose column in each input line holds a gamepad */
+    fn ports(&self) -> Vec<bool> {
+        if self.fourscore {
+            vec![true; 4]
+        } else {
+            self.gamepads.to_vec()
+        }
+    }
+}
+
+/* The first 64 bits of the movie's GUID */
+fn guid_identifier(guid: &str) -> u64 {
+    let hex: String = guid
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .take(16)
+        .collect();
+    u64::from_str_radix(&hex, 16).unwrap_or_default()
+}
+
+fn read_input_line(
+    number: usize,
+    line: &str,
+    ports: &[bool],
+    first: bool,
+    frame: &mut Frame,
+) -> Result<()> {
+    let bad = |what: &str| ReplayError::Fm2(number, what.to_string());
+    let mut fields = line.trim_end().trim_start_matches('|').split('|');
+    let commands: u8 = fields
+        .next()
+        .and_then(|commands| commands.parse().ok())
+        .ok_or_else(|| bad("missing commands"))?;
+    let allowed = if first { FIRST_FRAME_COMMANDS } else { 0 };
+    if commands & !allowed != 0 {
+        return Err(bad("reset, power and disk commands aren't supported"));
+    }
+    frame.clear();
+    for (port, gamepad) in (0_u8..).zip(ports) {
+        let buttons = fields.next().ok_or_else(|| bad("missing gamepad"))?;
+        if !gamepad {
+            continue;
+        }
+        if buttons.chars().count() != BUTTONS.len() {
+            return Err(bad("gamepad isn't 8 buttons"));
+        }
+        let mask = buttons
+            .chars()
+            .zip(BUTTONS)
+            .filter(|(c, _)| *c != '.' && *c != ' ')
+            .fold(0_u16, |mask, (_, (_, id))| mask | 1 << id);
+        frame.input_events.push(InputData {
+            port,
+            device: JOYPAD,
+            idx: 0,
+            id: JOYPAD_MASK,
+            val: mask.cast_signed(),
+        });
+    }
+    Ok(())
+}
+
+/* An fm2 movie's input lines, read one frame at a time */
+struct Fm2Frames<'r> {
+    lines: std::iter::Enumerate<std::io::Lines<Box<dyn BufRead + 'r>>>,
+    /* The first input line, read while looking for the end of the header */
+    first: Option<(usize, String)>,
+    ports: Vec<bool>,
+    frames: u64,
+    failed: bool,
+}
+
+impl Fm2Frames<'_> {
+    fn next_line(&mut self) -> Option<Result<(usize, String)>> {
+        if let Some(first) = self.first.take() {
+            return Some(Ok(first));
+        }
+        loop {
+            let (i, line) = self.lines.next()?;
+            match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => return Some(Ok((i + 1, line))),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+impl Iterator for Fm2Frames<'_> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_line()?.and_then(|(number, line)| {
+            let mut frame = Frame::default();
+            read_input_line(number, &line, &self.ports, self.frames == 0, &mut frame)?;
+            Ok(frame)
+        });
+        self.frames += 1;
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// FCEUX's `.fm2` text movies, as imported by [`read_fm2`].
+pub struct Fm2;
+
+impl MovieFormat for Fm2 {
+    fn name(&self) -> &'static str {
+        "fm2"
+    }
+    fn sniff(&self, start: &[u8]) -> bool {
+        start.starts_with(b"version ")
+    }
+    fn open<'r>(&self, input: Box<dyn BufRead + 'r>) -> Result<Movie<'r>> {
+        let mut lines = input.lines().enumerate();
+        let mut settings = Settings::new();
+        let mut first = None;
+        for (i, line) in lines.by_ref() {
+            let line = line?;
+            if line.starts_with('|') {
+                first = Some((i + 1, line));
+                break;
+            }
+            settings.parse(i + 1, line.trim_end())?;
+        }
+        let frames = Fm2Frames {
+            lines,
+            first,
+            ports: settings.ports(),
+            frames: 0,
+            failed: false,
+        };
+        Ok(Movie {
+            header: settings.header,
+            initial_state: vec![],
+            frames: Box::new(frames),
+        })
+    }
+}
+
+/// Encodes an FCEUX `.fm2` text movie to `out` as a replay.  Each frame's
+/// gamepads become one joypad bitmask poll per port, with the NES buttons
+/// on their RetroPad counterparts (NES A on RetroPad A, B on B).  The
+/// header is made up: the identifier is the start of the movie's GUID,
+/// and the ROM's file name, MD5 checksum, and any `comment author` line
+/// go in the metadata.  Movies play from power-on, so the replay has no
+/// initial state and, without a core to save them, no checkpoints.
+///
+/// Returns the number of frames written.
+/// # Errors
+/// [`ReplayError::Fm2`]: The movie is malformed or uses something a replay
+/// can't express, such as a savestate start, a Zapper, or a mid-movie reset
+/// Any error from reading the movie or encoding the replay
+pub fn read_fm2<R: BufRead, W: Write + Seek>(input: R, out: &mut W) -> Result<u64> {
+    Fm2.open(Box::new(input))?.encode(out)
+}
+
+/// Writes the rest of `rply` to `out` as an FCEUX `.fm2` text movie,
+/// inverting [`read_fm2`]: joypad polls on ports 0 to 3, whether bitmasks
+/// or single buttons, become gamepad columns, with the four-player adapter
+/// switched on if ports 2 or 3 were used.  Key events and checkpoints are
+/// dropped, and FCEUX starts the movie from power-on whatever the replay's
+/// initial state.
+///
+/// Returns the number of frames written.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: Tried to export a version 0 replay
+/// Any error from reading frames or writing the movie
+pub fn write_fm2<R: BufRead, W: Write>(rply: &mut ReplayDecoder<R>, out: &mut W) -> Result<u64> {
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    /* The header says how many gamepads there are, so read every frame first */
+    let mut pads: Vec<[u16; 4]> = vec![];
+    let mut ports = 2;
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame_skipping_checkpoints(&mut frame)?;
+        let mut masks = [0; 4];
+        for input in &frame.input_events {
+            let Some(mask) = masks.get_mut(usize::from(input.port)) else {
+                continue;
+            };
+            if input.device != JOYPAD || input.idx != 0 {
+                continue;
+            }
+            *mask |= match input.id {
+                JOYPAD_MASK => input.val.cast_unsigned(),
+                id if id < 16 && input.val != 0 => 1 << id,
+                _ => 0,
+            };
+            ports = ports.max(usize::from(input.port) + 1);
+        }
+        pads.push(masks);
+    }
+    let metadata = rply.header.metadata();
+    let title = metadata.and_then(Metadata::title).unwrap_or("unknown");
+    writeln!(
+        out,
+        "version 3\nemuVersion 22020\nrerecordCount 0\npalFlag 0"
+    )?;
+    writeln!(out, "romFilename {title}")?;
+    if let Some(hash) = metadata.and_then(Metadata::rom_hash) {
+        writeln!(out, "romChecksum base64:{}", BASE64.encode(hash))?;
+    }
+    let id = rply.header.identifier();
+    writeln!(
+        out,
+        "guid {:08X}-{:04X}-{:04X}-0000-000000000000",
+        id >> 32,
+        (id >> 16) & 0xffff,
+        id & 0xffff
+    )?;
+    writeln!(out, "fourscore {}", u8::from(ports > 2))?;
+    writeln!(
+        out,
+        "microphone 0\nport0 1\nport1 1\nport2 0\nFDS 0\nNewPPU 0"
+    )?;
+    if let Some(author) = metadata.and_then(Metadata::author) {
+        writeln!(out, "comment author {author}")?;
+    }
+    let ports = if ports > 2 { 4 } else { 2 };
+    for masks in &pads {
+        write!(out, "|0|")?;
+        for mask in &masks[..ports] {
+            for (c, id) in BUTTONS {
+                write!(out, "{}", if mask & 1 << id == 0 { '.' } else { c })?;
+            }
+            write!(out, "|")?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(pads.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVIE: &str = "version 3
+emuVersion 22020
+rerecordCount 12
+palFlag 0
+romFilename Super Mario Bros.
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B
+fourscore 0
+microphone 0
+port0 1
+port1 1
+port2 0
+FDS 0
+NewPPU 0
+comment author someone
+|1|........|........||
+|0|.......A|........||
+|0|R......A|..D..S..||
+|0|........|...U....||
+";
+
+    #[test]
+    fn fm2_roundtrip() {
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(read_fm2(MOVIE.as_bytes(), &mut out).unwrap(), 4);
+        let mut rply = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        assert_eq!(rply.header.identifier(), 0x452d_e2c3_ef43_2fa9);
+        assert!(rply.initial_state.is_empty());
+        let metadata = rply.header.metadata().unwrap();
+        assert_eq!(metadata.title(), Some("Super Mario Bros."));
+        assert_eq!(metadata.author(), Some("someone"));
+        assert_eq!(metadata.rom_hash().map(<[u8]>::len), Some(16));
+        let frames = rply.frames().collect::<Result<Vec<_>>>().unwrap();
+        let masks: Vec<Vec<i16>> = frames
+            .iter()
+            .map(|frame| frame.input_events.iter().map(|input| input.val).collect())
+            .collect();
+        // RIGHT is 1 << 7, A is 1 << 8, DOWN is 1 << 5, SELECT is 1 << 2
+        assert_eq!(masks, [[0, 0], [256, 0], [384, 36], [0, 16]]);
+
+        let mut rply = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        let mut fm2 = vec![];
+        assert_eq!(write_fm2(&mut rply, &mut fm2).unwrap(), 4);
+        let fm2 = String::from_utf8(fm2).unwrap();
+        assert!(fm2.contains("guid 452DE2C3-EF43-2FA9-0000-000000000000\n"));
+        assert!(fm2.contains("romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n"));
+        // The reset on the first frame is what the replay starts from
+        let inputs: Vec<_> = MOVIE.lines().filter(|l| l.starts_with('|')).collect();
+        let mut expected = vec!["|0|........|........||"];
+        expected.extend(&inputs[1..]);
+        assert_eq!(
+            fm2.lines()
+                .filter(|l| l.starts_with('|'))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn fm2_unsupported() {
+        let reset = MOVIE.replace("|0|R......A", "|1|R......A");
+        let zapper = MOVIE.replace("port1 1", "port1 2");
+        for (movie, line) in [(reset, 18), (zapper, 11)] {
+            let mut out = std::io::Cursor::new(vec![]);
+            assert!(matches!(
+                read_fm2(movie.as_bytes(), &mut out),
+                Err(ReplayError::Fm2(n, _)) if n == line
+            ));
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::Compression;
+use crate::compression::Compressors;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+
+/// Tracks how many bytes have been consumed from the wrapped reader, so the
+/// decoder knows the stream offsets of frames without requiring [`Seek`].
+/// Once [`CountingReader::start_packing`] is called, the rest of the stream
+/// is read as frame packs, and `pos` counts bytes of the unpacked frames.
+pub(crate) struct CountingReader<R> {
+    pub(crate) inner: R,
+    pub(crate) pos: u64,
+    packs: Option<Box<Packs>>,
+}
+
+/* Length of a frame pack's header: compression, packed and unpacked lengths */
+pub(crate) const PACK_HEADER_LEN: u64 = 9;
+
+/* Most bytes of a pack read at once, so that what's buffered grows with
+what actually arrives rather than with what its header claims */
+const READ_CHUNK: usize = 64 * 1024;
+
+/* A frame pack seen so far */
+struct Pack {
+    /* Offset of its first unpacked byte */
+    virt: u64,
+    /* Offset of its header in the stream */
+    phys: u64,
+    len: u32,
+    packed: u32,
+}
+
+struct Packs {
+    compressors: Compressors,
+    /* Most bytes a pack may hold, packed or unpacked */
+    max_len: usize,
+    /* Offset where packing started, in the stream and the unpacked frames alike */
+    start: u64,
+    /* Where the wrapped reader is, counted like `Pack::phys` */
+    phys: u64,
+    index: Vec<Pack>,
+    /* The next pack to read from the wrapped reader, which is just before it */
+    next: usize,
+    /* Unpacked bytes of the pack being read, which starts at `data_virt` */
+    data: Vec<u8>,
+    data_virt: u64,
+    /* Bytes of a pack not yet wholly readable, e.g. still being written */
+    partial: Vec<u8>,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pos: 0,
+            packs: None,
+        }
+    }
+    /// Reads the rest of the stream as frame packs, each a header (the
+    /// pack's [`Compression`] byte and little-endian u32 packed and
+    /// unpacked lengths) followed by its packed bytes.  Packs longer than
+    /// `max_len`, packed or unpacked, fail with
+    /// [`crate::ReplayError::OverLimit`].
+    pub(crate) fn start_packing(&mut self, max_len: usize) {
+        self.packs = Some(Box::new(Packs {
+            compressors: Compressors::default(),
+            max_len,
+            start: self.pos,
+            phys: self.pos,
+            index: vec![],
+            next: 0,
+            data: vec![],
+            data_virt: self.pos,
+            partial: vec![],
+        }));
+    }
+    /// Bytes consumed from the wrapped reader: `pos`, or for packed frames
+    /// the end of the last pack read.
+    pub(crate) fn physical_pos(&self) -> u64 {
+        match &self.packs {
+            Some(packs) => packs.phys - packs.partial.len() as u64,
+            None => self.pos,
+        }
+    }
+}
+
+impl<R: Read> CountingReader<R> {
+    /* The unpacked bytes from `pos` to the end of its pack, reading the
+    next pack if need be; empty at the end of the stream, including when
+    the last pack is cut off */
+    fn packed_buf(&mut self) -> std::io::Result<&[u8]> {
+        let packs = self.packs.as_mut().unwrap();
+        let in_data = |packs: &Packs, pos: u64| {
+            pos >= packs.data_virt && pos < packs.data_virt + packs.data.len() as u64
+        };
+        if !in_data(packs, self.pos)
+            && packs.read_next(&mut self.inner)?
+            && packs.data_virt != self.pos
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame pack doesn't start where the last one ended",
+            ));
+        }
+        if !in_data(packs, self.pos) {
+            return Ok(&[]);
+        }
+        let offset = usize::try_from(self.pos - packs.data_virt).unwrap();
+        Ok(&packs.data[offset..])
+    }
+}
+
+impl Packs {
+    /* Reads the pack the wrapped reader is at into `data`, returning false
+    if it isn't all there */
+    fn read_next(&mut self, inner: &mut impl Read) -> std::io::Result<bool> {
+        #[allow(clippy::cast_possible_truncation)]
+        let header_len = PACK_HEADER_LEN as usize;
+        if !self.fill_partial(inner, header_len)? {
+            return Ok(false);
+        }
+        let packed = u32::from_le_bytes(self.partial[1..5].try_into().unwrap());
+        let len = u32::from_le_bytes(self.partial[5..9].try_into().unwrap());
+        for size in [packed, len] {
+            if size as usize > self.max_len {
+                return Err(std::io::Error::other(crate::ReplayError::OverLimit(
+                    "frame pack",
+                    u64::from(size),
+                )));
+            }
+        }
+        if !self.fill_partial(inner, header_len + packed as usize)? {
+            return Ok(false);
+        }
+        let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let compression = Compression::try_from(self.partial[0])
+            .map_err(|_| invalid("bad frame pack compression"))?;
+        if len == 0 {
+            return Err(invalid("empty frame pack"));
+        }
+        let compressor = self
+            .compressors
+            .get(compression)
+            .ok_or_else(|| invalid("frame pack compression not supported"))?;
+        let mut payload = &self.partial[header_len..];
+        self.data.clear();
+        compressor
+            .decompress(&mut payload)?
+            .take(u64::from(len) + 1)
+            .read_to_end(&mut self.data)?;
+        if self.data.len() != len as usize {
+            return Err(invalid("frame pack unpacks to the wrong length"));
+        }
+        let phys = self.phys - self.partial.len() as u64;
+        self.partial.clear();
+        let virt = self.next.checked_sub(1).map_or(self.start, |prev| {
+            self.index[prev].virt + u64::from(self.index[prev].len)
+        });
+        if self.next == self.index.len() {
+            self.index.push(Pack {
+                virt,
+                phys,
+                len,
+                packed,
+            });
+        }
+        self.next += 1;
+        self.data_virt = virt;
+        Ok(true)
+    }
+    /* Reads from `inner` until `partial` holds `len` bytes, returning false
+    if it runs out first */
+    fn fill_partial(&mut self, inner: &mut impl Read, len: usize) -> std::io::Result<bool> {
+        while self.partial.len() < len {
+            let have = self.partial.len();
+            self.partial.resize(len.min(have + READ_CHUNK), 0);
+            let amt = match inner.read(&mut self.partial[have..]) {
+                Ok(amt) => amt,
+                Err(e) => {
+                    self.partial.truncate(have);
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+            self.partial.truncate(have + amt);
+            self.phys += amt as u64;
+            if amt == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    /* The pack holding unpacked offset `pos` among those seen */
+    fn find(&self, pos: u64) -> Option<usize> {
+        let i = self.index.partition_point(|pack| pack.virt <= pos);
+        let pack = &self.index[i.checked_sub(1)?];
+        (pos < pack.virt + u64::from(pack.len)).then_some(i - 1)
+    }
+    fn end(&self) -> (u64, u64) {
+        self.index.last().map_or((self.start, self.start), |pack| {
+            (
+                pack.virt + u64::from(pack.len),
+                pack.phys + PACK_HEADER_LEN + u64::from(pack.packed),
+            )
+        })
+    }
+}
+
+impl<R: Read + Seek> CountingReader<R> {
+    fn seek_physical(&mut self, phys: u64) -> std::io::Result<()> {
+        let packs = self.packs.as_mut().unwrap();
+        #[allow(clippy::cast_possible_wrap)]
+        let delta = phys.wrapping_sub(packs.phys) as i64;
+        self.inner.seek(SeekFrom::Current(delta))?;
+        packs.phys = phys;
+        packs.partial.clear();
+        Ok(())
+    }
+    /* Indexes packs without unpacking them until one holds `pos` or the
+    stream ends */
+    fn index_packs(&mut self, pos: u64) -> std::io::Result<()> {
+        loop {
+            let packs = self.packs.as_ref().unwrap();
+            let (virt, phys) = packs.end();
+            if pos < virt {
+                return Ok(());
+            }
+            self.seek_physical(phys)?;
+            let mut header = [0; PACK_HEADER_LEN as usize];
+            let mut have = 0;
+            while have < header.len() {
+                match self.inner.read(&mut header[have..])? {
+                    0 => break,
+                    amt => have += amt,
+                }
+            }
+            let packs = self.packs.as_mut().unwrap();
+            packs.phys += have as u64;
+            let len = u32::from_le_bytes(header[5..9].try_into().unwrap());
+            if have < header.len() || len == 0 || Compression::try_from(header[0]).is_err() {
+                return Ok(());
+            }
+            packs.index.push(Pack {
+                virt,
+                phys,
+                len,
+                packed: u32::from_le_bytes(header[1..5].try_into().unwrap()),
+            });
+        }
+    }
+    /// Moves to a position relative to where counting started, which for
+    /// packed frames is an offset into the unpacked frames.
+    pub(crate) fn seek_to(&mut self, pos: u64) -> std::io::Result<()> {
+        let Some(packs) = &self.packs else {
+            #[allow(clippy::cast_possible_wrap)]
+            let delta = pos.wrapping_sub(self.pos) as i64;
+            self.inner.seek(SeekFrom::Current(delta))?;
+            self.pos = pos;
+            return Ok(());
+        };
+        if pos < packs.start {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the frame packs",
+            ));
+        }
+        if pos >= packs.data_virt && pos < packs.data_virt + packs.data.len() as u64 {
+            self.pos = pos;
+            return Ok(());
+        }
+        self.index_packs(pos)?;
+        let packs = self.packs.as_ref().unwrap();
+        let (next, phys) = match packs.find(pos) {
+            Some(i) => (i, packs.index[i].phys),
+            None => (packs.index.len(), packs.end().1),
+        };
+        self.seek_physical(phys)?;
+        let packs = self.packs.as_mut().unwrap();
+        packs.next = next;
+        packs.data.clear();
+        packs.data_virt = pos;
+        self.pos = pos;
+        if next < packs.index.len() {
+            packs.read_next(&mut self.inner)?;
+            let pack = &packs.index[next];
+            if (pack.virt, pack.len as usize) != (packs.data_virt, packs.data.len()) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "frame pack changed since it was indexed",
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// How far the stream goes, counted like `pos`.
+    pub(crate) fn stream_len(&mut self) -> std::io::Result<u64> {
+        if self.packs.is_some() {
+            let (pos, phys) = (self.pos, self.physical_pos());
+            self.index_packs(u64::MAX)?;
+            let end = self.packs.as_ref().unwrap().end().0;
+            // Back to the pack being read, which is still in `data`
+            self.seek_physical(phys)?;
+            self.pos = pos;
+            return Ok(end);
+        }
+        let here = self.inner.stream_position()?;
+        let end = self.inner.seek(SeekFrom::End(0))?;
+        self.inner.seek(SeekFrom::Start(here))?;
+        Ok(self.pos + (end - here))
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.packs.is_some() {
+            let data = self.packed_buf()?;
+            let amt = data.len().min(buf.len());
+            buf[..amt].copy_from_slice(&data[..amt]);
+            self.pos += amt as u64;
+            return Ok(amt);
+        }
+        let amt = self.inner.read(buf)?;
+        self.pos += amt as u64;
+        Ok(amt)
+    }
+}
+
+impl<R: BufRead> CountingReader<R> {
+    /// Reads past the next `n` bytes rather than seeking over them, so a
+    /// buffered reader keeps its buffer; for skips shorter than a buffer.
+    /// # Errors
+    /// [`std::io::ErrorKind::UnexpectedEof`] if the stream ends first
+    pub(crate) fn skip(&mut self, mut n: u64) -> std::io::Result<()> {
+        while n > 0 {
+            let available = self.fill_buf()?.len();
+            let amt = usize::try_from(n).map_or(available, |n| n.min(available));
+            if amt == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.consume(amt);
+            n -= amt as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.packs.is_some() {
+            return self.packed_buf();
+        }
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        if self.packs.is_none() {
+            self.inner.consume(amt);
+        }
+        self.pos += amt as u64;
+    }
+}
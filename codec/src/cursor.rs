@@ -0,0 +1,154 @@
+use crate::{CheckpointInfo, Core, Frame, ReplayDecoder, ReplayError};
+use std::io::{BufRead, Seek};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// A position between frames of a replay that can step both ways, for
+/// timeline views.  At position `n`, `n` frames have been played; the
+/// iterator steps forward over the frame after the position, and
+/// [`Cursor::prev`] steps back over the frame before it.  Created by
+/// [`ReplayDecoder::cursor`].
+pub struct Cursor<'d, R: BufRead + Seek> {
+    decoder: &'d mut ReplayDecoder<R>,
+    checkpoints: Vec<CheckpointInfo>,
+}
+
+impl<R: BufRead + Seek> ReplayDecoder<R> {
+    /// Creates a [`Cursor`] at the decoder's current frame.
+    /// # Errors
+    /// As [`ReplayDecoder::checkpoints`]
+    pub fn cursor(&mut self) -> Result<Cursor<'_, R>> {
+        let checkpoints = self.checkpoints()?;
+        Ok(Cursor {
+            decoder: self,
+            checkpoints,
+        })
+    }
+}
+
+impl<R: BufRead + Seek> Cursor<'_, R> {
+    /// Number of frames before the cursor.
+    #[must_use]
+    pub fn frame(&self) -> u64 {
+        self.decoder.frame_number
+    }
+
+    /// Moves the cursor to just after `frame` frames.
+    /// # Errors
+    /// As [`ReplayDecoder::seek_to_frame`]
+    pub fn goto(&mut self, frame: u64) -> Result<()> {
+        self.decoder.seek_to_frame(frame)
+    }
+
+    /// Steps back over the frame before the cursor and returns it, or
+    /// `None` at the start of the replay.
+    pub fn prev(&mut self) -> Option<Result<Frame>> {
+        let frame_number = self.frame().checked_sub(1)?;
+        let mut frame = Frame::default();
+        let result = self
+            .goto(frame_number)
+            .and_then(|()| self.decoder.read_frame(&mut frame))
+            .and_then(|()| self.goto(frame_number));
+        Some(result.map(|()| frame))
+    }
+
+    /// The savestate at the cursor, with the frame it is for.  Without a
+    /// `core`, this is the last checkpoint at or before the cursor (or the
+    /// initial state); with one, the core is loaded with that checkpoint
+    /// and runs the frames up to the cursor, so the state is exactly at
+    /// the cursor.  The cursor doesn't move.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core failed to save or load a state
+    /// Any error from decoding the frames along the way
+    pub fn state_at_cursor(&mut self, core: Option<&mut dyn Core>) -> Result<(u64, Vec<u8>)> {
+        let target = self.frame();
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .map(|cp| cp.frame)
+            .rfind(|&frame| frame <= target);
+        let (start, mut state) = match checkpoint {
+            Some(start) => {
+                let mut frame = Frame::default();
+                self.goto(start - 1)?;
+                self.decoder.read_frame(&mut frame)?;
+                (start, frame.checkpoint_bytes)
+            }
+            None => {
+                self.goto(0)?;
+                (0, self.decoder.initial_state.clone())
+            }
+        };
+        let Some(core) = core.filter(|_| start < target) else {
+            self.goto(target)?;
+            return Ok((start, state));
+        };
+        if !core.load_state(&state) {
+            return Err(ReplayError::CoreState(start));
+        }
+        let mut frame = Frame::default();
+        while self.frame() < target {
+            self.decoder.read_frame(&mut frame)?;
+            core.run_frame(&frame);
+        }
+        if !core.save_state(&mut state) {
+            return Err(ReplayError::CoreState(target));
+        }
+        Ok((target, state))
+    }
+}
+
+impl<R: BufRead + Seek> Iterator for Cursor<'_, R> {
+    type Item = Result<Frame>;
+
+    /// Steps forward over the frame after the cursor and returns it, or
+    /// `None` at the end of the replay.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.decoder.at_end() {
+            Ok(true) => None,
+            Ok(false) => {
+                let mut frame = Frame::default();
+                Some(self.decoder.read_frame(&mut frame).map(|()| frame))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::verify::tests::{Counter, replay};
+
+    #[test]
+    fn steps_both_ways() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut cursor = rply.cursor().unwrap();
+        assert!(cursor.prev().is_none());
+        let forward = cursor
+            .by_ref()
+            .take(130)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(cursor.frame(), 130);
+        for frame in forward.iter().rev().take(50) {
+            assert_eq!(&cursor.prev().unwrap().unwrap(), frame);
+        }
+        assert_eq!(cursor.frame(), 80);
+        assert_eq!(cursor.next().unwrap().unwrap(), forward[80]);
+
+        cursor.goto(130).unwrap();
+        let (frame, state) = cursor.state_at_cursor(None).unwrap();
+        assert_eq!((frame, state[0]), (120, 120));
+        assert_eq!(cursor.frame(), 130);
+        let (frame, state) = cursor.state_at_cursor(Some(&mut Counter(vec![]))).unwrap();
+        assert_eq!((frame, state[0]), (130, 130));
+        cursor.goto(20).unwrap();
+        let (frame, state) = cursor.state_at_cursor(None).unwrap();
+        assert_eq!((frame, state[0]), (0, 0));
+        assert_eq!(cursor.next().unwrap().unwrap(), forward[20]);
+
+        cursor.goto(1000).unwrap();
+        assert!(cursor.next().is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::InvalidDeterminant;
+use std::{collections::HashMap, hash::BuildHasherDefault};
+use xxhash_rust::xxh3::xxh3_64 as xxh;
+
+/* Source positions are indexed every WINDOW bytes; matches found through the
+ * index are at least this long */
+const WINDOW: usize = 16;
+/* Shorter copies at the current source/target offset aren't worth a token */
+const MIN_COPY: usize = 12;
+
+#[repr(u8)]
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum DeltaToken {
+    Copy = 0,
+    Add = 1,
+    End = 2,
+}
+impl TryFrom<u8> for DeltaToken {
+    type Error = InvalidDeterminant;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DeltaToken::Copy),
+            1 => Ok(DeltaToken::Add),
+            2 => Ok(DeltaToken::End),
+            _ => Err(InvalidDeterminant(value)),
+        }
+    }
+}
+
+impl From<DeltaToken> for u8 {
+    fn from(value: DeltaToken) -> Self {
+        match value {
+            DeltaToken::Copy => 0,
+            DeltaToken::Add => 1,
+            DeltaToken::End => 2,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DeltaError {
+    #[error("Invalid token {0}")]
+    InvalidToken(#[from] InvalidDeterminant),
+    #[error("Copy of {1} bytes from {0} is outside the source checkpoint")]
+    BadCopy(u64, u64),
+    #[error("Delta produced {0} bytes but checkpoint should have {1}")]
+    WrongSize(usize, usize),
+}
+
+fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn write_add(out: &mut Vec<u8>, bytes: &[u8]) -> std::io::Result<()> {
+    use rmp::encode as r;
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    r::write_uint(out, u64::from(u8::from(DeltaToken::Add)))?;
+    r::write_bin(out, bytes)?;
+    Ok(())
+}
+
+/// Writes `target` as a sequence of copies out of `source` and literal
+/// additions, returning the number of bytes written.
+pub(crate) fn encode<W: std::io::Write>(
+    writer: &mut W,
+    source: &[u8],
+    target: &[u8],
+) -> std::io::Result<u32> {
+    use rmp::encode as r;
+    let mut index: HashMap<u64, u32, BuildHasherDefault<nohash_hasher::NoHashHasher<u64>>> =
+        HashMap::with_capacity_and_hasher(source.len() / WINDOW, BuildHasherDefault::default());
+    for (i, window) in source.chunks_exact(WINDOW).enumerate() {
+        index.entry(xxh(window)).or_insert(
+            u32::try_from(i * WINDOW)
+                .map_err(|e| std::io::Error::other(crate::ReplayError::CheckpointTooBig(e)))?,
+        );
+    }
+    let mut out = Vec::with_capacity(target.len() / 8);
+    let mut pending = 0;
+    let mut t = 0;
+    /* Savestates mostly keep their layout, so first try to continue copying
+     * at the same offset as the last copy (initially, the aligned offset) */
+    let mut rel: isize = 0;
+    while t < target.len() {
+        let mut best = None;
+        if let Some(s) = t.checked_add_signed(rel)
+            && s < source.len()
+        {
+            let len = match_len(&source[s..], &target[t..]);
+            if len >= MIN_COPY {
+                best = Some((s, len));
+            }
+        }
+        if best.is_none()
+            && t + WINDOW <= target.len()
+            && let Some(&s) = index.get(&xxh(&target[t..t + WINDOW]))
+        {
+            let s = s as usize;
+            let len = match_len(&source[s..], &target[t..]);
+            if len >= WINDOW {
+                best = Some((s, len));
+            }
+        }
+        let Some((s, len)) = best else {
+            t += 1;
+            continue;
+        };
+        let back = source[..s]
+            .iter()
+            .rev()
+            .zip(target[pending..t].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+        write_add(&mut out, &target[pending..t - back])?;
+        r::write_uint(&mut out, u64::from(u8::from(DeltaToken::Copy)))?;
+        r::write_uint(&mut out, (s - back) as u64)?;
+        r::write_uint(&mut out, (len + back) as u64)?;
+        #[allow(clippy::cast_possible_wrap)]
+        {
+            rel = s as isize - t as isize;
+        }
+        t += len;
+        pending = t;
+    }
+    write_add(&mut out, &target[pending..])?;
+    r::write_uint(&mut out, u64::from(u8::from(DeltaToken::End)))?;
+    writer.write_all(&out)?;
+    u32::try_from(out.len())
+        .map_err(|e| std::io::Error::other(crate::ReplayError::CheckpointTooBig(e)))
+}
+
+/// Reads a delta against `source`, filling `target` (which must already
+/// have the checkpoint's size).
+pub(crate) fn decode<R: std::io::Read>(
+    reader: &mut R,
+    source: &[u8],
+    target: &mut [u8],
+) -> std::io::Result<()> {
+    use rmp::decode as r;
+    let mut t = 0;
+    loop {
+        let tok: u8 = r::read_int(reader).map_err(std::io::Error::other)?;
+        match DeltaToken::try_from(tok)
+            .map_err(|e| std::io::Error::other(DeltaError::InvalidToken(e)))?
+        {
+            DeltaToken::Copy => {
+                let start: u64 = r::read_int(reader).map_err(std::io::Error::other)?;
+                let len: u64 = r::read_int(reader).map_err(std::io::Error::other)?;
+                let (Ok(s), Ok(l)) = (usize::try_from(start), usize::try_from(len)) else {
+                    return Err(std::io::Error::other(DeltaError::BadCopy(start, len)));
+                };
+                if s.saturating_add(l) > source.len() || t + l > target.len() {
+                    return Err(std::io::Error::other(DeltaError::BadCopy(start, len)));
+                }
+                target[t..t + l].copy_from_slice(&source[s..s + l]);
+                t += l;
+            }
+            DeltaToken::Add => {
+                let len = r::read_bin_len(reader).map_err(std::io::Error::other)? as usize;
+                if t + len > target.len() {
+                    return Err(std::io::Error::other(DeltaError::WrongSize(
+                        t + len,
+                        target.len(),
+                    )));
+                }
+                reader.read_exact(&mut target[t..t + len])?;
+                t += len;
+            }
+            DeltaToken::End => break,
+        }
+    }
+    if t != target.len() {
+        return Err(std::io::Error::other(DeltaError::WrongSize(
+            t,
+            target.len(),
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(source: &[u8], target: &[u8]) -> usize {
+        let mut out = vec![];
+        let size = encode(&mut out, source, target).unwrap();
+        assert_eq!(size as usize, out.len());
+        let mut decoded = vec![0; target.len()];
+        decode(&mut out.as_slice(), source, &mut decoded).unwrap();
+        assert_eq!(decoded, target);
+        out.len()
+    }
+
+    #[test]
+    fn shifted_and_edited() {
+        let source: Vec<u8> = (0..8192_u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let mut target = source.clone();
+        target[100] ^= 0xFF;
+        target[5000..5010].fill(3);
+        target.insert(2000, 42);
+        target.truncate(8000);
+        assert!(roundtrip(&source, &target) < 128);
+        assert!(roundtrip(&[], &target) <= target.len() + 8);
+        roundtrip(&source, &[]);
+    }
+}
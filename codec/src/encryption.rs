@@ -0,0 +1,350 @@
+use crate::InvalidDeterminant;
+
+/// Which parts of a v2 replay are encrypted.  Stored in the low byte of the
+/// header's checkpoint configuration word, so replays without encryption
+/// are unchanged.
+///
+/// Encrypting only checkpoints keeps inputs readable (e.g. to share a
+/// sync-verifiable run while keeping savestates, which may embed personal
+/// data, private); encrypting only inputs does the reverse.  A decoder
+/// without the key reads encrypted sections as empty.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncryptedSections {
+    /// The initial state and every checkpoint payload
+    pub checkpoints: bool,
+    /// The key and input events of every frame
+    pub inputs: bool,
+}
+
+impl EncryptedSections {
+    #[must_use]
+    pub fn any(self) -> bool {
+        self.checkpoints || self.inputs
+    }
+}
+
+impl TryFrom<u8> for EncryptedSections {
+    type Error = InvalidDeterminant;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        if value & !0b11 != 0 {
+            return Err(InvalidDeterminant(value));
+        }
+        Ok(Self {
+            checkpoints: value & 0b01 != 0,
+            inputs: value & 0b10 != 0,
+        })
+    }
+}
+
+impl From<EncryptedSections> for u8 {
+    fn from(value: EncryptedSections) -> Self {
+        u8::from(value.checkpoints) | (u8::from(value.inputs) << 1)
+    }
+}
+
+/// Size of the random salt stored between the header and the initial state of encrypted replays
+pub(crate) const SALT_LEN: usize = 16;
+
+/// Distinguishes the nonces of sections sealed on the same frame
+#[derive(Clone, Copy)]
+pub(crate) enum Section {
+    InitialState = 0,
+    Checkpoint = 1,
+    Inputs = 2,
+}
+
+#[cfg(feature = "encryption")]
+pub(crate) use cipher::Cipher;
+#[cfg(feature = "encryption")]
+pub use cipher::EncryptionKey;
+#[cfg(not(feature = "encryption"))]
+pub(crate) use disabled::Cipher;
+
+#[cfg(feature = "encryption")]
+mod cipher {
+    use super::{SALT_LEN, Section};
+    use crate::{ReplayError, checkpoint::Codecs};
+    use chacha20poly1305::{
+        AeadInPlace, KeyInit, XChaCha20Poly1305, XNonce, aead::generic_array::GenericArray,
+    };
+
+    /// A 256-bit key for the `encryption` feature.
+    #[derive(Clone)]
+    pub struct EncryptionKey([u8; 32]);
+
+    impl EncryptionKey {
+        #[must_use]
+        pub fn new(bytes: [u8; 32]) -> Self {
+            Self(bytes)
+        }
+    }
+
+    impl std::fmt::Debug for EncryptionKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("EncryptionKey(..)")
+        }
+    }
+
+    pub(crate) struct Cipher {
+        aead: XChaCha20Poly1305,
+        salt: [u8; SALT_LEN],
+    }
+
+    impl Cipher {
+        /// The cipher for a replay with the given salt, if a key was registered.
+        pub(crate) fn with_salt(codecs: &Codecs, salt: [u8; SALT_LEN]) -> Option<Self> {
+            codecs.encryption_key().map(|key| Self {
+                aead: XChaCha20Poly1305::new(GenericArray::from_slice(&key.0)),
+                salt,
+            })
+        }
+        /// The cipher for a new replay with a fresh random salt, if a key was registered.
+        pub(crate) fn generate(codecs: &Codecs) -> std::io::Result<Option<Self>> {
+            let mut salt = [0; SALT_LEN];
+            getrandom::getrandom(&mut salt).map_err(std::io::Error::other)?;
+            Ok(Self::with_salt(codecs, salt))
+        }
+        pub(crate) fn salt(&self) -> &[u8; SALT_LEN] {
+            &self.salt
+        }
+        /* salt || section || low 7 bytes of the frame number */
+        fn nonce(&self, section: Section, frame: u64) -> XNonce {
+            let mut nonce = XNonce::default();
+            nonce[..SALT_LEN].copy_from_slice(&self.salt);
+            nonce[SALT_LEN] = section as u8;
+            nonce[SALT_LEN + 1..].copy_from_slice(&frame.to_le_bytes()[..7]);
+            nonce
+        }
+        pub(crate) fn seal(
+            &self,
+            section: Section,
+            frame: u64,
+            buf: &mut Vec<u8>,
+        ) -> std::io::Result<()> {
+            self.aead
+                .encrypt_in_place(&self.nonce(section, frame), b"", buf)
+                .map_err(|_| std::io::Error::other("Section too large to encrypt"))
+        }
+        pub(crate) fn open(
+            &self,
+            section: Section,
+            frame: u64,
+            buf: &mut Vec<u8>,
+        ) -> Result<(), ReplayError> {
+            self.aead
+                .decrypt_in_place(&self.nonce(section, frame), b"", buf)
+                .map_err(|_| ReplayError::Decryption())
+        }
+    }
+}
+
+/* Without the feature there is never a key, so no cipher can exist */
+#[cfg(not(feature = "encryption"))]
+mod disabled {
+    use super::{SALT_LEN, Section};
+    use crate::{ReplayError, checkpoint::Codecs};
+
+    pub(crate) enum Cipher {}
+
+    impl Cipher {
+        pub(crate) fn with_salt(_codecs: &Codecs, _salt: [u8; SALT_LEN]) -> Option<Self> {
+            None
+        }
+        #[allow(clippy::unnecessary_wraps)]
+        pub(crate) fn generate(_codecs: &Codecs) -> std::io::Result<Option<Self>> {
+            Ok(None)
+        }
+        pub(crate) fn salt(&self) -> &[u8; SALT_LEN] {
+            match *self {}
+        }
+        pub(crate) fn seal(&self, _: Section, _: u64, _: &mut Vec<u8>) -> std::io::Result<()> {
+            match *self {}
+        }
+        pub(crate) fn open(&self, _: Section, _: u64, _: &mut Vec<u8>) -> Result<(), ReplayError> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+    use crate::{CodecRegistry, Frame, ReplayDecoder, ReplayEncoder, ReplayError};
+
+    fn registry(key: u8) -> CodecRegistry {
+        let mut registry = CodecRegistry::default();
+        registry.set_encryption_key(EncryptionKey::new([key; 32]));
+        registry
+    }
+
+    #[test]
+    fn sections_roundtrip() {
+        let mut rply = crate::decode(std::io::BufReader::new(
+            std::fs::File::open(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../examples/bobl.replay"
+            ))
+            .unwrap(),
+        ))
+        .unwrap();
+        let frames: Vec<Frame> = (0..400)
+            .map(|_| {
+                let mut frame = Frame::default();
+                rply.read_frame(&mut frame).unwrap();
+                frame
+            })
+            .collect();
+        for (checkpoints, inputs) in [(true, false), (false, true), (true, true)] {
+            let sections = EncryptedSections {
+                checkpoints,
+                inputs,
+            };
+            let mut header = rply.header.clone();
+            header.set_encrypted_sections(sections);
+            let mut out = std::io::Cursor::new(vec![]);
+            {
+                let mut enc = ReplayEncoder::with_codecs(
+                    header.clone(),
+                    &rply.initial_state,
+                    &mut out,
+                    registry(7),
+                )
+                .unwrap();
+                for frame in &frames {
+                    enc.write_frame(frame).unwrap();
+                }
+                enc.finish().unwrap();
+            }
+            let bytes = out.into_inner();
+            assert!(matches!(
+                ReplayEncoder::new(
+                    header,
+                    &rply.initial_state,
+                    &mut std::io::Cursor::new(vec![])
+                ),
+                Err(ReplayError::MissingKey())
+            ));
+            if checkpoints {
+                assert!(matches!(
+                    ReplayDecoder::with_codecs(bytes.as_slice(), registry(8)),
+                    Err(ReplayError::Decryption())
+                ));
+            }
+
+            let mut dec =
+                ReplayDecoder::with_codecs(std::io::Cursor::new(&bytes), registry(7)).unwrap();
+            assert_eq!(dec.locked_sections(), EncryptedSections::default());
+            assert_eq!(dec.initial_state, rply.initial_state);
+            dec.seek_to_frame(250).unwrap();
+            dec.seek_to_frame(0).unwrap();
+            let mut locked = crate::decode(bytes.as_slice()).unwrap();
+            assert_eq!(locked.locked_sections(), sections);
+            assert_eq!(locked.initial_state.is_empty(), checkpoints);
+            let mut frame = Frame::default();
+            for orig in &frames {
+                dec.read_frame(&mut frame).unwrap();
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+                assert_eq!(frame.inputs(), orig.inputs());
+                locked.read_frame(&mut frame).unwrap();
+                assert_eq!(
+                    frame.checkpoint_bytes.is_empty(),
+                    checkpoints || orig.checkpoint_bytes.is_empty()
+                );
+                assert_eq!(
+                    frame.input_events.is_empty(),
+                    inputs || orig.input_events.is_empty()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sealed_sizes() {
+        let mut header = crate::HeaderV2::builder(1, 0).build().unwrap();
+        header.set_encrypted_sections(EncryptedSections {
+            checkpoints: false,
+            inputs: true,
+        });
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = ReplayEncoder::with_codecs(header, &[], &mut out, registry(7)).unwrap();
+            enc.write_frame(&Frame::default()).unwrap();
+            enc.finish().unwrap();
+        }
+        let mut bytes = out.into_inner();
+        // The frame's backref, then its sealed events' size
+        let at = usize::try_from(crate::decode(bytes.as_slice()).unwrap().position()).unwrap() + 4;
+        bytes[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        // Claiming 4 GiB of events doesn't get them allocated, with or without the key
+        for key in [Some(7), None] {
+            let codecs = key.map_or_else(CodecRegistry::default, registry);
+            let mut dec = ReplayDecoder::with_codecs(bytes.as_slice(), codecs).unwrap();
+            assert!(matches!(
+                dec.read_frame(&mut Frame::default()),
+                Err(ReplayError::AtFrame(_, _, e))
+                    if matches!(&*e, ReplayError::IO(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+            ));
+        }
+    }
+
+    /* A stream whose writes fail while `full` is set */
+    struct FailingDisk {
+        inner: std::io::Cursor<Vec<u8>>,
+        full: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    impl std::io::Write for FailingDisk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.full.get() {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for FailingDisk {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn failed_frame_stops_encoder() {
+        let bytes = crate::verify::tests::replay(None);
+        let rply = crate::decode(bytes.as_slice()).unwrap();
+        let mut header = rply.header.clone();
+        header.set_encrypted_sections(EncryptedSections {
+            checkpoints: false,
+            inputs: true,
+        });
+        let full = std::rc::Rc::new(std::cell::Cell::new(false));
+        let mut disk = FailingDisk {
+            inner: std::io::Cursor::new(vec![]),
+            full: full.clone(),
+        };
+        let mut enc =
+            ReplayEncoder::with_codecs(header, &rply.initial_state, &mut disk, registry(7))
+                .unwrap();
+        // Even with rollback, a retry would reuse the failed frame's nonce
+        enc.set_rollback(true);
+        let frame = Frame::default();
+        enc.write_frame(&frame).unwrap();
+        full.set(true);
+        assert!(matches!(enc.write_frame(&frame), Err(ReplayError::IO(_))));
+        full.set(false);
+        assert!(matches!(
+            enc.write_frame(&frame),
+            Err(ReplayError::EncoderOutOfStep())
+        ));
+        enc.finish().unwrap();
+        drop(enc);
+        let mut dec =
+            ReplayDecoder::with_codecs(disk.inner.get_ref().as_slice(), registry(7)).unwrap();
+        assert_eq!(dec.frames().count(), 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Ready-made wiring of the crate's pieces, as used by the programs in
+//! `codec/examples`: a [`Player`] that drives a [`Core`] from a
+//! [`ReplayDecoder`] and hands each frame to a [`Renderer`], and, with the
+//! `retro` feature, [`record`] for running a libretro core into a
+//! [`ReplayRecorder`](crate::ReplayRecorder) and [`PpmFrames`] for saving
+//! what it shows.  Each is small enough to copy and adapt.
+
+use crate::{Core, Frame, ReplayDecoder, ReplayError};
+use std::io::{BufRead, Seek};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// Receives each frame a [`Player`] plays, after the core has run it, e.g.
+/// to show the core's video or encode it.  Closures taking the frame
+/// number, the frame, and the core are renderers too.
+pub trait Renderer<C: Core> {
+    /// # Errors
+    /// Whatever stops rendering; [`Player::play`] passes it on
+    fn render(&mut self, frame_number: u64, frame: &Frame, core: &mut C) -> Result<()>;
+    /// Called once [`Player::play`] has played the last frame.
+    /// # Errors
+    /// As [`Renderer::render`]
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<C: Core, F: FnMut(u64, &Frame, &mut C) -> Result<()>> Renderer<C> for F {
+    fn render(&mut self, frame_number: u64, frame: &Frame, core: &mut C) -> Result<()> {
+        self(frame_number, frame, core)
+    }
+}
+
+/// Plays a replay on a core: loads its initial state, then runs each
+/// frame's inputs.  With [`Player::set_resync`], the core also loads every
+/// checkpoint it reaches, so that a core which drifts from the recording
+/// (e.g. a different build) is pulled back in step.
+pub struct Player<R: BufRead + Seek, C: Core> {
+    rply: ReplayDecoder<R>,
+    core: C,
+    frame: Frame,
+    resync: bool,
+}
+
+impl<R: BufRead + Seek, C: Core> Player<R, C> {
+    /// # Errors
+    /// [`ReplayError::NoCoreRead`]: Version 0 replays can't be played this way; see [`crate::decode_any`]
+    /// [`ReplayError::CoreState`]: The core could not load the initial state
+    /// Otherwise as [`ReplayDecoder::seek_to_frame`]
+    pub fn new(mut rply: ReplayDecoder<R>, mut core: C) -> Result<Self> {
+        if !rply.header.capabilities().coreless_frames {
+            return Err(ReplayError::NoCoreRead());
+        }
+        rply.seek_to_frame(0)?;
+        if !rply.initial_state.is_empty() && !core.load_state(&rply.initial_state) {
+            return Err(ReplayError::CoreState(0));
+        }
+        Ok(Self {
+            rply,
+            core,
+            frame: Frame::default(),
+            resync: false,
+        })
+    }
+    /// Loads each checkpoint into the core when it's reached (off by default).
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+    /// Frames played so far
+    #[must_use]
+    pub fn frame_number(&self) -> u64 {
+        self.rply.frame_number
+    }
+    pub fn decoder(&mut self) -> &mut ReplayDecoder<R> {
+        &mut self.rply
+    }
+    pub fn core(&mut self) -> &mut C {
+        &mut self.core
+    }
+    #[must_use]
+    pub fn into_parts(self) -> (ReplayDecoder<R>, C) {
+        (self.rply, self.core)
+    }
+    /// Plays one frame, returning it, or `None` at the end of the replay.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core could not load a checkpoint
+    /// Otherwise as [`ReplayDecoder::read_frame`]
+    pub fn step(&mut self) -> Result<Option<&Frame>> {
+        if self.rply.at_end()? {
+            return Ok(None);
+        }
+        self.rply.read_frame(&mut self.frame)?;
+        self.core.run_frame(&self.frame);
+        if self.resync
+            && !self.frame.checkpoint_bytes.is_empty()
+            && !self.core.load_state(&self.frame.checkpoint_bytes)
+        {
+            return Err(ReplayError::CoreState(self.rply.frame_number));
+        }
+        Ok(Some(&self.frame))
+    }
+    /// Puts the core in its state after `frame` frames, by loading the
+    /// last checkpoint at or before it (or the initial state) and playing
+    /// the frames from there.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core could not load the state
+    /// Otherwise as [`ReplayDecoder::checkpoints`] and [`Player::step`]
+    pub fn seek(&mut self, frame: u64) -> Result<()> {
+        let from = self
+            .rply
+            .checkpoints()?
+            .into_iter()
+            .map(|info| info.frame)
+            .filter(|&cp| cp > 0 && cp <= frame)
+            .max();
+        if let Some(from) = from {
+            self.rply.seek_to_frame(from - 1)?;
+            self.rply.read_frame(&mut self.frame)?;
+            if !self.core.load_state(&self.frame.checkpoint_bytes) {
+                return Err(ReplayError::CoreState(from));
+            }
+        } else {
+            self.rply.seek_to_frame(0)?;
+            if !self.core.load_state(&self.rply.initial_state) {
+                return Err(ReplayError::CoreState(0));
+            }
+        }
+        while self.rply.frame_number < frame && self.step()?.is_some() {}
+        Ok(())
+    }
+    /// Plays the rest of the replay into `renderer`, returning the number
+    /// of frames played.
+    /// # Errors
+    /// Any error from [`Player::step`] or `renderer`
+    pub fn play(&mut self, renderer: &mut impl Renderer<C>) -> Result<u64> {
+        let start = self.rply.frame_number;
+        while self.step()?.is_some() {
+            renderer.render(self.rply.frame_number, &self.frame, &mut self.core)?;
+        }
+        renderer.finish()?;
+        Ok(self.rply.frame_number - start)
+    }
+}
+
+#[cfg(feature = "retro")]
+pub use retro::{PpmFrames, record};
+
+#[cfg(feature = "retro")]
+mod retro {
+    use super::{Renderer, Result};
+    use crate::{Frame, Header, ReplayError, ReplayRecorder};
+    use retro_rs::Emulator;
+    use std::cell::RefCell;
+    use std::io::{Seek, Write};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    /// Records `frames` frames of `emu` into `rply`, with a checkpoint
+    /// every `checkpoint_interval` frames, asking `input(frame, port,
+    /// device, index, id)` for each input the core polls; hands the
+    /// emulator back once the replay is finished.
+    /// # Errors
+    /// As [`ReplayRecorder::new`], [`ReplayRecorder::run_frame`], and
+    /// [`ReplayRecorder::finish`]
+    pub fn record<W: Write + Seek>(
+        emu: Emulator,
+        header: Header,
+        rply: &mut W,
+        frames: u64,
+        checkpoint_interval: u64,
+        input: impl FnMut(u64, u32, u32, u32, u32) -> i16 + 'static,
+    ) -> Result<Emulator> {
+        let mut recorder = ReplayRecorder::new(emu, header, rply)?;
+        recorder.set_checkpoint_interval(checkpoint_interval);
+        // Each frame's callback must own what it uses, so they share the caller's
+        let input = Rc::new(RefCell::new(input));
+        for _ in 0..frames {
+            let frame = recorder.frame_number() + 1;
+            let input = Rc::clone(&input);
+            recorder.run_frame(move |port, device, idx, id| {
+                (input.borrow_mut())(frame, port, device, idx, id)
+            })?;
+        }
+        recorder.finish()
+    }
+
+    /// Saves the emulator's screen as a binary PPM image in a directory
+    /// every so many frames, named by frame number; handy for checking a
+    /// replay by eye without a video encoder.
+    pub struct PpmFrames {
+        dir: PathBuf,
+        every: u64,
+        rgb: Vec<u8>,
+    }
+
+    impl PpmFrames {
+        /// Saves every `every`th frame (at least every one) into `dir`,
+        /// which must exist.
+        pub fn new(dir: impl Into<PathBuf>, every: u64) -> Self {
+            Self {
+                dir: dir.into(),
+                every: every.max(1),
+                rgb: vec![],
+            }
+        }
+    }
+
+    impl Renderer<Emulator> for PpmFrames {
+        fn render(&mut self, frame_number: u64, _frame: &Frame, emu: &mut Emulator) -> Result<()> {
+            if !frame_number.is_multiple_of(self.every) {
+                return Ok(());
+            }
+            let (w, h) = emu.framebuffer_size();
+            self.rgb.resize(w * h * 3, 0);
+            emu.copy_framebuffer_rgb888(&mut self.rgb)
+                .map_err(|_| ReplayError::CoreState(frame_number))?;
+            let path = self.dir.join(format!("{frame_number:08}.ppm"));
+            let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+            write!(out, "P6\n{w} {h}\n255\n")?;
+            out.write_all(&self.rgb)?;
+            out.flush()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::{Counter, replay};
+
+    #[test]
+    fn player() {
+        let bytes = replay(None);
+        let open = || crate::decode(std::io::Cursor::new(bytes.clone())).unwrap();
+        let mut player = Player::new(open(), Counter(vec![])).unwrap();
+        let mut states = vec![];
+        let played = player
+            .play(&mut |n: u64, frame: &Frame, core: &mut Counter| {
+                assert_eq!(u64::from(core.0[0]), n % 256);
+                if !frame.checkpoint_bytes.is_empty() {
+                    assert_eq!(core.0, frame.checkpoint_bytes);
+                }
+                states.push(core.0.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(played, 1000);
+        assert!(player.step().unwrap().is_none());
+
+        // Seeking loads the checkpoint at frame 480 and plays on from there
+        player.seek(519).unwrap();
+        assert_eq!(player.frame_number(), 519);
+        assert_eq!(player.core().0, states[518]);
+        player.seek(10).unwrap();
+        assert_eq!(player.core().0, states[9]);
+        player.set_resync(true);
+        let frame = player.step().unwrap().unwrap().clone();
+        assert_eq!((player.frame_number(), frame.input_events.len()), (11, 1));
+        let (rply, core) = player.into_parts();
+        assert_eq!((rply.frame_number, core.0), (11, states[10].clone()));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{Frame, ReplayDecoder, ReplayError, Verification};
+use std::io::{BufRead, Seek, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/* Runs `command replay from to` with `state` on stdin, returning the hash it prints */
+fn run(
+    command: &mut impl FnMut() -> Command,
+    replay: &Path,
+    from: u64,
+    to: u64,
+    state: &[u8],
+) -> Result<String> {
+    let mut command = command();
+    let mut child = command
+        .arg(replay)
+        .arg(from.to_string())
+        .arg(to.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    match stdin.write_all(state) {
+        // Commands needn't read all of the state, e.g. to hash part of it
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        result => result?,
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(ReplayError::ExternalCommand(to, output.status.to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Verifies `rply`, read from the file `replay`, with an external program
+/// instead of a linked [`crate::Core`], so emulators this crate can't
+/// drive can check replays too.  For each checkpoint, the command made by
+/// `command` is run twice with three more arguments, `replay from to`,
+/// and a savestate on stdin:
+///
+/// - with the state at frame `from` (the previous checkpoint, or the
+///   initial state, which is empty for replays starting from power-on),
+///   it must load the state, run frames `from + 1` through `to` of the
+///   replay, and print a hash of the emulator's state;
+/// - with `from` equal to `to` and the recorded checkpoint, it must load
+///   the state and print its hash without running any frames.
+///
+/// The checkpoint matches if both print the same hash (surrounding
+/// whitespace aside).  The hash is up to the command, so it can leave out
+/// parts of the state that don't affect emulation.  Stops at the first
+/// desync; hashes can't say where states differ, so the result's
+/// [`Verification::differences`] is always empty.
+/// # Errors
+/// [`ReplayError::ExternalCommand`]: The command exited unsuccessfully
+/// [`ReplayError::IO`]: The command could not be run
+/// Any error from decoding the replay
+pub fn verify_external<R: BufRead + Seek>(
+    rply: &mut ReplayDecoder<R>,
+    replay: &Path,
+    mut command: impl FnMut() -> Command,
+) -> Result<Verification> {
+    rply.seek_to_frame(0)?;
+    let mut start = 0;
+    let mut state = rply.initial_state.clone();
+    let mut checkpoints = 0;
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        if frame.checkpoint_bytes.is_empty() {
+            continue;
+        }
+        let end = rply.frame_number;
+        let expected = run(&mut command, replay, end, end, &frame.checkpoint_bytes)?;
+        if run(&mut command, replay, start, end, &state)? != expected {
+            return Ok(Verification {
+                frames: end,
+                checkpoints,
+                desync: Some(end),
+                differences: vec![],
+            });
+        }
+        checkpoints += 1;
+        start = end;
+        state = std::mem::take(&mut frame.checkpoint_bytes);
+    }
+    Ok(Verification {
+        frames: rply.frame_number,
+        checkpoints,
+        desync: None,
+        differences: vec![],
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    /* An "emulator" whose state is the test core's frame counter, which it
+    reads from the first byte of the savestate; running frames up to 200 or
+    later adds `bug` to it */
+    fn counter(bug: u32) -> impl FnMut() -> Command {
+        move || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(format!(
+                r#"n=$(od -An -tu1 -N1); [ "$3" -ge 200 ] && [ "$2" != "$3" ] && n=$((n + {bug})); echo $(( (n + $3 - $2) % 256 ))"#
+            ));
+            command.arg("sh");
+            command
+        }
+    }
+
+    #[test]
+    fn external_verify() {
+        let bytes = replay(None);
+        let path = Path::new("bobl.replay");
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let verification = verify_external(&mut rply, path, counter(0)).unwrap();
+        assert_eq!(
+            (
+                verification.desync,
+                verification.checkpoints,
+                verification.frames
+            ),
+            (None, 25, 1000)
+        );
+
+        let verification = verify_external(&mut rply, path, counter(1)).unwrap();
+        assert_eq!(
+            (verification.desync, verification.checkpoints),
+            (Some(200), 4)
+        );
+
+        let fail = || {
+            let mut command = Command::new("sh");
+            command.args(["-c", "exit 3", "sh"]);
+            command
+        };
+        assert!(matches!(
+            verify_external(&mut rply, path, fail),
+            Err(ReplayError::ExternalCommand(40, _))
+        ));
+    }
+}
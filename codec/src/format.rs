@@ -0,0 +1,104 @@
+//! What each version of the replay format can store, so that tools can
+//! feature-detect instead of comparing version numbers themselves.
+
+/// The newest format version this crate reads and writes
+pub const LATEST: u32 = 3;
+
+/// Largest sizes a format version can represent.  Each is the largest
+/// value of the field that stores it, not a practical limit; see
+/// [`crate::DecodeLimits`] for those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxSizes {
+    /// Bytes in the initial state
+    pub initial_state_bytes: u64,
+    /// Bytes in one checkpoint, after encoding and compression
+    pub checkpoint_bytes: u64,
+    /// Key events in one frame
+    pub key_events: u64,
+    /// Input events in one frame
+    pub input_events: u64,
+    /// Frames in the header's frame count, if the header has one
+    pub frames: Option<u64>,
+}
+
+/// Features of one version of the replay format; see [`capabilities`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u32,
+    /// Whether this crate can read replays of this version at all
+    pub supported: bool,
+    /// Frames can be read without a loaded core; version 0 replays hold raw
+    /// button polls that only make sense to the core that made them
+    pub coreless_frames: bool,
+    /// Each frame starts with the distance back to the previous one, so
+    /// decoders can seek backwards without restarting
+    pub has_backrefs: bool,
+    /// The header records the frame count, block and superblock sizes, and
+    /// checkpoint commit settings
+    pub has_frame_count: bool,
+    /// Checkpoints may use encodings other than [`crate::Encoding::Raw`], e.g. statestream
+    pub has_statestream: bool,
+    /// Checkpoints may be compressed
+    pub has_compression: bool,
+    /// Sections may be encrypted
+    pub has_encryption: bool,
+    /// The header may carry a [`crate::Metadata`] block
+    pub has_metadata: bool,
+    pub max_sizes: MaxSizes,
+}
+
+/// The features of replay format `version`.  Versions newer than [`LATEST`]
+/// are reported as unsupported with no features.
+#[must_use]
+pub fn capabilities(version: u32) -> Capabilities {
+    let supported = version <= LATEST;
+    let v2 = supported && version >= 2;
+    Capabilities {
+        version,
+        supported,
+        coreless_frames: supported && version >= 1,
+        has_backrefs: v2,
+        has_frame_count: v2,
+        has_statestream: v2,
+        has_compression: v2,
+        has_encryption: v2,
+        has_metadata: supported && version >= 3,
+        max_sizes: MaxSizes {
+            initial_state_bytes: u64::from(u32::MAX),
+            checkpoint_bytes: if v2 { u64::from(u32::MAX) } else { u64::MAX },
+            key_events: u64::from(u8::MAX),
+            input_events: u64::from(u16::MAX),
+            frames: v2.then_some(u64::from(u32::MAX)),
+        },
+    }
+}
+
+impl crate::Header {
+    /// The [`Capabilities`] of this header's format version
+    #[must_use]
+    pub fn capabilities(&self) -> Capabilities {
+        capabilities(self.version())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        let v0 = capabilities(0);
+        assert!(v0.supported && !v0.coreless_frames && !v0.has_backrefs);
+        let v1 = capabilities(1);
+        assert!(v1.coreless_frames && !v1.has_statestream && v1.max_sizes.frames.is_none());
+        let v2 = capabilities(2);
+        assert!(v2.has_backrefs && v2.has_compression && !v2.has_metadata);
+        assert_eq!(v2.max_sizes.frames, Some(u64::from(u32::MAX)));
+        assert!(capabilities(LATEST).has_metadata);
+        let future = capabilities(LATEST + 1);
+        assert!(!future.supported && !future.coreless_frames && !future.has_metadata);
+        let bytes = crate::verify::tests::replay(None);
+        let rply = crate::decode(bytes.as_slice()).unwrap();
+        assert_eq!(rply.header.capabilities(), capabilities(2));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{Core, ReplayDecoder, ReplayError};
+use std::io::{BufRead, Seek, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// A core whose memory can be read, e.g. its system RAM.
+pub trait Memory {
+    /// Fills `buf` with the bytes at `address`.  Returns false if the
+    /// core can't read them.
+    fn peek(&mut self, address: usize, buf: &mut [u8]) -> bool;
+}
+
+/// A memory value recorded every frame, such as a player coordinate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GhostField {
+    /// Column name in the exported track
+    pub name: String,
+    pub address: usize,
+    /// Width of the value in bytes, from 1 to 8
+    pub size: u8,
+    pub signed: bool,
+    pub big_endian: bool,
+}
+
+impl GhostField {
+    fn read<M: Memory + ?Sized>(&self, core: &mut M) -> Option<i64> {
+        let size = usize::from(self.size.clamp(1, 8));
+        let mut bytes = [0; 8];
+        if !core.peek(self.address, &mut bytes[..size]) {
+            return None;
+        }
+        if self.big_endian {
+            bytes[..size].reverse();
+        }
+        let value = u64::from_le_bytes(bytes);
+        let shift = 64 - 8 * size as u32;
+        Some(if self.signed {
+            ((value << shift).cast_signed()) >> shift
+        } else {
+            value.cast_signed()
+        })
+    }
+}
+
+/// Reduces a replay to a "ghost" track: for every frame, the joypad state
+/// of each port and the value of each [`GhostField`] after the frame runs.
+/// The track is CSV with a header row, e.g. `frame,port0,port1,x,y`, so
+/// frontends implementing ghost racing can load it without this crate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ghost {
+    pub fields: Vec<GhostField>,
+    /// Ports whose joypad bitmask gets a column (default 2)
+    pub ports: u8,
+}
+
+impl Default for Ghost {
+    fn default() -> Self {
+        Self {
+            fields: vec![],
+            ports: 2,
+        }
+    }
+}
+
+impl Ghost {
+    /// Runs `rply` from the start through `core`, writing one row per frame
+    /// to `out`.  Returns the number of frames exported.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core failed to load the initial state
+    /// [`ReplayError::Memory`]: The core couldn't read a field's address
+    /// [`ReplayError::IO`]: Error writing the track
+    /// Any error from decoding the replay
+    pub fn export<R: BufRead + Seek, C: Core + Memory + ?Sized, W: Write>(
+        &self,
+        rply: &mut ReplayDecoder<R>,
+        core: &mut C,
+        out: &mut W,
+    ) -> Result<u64> {
+        rply.seek_to_frame(0)?;
+        if !rply.initial_state.is_empty() && !core.load_state(&rply.initial_state) {
+            return Err(ReplayError::CoreState(0));
+        }
+        write!(out, "frame")?;
+        for port in 0..self.ports {
+            write!(out, ",port{port}")?;
+        }
+        for field in &self.fields {
+            write!(out, ",{}", field.name)?;
+        }
+        writeln!(out)?;
+        let mut buttons = vec![0_i16; usize::from(self.ports)];
+        for (i, frame) in rply.frames().enumerate() {
+            let frame = frame?;
+            core.run_frame(&frame);
+            buttons.fill(0);
+            // Joypads (device 1) record their buttons as bitmasks
+            for input in frame.input_events.iter().filter(|i| i.device == 1) {
+                if let Some(mask) = buttons.get_mut(usize::from(input.port)) {
+                    *mask |= input.val;
+                }
+            }
+            write!(out, "{}", i + 1)?;
+            for mask in &buttons {
+                write!(out, ",{}", mask.cast_unsigned())?;
+            }
+            for field in &self.fields {
+                let value = field.read(core).ok_or(ReplayError::Memory(field.address))?;
+                write!(out, ",{value}")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(rply.frame_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::{Counter, replay};
+
+    impl Memory for Counter {
+        fn peek(&mut self, address: usize, buf: &mut [u8]) -> bool {
+            let Some(bytes) = self.0.get(address..address + buf.len()) else {
+                return false;
+            };
+            buf.copy_from_slice(bytes);
+            true
+        }
+    }
+
+    #[test]
+    fn ghost_track() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let field = |name: &str, address, size, signed, big_endian| GhostField {
+            name: name.into(),
+            address,
+            size,
+            signed,
+            big_endian,
+        };
+        let ghost = Ghost {
+            fields: vec![
+                field("frames", 0, 1, false, false),
+                field("signed", 0, 1, true, false),
+                field("wide", 0, 2, false, true),
+            ],
+            ports: 1,
+        };
+        let mut out = vec![];
+        let frames = ghost
+            .export(&mut rply, &mut Counter(vec![]), &mut out)
+            .unwrap();
+        assert_eq!(frames, 1000);
+        let track = String::from_utf8(out).unwrap();
+        let rows: Vec<_> = track.lines().collect();
+        assert_eq!(rows.len(), 1001);
+        assert_eq!(rows[0], "frame,port0,frames,signed,wide");
+        /* The toy core's first byte counts frames; the input isn't a joypad */
+        assert_eq!(
+            rows[1].split(',').take(3).collect::<Vec<_>>(),
+            ["1", "0", "1"]
+        );
+        assert!(rows[200].starts_with("200,0,200,-56,"));
+
+        let ghost = Ghost {
+            fields: vec![field("oob", 1000, 4, false, false)],
+            ..Ghost::default()
+        };
+        assert!(matches!(
+            ghost.export(&mut rply, &mut Counter(vec![]), &mut vec![]),
+            Err(ReplayError::Memory(1000))
+        ));
+    }
+}
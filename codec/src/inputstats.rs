@@ -0,0 +1,193 @@
+use crate::{Frame, InputData, ReplayDecoder, ReplayError};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::ops::Range;
+
+/* Idle stretches kept in InputStats::idle */
+const IDLE_STRETCHES: usize = 5;
+
+/* Port, device, index, and id of an input */
+type Key = (u8, u8, u8, u16);
+
+/* The inputs `input` holds down: a joypad bitmask's buttons, or else itself if nonzero */
+fn held_by(input: &InputData) -> impl Iterator<Item = Key> + '_ {
+    let ids: Vec<u16> = match input.joypad_mask() {
+        Some(mask) => (0..16).filter(|bit| mask & (1 << bit) != 0).collect(),
+        None if input.val != 0 => vec![input.id],
+        None => vec![],
+    };
+    ids.into_iter()
+        .map(|id| (input.port, input.device, input.idx, id))
+}
+
+/// How one input of one port was used, from [`input_stats`].  Any nonzero
+/// value counts as held, so analog axes count while off centre, and
+/// joypad bitmask events are split into their buttons' ids.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ButtonStats {
+    pub port: u8,
+    pub device: u8,
+    pub idx: u8,
+    pub id: u16,
+    /// Frames where it went from released, or not polled, to held
+    pub presses: u64,
+    /// Frames it was held in
+    pub held_frames: u64,
+    /// The longest run of frames it was held for
+    pub longest_hold: u64,
+}
+
+/// Input activity over a replay, from [`input_stats`], e.g. for a
+/// dashboard's button heatmap or a video overlay.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InputStats {
+    /// Frames read
+    pub frames: u64,
+    /// Every input held at least once, by port, device, index, and id
+    pub buttons: Vec<ButtonStats>,
+    /// The longest runs of frames (numbered from 1) with no input held and
+    /// no key pressed, longest first, at most 5
+    pub idle: Vec<Range<u64>>,
+    /// Presses of all inputs in each second of play, at the `fps` given
+    pub presses_per_second: Vec<u64>,
+    pub fps: f64,
+}
+
+/// Reads the rest of `rply`, skipping checkpoint payloads, and counts the
+/// presses and holds of each input, the longest idle stretches, and the
+/// presses in each second at `fps` frames per second.
+/// # Errors
+/// As [`ReplayDecoder::read_frame_skipping_checkpoints`]
+pub fn input_stats<R: BufRead>(
+    rply: &mut ReplayDecoder<R>,
+    fps: f64,
+) -> Result<InputStats, ReplayError> {
+    let mut buttons: BTreeMap<Key, ButtonStats> = BTreeMap::new();
+    /* Inputs held in the last frame, with how long they've been held */
+    let mut held: BTreeMap<Key, u64> = BTreeMap::new();
+    let mut idle: Vec<Range<u64>> = vec![];
+    let mut idle_since = None;
+    let mut stats = InputStats {
+        fps,
+        ..InputStats::default()
+    };
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame_skipping_checkpoints(&mut frame)?;
+        let number = rply.frame_number;
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        let second = ((number - 1) as f64 / fps) as usize;
+        if stats.presses_per_second.len() <= second {
+            stats.presses_per_second.resize(second + 1, 0);
+        }
+        let mut now = BTreeMap::new();
+        for key in frame.input_events.iter().flat_map(held_by) {
+            if now.contains_key(&key) {
+                continue;
+            }
+            let run = held.get(&key).map_or(1, |run| run + 1);
+            now.insert(key, run);
+            let (port, device, idx, id) = key;
+            let button = buttons.entry(key).or_insert_with(|| ButtonStats {
+                port,
+                device,
+                idx,
+                id,
+                ..ButtonStats::default()
+            });
+            if run == 1 {
+                button.presses += 1;
+                stats.presses_per_second[second] += 1;
+            }
+            button.held_frames += 1;
+            button.longest_hold = button.longest_hold.max(run);
+        }
+        held = now;
+        let is_idle = held.is_empty() && frame.key_events.iter().all(|key| key.down == 0);
+        match (is_idle, idle_since) {
+            (true, None) => idle_since = Some(number),
+            (false, Some(start)) => {
+                idle.push(start..number);
+                idle_since = None;
+            }
+            _ => {}
+        }
+    }
+    stats.frames = rply.frame_number;
+    if let Some(start) = idle_since {
+        idle.push(start..stats.frames + 1);
+    }
+    // Stable, so equally long stretches stay in order
+    idle.sort_by_key(|stretch| std::cmp::Reverse(stretch.end - stretch.start));
+    idle.truncate(IDLE_STRETCHES);
+    stats.idle = idle;
+    stats.buttons = buttons.into_values().collect();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rply::{JOYPAD, JOYPAD_MASK};
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn counts_inputs() {
+        // Frame n of this replay has one input with value n * 7 % 300
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let stats = input_stats(&mut rply, 50.0).unwrap();
+        assert_eq!(stats.frames, 1000);
+        assert_eq!(
+            stats.buttons,
+            [ButtonStats {
+                presses: 4,
+                held_frames: 997,
+                longest_hold: 299,
+                ..ButtonStats::default()
+            }]
+        );
+        assert_eq!(stats.idle, [300..301, 600..601, 900..901]);
+        let mut presses = vec![0; 20];
+        for second in [0, 6, 12, 18] {
+            presses[second] = 1;
+        }
+        assert_eq!(stats.presses_per_second, presses);
+    }
+
+    #[test]
+    fn splits_joypad_masks() {
+        let header = crate::HeaderV2::builder(1, 0).build().unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(header, &[], &mut out).unwrap();
+        // B and Start, then Start alone, then nothing
+        for mask in [0b1001, 0b1000, 0] {
+            let mut frame = Frame::default();
+            frame.input_events.push(InputData {
+                device: JOYPAD,
+                id: JOYPAD_MASK,
+                val: mask,
+                ..InputData::default()
+            });
+            enc.write_frame(&frame).unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        let mut rply = crate::decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        let stats = input_stats(&mut rply, 60.0).unwrap();
+        let holds: Vec<_> = stats
+            .buttons
+            .iter()
+            .map(|b| (b.id, b.presses, b.held_frames))
+            .collect();
+        assert_eq!(holds, [(0, 1, 1), (3, 1, 2)]);
+        assert_eq!(stats.idle, vec![3..4]);
+        assert_eq!(stats.presses_per_second, [2]);
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{
+    Compression, EncryptedSections, Frame, Header, HeaderBase, HeaderV2, InputData, KeyData,
+    Metadata, ReplayDecoder, ReplayEncoder, ReplayError,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::{Map, Value, json};
+use std::io::{BufRead, Read, Seek, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// How [`write_json`] lays out a replay.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JsonOptions {
+    /// Write the header and then each frame as its own line, rather than
+    /// one document with a `frames` array
+    pub ndjson: bool,
+    /// Include each checkpoint's decoded bytes in base64.  Without them
+    /// checkpoints are only described, and [`read_json`] drops them.
+    pub checkpoint_bytes: bool,
+}
+
+fn header_json(header: &Header, initial_state: &[u8]) -> Value {
+    let mut object = json!({
+        "version": header.version(),
+        "identifier": header.identifier(),
+        "content_crc": header.content_crc(),
+        "frame_count": header.frame_count(),
+        "block_size": header.block_size(),
+        "superblock_size": header.superblock_size(),
+        "checkpoint_commit_interval": header.checkpoint_commit_interval(),
+        "checkpoint_commit_threshold": header.checkpoint_commit_threshold(),
+        "checkpoint_compression": u8::from(header.checkpoint_compression()),
+    });
+    if let Some(metadata) = header.metadata() {
+        let chunks: Map<String, Value> = metadata
+            .chunks()
+            .map(|(tag, data)| {
+                (
+                    String::from_utf8_lossy(tag).into_owned(),
+                    BASE64.encode(data).into(),
+                )
+            })
+            .collect();
+        object["metadata"] = chunks.into();
+    }
+    json!({"header": object, "initial_state": BASE64.encode(initial_state)})
+}
+
+fn frame_json(number: u64, frame: &Frame, checkpoint_size: Option<u64>) -> Value {
+    let keys: Vec<Value> = frame
+        .key_events
+        .iter()
+        .map(|key| json!({"down": key.down, "mod": key.modf, "code": key.code, "char": key.chr}))
+        .collect();
+    let inputs: Vec<Value> = frame
+        .input_events
+        .iter()
+        .map(|input| {
+            json!({
+                "port": input.port,
+                "device": input.device,
+                "index": input.idx,
+                "id": input.id,
+                "value": input.val,
+            })
+        })
+        .collect();
+    let mut object = json!({"frame": number, "keys": keys, "inputs": inputs});
+    if let Some(size) = checkpoint_size {
+        let mut checkpoint = json!({
+            "compression": u8::from(frame.checkpoint_compression),
+            "encoding": u8::from(frame.checkpoint_encoding),
+            "size": size,
+        });
+        if !frame.checkpoint_bytes.is_empty() {
+            checkpoint["bytes"] = BASE64.encode(&frame.checkpoint_bytes).into();
+        }
+        object["checkpoint"] = checkpoint;
+    }
+    object
+}
+
+/// Writes the rest of `rply` to `out` as JSON: the header, initial state,
+/// and every frame's events and checkpoint description.  Frames are
+/// numbered from 1, as they are in [`ReplayDecoder::frame_number`] once read.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: Tried to export a version 0 replay
+/// [`ReplayError::IO`]: Error writing the JSON
+/// Any error from reading frames
+pub fn write_json<R: BufRead, W: Write>(
+    rply: &mut ReplayDecoder<R>,
+    out: &mut W,
+    options: &JsonOptions,
+) -> Result<()> {
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    let head = header_json(&rply.header, &rply.initial_state).to_string();
+    let mut frame = Frame::default();
+    if options.ndjson {
+        writeln!(out, "{head}")?;
+    } else {
+        /* Written piece by piece so long replays never have to be held as one value */
+        write!(out, "{},\"frames\":[", &head[..head.len() - 1])?;
+    }
+    let mut first = true;
+    while !rply.at_end()? {
+        let size = if options.checkpoint_bytes {
+            rply.read_frame(&mut frame)?;
+            (!frame.checkpoint_bytes.is_empty()).then_some(frame.checkpoint_bytes.len() as u64)
+        } else {
+            rply.read_frame_skipping_checkpoints(&mut frame)?
+        };
+        let value = frame_json(rply.frame_number, &frame, size);
+        if options.ndjson {
+            writeln!(out, "{value}")?;
+        } else {
+            write!(out, "{}{value}", if first { "" } else { "," })?;
+        }
+        first = false;
+    }
+    if !options.ndjson {
+        writeln!(out, "]}}")?;
+    }
+    Ok(())
+}
+
+fn malformed(what: &str) -> ReplayError {
+    ReplayError::Json(what.to_string())
+}
+
+fn field<'v>(object: &'v Value, name: &str) -> Result<&'v Value> {
+    object.get(name).ok_or_else(|| malformed(name))
+}
+
+fn int<T: TryFrom<u64>>(object: &Value, name: &str) -> Result<T> {
+    field(object, name)?
+        .as_u64()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| malformed(name))
+}
+
+fn bytes(object: &Value, name: &str) -> Result<Vec<u8>> {
+    field(object, name)?
+        .as_str()
+        .and_then(|s| BASE64.decode(s).ok())
+        .ok_or_else(|| malformed(name))
+}
+
+fn read_header(value: &Value) -> Result<(Header, Vec<u8>)> {
+    let header = field(value, "header")?;
+    let mut metadata = Metadata::default();
+    if let Some(chunks) = header.get("metadata").and_then(Value::as_object) {
+        for tag in chunks.keys() {
+            let chunk = bytes(&header["metadata"], tag)?;
+            let tag = tag.as_bytes().try_into().map_err(|_| malformed(tag))?;
+            metadata.set(tag, chunk);
+        }
+    }
+    let header = Header::V2(HeaderV2 {
+        base: HeaderBase {
+            version: 2,
+            content_crc: int(header, "content_crc")?,
+            initial_state_size: 0,
+            identifier: int(header, "identifier")?,
+        },
+        frame_count: 0,
+        block_size: int(header, "block_size")?,
+        superblock_size: int(header, "superblock_size")?,
+        checkpoint_commit_interval: int(header, "checkpoint_commit_interval")?,
+        checkpoint_commit_threshold: int(header, "checkpoint_commit_threshold")?,
+        checkpoint_compression: Compression::try_from(int::<u8>(header, "checkpoint_compression")?)
+            .map_err(ReplayError::Compression)?,
+        encrypted_sections: EncryptedSections::default(),
+        metadata,
+    });
+    Ok((header, bytes(value, "initial_state")?))
+}
+
+fn read_frame(value: &Value, frame: &mut Frame) -> Result<()> {
+    frame.clear();
+    for key in field(value, "keys")?
+        .as_array()
+        .ok_or_else(|| malformed("keys"))?
+    {
+        frame.key_events.push(KeyData {
+            down: int(key, "down")?,
+            modf: int(key, "mod")?,
+            code: int(key, "code")?,
+            chr: int(key, "char")?,
+        });
+    }
+    for input in field(value, "inputs")?
+        .as_array()
+        .ok_or_else(|| malformed("inputs"))?
+    {
+        frame.input_events.push(InputData {
+            port: int(input, "port")?,
+            device: int(input, "device")?,
+            idx: int(input, "index")?,
+            id: int(input, "id")?,
+            val: field(input, "value")?
+                .as_i64()
+                .and_then(|v| i16::try_from(v).ok())
+                .ok_or_else(|| malformed("value"))?,
+        });
+    }
+    if let Some(checkpoint) = value.get("checkpoint")
+        && checkpoint.get("bytes").is_some()
+    {
+        frame.checkpoint_bytes = bytes(checkpoint, "bytes")?;
+    }
+    Ok(())
+}
+
+/// Encodes a replay written by [`write_json`], in either layout, to `out`.
+/// The result is always a current-version replay; checkpoints without
+/// their bytes are left out, and the rest are re-encoded with the header's
+/// settings.  Returns the number of frames written.
+/// # Errors
+/// [`ReplayError::Json`]: The input isn't JSON or is missing a field
+/// Any error from encoding the replay
+pub fn read_json<R: Read, W: Write + Seek>(input: R, out: &mut W) -> Result<u64> {
+    let mut values = serde_json::Deserializer::from_reader(input).into_iter::<Value>();
+    let head = values
+        .next()
+        .ok_or_else(|| malformed("header"))?
+        .map_err(|e| ReplayError::Json(e.to_string()))?;
+    let (header, initial_state) = read_header(&head)?;
+    let mut enc = ReplayEncoder::new(header, &initial_state, out)?;
+    let mut frame = Frame::default();
+    if let Some(frames) = head.get("frames") {
+        for value in frames.as_array().ok_or_else(|| malformed("frames"))? {
+            read_frame(value, &mut frame)?;
+            enc.write_frame(&frame)?;
+        }
+    }
+    for value in values {
+        read_frame(
+            &value.map_err(|e| ReplayError::Json(e.to_string()))?,
+            &mut frame,
+        )?;
+        enc.write_frame(&frame)?;
+    }
+    enc.finish()?;
+    Ok(enc.frame_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn json_roundtrip() {
+        let bytes = replay(None);
+        for ndjson in [false, true] {
+            let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+            let mut json = vec![];
+            let options = JsonOptions {
+                ndjson,
+                checkpoint_bytes: true,
+            };
+            write_json(&mut rply, &mut json, &options).unwrap();
+            assert_eq!(
+                json.iter().filter(|&&b| b == b'\n').count(),
+                if ndjson { 1001 } else { 1 }
+            );
+            let mut out = std::io::Cursor::new(vec![]);
+            assert_eq!(read_json(json.as_slice(), &mut out).unwrap(), 1000);
+            let mut original = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+            let mut imported = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+            assert_eq!(imported.initial_state, original.initial_state);
+            assert_eq!(imported.header.identifier(), 1234);
+            for (a, b) in original.frames().zip(imported.frames()) {
+                let (a, b) = (a.unwrap(), b.unwrap());
+                assert_eq!(a.input_events, b.input_events);
+                assert_eq!(a.checkpoint_bytes, b.checkpoint_bytes);
+            }
+        }
+
+        // Without checkpoint bytes only the events survive
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut json = vec![];
+        write_json(&mut rply, &mut json, &JsonOptions::default()).unwrap();
+        let text = String::from_utf8(json.clone()).unwrap();
+        assert!(text.contains(r#""frame":40,"#) && text.contains(r#""size":100"#));
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(read_json(json.as_slice(), &mut out).unwrap(), 1000);
+        let mut imported = crate::decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        assert!(imported.checkpoints().unwrap().is_empty());
+
+        assert!(matches!(
+            read_json(&b"{\"header\":{}}"[..], &mut out),
+            Err(ReplayError::Json(_))
+        ));
+    }
+}
@@ -1,8 +1,125 @@
+#![cfg_attr(
+    all(
+        feature = "forbid-unsafe",
+        not(any(feature = "capi", feature = "wasm", feature = "mmap"))
+    ),
+    forbid(unsafe_code)
+)]
+// The C ABI can't be written without unsafe, nor can wasm-bindgen's glue or
+// memory mapping, so only they may use any
+#![cfg_attr(
+    all(
+        feature = "forbid-unsafe",
+        any(feature = "capi", feature = "wasm", feature = "mmap")
+    ),
+    deny(unsafe_code)
+)]
+#[cfg(feature = "retro")]
+mod any;
+mod blockmap;
+#[cfg(feature = "retro")]
+mod bsv1;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod checkpoint;
+mod clip;
 mod clock;
+mod compat;
+mod compression;
+#[cfg(feature = "fm2")]
+mod convert;
+mod counting;
+mod cursor;
+mod delta;
+mod encryption;
+#[cfg(feature = "examples-support")]
+pub mod examples_support;
+mod external;
+pub mod format;
+mod ghost;
+mod inputstats;
+#[cfg(feature = "json")]
+mod json;
+mod lint;
+mod merge;
+mod metadata;
+mod movie;
+mod recompress;
+#[cfg(feature = "retro")]
+mod recorder;
+mod regions;
+mod repair;
+#[cfg(feature = "research")]
+mod research;
 mod rply;
+mod sanitize;
+mod seekindex;
+mod source;
 mod statestream;
-pub use clock::{Counter, Timer, Times, counts, stats};
+mod summary;
+mod tee;
+pub mod testvectors;
+mod trend;
+mod tune;
+mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "retro")]
+pub use any::{AnyDecoder, decode_any};
+pub use blockmap::{
+    BlockChanges, BlockMap, BlockUse, MemoryRegion, RegionStats, block_changes, block_map,
+    region_stats,
+};
+#[cfg(feature = "retro")]
+pub use bsv1::import_bsv1;
+pub use checkpoint::{CheckpointCodec, CheckpointContext, CodecRegistry};
+pub use clip::{clip, splice, thin_checkpoints, trim};
+pub use clock::{Counter, Span, Stats, Timer, Times, counts, stats, write_trace};
+pub use compat::{COMPAT, CompatEntry, CompatMatrix};
+#[cfg(feature = "zstd")]
+pub use compression::train_zstd_dictionary;
+pub use compression::{CompressWrite, CompressionOptions, Compressor};
+#[cfg(feature = "fm2")]
+pub use convert::{Fm2, read_fm2, write_fm2};
+pub use cursor::Cursor;
+pub use encryption::EncryptedSections;
+#[cfg(feature = "encryption")]
+pub use encryption::EncryptionKey;
+pub use external::verify_external;
+pub use ghost::{Ghost, GhostField, Memory};
+pub use inputstats::{ButtonStats, InputStats, input_stats};
+#[cfg(feature = "json")]
+pub use json::{JsonOptions, read_json, write_json};
+pub use lint::{LintIssue, LintOptions, lint};
+pub use merge::{CheckpointDivergence, Divergence, Merge, diff, diverge, merge};
+pub use metadata::{
+    ALLOWED_USES, AUTHOR, AllowedUses, BLOCK_COMMITS, COMMENTARY, CORE_NAME, CORE_VERSION, CREATED,
+    ChunkTag, Commentary, FRAME_PACKING, LICENSE, LOCALE, Metadata, ROM_HASH, SESSION,
+    STATE_REGIONS, TIMEZONE, TITLE, ZSTD_DICTIONARY,
+};
+pub use movie::{Movie, MovieFormat, MovieRegistry};
+pub use recompress::recompress;
+#[cfg(feature = "retro")]
+pub use recorder::{
+    AdaptiveCheckpoints, CheckpointPolicy, FixedInterval, ReplayRecorder, SinceCheckpoint,
+};
+pub use repair::{Repair, repair, repair_file};
+#[cfg(feature = "research")]
+pub use research::ResearchLog;
 pub use rply::*;
+pub use sanitize::{SanitizeLimits, sanitize};
+pub use seekindex::SeekIndex;
+#[cfg(feature = "http")]
+pub use source::HttpSource;
+#[cfg(feature = "mmap")]
+pub use source::MmapSource;
+pub use source::{ReplaySource, SourceReader, decode_source};
+pub use statestream::BlockHash;
+pub use summary::{Summary, summarize};
+pub use tee::{Tee, TeeEncoder, WriteSeek};
+pub use trend::{CheckpointSizes, SizeJump, SizeTrend};
+pub use tune::tune_block_sizes;
+pub use verify::{Core, Verification, Verifier, VerifyProgress, verify, verify_parallel};
 
 #[derive(Debug, thiserror::Error)]
 pub struct InvalidDeterminant(pub u8);
@@ -16,10 +133,956 @@ impl std::fmt::Display for InvalidDeterminant {
 mod tests {
     use super::*;
 
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../examples/bobl.replay");
+
+    fn example_frames() -> (Header, Vec<u8>, Vec<Frame>) {
+        let mut rply = decode(std::io::BufReader::new(
+            std::fs::File::open(EXAMPLE).unwrap(),
+        ))
+        .unwrap();
+        let frames = rply.frames().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(Some(frames.len() as u64), rply.header.frame_count());
+        assert!(rply.frames().next().is_none());
+        (rply.header, rply.initial_state, frames)
+    }
+
+    fn assert_roundtrip(
+        mut header: Header,
+        initial_state: &[u8],
+        frames: &[Frame],
+        compression: Compression,
+        encoding: Encoding,
+    ) -> usize {
+        header.set_checkpoint_compression(compression);
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, initial_state, &mut out).unwrap();
+            enc.set_checkpoint_encoding(encoding);
+            for frame in frames {
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        let bytes = out.into_inner();
+        let mut dec = decode(bytes.as_slice()).unwrap();
+        assert_eq!(dec.initial_state, initial_state);
+        let mut frame = Frame::default();
+        for orig in frames {
+            dec.read_frame(&mut frame).unwrap();
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+            assert_eq!(frame.input_events.len(), orig.input_events.len());
+            if !frame.checkpoint_bytes.is_empty() {
+                assert_eq!(frame.checkpoint_encoding, encoding);
+            }
+        }
+        bytes.len()
+    }
+
+    #[test]
+    fn decodes_replays_without_commits() {
+        // The first 600 frames of the example with 128-byte blocks, written
+        // before commits were honored; its header still asks for commits
+        // every 4 checkpoints with threshold 2
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../examples/bobl_smallblocks.replay"
+        );
+        let (_, initial_state, frames) = example_frames();
+        let mut dec = decode(std::io::BufReader::new(std::fs::File::open(path).unwrap())).unwrap();
+        assert_eq!(dec.header.checkpoint_commit_interval(), 4);
+        assert_eq!(dec.initial_state, initial_state);
+        let decoded = dec.frames().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded.len(), 600);
+        for (frame, orig) in decoded.iter().zip(&frames) {
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+        }
+    }
+
+    #[test]
+    fn block_commits() {
+        let (mut header, initial_state, frames) = example_frames();
+        header.set_block_size(128);
+        header.set_superblock_size(128);
+        header.metadata_mut().set_block_commits(true);
+        let evicted = clock::counts(clock::Counter::EncEvictedBlocks);
+        assert_roundtrip(
+            header,
+            &initial_state,
+            &frames,
+            Compression::None,
+            Encoding::Statestream,
+        );
+        // Other tests may be encoding at the same time
+        assert!(clock::counts(clock::Counter::EncEvictedBlocks) > evicted);
+    }
+
+    #[test]
+    // Just None without the compression features
+    #[allow(clippy::single_element_loop)]
+    fn delta_roundtrip() {
+        let (header, initial_state, frames) = example_frames();
+        for compression in [
+            Compression::None,
+            #[cfg(feature = "zlib")]
+            Compression::Zlib,
+        ] {
+            assert_roundtrip(
+                header.clone(),
+                &initial_state,
+                &frames,
+                compression,
+                Encoding::Delta,
+            );
+        }
+    }
+
+    #[test]
+    // Just None without the compression features
+    #[allow(clippy::single_element_loop)]
+    fn statestream_roundtrip() {
+        let (header, initial_state, frames) = example_frames();
+        for compression in [
+            Compression::None,
+            #[cfg(feature = "zlib")]
+            Compression::Zlib,
+        ] {
+            assert_roundtrip(
+                header.clone(),
+                &initial_state,
+                &frames,
+                compression,
+                Encoding::Statestream,
+            );
+        }
+    }
+
+    #[test]
+    // Just None without the compression features
+    #[allow(clippy::single_element_loop)]
+    fn compression_roundtrip() {
+        let (header, initial_state, frames) = example_frames();
+        for compression in [
+            Compression::None,
+            #[cfg(feature = "zlib")]
+            Compression::Zlib,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "brotli")]
+            Compression::Brotli,
+        ] {
+            assert_roundtrip(
+                header.clone(),
+                &initial_state,
+                &frames[..1200],
+                compression,
+                Encoding::Statestream,
+            );
+        }
+    }
+
+    #[test]
+    fn seek_to_frame() {
+        let (_, _, frames) = example_frames();
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let mut dec = decode(std::io::Cursor::new(bytes)).unwrap();
+        let mut frame = Frame::default();
+        for target in [3000, 10, 2999, 4500, 0, 6000, 6000, 1] {
+            dec.seek_to_frame(target).unwrap();
+            assert_eq!(dec.frame_number, target);
+            for orig in &frames[target as usize..target as usize + 50] {
+                dec.read_frame(&mut frame).unwrap();
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+                assert_eq!(frame.input_events.len(), orig.input_events.len());
+            }
+        }
+    }
+
+    #[test]
+    fn seek_back_with_delta_checkpoints() {
+        let (header, initial_state, frames) = example_frames();
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            enc.set_checkpoint_encoding(Encoding::Delta);
+            for frame in &frames {
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        // Restarting at a checkpoint found by backrefs would decode the
+        // checkpoints after it against the wrong one
+        let mut dec = decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        let mut frame = Frame::default();
+        for target in [6000, 120, 3000, 2999] {
+            dec.seek_to_frame(target).unwrap();
+            let start = usize::try_from(target).unwrap();
+            for orig in &frames[start..start + 300] {
+                dec.read_frame(&mut frame).unwrap();
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_budget() {
+        let (header, initial_state, frames) = example_frames();
+        let evicted = clock::counts(clock::Counter::EncEvictedBlocks);
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            enc.set_memory_budget(Some(64 * 1024));
+            for frame in &frames {
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        // Other tests may be encoding at the same time
+        assert!(clock::counts(clock::Counter::EncEvictedBlocks) > evicted);
+        let mut dec = decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        let mut frame = Frame::default();
+        for target in [0, 3000, 10, 6000] {
+            dec.seek_to_frame(target).unwrap();
+            for orig in &frames[target as usize..target as usize + 300] {
+                dec.read_frame(&mut frame).unwrap();
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let (mut header, _, frames) = example_frames();
+        header.metadata_mut().set_title("Bubble Bobble");
+        let json = serde_json::to_string(&header).unwrap();
+        assert_eq!(serde_json::from_str::<Header>(&json).unwrap(), header);
+        let checkpoint = frames.iter().find(|f| !f.checkpoint_bytes.is_empty());
+        for frame in [&frames[0], checkpoint.unwrap()] {
+            let json = serde_json::to_string(frame).unwrap();
+            assert_eq!(&serde_json::from_str::<Frame>(&json).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn frames_presized() {
+        let (header, initial_state, _) = example_frames();
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            for n in 0..200 {
+                let mut frame = Frame::default();
+                let keys = if n == 0 { 200 } else { 10 };
+                frame.key_events.resize_with(keys, KeyData::default);
+                enc.write_frame(&frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        let bytes = out.into_inner();
+        let mut dec = decode(bytes.as_slice()).unwrap();
+        assert_eq!(dec.new_frame().key_events.capacity(), 0);
+        let frames = dec.frames().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(frames[1].key_events.capacity() >= 200);
+        // The one busy frame stops mattering after a while
+        let capacity = dec.new_frame().key_events.capacity();
+        assert!((10..200).contains(&capacity));
+        assert!(frames[199].key_events.capacity() < 200);
+    }
+
+    #[test]
+    fn decode_limits() {
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let open =
+            |limits| ReplayDecoder::with_limits(bytes.as_slice(), CodecRegistry::default(), limits);
+        let over = |result: Result<_, ReplayError>| match result.map_err(ReplayError::into_root) {
+            Err(ReplayError::OverLimit(what, size)) => (what, size),
+            other => panic!("{:?}", other.err()),
+        };
+        let limits = DecodeLimits {
+            max_index_entries: 10,
+            ..DecodeLimits::default()
+        };
+        assert_eq!(
+            over(open(limits).map(|_| ())),
+            ("statestream index entries", 11)
+        );
+        let limits = DecodeLimits {
+            max_input_events: 2,
+            ..DecodeLimits::default()
+        };
+        let mut rply = open(limits).unwrap();
+        assert_eq!(
+            over(rply.read_frame(&mut Frame::default())),
+            ("input events", 3)
+        );
+        let limits = DecodeLimits {
+            max_state_bytes: 100,
+            ..DecodeLimits::default()
+        };
+        assert_eq!(over(open(limits).map(|_| ())), ("initial state", 2531));
+    }
+
+    #[test]
+    fn frame_pack_limits() {
+        let mut header = HeaderV2::builder(1, 0).build().unwrap();
+        header
+            .metadata_mut()
+            .set_frame_packing(Some(Compression::None));
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = encode(header, &[], &mut out).unwrap();
+        for _ in 0..3 {
+            enc.write_frame(&Frame::default()).unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        let bytes = out.into_inner();
+        let start = usize::try_from(decode(bytes.as_slice()).unwrap().position()).unwrap();
+        let limits = DecodeLimits {
+            max_state_bytes: 1 << 20,
+            ..DecodeLimits::default()
+        };
+        // A pack claiming 4 GiB, packed or unpacked, is refused before it's read
+        for field in [start + 1, start + 5] {
+            let mut bytes = bytes.clone();
+            bytes[field..field + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let mut rply =
+                ReplayDecoder::with_limits(bytes.as_slice(), CodecRegistry::default(), limits)
+                    .unwrap();
+            match rply
+                .read_frame(&mut Frame::default())
+                .map_err(ReplayError::into_root)
+            {
+                Err(ReplayError::OverLimit(what, size)) => {
+                    assert_eq!((what, size), ("frame pack", u64::from(u32::MAX)));
+                }
+                other => panic!("{other:?}"),
+            }
+        }
+        let mut rply =
+            ReplayDecoder::with_limits(bytes.as_slice(), CodecRegistry::default(), limits).unwrap();
+        assert_eq!(rply.frames().count(), 3);
+    }
+
+    #[test]
+    fn frame_reuse() {
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let fresh = decode(bytes.as_slice())
+            .unwrap()
+            .frames()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        // One frame read into over the whole replay, with and without checkpoints
+        let mut dec = decode(bytes.as_slice()).unwrap();
+        let mut frame = Frame::default();
+        let mut biggest = Frame::default();
+        for expected in &fresh {
+            dec.read_frame(&mut frame).unwrap();
+            assert_eq!(&frame, expected);
+            if frame.checkpoint_bytes.len() > biggest.checkpoint_bytes.len() {
+                biggest.clone_from(&frame);
+            }
+        }
+        let checkpoint = fresh
+            .iter()
+            .find(|f| !f.checkpoint_bytes.is_empty())
+            .unwrap();
+        frame.reset_to(checkpoint);
+        assert_eq!(&frame, checkpoint);
+        frame.reset_to(&fresh[0]);
+        assert_eq!(frame, fresh[0]);
+
+        let capacity = biggest.checkpoint_bytes.capacity();
+        biggest.clear();
+        assert_eq!(biggest, Frame::default());
+        assert_eq!(biggest.checkpoint_bytes.capacity(), capacity);
+        biggest.shrink_to_fit();
+        assert_eq!(biggest.checkpoint_bytes.capacity(), 0);
+    }
+
+    #[test]
+    fn per_decoder_stats() {
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let mut a = decode(bytes.as_slice()).unwrap();
+        let mut b = decode(bytes.as_slice()).unwrap();
+        let mut frame = Frame::default();
+        for _ in 0..300 {
+            a.read_frame(&mut frame).unwrap();
+        }
+        for _ in 0..100 {
+            b.read_frame(&mut frame).unwrap();
+            a.read_frame(&mut frame).unwrap();
+        }
+        assert_eq!(a.stats().stats(Timer::DecodeFrame).count, 400);
+        assert_eq!(b.stats().stats(Timer::DecodeFrame).count, 100);
+        assert!(
+            a.stats().stats(Timer::DecodeStatestream).count
+                > b.stats().stats(Timer::DecodeStatestream).count
+        );
+        assert!(
+            a.stats().counts(Counter::DecSkippedBlocks)
+                > b.stats().counts(Counter::DecSkippedBlocks)
+        );
+        assert!(stats(Timer::DecodeFrame).count >= 500);
+    }
+
+    #[test]
+    fn live_replay() {
+        /* A replay still being recorded has no frame count yet and may end mid-frame */
+        let mut bytes = std::fs::read(EXAMPLE).unwrap();
+        bytes[24..28].fill(0);
+        let mut rply = decode(bytes.as_slice()).unwrap();
+        assert!(rply.at_end().unwrap());
+        rply.set_live(true);
+        assert_eq!(rply.frames().count(), 6383);
+        bytes.truncate(bytes.len() - 3);
+        let mut rply = decode(bytes.as_slice()).unwrap();
+        rply.set_live(true);
+        let frames: Vec<_> = rply.frames().collect();
+        assert_eq!(frames.len(), 6383);
+        let Some(Err(ReplayError::AtFrame(frame, offset, e))) = frames.last() else {
+            panic!("{:?}", frames.last());
+        };
+        assert_eq!((*frame, *offset), (6383, bytes.len() as u64));
+        assert!(
+            matches!(e.root(), ReplayError::IO(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn skipping_checkpoints() {
+        let (_, _, frames) = example_frames();
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let mut dec = decode(std::io::Cursor::new(bytes)).unwrap();
+        let mut frame = Frame::default();
+        for orig in &frames[..3000] {
+            let skipped = dec.read_frame_skipping_checkpoints(&mut frame).unwrap();
+            assert!(frame.checkpoint_bytes.is_empty());
+            assert_eq!(
+                skipped,
+                (!orig.checkpoint_bytes.is_empty()).then_some(orig.checkpoint_bytes.len() as u64)
+            );
+            assert_eq!(frame.inputs(), orig.inputs());
+        }
+        let next_checkpoint = frames[3000..]
+            .iter()
+            .position(|f| !f.checkpoint_bytes.is_empty())
+            .unwrap();
+        for _ in 0..next_checkpoint {
+            dec.read_frame(&mut frame).unwrap();
+        }
+        assert!(matches!(
+            dec.read_frame(&mut frame).map_err(ReplayError::into_root),
+            Err(ReplayError::SkippedCheckpoints())
+        ));
+        dec.seek_to_frame(2990).unwrap();
+        for orig in &frames[2990..3100] {
+            dec.read_frame(&mut frame).unwrap();
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+        }
+        while !dec.at_end().unwrap() {
+            dec.read_frame_skipping_checkpoints(&mut frame).unwrap();
+        }
+        assert_eq!(dec.frame_number, frames.len() as u64);
+    }
+
+    #[test]
+    fn recovering() {
+        let mut bytes = crate::verify::tests::replay(None);
+        let offsets = decode(std::io::Cursor::new(bytes.clone()))
+            .unwrap()
+            .build_seek_index()
+            .unwrap()
+            .frame_offsets;
+        let at = |frame: usize| usize::try_from(offsets[frame]).unwrap();
+        // A bad token on frame 501, and frames 701 to 703 overwritten; lost
+        // frames aren't counted, so the second damage is found at frame 700
+        bytes[at(501) - 1] = b'x';
+        bytes[at(700) + 5..at(703)].fill(0xff);
+        let mut dec = decode(std::io::Cursor::new(bytes)).unwrap();
+        let (mut frame, mut damage, mut frames) = (Frame::default(), vec![], 0);
+        while dec.read_frame_recovering(&mut frame, &mut damage).unwrap() {
+            frames += 1;
+            assert_eq!(frame.input_events.len(), 1);
+        }
+        assert_eq!(frames, 996);
+        let skipped: Vec<_> = damage
+            .iter()
+            .filter(|d| !d.bytes.is_empty())
+            .map(|d| (d.frame, d.bytes.clone()))
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                (501, offsets[500]..offsets[501]),
+                (700, offsets[700]..offsets[703])
+            ]
+        );
+        assert!(matches!(damage[0].error, ReplayError::BadFrameToken(b'x')));
+        // Statestream checkpoints after the damage need ones before it, so
+        // their frames are kept without them
+        let dropped: Vec<_> = damage.iter().filter(|d| d.bytes.is_empty()).collect();
+        assert_eq!(dropped.len(), 13);
+        assert!(
+            dropped
+                .iter()
+                .all(|d| matches!(d.error, ReplayError::SkippedCheckpoints()))
+        );
+    }
+
+    #[test]
+    fn checkpoint_sizes() {
+        for compression in [
+            Compression::None,
+            #[cfg(feature = "zlib")]
+            Compression::Zlib,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ] {
+            let mut rply =
+                decode(std::io::Cursor::new(crate::verify::tests::replay(None))).unwrap();
+            let mut header = rply.header.clone();
+            header.set_checkpoint_compression(compression);
+            let mut out = std::io::Cursor::new(vec![]);
+            let mut enc = encode(header, &rply.initial_state, &mut out).unwrap();
+            for frame in rply.frames() {
+                enc.write_frame(&frame.unwrap()).unwrap();
+            }
+            enc.finish().unwrap();
+            drop(enc);
+            let bytes = out.into_inner();
+            let offsets = decode(std::io::Cursor::new(bytes.clone()))
+                .unwrap()
+                .build_seek_index()
+                .unwrap()
+                .frame_offsets;
+            // The frame token of the first checkpoint, after its events
+            let token = offsets
+                .iter()
+                .map(|&at| usize::try_from(at).unwrap() + 15)
+                .find(|&at| bytes[at] == b'C')
+                .unwrap();
+            let size = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+            let (encoded, compressed) = (size(token + 7), size(token + 11));
+            let read = |at: usize, stored: u32| {
+                let mut bytes = bytes.clone();
+                bytes[at..at + 4].copy_from_slice(&stored.to_le_bytes());
+                let mut rply = decode(bytes.as_slice()).unwrap();
+                rply.frames()
+                    .find_map(Result::err)
+                    .map(ReplayError::into_root)
+            };
+            assert!(read(token + 7, encoded).is_none());
+            for (at, stored, what) in [
+                (token + 7, encoded + 1, "encoded"),
+                (token + 7, encoded - 1, "encoded"),
+                (token + 11, compressed + 1, "compressed"),
+                // Short of zlib's checksum, the data can all be decoded
+                (token + 11, compressed - 8, "compressed"),
+            ] {
+                let error = read(at, stored);
+                // Uncompressed, the two sizes are of the same bytes, so
+                // either can be blamed
+                let blamed = match &error {
+                    Some(ReplayError::CheckpointSize(w, s)) => {
+                        (*w, *s) == (what, stored) || compression == Compression::None
+                    }
+                    _ => false,
+                };
+                assert!(blamed, "{compression:?} {what} {stored}: {error:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn checkpoint_index() {
+        let (_, _, frames) = example_frames();
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let mut dec = decode(std::io::Cursor::new(bytes)).unwrap();
+        let mut frame = Frame::default();
+        for _ in 0..500 {
+            dec.read_frame(&mut frame).unwrap();
+        }
+        let checkpoints = dec.checkpoints().unwrap();
+        let expected: Vec<_> = frames
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.checkpoint_bytes.is_empty())
+            .map(|(i, f)| (i as u64 + 1, f.checkpoint_bytes.len() as u64))
+            .collect();
+        assert_eq!(
+            checkpoints
+                .iter()
+                .map(|cp| (cp.frame, cp.uncompressed_size))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert!(checkpoints.windows(2).all(|w| w[0].offset < w[1].offset));
+        /* The scan leaves the decoder where it was */
+        assert_eq!(dec.frame_number, 500);
+        for orig in &frames[500..700] {
+            dec.read_frame(&mut frame).unwrap();
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+        }
+    }
+
+    /* A reader that counts the seeks made on it */
+    struct CountingSeeks {
+        inner: std::io::Cursor<Vec<u8>>,
+        seeks: usize,
+    }
+
+    impl std::io::Read for CountingSeeks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl std::io::BufRead for CountingSeeks {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            self.inner.fill_buf()
+        }
+        fn consume(&mut self, amt: usize) {
+            self.inner.consume(amt);
+        }
+    }
+
+    impl std::io::Seek for CountingSeeks {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.seeks += 1;
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn checkpoint_scan_seeks() {
+        let inner = std::io::Cursor::new(std::fs::read(EXAMPLE).unwrap());
+        let mut dec = decode(CountingSeeks { inner, seeks: 0 }).unwrap();
+        let checkpoints = dec.checkpoints().unwrap().len();
+        // Only checkpoint payloads are seeked over, not every frame's events
+        let seeks = dec.into_inner().seeks;
+        assert!(
+            seeks <= checkpoints + 4,
+            "{seeks} seeks for {checkpoints} checkpoints"
+        );
+    }
+
+    #[test]
+    fn seek_index() {
+        let (_, _, frames) = example_frames();
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let mut dec = decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut frame = Frame::default();
+        dec.read_frame(&mut frame).unwrap();
+        let index = dec.build_seek_index().unwrap();
+        assert_eq!(dec.frame_number, 1);
+        assert_eq!(index.frame_offsets().len(), frames.len());
+        assert_eq!(index.checkpoints(), dec.checkpoints().unwrap());
+        let mut sidecar = vec![];
+        index.write(&mut sidecar).unwrap();
+        let index = SeekIndex::read(&mut sidecar.as_slice()).unwrap();
+
+        let mut dec = decode(std::io::Cursor::new(&bytes)).unwrap();
+        dec.load_seek_index(index.clone()).unwrap();
+        for target in [6000, 3001, 10, 4500] {
+            dec.seek_to_frame(target).unwrap();
+            assert_eq!(dec.frame_number, target);
+            for orig in &frames[target as usize..(target as usize + 300).min(frames.len())] {
+                dec.read_frame(&mut frame).unwrap();
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+                assert_eq!(frame.inputs(), orig.inputs());
+            }
+        }
+
+        let mut other = index;
+        other.identifier += 1;
+        let mut dec = decode(std::io::Cursor::new(&bytes)).unwrap();
+        assert!(matches!(
+            dec.load_seek_index(other),
+            Err(ReplayError::SeekIndex())
+        ));
+    }
+
+    #[test]
+    fn owned_decoder() {
+        fn assert_send<T: Send + 'static>(_: &T) {}
+        let file = std::io::BufReader::new(std::fs::File::open(EXAMPLE).unwrap());
+        let mut rply = decode(file).unwrap();
+        assert_send(&rply);
+        let first = rply.frames().next().unwrap().unwrap();
+        let rply = std::thread::spawn(move || {
+            rply.frames().nth(9).unwrap().unwrap();
+            rply
+        })
+        .join()
+        .unwrap();
+        assert_eq!(rply.frame_number, 11);
+        assert!(first.checkpoint_bytes.is_empty());
+        let mut file = rply.into_inner();
+        assert!(!std::io::BufRead::fill_buf(&mut file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let (mut header, initial_state, frames) = example_frames();
+        assert!(header.metadata().unwrap().is_empty());
+        let metadata = header.metadata_mut();
+        metadata.set_license("CC-BY-4.0");
+        metadata.set_allowed_uses(AllowedUses::REDISTRIBUTE | AllowedUses::RESEARCH);
+        metadata.set(*b"XTRA", vec![9; 5]);
+        metadata.set_title("Bubble Bobble");
+        metadata.set_session(1000..1000);
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header.clone(), &initial_state, &mut out).unwrap();
+            for frame in &frames[..100] {
+                enc.write_frame(frame).unwrap();
+            }
+            // Fixed-size chunks can be filled in after the header is written
+            enc.update_metadata(|m| m.set_session(1000..1060)).unwrap();
+            assert!(matches!(
+                enc.update_metadata(|m| m.set_title("Bubble Bobble 2")),
+                Err(ReplayError::MetadataResized())
+            ));
+            enc.finish().unwrap();
+        }
+        header.metadata_mut().set_session(1000..1060);
+        let bytes = out.into_inner();
+        let mut dec = decode(bytes.as_slice()).unwrap();
+        assert_eq!(dec.header.version(), 3);
+        assert_eq!(dec.header.metadata(), header.metadata());
+        assert_eq!(
+            dec.header.metadata().unwrap().title(),
+            Some("Bubble Bobble")
+        );
+        assert_eq!(dec.initial_state, initial_state);
+        let decoded = dec.frames().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded.len(), 100);
+        assert_eq!(decoded[99].checkpoint_bytes, frames[99].checkpoint_bytes);
+
+        // Clearing the metadata writes a plain v2 replay again
+        let mut header = dec.header.clone();
+        header.set_metadata(Metadata::default());
+        let mut out = std::io::Cursor::new(vec![]);
+        encode(header, &initial_state, &mut out)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(
+            decode(out.get_ref().as_slice()).unwrap().header.version(),
+            2
+        );
+    }
+
+    /* A 13 byte header, then two mirrored banks of RAM that don't sit on block boundaries */
+    fn region_frames() -> (Header, Vec<u8>, Vec<Frame>) {
+        let header = Header::V2(HeaderV2 {
+            base: HeaderBase {
+                version: 2,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 0,
+            },
+            frame_count: 0,
+            block_size: 64,
+            superblock_size: 4,
+            checkpoint_commit_interval: 0,
+            checkpoint_commit_threshold: 0,
+            checkpoint_compression: Compression::None,
+            encrypted_sections: EncryptedSections::default(),
+            metadata: Metadata::default(),
+        });
+        let mut state = vec![0_u8; 2020];
+        let mut frames = vec![];
+        for i in 0..30_u8 {
+            state[..13].fill(i);
+            for (j, b) in state[13..1013].iter_mut().enumerate() {
+                *b = (j as u8).wrapping_mul(7).wrapping_add(i);
+            }
+            state.copy_within(13..1013, 1020);
+            frames.push(Frame {
+                checkpoint_bytes: state.clone(),
+                ..Frame::default()
+            });
+        }
+        (header, state, frames)
+    }
+
+    #[test]
+    fn state_regions() {
+        let (mut header, state, frames) = region_frames();
+        let plain = assert_roundtrip(
+            header.clone(),
+            &state,
+            &frames,
+            Compression::None,
+            Encoding::Statestream,
+        );
+        let regions = [13..1013, 1020..2020];
+        header.metadata_mut().set_state_regions(&regions);
+        assert_eq!(
+            header.metadata().unwrap().state_regions(),
+            Some(regions.to_vec())
+        );
+        let aligned = assert_roundtrip(
+            header,
+            &state,
+            &frames,
+            Compression::None,
+            Encoding::Statestream,
+        );
+        // Aligned, the second bank's blocks are the first's
+        assert!(aligned * 5 < plain * 4, "{aligned} vs {plain}");
+    }
+
+    #[test]
+    fn region_encodings() {
+        let (mut header, state, frames) = region_frames();
+        header
+            .metadata_mut()
+            .set_state_regions(&[0..13, 13..1013, 1020..2020]);
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, &state, &mut out).unwrap();
+            enc.set_checkpoint_encoding(Encoding::Regions);
+            enc.set_region_encodings(vec![
+                (Encoding::Raw, Compression::None),
+                (Encoding::Statestream, Compression::None),
+                (Encoding::Raw, Compression::None),
+            ]);
+            for frame in &frames {
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        let mut dec = decode(std::io::Cursor::new(out.get_ref())).unwrap();
+        let mut frame = Frame::default();
+        for orig in &frames {
+            dec.read_frame(&mut frame).unwrap();
+            assert_eq!(frame.checkpoint_encoding, Encoding::Regions);
+            assert_eq!(frame.checkpoint_compression, Compression::None);
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+        }
+        // Seeking back decodes them again
+        dec.seek_to_frame(10).unwrap();
+        dec.read_frame(&mut frame).unwrap();
+        assert_eq!(frame.checkpoint_bytes, frames[10].checkpoint_bytes);
+    }
+
+    #[test]
+    fn changing_state_sizes() {
+        let (header, initial_state, mut frames) = example_frames();
+        frames.truncate(400);
+        /* As if the core's state grew by a few bytes, then shrank */
+        for (i, frame) in frames
+            .iter_mut()
+            .filter(|f| !f.checkpoint_bytes.is_empty())
+            .enumerate()
+        {
+            let len = frame.checkpoint_bytes.len();
+            let len = [len, len + 3, len + 40, len - 5, len + 1][i % 5];
+            frame.checkpoint_bytes.resize(len, 0xAA);
+        }
+        for encoding in [Encoding::Raw, Encoding::Statestream, Encoding::Delta] {
+            assert_roundtrip(
+                header.clone(),
+                &initial_state,
+                &frames,
+                Compression::None,
+                encoding,
+            );
+        }
+
+        // A core that only loads states of one size can have them fitted
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            for frame in &frames {
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        let mut dec = decode(out.get_ref().as_slice()).unwrap();
+        let size = StateSize::Fixed(initial_state.len() + 2);
+        dec.set_state_size(size);
+        for orig in &frames {
+            let mut frame = Frame::default();
+            dec.read_frame(&mut frame).unwrap();
+            let mut expected = orig.checkpoint_bytes.clone();
+            if !expected.is_empty() {
+                size.fit(&mut expected);
+            }
+            assert_eq!(frame.checkpoint_bytes, expected);
+        }
+    }
+
+    #[test]
+    fn write_versions() {
+        let (header, initial_state, frames) = example_frames();
+        let frames = &frames[..200];
+        for version in 1..=3 {
+            let mut out = std::io::Cursor::new(vec![]);
+            {
+                let mut enc =
+                    ReplayEncoder::with_version(header.clone(), &initial_state, &mut out, version)
+                        .unwrap();
+                for frame in frames {
+                    enc.write_frame(frame).unwrap();
+                }
+                enc.finish().unwrap();
+            }
+            let bytes = out.into_inner();
+            let mut dec = decode(bytes.as_slice()).unwrap();
+            assert_eq!(dec.header.version(), version);
+            assert_eq!(dec.initial_state, initial_state);
+            let decoded = dec.frames().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(decoded.len(), frames.len());
+            for (frame, orig) in decoded.iter().zip(frames) {
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+                assert_eq!(frame.inputs(), orig.inputs());
+            }
+        }
+
+        let mut header = header;
+        header.metadata_mut().set_license("CC0-1.0");
+        let mut out = std::io::Cursor::new(vec![]);
+        assert!(matches!(
+            ReplayEncoder::with_version(header.clone(), &initial_state, &mut out, 2),
+            Err(ReplayError::VersionFeature(2, "metadata"))
+        ));
+        header.set_metadata(Metadata::default());
+        header.set_checkpoint_compression(Compression::Custom(200));
+        assert!(matches!(
+            ReplayEncoder::with_version(header, &initial_state, &mut out, 1),
+            Err(ReplayError::VersionFeature(1, _))
+        ));
+        let mut enc = ReplayEncoder::with_version(
+            Header::V0V1(HeaderBase {
+                version: 0,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 0,
+            }),
+            &initial_state,
+            &mut out,
+            1,
+        )
+        .unwrap();
+        enc.set_checkpoint_encoding(Encoding::Statestream);
+        assert!(matches!(
+            enc.write_frame(
+                frames
+                    .iter()
+                    .find(|f| !f.checkpoint_bytes.is_empty())
+                    .unwrap()
+            ),
+            Err(ReplayError::VersionFeature(1, _))
+        ));
+    }
+
     #[test]
     fn v2_header() {
-        let mut file =
-            std::io::BufReader::new(std::fs::File::open("examples/bobl.replay").unwrap());
+        let mut file = std::io::BufReader::new(std::fs::File::open(EXAMPLE).unwrap());
         let header = match rply::decode(&mut file).unwrap().header {
             rply::Header::V0V1(_) => panic!("Version too low"),
             rply::Header::V2(h) => h,
@@ -35,4 +1098,701 @@ mod tests {
         assert_eq!(header.checkpoint_commit_threshold, 2);
         assert_eq!(header.checkpoint_compression, rply::Compression::None);
     }
+
+    #[test]
+    fn keyframes() {
+        let bytes = crate::verify::tests::replay(None);
+        let mut rply = decode(bytes.as_slice()).unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+        enc.set_keyframe_interval(Some(200));
+        for frame in rply.frames() {
+            enc.write_frame(&frame.unwrap()).unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        let keyed = out.into_inner();
+        let originals = || {
+            decode(bytes.as_slice())
+                .unwrap()
+                .frames()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+        let mut rply = decode(std::io::Cursor::new(keyed.as_slice())).unwrap();
+        let keyframes = rply.keyframes().unwrap();
+        assert_eq!(
+            keyframes.iter().map(|k| k.frame).collect::<Vec<_>>(),
+            [200, 400, 600, 800, 1000]
+        );
+        assert!(rply.frames().map(Result::unwrap).eq(originals()));
+
+        let mut rply = decode(std::io::Cursor::new(keyed.as_slice())).unwrap();
+        rply.seek_to_keyframe(&keyframes[2]).unwrap();
+        assert!(
+            rply.frames()
+                .map(Result::unwrap)
+                .eq(originals().into_iter().skip(599))
+        );
+        assert_eq!(rply.frame_number, 1000);
+        // Other checkpoints need the ones before them
+        let mut rply = decode(std::io::Cursor::new(keyed.as_slice())).unwrap();
+        let checkpoint = rply.checkpoints().unwrap()[15];
+        assert_eq!(checkpoint.frame, 640);
+        rply.seek_to_keyframe(&checkpoint).unwrap();
+        assert!(matches!(
+            rply.frames()
+                .find_map(Result::err)
+                .map(ReplayError::into_root),
+            Some(ReplayError::SkippedCheckpoints())
+        ));
+    }
+
+    #[test]
+    fn xor_deltas() {
+        let bytes = crate::verify::tests::replay(None);
+        let reencode = |xor: bool| {
+            let mut rply = decode(bytes.as_slice()).unwrap();
+            let mut out = std::io::Cursor::new(vec![]);
+            let mut enc = encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+            enc.set_xor_deltas(xor);
+            enc.set_keyframe_interval(Some(400));
+            for frame in rply.frames() {
+                enc.write_frame(&frame.unwrap()).unwrap();
+            }
+            enc.finish().unwrap();
+            drop(enc);
+            out.into_inner()
+        };
+        let (plain, xored) = (reencode(false), reencode(true));
+        assert_ne!(plain, xored);
+        let frames = |bytes: &[u8]| {
+            decode(bytes)
+                .unwrap()
+                .frames()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+        let originals = frames(&bytes);
+        assert_eq!(frames(&xored), originals);
+
+        // Seeking back decodes from the start to have each previous checkpoint
+        let mut rply = decode(std::io::Cursor::new(xored.as_slice())).unwrap();
+        rply.seek_to_frame(1000).unwrap();
+        rply.seek_to_frame(519).unwrap();
+        assert!(
+            rply.frames()
+                .map(Result::unwrap)
+                .eq(originals[519..].iter().cloned())
+        );
+        // Keyframes aren't XORed, but the checkpoints after them are
+        let mut rply = decode(std::io::Cursor::new(xored.as_slice())).unwrap();
+        let keyframes = rply.keyframes().unwrap();
+        rply.seek_to_keyframe(&keyframes[0]).unwrap();
+        assert!(
+            rply.frames()
+                .map(Result::unwrap)
+                .eq(originals[399..].iter().cloned())
+        );
+        // A seek index jumps straight past the previous checkpoint, which shows
+        let mut rply = decode(std::io::Cursor::new(xored.as_slice())).unwrap();
+        let index = rply.build_seek_index().unwrap();
+        rply.load_seek_index(index).unwrap();
+        rply.seek_to_frame(500).unwrap();
+        assert!(matches!(
+            rply.frames()
+                .find_map(Result::err)
+                .map(ReplayError::into_root),
+            Some(ReplayError::SkippedCheckpoints())
+        ));
+    }
+
+    #[test]
+    fn snapshots() {
+        let bytes = crate::verify::tests::replay(None);
+        let open = || decode(std::io::Cursor::new(bytes.as_slice())).unwrap();
+        let mut rply = open();
+        let mut frame = Frame::default();
+        for _ in 0..500 {
+            rply.read_frame(&mut frame).unwrap();
+        }
+        let snapshot = rply.snapshot();
+        assert_eq!(snapshot.frame_number(), 500);
+        assert!(snapshot.table_bytes() > 0);
+        let rest: Vec<_> = rply.frames().map(Result::unwrap).collect();
+        assert_eq!(rest.len(), 500);
+        // Another decoder, on another thread, carries on from the same point
+        let worker = {
+            let (snapshot, bytes) = (snapshot.clone(), bytes.clone());
+            std::thread::spawn(move || {
+                let mut rply = decode(std::io::Cursor::new(bytes)).unwrap();
+                rply.restore(&snapshot).unwrap();
+                rply.frames().map(Result::unwrap).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(worker.join().unwrap(), rest);
+        // And so does this one, after reading to the end
+        rply.restore(&snapshot).unwrap();
+        assert!(rply.frames().map(Result::unwrap).eq(rest));
+
+        let mut other = rply.header.clone();
+        other.set_identifier(4321);
+        let mut out = std::io::Cursor::new(vec![]);
+        encode(other, &rply.initial_state, &mut out)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let mut other = decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        assert!(matches!(
+            other.restore(&snapshot),
+            Err(ReplayError::Snapshot())
+        ));
+    }
+
+    #[test]
+    fn seeded_tables() {
+        // A savestate with little repetition, and another nearly like it
+        let mut x = 1_u32;
+        let state: Vec<u8> = (0..4096)
+            .map(|_| {
+                x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (x >> 24) as u8
+            })
+            .collect();
+        let mut later = state.clone();
+        later[1000] ^= 0xff;
+        let header = || {
+            let mut header = Header::V0V1(HeaderBase {
+                version: 2,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 7,
+            });
+            header.set_block_size(64);
+            header.set_superblock_size(16);
+            header
+        };
+        let record = |initial: &[u8], seed: Option<&[u8]>| {
+            let mut out = std::io::Cursor::new(vec![]);
+            let mut enc = encode(header(), initial, &mut out).unwrap();
+            if let Some(seed) = seed {
+                enc.seed_tables(seed).unwrap();
+            }
+            let mut frame = Frame::default();
+            frame.checkpoint_bytes.clone_from(&later);
+            enc.write_frame(&frame).unwrap();
+            let size = enc.last_checkpoint_sizes().unwrap().compressed;
+            assert!(matches!(
+                enc.seed_tables(&state),
+                Err(ReplayError::LateSeed())
+            ));
+            enc.finish().unwrap();
+            drop(enc);
+            (out.into_inner(), size)
+        };
+        // The first checkpoint reuses the initial state's blocks
+        let (_, unseeded) = record(&[], None);
+        let (_, from_initial) = record(&state, None);
+        assert!(from_initial * 10 < unseeded);
+        // As it does a seed's, once the decoder has the seed too
+        let (bytes, seeded) = record(&[], Some(&state));
+        assert_eq!(seeded, from_initial);
+        assert!(
+            decode(bytes.as_slice())
+                .unwrap()
+                .frames()
+                .any(|f| f.is_err())
+        );
+        let mut rply = decode(bytes.as_slice()).unwrap();
+        rply.seed_tables(&state).unwrap();
+        let frames: Vec<_> = rply.frames().map(Result::unwrap).collect();
+        assert_eq!(frames[0].checkpoint_bytes, later);
+        assert!(matches!(
+            rply.seed_tables(&state),
+            Err(ReplayError::LateSeed())
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn packed_frames() {
+        let (mut header, initial_state, frames) = example_frames();
+        let plain = assert_roundtrip(
+            header.clone(),
+            &initial_state,
+            &frames,
+            Compression::Zstd,
+            Encoding::Statestream,
+        );
+        header.set_checkpoint_compression(Compression::Zstd);
+        header
+            .metadata_mut()
+            .set_frame_packing(Some(Compression::Zstd));
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            for frame in &frames {
+                enc.write_frame(frame).unwrap();
+            }
+        }
+        let bytes = out.into_inner();
+        assert!(bytes.len() < plain);
+        let mut dec = decode(std::io::Cursor::new(bytes.as_slice())).unwrap();
+        let decoded = dec.frames().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded.len(), frames.len());
+        for (frame, orig) in decoded.iter().zip(&frames) {
+            assert_eq!(frame.inputs(), orig.inputs());
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+        }
+        let checkpoints = frames.iter().filter(|f| !f.checkpoint_bytes.is_empty());
+        assert_eq!(dec.checkpoints().unwrap().len(), checkpoints.count());
+        dec.seek_to_frame(3000).unwrap();
+        let mut frame = Frame::default();
+        for orig in &frames[3000..3100] {
+            dec.read_frame(&mut frame).unwrap();
+            assert_eq!(frame.inputs(), orig.inputs());
+            assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+        }
+
+        // A replay cut off mid-pack loses the whole pack
+        let mut cut = std::io::Cursor::new(bytes[..bytes.len() - 100].to_vec());
+        let repair = crate::repair(&mut cut).unwrap();
+        assert!(repair.frames < frames.len() as u64 && repair.cut >= 100);
+        let mut live = decode(cut.get_ref().as_slice()).unwrap();
+        live.set_live(true);
+        assert_eq!(live.frames().count() as u64, repair.frames);
+        let mut cut = cut.into_inner();
+        cut.truncate(usize::try_from(repair.length).unwrap());
+        let mut repaired = decode(cut.as_slice()).unwrap();
+        let count = repaired.frames().map(Result::unwrap).count();
+        assert_eq!(count as u64, repair.frames);
+    }
+
+    #[test]
+    fn borrowed_frames() {
+        let (_, _, frames) = example_frames();
+        let mut rply = decode(std::io::BufReader::new(
+            std::fs::File::open(EXAMPLE).unwrap(),
+        ))
+        .unwrap();
+        let mut copy = Frame::default();
+        let mut buffers = std::collections::HashSet::new();
+        for (i, orig) in frames.iter().enumerate() {
+            // Plain reads in between keep the decoder in step
+            if i % 500 == 499 {
+                rply.read_frame(&mut copy).unwrap();
+                assert_eq!(&copy, orig);
+                continue;
+            }
+            let frame = rply.read_frame_ref().unwrap();
+            assert_eq!(frame, FrameRef::from(orig));
+            if !frame.checkpoint_bytes.is_empty() {
+                buffers.insert(frame.checkpoint_bytes.as_ptr());
+            }
+            frame.copy_to(&mut copy);
+            assert_eq!(&copy, orig);
+        }
+        // Checkpoints are lent from the decoder's own two buffers, not copied
+        assert_eq!(buffers.len(), 2);
+        assert!(rply.read_frame_ref().is_err());
+
+        let bytes = std::fs::read(EXAMPLE).unwrap();
+        let mut rply = decode(bytes.as_slice()).unwrap();
+        rply.set_state_size(StateSize::Fixed(100));
+        for orig in &frames[..300] {
+            let frame = rply.read_frame_ref().unwrap();
+            if !orig.checkpoint_bytes.is_empty() {
+                assert_eq!(frame.checkpoint_bytes, &orig.checkpoint_bytes[..100]);
+            }
+        }
+    }
+
+    #[test]
+    fn reused_frames() {
+        let (_, _, frames) = example_frames();
+        let mut rply = decode(std::io::BufReader::new(
+            std::fs::File::open(EXAMPLE).unwrap(),
+        ))
+        .unwrap();
+        let mut frame = Frame::default();
+        let mut checkpoint_buf = None;
+        for orig in &frames {
+            rply.read_frame(&mut frame).unwrap();
+            assert_eq!(&frame, orig);
+            // Checkpoints after the first are decoded into the same allocation
+            if !frame.checkpoint_bytes.is_empty() {
+                let buf = frame.checkpoint_bytes.as_ptr();
+                assert_eq!(*checkpoint_buf.get_or_insert(buf), buf);
+            }
+        }
+        assert!(rply.read_frame(&mut frame).is_err());
+    }
+
+    #[test]
+    fn header_flush() {
+        let (header, initial_state, frames) = example_frames();
+        for packing in [
+            None,
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd),
+        ] {
+            let mut header = header.clone();
+            header.metadata_mut().set_frame_packing(packing);
+            let mut out = std::io::Cursor::new(vec![]);
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            enc.set_header_flush(Some(HeaderFlush {
+                frames: Some(1000),
+                interval: None,
+            }));
+            for frame in &frames[..2500] {
+                enc.write_frame(frame).unwrap();
+            }
+            // The recorder crashes before it can finish
+            std::mem::forget(enc);
+            let mut rply = decode(out.get_ref().as_slice()).unwrap();
+            assert_eq!(rply.header.frame_count(), Some(2000));
+            let read: Vec<_> = rply.frames().map(Result::unwrap).collect();
+            assert_eq!(read.len(), 2000);
+            for (frame, orig) in read.iter().zip(&frames) {
+                assert_eq!(frame.input_events, orig.input_events);
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+            }
+        }
+    }
+
+    /* A stream whose syncs fail while `failing` is set */
+    struct FailingSync {
+        inner: std::io::Cursor<Vec<u8>>,
+        failing: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    impl std::io::Write for FailingSync {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for FailingSync {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl SyncData for FailingSync {
+        fn sync_data(&mut self) -> std::io::Result<()> {
+            if self.failing.get() {
+                return Err(std::io::ErrorKind::Other.into());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_header_flush() {
+        let (header, initial_state, frames) = example_frames();
+        for packing in [
+            None,
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd),
+        ] {
+            let mut header = header.clone();
+            header.metadata_mut().set_frame_packing(packing);
+            let failing = std::rc::Rc::new(std::cell::Cell::new(false));
+            let mut disk = FailingSync {
+                inner: std::io::Cursor::new(vec![]),
+                failing: failing.clone(),
+            };
+            let mut enc = encode(header, &initial_state, &mut disk).unwrap();
+            enc.set_header_flush(Some(HeaderFlush {
+                frames: Some(1000),
+                interval: None,
+            }));
+            for (i, frame) in frames[..2500].iter().enumerate() {
+                if i == 2000 {
+                    // The flush due before this frame fails, and the frame with it
+                    failing.set(true);
+                    let result = enc.write_frame(frame);
+                    assert!(matches!(result, Err(ReplayError::IO(_))));
+                    assert_eq!(enc.frame_number, 2000);
+                    failing.set(false);
+                }
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+            drop(enc);
+            // The retried frame is there once
+            let mut rply = decode(disk.inner.get_ref().as_slice()).unwrap();
+            let read: Vec<_> = rply.frames().map(Result::unwrap).collect();
+            assert_eq!(read.len(), 2500);
+            for (frame, orig) in read.iter().zip(&frames) {
+                assert_eq!(frame.input_events, orig.input_events);
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+            }
+        }
+    }
+
+    /* A stream on a disk with `room` bytes */
+    struct FullDisk {
+        inner: std::io::Cursor<Vec<u8>>,
+        room: std::rc::Rc<std::cell::Cell<u64>>,
+    }
+
+    impl std::io::Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let left = self.room.get().saturating_sub(self.inner.position());
+            let fits = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            if fits == 0 && !buf.is_empty() {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            self.inner.write(&buf[..fits])
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for FullDisk {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn rollback() {
+        let (header, initial_state, frames) = example_frames();
+        let regular = (2000..)
+            .find(|&i| frames[i].checkpoint_bytes.is_empty())
+            .unwrap();
+        let checkpoint = (3000..)
+            .find(|&i| !frames[i].checkpoint_bytes.is_empty())
+            .unwrap();
+        for (rollback, packing) in [
+            (false, None),
+            (true, None),
+            #[cfg(feature = "zstd")]
+            (true, Some(Compression::Zstd)),
+        ] {
+            let mut header = header.clone();
+            header.metadata_mut().set_frame_packing(packing);
+            let room = std::rc::Rc::new(std::cell::Cell::new(u64::MAX));
+            let mut disk = FullDisk {
+                inner: std::io::Cursor::new(vec![]),
+                room: room.clone(),
+            };
+            let mut enc = encode(header, &initial_state, &mut disk).unwrap();
+            enc.set_rollback(rollback);
+            let mut written = frames.len();
+            for (i, frame) in frames.iter().enumerate() {
+                if i == regular || i == checkpoint {
+                    // The disk fills up partway through the frame
+                    room.set(enc.bytes_written().unwrap() + 3);
+                    // Packed frames only fill the pack being written
+                    let result = enc.write_frame(frame);
+                    room.set(u64::MAX);
+                    match result {
+                        Ok(()) => continue,
+                        Err(e) => assert!(matches!(e, ReplayError::IO(_)), "{e}"),
+                    }
+                    assert_eq!(enc.frame_number, i as u64);
+                }
+                if let Err(e) = enc.write_frame(frame) {
+                    assert!(!rollback && i == checkpoint);
+                    assert!(matches!(e, ReplayError::EncoderOutOfStep()));
+                    written = i;
+                    break;
+                }
+            }
+            enc.finish().unwrap();
+            drop(enc);
+            let mut rply = decode(disk.inner.get_ref().as_slice()).unwrap();
+            let read: Vec<_> = rply.frames().map(Result::unwrap).collect();
+            assert_eq!(read.len(), written);
+            for (frame, orig) in read.iter().zip(&frames) {
+                assert_eq!(frame.input_events, orig.input_events);
+                assert_eq!(frame.checkpoint_bytes, orig.checkpoint_bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_offsets() {
+        let (header, initial_state, frames) = example_frames();
+        for packing in [
+            None,
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd),
+        ] {
+            let mut header = header.clone();
+            header.metadata_mut().set_frame_packing(packing);
+            let mut written = vec![];
+            let mut out = std::io::Cursor::new(vec![]);
+            let mut enc = encode(header, &initial_state, &mut out).unwrap();
+            enc.record_frame_offsets(true);
+            enc.set_frame_hook(|frame: &WrittenFrame| written.push(*frame));
+            for frame in &frames {
+                enc.write_frame(frame).unwrap();
+            }
+            enc.finish().unwrap();
+            let offsets = enc.frame_offsets().unwrap().to_vec();
+            drop(enc);
+            let mut rply = decode(std::io::Cursor::new(out.into_inner())).unwrap();
+            let index = rply.build_seek_index().unwrap();
+            assert_eq!(offsets, index.frame_offsets());
+            assert_eq!(written.len(), frames.len());
+            for (i, (frame, orig)) in written.iter().zip(&frames).enumerate() {
+                assert_eq!(frame.frame, i as u64);
+                assert_eq!(frame.offset, offsets[i]);
+                assert_eq!(frame.checkpoint, !orig.checkpoint_bytes.is_empty());
+                if let Some(next) = offsets.get(i + 1) {
+                    assert_eq!(frame.offset + frame.len, *next);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encoder_events() {
+        enum Event {
+            Frame(WrittenFrame),
+            Checkpoint(EncodedCheckpoint),
+        }
+        struct Log<'a>(&'a mut Vec<Event>);
+        impl EncoderEvents for Log<'_> {
+            fn on_frame(&mut self, frame: &WrittenFrame) {
+                self.0.push(Event::Frame(*frame));
+            }
+            fn on_checkpoint(&mut self, checkpoint: &EncodedCheckpoint) {
+                self.0.push(Event::Checkpoint(*checkpoint));
+            }
+        }
+        let (header, initial_state, frames) = example_frames();
+        let mut events = vec![];
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = encode(header, &initial_state, &mut out).unwrap();
+        enc.set_events(Log(&mut events));
+        for frame in &frames {
+            enc.write_frame(frame).unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        let mut rply = decode(std::io::Cursor::new(out.into_inner())).unwrap();
+        let mut checkpoints = rply.checkpoints().unwrap().into_iter();
+        let mut frames = frames.iter();
+        let mut pending = None;
+        for event in events {
+            match event {
+                Event::Checkpoint(checkpoint) => {
+                    assert!(pending.replace(checkpoint).is_none());
+                }
+                Event::Frame(written) => {
+                    let orig = frames.next().unwrap();
+                    assert_eq!(written.checkpoint, !orig.checkpoint_bytes.is_empty());
+                    // A checkpoint is reported just before the frame it ends
+                    let Some(checkpoint) = pending.take() else {
+                        assert!(!written.checkpoint);
+                        continue;
+                    };
+                    let info = checkpoints.next().unwrap();
+                    assert_eq!(checkpoint.sizes.frame, written.frame + 1);
+                    assert_eq!(checkpoint.sizes.frame, info.frame);
+                    assert_eq!(checkpoint.sizes.compressed, info.compressed_size);
+                    assert_eq!(checkpoint.sizes.uncompressed, info.uncompressed_size);
+                    assert_eq!(checkpoint.encoding, info.encoding);
+                    assert!(written.micros >= checkpoint.micros);
+                }
+            }
+        }
+        assert!(frames.next().is_none() && checkpoints.next().is_none());
+    }
+
+    #[test]
+    fn header_builder() {
+        let mut upgraded = Header::V0V1(HeaderBase {
+            version: 2,
+            content_crc: 7,
+            initial_state_size: 0,
+            identifier: 9,
+        });
+        upgraded.upgrade();
+        assert_eq!(HeaderV2::builder(9, 7).build().unwrap(), upgraded);
+        for invalid in [
+            HeaderV2::builder(9, 7).block_size(0),
+            HeaderV2::builder(9, 7).superblock_size(0),
+            HeaderV2::builder(9, 7).checkpoint_commit_settings(4, 5),
+        ] {
+            assert!(matches!(
+                invalid.build(),
+                Err(ReplayError::InvalidHeader(_))
+            ));
+        }
+        let (_, initial_state, frames) = example_frames();
+        let header = HeaderV2::builder(9, 7)
+            .block_size(128)
+            .superblock_size(64)
+            .checkpoint_commit_settings(4, 4)
+            .build()
+            .unwrap();
+        assert_roundtrip(
+            header,
+            &initial_state,
+            &frames[..600],
+            Compression::None,
+            Encoding::Statestream,
+        );
+    }
+
+    #[test]
+    fn find_frames() {
+        // Frame n of this replay has one input with value n * 7 % 300
+        let bytes = crate::verify::tests::replay(None);
+        let mut rply = decode(std::io::Cursor::new(&bytes)).unwrap();
+        let pattern = InputPattern {
+            val: Some(21),
+            ..InputPattern::pressed(0, 0, 0)
+        };
+        assert_eq!(rply.find_frame(|f| pattern.matches(f)).unwrap(), Some(3));
+        assert_eq!(rply.find_frame(|f| pattern.matches(f)).unwrap(), Some(303));
+        assert_eq!(
+            rply.find_frames(|f| pattern.matches(f)).unwrap(),
+            [603, 903]
+        );
+        assert_eq!(rply.find_frame(|f| pattern.matches(f)).unwrap(), None);
+        // Released only when n is a multiple of 300
+        let mut rply = decode(std::io::Cursor::new(&bytes)).unwrap();
+        let released = rply
+            .find_frames(|f| !InputPattern::pressed(0, 0, 0).matches(f))
+            .unwrap();
+        assert_eq!(released, [300, 600, 900]);
+        let mut rply = decode(std::io::Cursor::new(&bytes)).unwrap();
+        let other_port = InputPattern::pressed(1, 0, 0);
+        assert_eq!(rply.find_frame(|f| other_port.matches(f)).unwrap(), None);
+    }
+
+    #[test]
+    fn find_frames_in_joypad_masks() {
+        // The example's core polls the joypad as a bitmask, with Start
+        // held on frames 52 to 57
+        let mut rply = decode(std::io::BufReader::new(
+            std::fs::File::open(EXAMPLE).unwrap(),
+        ))
+        .unwrap();
+        let start = InputPattern::pressed(0, 1, 3);
+        assert_eq!(
+            rply.find_frames(|f| start.matches(f)).unwrap(),
+            (52..58).collect::<Vec<_>>()
+        );
+        rply.seek_to_frame(52).unwrap();
+        let released = InputPattern {
+            val: Some(0),
+            ..start
+        };
+        assert_eq!(rply.find_frame(|f| released.matches(f)).unwrap(), Some(58));
+        // The bitmask event itself still matches its own id
+        rply.seek_to_frame(0).unwrap();
+        let mask = InputPattern::pressed(0, 1, 256);
+        assert!(rply.find_frame(|f| mask.matches(f)).unwrap().is_some());
+    }
 }
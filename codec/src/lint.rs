@@ -0,0 +1,247 @@
+use crate::{Frame, ReplayDecoder, ReplayError};
+use std::io::BufRead;
+
+/// Thresholds for [`lint`].
+#[derive(Debug, Clone)]
+pub struct LintOptions {
+    /// Longest run of frames without a checkpoint before it is flagged
+    /// (default 18000, five minutes at 60fps)
+    pub max_checkpoint_gap: u64,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        Self {
+            max_checkpoint_gap: 18_000,
+        }
+    }
+}
+
+/// Highest input port a frontend plausibly records
+const MAX_PORT: u8 = 7;
+
+/// Something suspicious about a replay, found by [`lint`].  Frame numbers
+/// count the frames read so far, so the first frame is frame 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintIssue {
+    /// More than [`LintOptions::max_checkpoint_gap`] frames passed without a checkpoint
+    CheckpointGap { from: u64, to: u64 },
+    /// The header's frame count disagrees with the frames in the stream
+    FrameCountMismatch { declared: u64, actual: u64 },
+    /// Frames follow the header's declared last frame
+    FramesAfterEnd { frames: u64 },
+    /// An input event names a port no frontend uses
+    BadPort { frame: u64, port: u8 },
+    /// An input event is for `RETRO_DEVICE_NONE`
+    NoDevice { frame: u64 },
+    /// Padding bytes in the frame's events are not zero
+    NonZeroPadding { frame: u64 },
+    /// The frame could not be read, so linting stopped there
+    Unreadable { frame: u64, error: String },
+}
+
+impl LintIssue {
+    /// A stable identifier for the kind of issue, for machine-readable output
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintIssue::CheckpointGap { .. } => "checkpoint-gap",
+            LintIssue::FrameCountMismatch { .. } => "frame-count-mismatch",
+            LintIssue::FramesAfterEnd { .. } => "frames-after-end",
+            LintIssue::BadPort { .. } => "bad-port",
+            LintIssue::NoDevice { .. } => "no-device",
+            LintIssue::NonZeroPadding { .. } => "nonzero-padding",
+            LintIssue::Unreadable { .. } => "unreadable",
+        }
+    }
+    /// The frame the issue was found on, if it concerns one frame
+    #[must_use]
+    pub fn frame(&self) -> Option<u64> {
+        match self {
+            LintIssue::CheckpointGap { from, .. } => Some(*from),
+            LintIssue::FrameCountMismatch { .. } | LintIssue::FramesAfterEnd { .. } => None,
+            LintIssue::BadPort { frame, .. }
+            | LintIssue::NoDevice { frame }
+            | LintIssue::NonZeroPadding { frame }
+            | LintIssue::Unreadable { frame, .. } => Some(*frame),
+        }
+    }
+    /// Whether the replay is damaged, rather than merely unusual
+    #[must_use]
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            LintIssue::FrameCountMismatch { .. } | LintIssue::Unreadable { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintIssue::CheckpointGap { from, to } => {
+                write!(f, "no checkpoint from frame {from} to frame {to}")
+            }
+            LintIssue::FrameCountMismatch { declared, actual } => {
+                write!(f, "header declares {declared} frames but found {actual}")
+            }
+            LintIssue::FramesAfterEnd { frames } => {
+                write!(f, "{frames} frames after the declared end")
+            }
+            LintIssue::BadPort { frame, port } => {
+                write!(f, "input on port {port} at frame {frame}")
+            }
+            LintIssue::NoDevice { frame } => write!(f, "input with no device at frame {frame}"),
+            LintIssue::NonZeroPadding { frame } => {
+                write!(f, "nonzero padding bytes at frame {frame}")
+            }
+            LintIssue::Unreadable { frame, error } => {
+                write!(f, "could not read frame {frame}: {error}")
+            }
+        }
+    }
+}
+
+/// Reads the rest of `rply`, skipping checkpoint payloads, and reports
+/// anything suspicious.  Frames past the header's frame count are read too,
+/// so that they can be reported.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: Tried to lint a version 0 replay
+/// [`ReplayError::IO`]: I/O error while checking for the end of the stream
+pub fn lint<R: BufRead>(
+    rply: &mut ReplayDecoder<R>,
+    options: &LintOptions,
+) -> Result<Vec<LintIssue>, ReplayError> {
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    let mut issues = vec![];
+    let mut frame = Frame::default();
+    let mut last_checkpoint = rply.frame_number;
+    let declared = rply.header.frame_count();
+    while !rply.stream_ended()? {
+        let number = rply.frame_number + 1;
+        let checkpoint = match rply.read_frame_skipping_checkpoints(&mut frame) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                issues.push(LintIssue::Unreadable {
+                    frame: number,
+                    error: e.to_string(),
+                });
+                break;
+            }
+        };
+        if checkpoint.is_some() {
+            if number - last_checkpoint > options.max_checkpoint_gap {
+                issues.push(LintIssue::CheckpointGap {
+                    from: last_checkpoint,
+                    to: number,
+                });
+            }
+            last_checkpoint = number;
+        }
+        if !rply.clean_padding {
+            issues.push(LintIssue::NonZeroPadding { frame: number });
+        }
+        if let Some(input) = frame.input_events.iter().find(|i| i.port > MAX_PORT) {
+            issues.push(LintIssue::BadPort {
+                frame: number,
+                port: input.port,
+            });
+        }
+        if frame.input_events.iter().any(|i| i.device == 0) {
+            issues.push(LintIssue::NoDevice { frame: number });
+        }
+    }
+    if rply.frame_number - last_checkpoint > options.max_checkpoint_gap {
+        issues.push(LintIssue::CheckpointGap {
+            from: last_checkpoint,
+            to: rply.frame_number,
+        });
+    }
+    if let Some(declared) = declared {
+        let actual = rply.frame_number;
+        if actual > declared {
+            issues.push(LintIssue::FramesAfterEnd {
+                frames: actual - declared,
+            });
+        }
+        if actual != declared {
+            issues.push(LintIssue::FrameCountMismatch { declared, actual });
+        }
+    }
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputData;
+
+    #[test]
+    fn lint_issues() {
+        let file = std::fs::File::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../examples/bobl.replay"
+        ))
+        .unwrap();
+        let mut rply = crate::decode(std::io::BufReader::new(file)).unwrap();
+        let frames: Vec<Frame> = rply.frames().take(300).map(Result::unwrap).collect();
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc =
+                crate::encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+            for (i, frame) in frames.iter().enumerate() {
+                let mut frame = Frame {
+                    input_events: frame
+                        .input_events
+                        .iter()
+                        .map(|e| InputData {
+                            port: e.port,
+                            device: e.device,
+                            idx: e.idx,
+                            id: e.id,
+                            val: e.val,
+                        })
+                        .collect(),
+                    ..Frame::default()
+                };
+                if i == 10 {
+                    frame.input_events.push(InputData {
+                        port: 9,
+                        device: 0,
+                        ..InputData::default()
+                    });
+                }
+                enc.write_frame(&frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        let mut bytes = out.into_inner();
+        /* Declare fewer frames than there are */
+        bytes[24..28].copy_from_slice(&250_u32.to_le_bytes());
+
+        let mut rply = crate::decode(bytes.as_slice()).unwrap();
+        let issues = lint(
+            &mut rply,
+            &LintOptions {
+                max_checkpoint_gap: 200,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            issues,
+            vec![
+                LintIssue::BadPort { frame: 11, port: 9 },
+                LintIssue::NoDevice { frame: 11 },
+                LintIssue::CheckpointGap { from: 0, to: 300 },
+                LintIssue::FramesAfterEnd { frames: 50 },
+                LintIssue::FrameCountMismatch {
+                    declared: 250,
+                    actual: 300
+                },
+            ]
+        );
+        assert!(issues.iter().any(LintIssue::is_error));
+    }
+}
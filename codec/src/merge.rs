@@ -0,0 +1,328 @@
+use crate::{Frame, ReplayDecoder, ReplayEncoder, ReplayError};
+use std::io::{BufRead, Seek, Write};
+use std::ops::Range;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/* The next frame of `rply`, or None at its end */
+fn next<R: BufRead>(rply: &mut ReplayDecoder<R>, checkpoints: bool) -> Result<Option<Frame>> {
+    if rply.at_end()? {
+        return Ok(None);
+    }
+    let mut frame = Frame::default();
+    if checkpoints {
+        rply.read_frame(&mut frame)?;
+    } else {
+        rply.read_frame_skipping_checkpoints(&mut frame)?;
+    }
+    Ok(Some(frame))
+}
+
+/* Whether two frames (or ends of replays) have the same inputs */
+fn same_inputs(a: Option<&Frame>, b: Option<&Frame>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.key_events == b.key_events && a.input_events == b.input_events,
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Frame ranges (numbered from 1) where the rest of `a` and `b` have
+/// different inputs, including the frames only the longer one has.
+/// Checkpoints are ignored.
+/// # Errors
+/// Any error from reading frames
+pub fn diff<A: BufRead, B: BufRead>(
+    a: &mut ReplayDecoder<A>,
+    b: &mut ReplayDecoder<B>,
+) -> Result<Vec<Range<u64>>> {
+    let mut ranges: Vec<Range<u64>> = vec![];
+    let mut frame = 0;
+    loop {
+        let (fa, fb) = (next(a, false)?, next(b, false)?);
+        if fa.is_none() && fb.is_none() {
+            return Ok(ranges);
+        }
+        frame += 1;
+        if same_inputs(fa.as_ref(), fb.as_ref()) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == frame => last.end += 1,
+            _ => ranges.push(frame..frame + 1),
+        }
+    }
+}
+
+/// Where two replays first diverge, from [`diverge`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Divergence {
+    /// The first frame (numbered from 1) with different inputs, if any
+    pub frame: Option<u64>,
+    /// The first checkpoint both replays have after the same frame, but
+    /// with different states, if any
+    pub checkpoint: Option<CheckpointDivergence>,
+}
+
+/// Two differing checkpoints taken after the same frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointDivergence {
+    /// Frames preceding the checkpoints
+    pub frame: u64,
+    /// Byte ranges where the checkpoints differ, including any bytes only
+    /// one of them has
+    pub differences: Vec<Range<usize>>,
+    /// The first replay's block size, or 256 if it has none
+    pub block_size: usize,
+    /// Ranges of block indices holding any of the differences
+    pub blocks: Vec<Range<usize>>,
+    /// Indices of the first replay's state regions (see
+    /// [`crate::Metadata::state_regions`]) holding any of the differences
+    pub regions: Vec<usize>,
+}
+
+impl CheckpointDivergence {
+    fn new(frame: u64, a: &[u8], b: &[u8], block_size: usize, regions: &[Range<usize>]) -> Self {
+        let differences = crate::verify::differences(a, b);
+        let blocks = crate::blockmap::block_ranges(&differences, block_size);
+        let regions = (0..regions.len())
+            .filter(|&i| {
+                let region = &regions[i];
+                differences
+                    .iter()
+                    .any(|d| d.start < region.end && region.start < d.end)
+            })
+            .collect();
+        Self {
+            frame,
+            differences,
+            block_size,
+            blocks,
+            regions,
+        }
+    }
+}
+
+/// Finds the first frame where the rest of `a` and `b` have different
+/// inputs, and the first frame after which both have a checkpoint but the
+/// checkpoints differ, summarizing which blocks and state regions of `a`
+/// changed.  A checkpoint divergence before any input difference means a
+/// core recorded the same inputs nondeterministically.
+/// # Errors
+/// Any error from reading frames
+pub fn diverge<A: BufRead, B: BufRead>(
+    a: &mut ReplayDecoder<A>,
+    b: &mut ReplayDecoder<B>,
+) -> Result<Divergence> {
+    let block_size = match a.header.block_size() {
+        0 => 256,
+        size => size as usize,
+    };
+    let regions = a
+        .header
+        .metadata()
+        .and_then(crate::Metadata::state_regions)
+        .unwrap_or_default();
+    let mut divergence = Divergence::default();
+    let mut frame = 0;
+    loop {
+        let checkpoints = divergence.checkpoint.is_none();
+        let (fa, fb) = (next(a, checkpoints)?, next(b, checkpoints)?);
+        if fa.is_none() && fb.is_none() {
+            return Ok(divergence);
+        }
+        frame += 1;
+        if divergence.frame.is_none() && !same_inputs(fa.as_ref(), fb.as_ref()) {
+            divergence.frame = Some(frame);
+        }
+        if let (Some(fa), Some(fb)) = (&fa, &fb)
+            && checkpoints
+            && !fa.checkpoint_bytes.is_empty()
+            && !fb.checkpoint_bytes.is_empty()
+            && fa.checkpoint_bytes != fb.checkpoint_bytes
+        {
+            divergence.checkpoint = Some(CheckpointDivergence::new(
+                frame,
+                &fa.checkpoint_bytes,
+                &fb.checkpoint_bytes,
+                block_size,
+                &regions,
+            ));
+        }
+        if divergence.frame.is_some() && divergence.checkpoint.is_some() {
+            return Ok(divergence);
+        }
+    }
+}
+
+/// What [`merge`] wrote.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Merge {
+    /// Frames in the merged replay
+    pub frames: u64,
+    /// Frames both sides changed differently from the base; the merged
+    /// replay has `ours` for them
+    pub conflicts: Vec<u64>,
+    /// Checkpoints kept from either side
+    pub checkpoints: u64,
+}
+
+/// Merges the input edits of two replays, `ours` and `theirs`, descended
+/// from `base`, writing the result to `out` with `ours`' header.  Each
+/// frame takes the inputs of whichever side changed them from `base`; a
+/// frame both sides changed differently is a conflict, and gets `ours`.
+/// Frames one side cut from the end count as changed, and the merged
+/// replay ends at its first frame with no inputs from either side.
+///
+/// A checkpoint is only valid while the inputs before it are the ones it
+/// was recorded with, so the merged replay keeps a side's checkpoint only
+/// if every merged frame up to it matches that side.  Re-record or verify
+/// the merged replay with a core to checkpoint the rest.
+/// # Errors
+/// [`ReplayError::MergeBase`]: `ours` and `theirs` start from different states
+/// Any error from decoding the replays or encoding the merge
+pub fn merge<B: BufRead, O: BufRead, T: BufRead, W: Write + Seek>(
+    base: &mut ReplayDecoder<B>,
+    ours: &mut ReplayDecoder<O>,
+    theirs: &mut ReplayDecoder<T>,
+    out: &mut W,
+) -> Result<Merge> {
+    if ours.initial_state != theirs.initial_state {
+        return Err(ReplayError::MergeBase());
+    }
+    let mut enc = ReplayEncoder::new(ours.header.clone(), &ours.initial_state, out)?;
+    let mut merge = Merge::default();
+    /* Whether every merged frame so far has had each side's inputs */
+    let (mut like_ours, mut like_theirs) = (true, true);
+    loop {
+        let (b, o, t) = (next(base, false)?, next(ours, true)?, next(theirs, true)?);
+        let take_theirs =
+            if same_inputs(o.as_ref(), t.as_ref()) || same_inputs(b.as_ref(), t.as_ref()) {
+                false
+            } else if same_inputs(b.as_ref(), o.as_ref()) {
+                true
+            } else {
+                merge.conflicts.push(merge.frames + 1);
+                false
+            };
+        let (Some(mut frame), other) = (if take_theirs { (t, o) } else { (o, t) }) else {
+            break;
+        };
+        let other_same = same_inputs(Some(&frame), other.as_ref());
+        let (like_taken, like_other) = if take_theirs {
+            (&mut like_theirs, &mut like_ours)
+        } else {
+            (&mut like_ours, &mut like_theirs)
+        };
+        *like_other &= other_same;
+        // The taken side always has this frame's inputs, so its checkpoint
+        // is good as long as every earlier frame matched it too
+        if !*like_taken || frame.checkpoint_bytes.is_empty() {
+            frame.checkpoint_bytes.clear();
+            if let Some(other) = other.filter(|o| *like_other && !o.checkpoint_bytes.is_empty()) {
+                frame.checkpoint_bytes = other.checkpoint_bytes;
+            }
+        }
+        if !frame.checkpoint_bytes.is_empty() {
+            merge.checkpoints += 1;
+        }
+        enc.write_frame(&frame)?;
+        merge.frames += 1;
+    }
+    enc.finish()?;
+    Ok(merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    /* `bytes` re-encoded with `edit` applied to each frame and its number */
+    fn edited(bytes: &[u8], frames: u64, edit: impl Fn(u64, &mut Frame)) -> Vec<u8> {
+        let mut rply = crate::decode(bytes).unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+        for (n, frame) in (1..=frames).zip(rply.frames()) {
+            let mut frame = frame.unwrap();
+            edit(n, &mut frame);
+            enc.write_frame(&frame).unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+        out.into_inner()
+    }
+
+    #[test]
+    fn merge_edits() {
+        let base = replay(None);
+        let set = |at: Vec<u64>, val: i16| {
+            move |n: u64, frame: &mut Frame| {
+                if at.contains(&n) {
+                    frame.input_events[0].val = val;
+                }
+            }
+        };
+        // Ours edits frames 100 and 500, theirs 700 and 500, and cuts the last 100 frames
+        let ours = edited(&base, 1000, set(vec![100, 500], 1));
+        let theirs = edited(&base, 900, set(vec![500, 700], 2));
+        let decode = |bytes: &[u8]| crate::decode(std::io::Cursor::new(bytes.to_vec())).unwrap();
+        assert_eq!(
+            diff(&mut decode(&base), &mut decode(&theirs)).unwrap(),
+            [500..501, 700..701, 901..1001]
+        );
+
+        let mut out = std::io::Cursor::new(vec![]);
+        let merged = merge(
+            &mut decode(&base),
+            &mut decode(&ours),
+            &mut decode(&theirs),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(merged.frames, 900);
+        assert_eq!(merged.conflicts, [500]);
+        // Checkpoints every 40 frames, kept while the merge matches ours, up to frame 680
+        assert_eq!(merged.checkpoints, 17);
+        let mut result = decode(out.get_ref());
+        let vals: Vec<_> = result
+            .frames()
+            .map(|frame| frame.unwrap().input_events[0].val)
+            .collect();
+        assert_eq!((vals[99], vals[499], vals[699]), (1, 1, 2));
+        assert_eq!(
+            diff(&mut decode(&base), &mut decode(out.get_ref())).unwrap(),
+            [100..101, 500..501, 700..701, 901..1001]
+        );
+    }
+
+    #[test]
+    fn diverges() {
+        let decode = |bytes: &[u8]| crate::decode(std::io::Cursor::new(bytes.to_vec())).unwrap();
+        let base = replay(None);
+        let edit = edited(&base, 1000, |n, frame| {
+            if n == 200 {
+                frame.input_events[0].val = 1;
+            }
+        });
+        // The edit keeps the original checkpoints, so only the inputs differ
+        assert_eq!(
+            diverge(&mut decode(&base), &mut decode(&edit)).unwrap(),
+            Divergence {
+                frame: Some(200),
+                checkpoint: None,
+            }
+        );
+        let mut a = decode(&base);
+        a.header
+            .metadata_mut()
+            .set_state_regions(&[0..40, 40..60, 60..100]);
+        let divergence = diverge(&mut a, &mut decode(&replay(Some(480)))).unwrap();
+        assert_eq!(divergence.frame, None);
+        let checkpoint = divergence.checkpoint.unwrap();
+        assert_eq!((checkpoint.frame, checkpoint.block_size), (480, 16));
+        assert_eq!(checkpoint.differences, vec![50..51]);
+        // Byte 50 is in the fourth block and the second region
+        assert_eq!(checkpoint.blocks, vec![3..4]);
+        assert_eq!(checkpoint.regions, [1]);
+    }
+}
@@ -0,0 +1,541 @@
+use crate::ReplayError;
+
+/// Tag of a metadata chunk: four ASCII bytes, as in PNG or RIFF.
+pub type ChunkTag = [u8; 4];
+
+/// UTF-8 license string, e.g. an SPDX identifier
+pub const LICENSE: ChunkTag = *b"LICN";
+/// [`AllowedUses`] flags as a little-endian u32
+pub const ALLOWED_USES: ChunkTag = *b"USES";
+/// UTF-8 title of the game being played
+pub const TITLE: ChunkTag = *b"TITL";
+/// UTF-8 name of the libretro core that recorded the replay
+pub const CORE_NAME: ChunkTag = *b"CORE";
+/// UTF-8 version string of the recording core
+pub const CORE_VERSION: ChunkTag = *b"CVER";
+/// UTF-8 name of the replay's author
+pub const AUTHOR: ChunkTag = *b"AUTH";
+/// Creation time as little-endian u64 seconds since the Unix epoch
+pub const CREATED: ChunkTag = *b"DATE";
+/// Digest of the ROM the replay was recorded against, in whatever hash the
+/// frontend uses (RetroArch uses the content CRC32, also in the header)
+pub const ROM_HASH: ChunkTag = *b"ROMH";
+/// Zstd dictionary that zstd-compressed checkpoints were compressed with
+pub const ZSTD_DICTIONARY: ChunkTag = *b"ZDIC";
+/// Regions of the core's savestate, e.g. from its memory map, as pairs of
+/// little-endian u64 offset and length
+pub const STATE_REGIONS: ChunkTag = *b"RGNS";
+/// Wall-clock span of the recording session, as little-endian u64 start
+/// and end seconds since the Unix epoch.  Only recorded on request.
+pub const SESSION: ChunkTag = *b"SESS";
+/// UTF-8 BCP 47 language tag of the recording machine, e.g. "en-US".  Only
+/// recorded on request.
+pub const LOCALE: ChunkTag = *b"LOCL";
+/// UTF-8 timezone of the recording machine, an IANA name like
+/// "Europe/Paris" or a UTC offset like "+01:00".  Only recorded on request.
+pub const TIMEZONE: ChunkTag = *b"TZON";
+/// An audio track to play alongside the replay, e.g. the player's voice
+/// commentary: a little-endian i64 [`Commentary::start_frame`] followed by
+/// the UTF-8 path of the audio file, relative to the replay's directory
+pub const COMMENTARY: ChunkTag = *b"CMTY";
+/// The [`crate::Compression`] byte that runs of frames are packed with; see
+/// [`Metadata::frame_packing`]
+pub const FRAME_PACKING: ChunkTag = *b"FPAK";
+/// Empty chunk marking replays whose statestream tables commit and evict
+/// blocks by the header's commit interval and threshold; see
+/// [`Metadata::block_commits`]
+pub const BLOCK_COMMITS: ChunkTag = *b"CMIT";
+
+/// Tags this crate gives a typed accessor
+const KNOWN: [ChunkTag; 17] = [
+    LICENSE,
+    ALLOWED_USES,
+    TITLE,
+    CORE_NAME,
+    CORE_VERSION,
+    AUTHOR,
+    CREATED,
+    ROM_HASH,
+    ZSTD_DICTIONARY,
+    STATE_REGIONS,
+    SESSION,
+    LOCALE,
+    TIMEZONE,
+    COMMENTARY,
+    FRAME_PACKING,
+    BLOCK_COMMITS,
+    crate::COMPAT,
+];
+
+/// Tags [`Metadata::anonymize`] removes
+const PERSONAL: [ChunkTag; 4] = [AUTHOR, SESSION, LOCALE, TIMEZONE];
+
+/// An audio file recorded alongside the replay, kept next to it rather
+/// than inside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commentary {
+    /// Where the audio file is, relative to the replay's directory
+    pub path: String,
+    /// How many frames run before the audio's first sample plays; negative
+    /// if the audio started before the replay, so its beginning is skipped
+    pub start_frame: i64,
+}
+
+/// Uses the replay's author permits, beyond whatever the license says.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedUses(pub u32);
+
+impl AllowedUses {
+    pub const REDISTRIBUTE: Self = Self(1 << 0);
+    pub const MODIFY: Self = Self(1 << 1);
+    pub const COMMERCIAL: Self = Self(1 << 2);
+    pub const BROADCAST: Self = Self(1 << 3);
+    pub const RESEARCH: Self = Self(1 << 4);
+    pub const MACHINE_LEARNING: Self = Self(1 << 5);
+    const NAMES: [(Self, &'static str); 6] = [
+        (Self::REDISTRIBUTE, "redistribute"),
+        (Self::MODIFY, "modify"),
+        (Self::COMMERCIAL, "commercial"),
+        (Self::BROADCAST, "broadcast"),
+        (Self::RESEARCH, "research"),
+        (Self::MACHINE_LEARNING, "machine-learning"),
+    ];
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for AllowedUses {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::fmt::Display for AllowedUses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rest = self.0;
+        let mut first = true;
+        for (flag, name) in Self::NAMES {
+            if self.contains(flag) {
+                write!(f, "{}{name}", if first { "" } else { "|" })?;
+                first = false;
+                rest &= !flag.0;
+            }
+        }
+        if rest != 0 {
+            write!(f, "{}{rest:#x}", if first { "" } else { "|" })?;
+        } else if first {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Metadata chunks carried by v3 replays, in file order.  Chunks this
+/// crate doesn't understand are kept as raw bytes so that transforms
+/// preserve them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    chunks: Vec<(ChunkTag, Vec<u8>)>,
+}
+
+impl Metadata {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+    /// All chunks as (tag, payload) pairs.
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkTag, &[u8])> {
+        self.chunks.iter().map(|(tag, data)| (tag, data.as_slice()))
+    }
+    #[must_use]
+    pub fn get(&self, tag: ChunkTag) -> Option<&[u8]> {
+        self.chunks
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, data)| data.as_slice())
+    }
+    /// Replaces the chunk with this tag, or appends it if there is none.
+    pub fn set(&mut self, tag: ChunkTag, data: Vec<u8>) {
+        match self.chunks.iter_mut().find(|(t, _)| *t == tag) {
+            Some((_, old)) => *old = data,
+            None => self.chunks.push((tag, data)),
+        }
+    }
+    pub fn remove(&mut self, tag: ChunkTag) -> Option<Vec<u8>> {
+        let idx = self.chunks.iter().position(|(t, _)| *t == tag)?;
+        Some(self.chunks.remove(idx).1)
+    }
+    /// Chunks without a typed accessor, e.g. those added by newer writers.
+    pub fn unknown_chunks(&self) -> impl Iterator<Item = (&ChunkTag, &[u8])> {
+        self.chunks().filter(|(tag, _)| !KNOWN.contains(tag))
+    }
+    /// A UTF-8 chunk, if present and valid.
+    #[must_use]
+    pub fn text(&self, tag: ChunkTag) -> Option<&str> {
+        std::str::from_utf8(self.get(tag)?).ok()
+    }
+    pub fn set_text(&mut self, tag: ChunkTag, text: &str) {
+        self.set(tag, text.as_bytes().to_vec());
+    }
+    /// The license string, if present and valid UTF-8.
+    #[must_use]
+    pub fn license(&self) -> Option<&str> {
+        self.text(LICENSE)
+    }
+    pub fn set_license(&mut self, license: &str) {
+        self.set_text(LICENSE, license);
+    }
+    #[must_use]
+    pub fn title(&self) -> Option<&str> {
+        self.text(TITLE)
+    }
+    pub fn set_title(&mut self, title: &str) {
+        self.set_text(TITLE, title);
+    }
+    #[must_use]
+    pub fn core_name(&self) -> Option<&str> {
+        self.text(CORE_NAME)
+    }
+    pub fn set_core_name(&mut self, name: &str) {
+        self.set_text(CORE_NAME, name);
+    }
+    #[must_use]
+    pub fn core_version(&self) -> Option<&str> {
+        self.text(CORE_VERSION)
+    }
+    pub fn set_core_version(&mut self, version: &str) {
+        self.set_text(CORE_VERSION, version);
+    }
+    #[must_use]
+    pub fn author(&self) -> Option<&str> {
+        self.text(AUTHOR)
+    }
+    pub fn set_author(&mut self, author: &str) {
+        self.set_text(AUTHOR, author);
+    }
+    /// Creation time in seconds since the Unix epoch
+    #[must_use]
+    pub fn created(&self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.get(CREATED)?.try_into().ok()?))
+    }
+    pub fn set_created(&mut self, seconds: u64) {
+        self.set(CREATED, seconds.to_le_bytes().to_vec());
+    }
+    #[must_use]
+    pub fn rom_hash(&self) -> Option<&[u8]> {
+        self.get(ROM_HASH)
+    }
+    pub fn set_rom_hash(&mut self, hash: &[u8]) {
+        self.set(ROM_HASH, hash.to_vec());
+    }
+    #[must_use]
+    pub fn allowed_uses(&self) -> Option<AllowedUses> {
+        let bytes = self.get(ALLOWED_USES)?;
+        Some(AllowedUses(u32::from_le_bytes(bytes.try_into().ok()?)))
+    }
+    pub fn set_allowed_uses(&mut self, uses: AllowedUses) {
+        self.set(ALLOWED_USES, uses.0.to_le_bytes().to_vec());
+    }
+    /// The zstd dictionary checkpoints are compressed with, e.g. one made
+    /// by [`crate::train_zstd_dictionary`].  Encoders and decoders load it
+    /// from the header themselves.
+    #[must_use]
+    pub fn zstd_dictionary(&self) -> Option<&[u8]> {
+        self.get(ZSTD_DICTIONARY)
+    }
+    pub fn set_zstd_dictionary(&mut self, dictionary: Vec<u8>) {
+        self.set(ZSTD_DICTIONARY, dictionary);
+    }
+    /// Byte ranges of the savestate holding separate pieces of the core's
+    /// memory.  The statestream encoding starts a new block at each region
+    /// boundary, so a region's blocks deduplicate however it sits in the
+    /// state.  Encoders and decoders load them from the header themselves.
+    #[must_use]
+    pub fn state_regions(&self) -> Option<Vec<std::ops::Range<usize>>> {
+        let bytes = self.get(STATE_REGIONS)?;
+        if !bytes.len().is_multiple_of(16) {
+            return None;
+        }
+        bytes
+            .chunks(16)
+            .map(|pair| {
+                let start = u64::from_le_bytes(pair[..8].try_into().unwrap());
+                let len = u64::from_le_bytes(pair[8..].try_into().unwrap());
+                let start = usize::try_from(start).ok()?;
+                Some(start..start.checked_add(usize::try_from(len).ok()?)?)
+            })
+            .collect()
+    }
+    /// When the recording session started and ended, in seconds since the
+    /// Unix epoch; verification committees use it to cross-check a run
+    /// against when its attempts were claimed.
+    #[must_use]
+    pub fn session(&self) -> Option<std::ops::Range<u64>> {
+        let bytes: &[u8; 16] = self.get(SESSION)?.try_into().ok()?;
+        let start = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let end = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+        Some(start..end)
+    }
+    pub fn set_session(&mut self, session: std::ops::Range<u64>) {
+        let bytes = [session.start, session.end]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        self.set(SESSION, bytes);
+    }
+    #[must_use]
+    pub fn locale(&self) -> Option<&str> {
+        self.text(LOCALE)
+    }
+    pub fn set_locale(&mut self, locale: &str) {
+        self.set_text(LOCALE, locale);
+    }
+    #[must_use]
+    pub fn timezone(&self) -> Option<&str> {
+        self.text(TIMEZONE)
+    }
+    pub fn set_timezone(&mut self, timezone: &str) {
+        self.set_text(TIMEZONE, timezone);
+    }
+    #[must_use]
+    pub fn commentary(&self) -> Option<Commentary> {
+        let bytes = self.get(COMMENTARY)?;
+        let start_frame = i64::from_le_bytes(bytes.get(..8)?.try_into().unwrap());
+        let path = std::str::from_utf8(&bytes[8..]).ok()?.to_owned();
+        Some(Commentary { path, start_frame })
+    }
+    pub fn set_commentary(&mut self, commentary: &Commentary) {
+        let mut bytes = commentary.start_frame.to_le_bytes().to_vec();
+        bytes.extend(commentary.path.as_bytes());
+        self.set(COMMENTARY, bytes);
+    }
+    /// How the frames after the initial state are packed: in runs, each
+    /// compressed as a unit with this compression (a checkpoint's frame
+    /// gets a run to itself and isn't compressed again), so that the
+    /// repetitive input events take less room.  Offsets into the frames,
+    /// e.g. in a [`crate::SeekIndex`], count unpacked bytes.  Encoders and
+    /// decoders pack and unpack frames themselves, with the built-in
+    /// compressors' default settings.
+    #[must_use]
+    pub fn frame_packing(&self) -> Option<crate::Compression> {
+        let &[byte] = self.get(FRAME_PACKING)? else {
+            return None;
+        };
+        crate::Compression::try_from(byte).ok()
+    }
+    pub fn set_frame_packing(&mut self, compression: Option<crate::Compression>) {
+        match compression {
+            Some(compression) => self.set(FRAME_PACKING, vec![u8::from(compression)]),
+            None => {
+                self.remove(FRAME_PACKING);
+            }
+        }
+    }
+    /// Whether the statestream tables commit blocks every
+    /// [`crate::HeaderV2::checkpoint_commit_interval`] checkpoints, evicting
+    /// those used fewer than
+    /// [`crate::HeaderV2::checkpoint_commit_threshold`] times.  Replays
+    /// without this chunk never evict anything, whatever their header says,
+    /// as replays written before commits were honored didn't.  Encoders and
+    /// decoders commit in lockstep by themselves.
+    #[must_use]
+    pub fn block_commits(&self) -> bool {
+        self.get(BLOCK_COMMITS).is_some()
+    }
+    pub fn set_block_commits(&mut self, commits: bool) {
+        if commits {
+            self.set(BLOCK_COMMITS, vec![]);
+        } else {
+            self.remove(BLOCK_COMMITS);
+        }
+    }
+    /// Removes the chunks that say who recorded the replay, or when and
+    /// where: the author, session span, locale and timezone.
+    pub fn anonymize(&mut self) {
+        self.chunks.retain(|(tag, _)| !PERSONAL.contains(tag));
+    }
+    pub fn set_state_regions(&mut self, regions: &[std::ops::Range<usize>]) {
+        let bytes = regions
+            .iter()
+            .flat_map(|region| [region.start, region.len()].map(|n| (n as u64).to_le_bytes()))
+            .flatten()
+            .collect();
+        self.set(STATE_REGIONS, bytes);
+    }
+
+    /// Reads a length-prefixed metadata block.
+    #[cfg(test)]
+    pub(crate) fn read<R: std::io::Read>(reader: &mut R) -> Result<Self, ReplayError> {
+        Self::read_limited(reader, usize::MAX)
+    }
+    /* Reads a metadata block of at most `max` bytes */
+    pub(crate) fn read_limited<R: std::io::Read>(
+        reader: &mut R,
+        max: usize,
+    ) -> Result<Self, ReplayError> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        let len = reader.read_u32::<LittleEndian>()? as usize;
+        if len > max {
+            return Err(ReplayError::OverLimit("metadata", len as u64));
+        }
+        let mut block = vec![0; len];
+        reader.read_exact(&mut block)?;
+        let mut rest = block.as_slice();
+        let mut chunks = vec![];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(ReplayError::Metadata());
+            }
+            let tag: ChunkTag = rest[..4].try_into().unwrap();
+            let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            rest = &rest[8..];
+            if rest.len() < size {
+                return Err(ReplayError::Metadata());
+            }
+            chunks.push((tag, rest[..size].to_vec()));
+            rest = &rest[size..];
+        }
+        Ok(Self { chunks })
+    }
+    /// Size of the block written by [`Metadata::write`], including its length prefix.
+    pub(crate) fn encoded_len(&self) -> usize {
+        4 + self
+            .chunks
+            .iter()
+            .map(|(_, data)| 8 + data.len())
+            .sum::<usize>()
+    }
+    pub(crate) fn write<W: std::io::Write>(&self, writer: &mut W) -> Result<(), ReplayError> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+        let too_big = |_| ReplayError::Metadata();
+        writer
+            .write_u32::<LittleEndian>(u32::try_from(self.encoded_len() - 4).map_err(too_big)?)?;
+        for (tag, data) in &self.chunks {
+            writer.write_all(tag)?;
+            writer.write_u32::<LittleEndian>(u32::try_from(data.len()).map_err(too_big)?)?;
+            writer.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds metadata from (tag, payload) pairs; later pairs replace earlier
+/// ones with the same tag.
+impl FromIterator<(ChunkTag, Vec<u8>)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (ChunkTag, Vec<u8>)>>(iter: I) -> Self {
+        let mut meta = Self::default();
+        meta.extend(iter);
+        meta
+    }
+}
+
+impl Extend<(ChunkTag, Vec<u8>)> for Metadata {
+    fn extend<I: IntoIterator<Item = (ChunkTag, Vec<u8>)>>(&mut self, iter: I) {
+        for (tag, data) in iter {
+            self.set(tag, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_roundtrip() {
+        let mut meta = Metadata::default();
+        meta.set_license("CC-BY-4.0");
+        meta.set_allowed_uses(AllowedUses::REDISTRIBUTE | AllowedUses::RESEARCH);
+        meta.set(*b"XTRA", vec![1, 2, 3]);
+        meta.set_license("CC0-1.0");
+        let mut out = vec![];
+        meta.write(&mut out).unwrap();
+        assert_eq!(out.len(), meta.encoded_len());
+        let read = Metadata::read(&mut out.as_slice()).unwrap();
+        assert_eq!(read, meta);
+        assert_eq!(read.license(), Some("CC0-1.0"));
+        assert_eq!(
+            read.allowed_uses().unwrap().to_string(),
+            "redistribute|research"
+        );
+        assert_eq!(read.get(*b"XTRA"), Some(&[1, 2, 3][..]));
+        assert_eq!(
+            read.unknown_chunks().collect::<Vec<_>>(),
+            vec![(b"XTRA", &[1, 2, 3][..])]
+        );
+        assert!(matches!(
+            Metadata::read(&mut &out[..out.len() - 1]),
+            Err(ReplayError::IO(_))
+        ));
+        out[0] -= 1;
+        assert!(matches!(
+            Metadata::read(&mut out.as_slice()),
+            Err(ReplayError::Metadata())
+        ));
+    }
+
+    #[test]
+    fn typed_chunks() {
+        let mut meta: Metadata = [
+            (TITLE, b"Bubble Bobble".to_vec()),
+            (CORE_NAME, b"fceumm".to_vec()),
+            (*b"NEWS", vec![7]),
+        ]
+        .into_iter()
+        .collect();
+        meta.set_core_version("1.52");
+        meta.set_author("jcoa");
+        meta.set_created(1_700_000_000);
+        meta.set_rom_hash(&[0xde, 0xad, 0xbe, 0xef]);
+        let mut out = vec![];
+        meta.write(&mut out).unwrap();
+        let read = Metadata::read(&mut out.as_slice()).unwrap();
+        assert_eq!(read.title(), Some("Bubble Bobble"));
+        assert_eq!(read.core_name(), Some("fceumm"));
+        assert_eq!(read.core_version(), Some("1.52"));
+        assert_eq!(read.author(), Some("jcoa"));
+        assert_eq!(read.created(), Some(1_700_000_000));
+        assert_eq!(read.rom_hash(), Some(&[0xde, 0xad, 0xbe, 0xef][..]));
+        assert_eq!(read.commentary(), None);
+        assert_eq!(
+            read.unknown_chunks().collect::<Vec<_>>(),
+            vec![(b"NEWS", &[7][..])]
+        );
+    }
+
+    #[test]
+    fn anonymize() {
+        let mut meta = Metadata::default();
+        meta.set_title("Bubble Bobble");
+        meta.set_author("jcoa");
+        meta.set_session(1_700_000_000..1_700_003_600);
+        meta.set_locale("fr-CA");
+        meta.set_timezone("America/Toronto");
+        assert_eq!(meta.session(), Some(1_700_000_000..1_700_003_600));
+        assert_eq!(meta.locale(), Some("fr-CA"));
+        assert_eq!(meta.timezone(), Some("America/Toronto"));
+        meta.anonymize();
+        assert_eq!(
+            meta.chunks().map(|(tag, _)| *tag).collect::<Vec<_>>(),
+            [TITLE]
+        );
+    }
+
+    #[test]
+    fn commentary() {
+        let mut meta = Metadata::default();
+        let commentary = Commentary {
+            path: "voice/run 3.ogg".into(),
+            start_frame: -120,
+        };
+        meta.set_commentary(&commentary);
+        assert_eq!(meta.commentary(), Some(commentary));
+        assert_eq!(meta.unknown_chunks().count(), 0);
+        meta.set(COMMENTARY, vec![1, 2, 3]);
+        assert_eq!(meta.commentary(), None);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{Frame, Header, ReplayEncoder, ReplayError};
+use std::io::{BufRead, Seek, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// A movie being imported, as a replay header, the state it starts from,
+/// and its frames' inputs.
+pub struct Movie<'r> {
+    pub header: Header,
+    /// The savestate the movie starts from; empty if it starts from power-on
+    pub initial_state: Vec<u8>,
+    /// Frames in order; iteration stops after the first error
+    pub frames: Box<dyn Iterator<Item = Result<Frame>> + 'r>,
+}
+
+impl Movie<'_> {
+    /// Encodes the movie to `out` as a replay with its own header.
+    ///
+    /// Returns the number of frames written.
+    /// # Errors
+    /// Any error from reading the movie or encoding the replay
+    pub fn encode<W: Write + Seek>(self, out: &mut W) -> Result<u64> {
+        let mut enc = ReplayEncoder::new(self.header, &self.initial_state, out)?;
+        for frame in self.frames {
+            enc.write_frame(&frame?)?;
+        }
+        enc.finish()?;
+        Ok(enc.frame_number)
+    }
+}
+
+/// A movie file format from another emulator or TAS tool (e.g. FCEUX's
+/// fm2, BizHawk's bk2, lsnes's lsmv, libTAS's ltm) that can be read as a
+/// replay without running it.  Formats whose frames only exist as what a
+/// core polls, like RetroArch's BSV1, need [`crate::import_bsv1`] instead.
+pub trait MovieFormat {
+    /// Short name, e.g. the usual file extension
+    fn name(&self) -> &'static str;
+    /// Whether a file starting with `start` looks like this format.
+    /// `start` is whatever the reader had buffered, at least a few hundred
+    /// bytes unless the file is shorter.
+    fn sniff(&self, start: &[u8]) -> bool;
+    /// Reads the movie's header from `input`, leaving its frames to be read
+    /// as the returned movie is iterated.
+    /// # Errors
+    /// The movie is malformed or uses something a replay can't express
+    fn open<'r>(&self, input: Box<dyn BufRead + 'r>) -> Result<Movie<'r>>;
+}
+
+/// The movie formats to try when importing a file, by name.  The default
+/// registry has every format built into this crate: fm2 with the `fm2`
+/// feature.
+pub struct MovieRegistry {
+    formats: Vec<Box<dyn MovieFormat>>,
+}
+
+impl Default for MovieRegistry {
+    #[cfg_attr(not(feature = "fm2"), allow(unused_mut))]
+    fn default() -> Self {
+        let mut registry = Self { formats: vec![] };
+        #[cfg(feature = "fm2")]
+        registry.register(Box::new(crate::convert::Fm2));
+        registry
+    }
+}
+
+impl MovieRegistry {
+    /// Registers `format`, returning any format previously registered under its name.
+    pub fn register(&mut self, format: Box<dyn MovieFormat>) -> Option<Box<dyn MovieFormat>> {
+        match self.formats.iter_mut().find(|f| f.name() == format.name()) {
+            Some(old) => Some(std::mem::replace(old, format)),
+            None => {
+                self.formats.push(format);
+                None
+            }
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<&dyn MovieFormat> {
+        self.formats
+            .iter()
+            .find(|f| f.name() == name)
+            .map(AsRef::as_ref)
+    }
+    /// Names of the registered formats, in the order they're sniffed
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.formats.iter().map(|f| f.name())
+    }
+    /// The first registered format that recognizes a file starting with `start`
+    #[must_use]
+    pub fn sniff(&self, start: &[u8]) -> Option<&dyn MovieFormat> {
+        self.formats
+            .iter()
+            .find(|f| f.sniff(start))
+            .map(AsRef::as_ref)
+    }
+    /// Opens `input` with whichever format recognizes its start.
+    /// # Errors
+    /// [`ReplayError::UnknownMovieFormat`]: No registered format recognizes the input
+    /// Otherwise as [`MovieFormat::open`]
+    pub fn open<'r, R: BufRead + 'r>(&self, mut input: R) -> Result<Movie<'r>> {
+        let format = self
+            .sniff(input.fill_buf()?)
+            .ok_or(ReplayError::UnknownMovieFormat())?;
+        format.open(Box::new(input))
+    }
+}
+
+#[cfg(all(test, feature = "fm2"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn movie_registry() {
+        let registry = MovieRegistry::default();
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["fm2"]);
+        assert!(matches!(
+            registry.open(&b"BK2 is a zip file"[..]),
+            Err(ReplayError::UnknownMovieFormat())
+        ));
+        let movie =
+            "version 3\nguid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n|0|.......A|........||\n";
+        let movie = registry.open(movie.as_bytes()).unwrap();
+        assert_eq!(movie.header.identifier(), 0x452d_e2c3_ef43_2fa9);
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(movie.encode(&mut out).unwrap(), 1);
+    }
+}
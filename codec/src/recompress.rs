@@ -0,0 +1,84 @@
+use crate::{Compression, Encoding, Frame, ReplayDecoder, ReplayEncoder, ReplayError};
+use std::io::{BufRead, Seek, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// Copies `rply` to `out`, decoding each checkpoint to its raw bytes and
+/// encoding it again with `encoding` and `compression`, e.g. to move an
+/// archive from zlib to zstd or raw checkpoints to statestream.  No core is
+/// needed: input events are copied unchanged, and the header keeps its
+/// other settings and metadata.
+///
+/// Returns the number of frames written.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: The replay is version 0, which can only be read by running it
+/// [`ReplayError::MissingKey`]: The replay has encrypted sections
+/// [`ReplayError::Compression`]: `compression`'s feature is disabled
+/// Any other error from decoding `rply` or encoding the copy
+pub fn recompress<R: BufRead + Seek, W: Write + Seek>(
+    rply: &mut ReplayDecoder<R>,
+    compression: Compression,
+    encoding: Encoding,
+    out: &mut W,
+) -> Result<u64> {
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    if rply.header.encrypted_sections().any() {
+        return Err(ReplayError::MissingKey());
+    }
+    rply.seek_to_frame(0)?;
+    let mut header = rply.header.clone();
+    header.set_checkpoint_compression(compression);
+    header.set_initial_state_size(0);
+    let mut enc = ReplayEncoder::new(header, &rply.initial_state, out)?;
+    enc.set_checkpoint_encoding(encoding);
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        enc.write_frame(&frame)?;
+    }
+    enc.finish()?;
+    Ok(enc.frame_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recompresses() {
+        let file = std::fs::File::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../examples/bobl.replay"
+        ))
+        .unwrap();
+        let mut rply = crate::decode(std::io::BufReader::new(file)).unwrap();
+        let frames: Vec<Frame> = rply.frames().map(Result::unwrap).collect();
+        for (compression, encoding) in [
+            #[cfg(feature = "zstd")]
+            (Compression::Zstd, Encoding::Statestream),
+            #[cfg(feature = "zlib")]
+            (Compression::Zlib, Encoding::Raw),
+            (Compression::None, Encoding::Delta),
+        ] {
+            let mut out = std::io::Cursor::new(vec![]);
+            let written = recompress(&mut rply, compression, encoding, &mut out).unwrap();
+            assert_eq!(written, frames.len() as u64);
+            let mut copy = crate::decode(std::io::Cursor::new(out.into_inner())).unwrap();
+            assert_eq!(copy.header.checkpoint_compression(), compression);
+            assert_eq!(copy.header.identifier(), rply.header.identifier());
+            assert_eq!(copy.initial_state, rply.initial_state);
+            for (copied, frame) in copy.frames().zip(&frames) {
+                let copied = copied.unwrap();
+                assert_eq!(copied.key_events, frame.key_events);
+                assert_eq!(copied.input_events, frame.input_events);
+                assert_eq!(copied.checkpoint_bytes, frame.checkpoint_bytes);
+                if !copied.checkpoint_bytes.is_empty() {
+                    assert_eq!(copied.checkpoint_compression, compression);
+                    assert_eq!(copied.checkpoint_encoding, encoding);
+                }
+            }
+        }
+    }
+}
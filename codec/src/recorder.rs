@@ -0,0 +1,393 @@
+use crate::{
+    Core, EncoderEvents, Frame, Header, HeaderFlush, InputData, ReplayEncoder, ReplayError,
+    SizeJump, SyncData, WrittenFrame,
+};
+use retro_rs::Emulator;
+use std::cell::RefCell;
+use std::io::{Seek, Write};
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// What a [`CheckpointPolicy`] knows about the frames recorded since the
+/// last checkpoint (or the start of the replay).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SinceCheckpoint {
+    /// Frames recorded, not counting the one about to be
+    pub frames: u64,
+    /// Bytes of replay written
+    pub bytes: u64,
+    /// Frames whose inputs differed from the frame before
+    pub input_changes: u64,
+    /// How long saving and encoding the last checkpoint took; zero before
+    /// the first
+    pub last_cost: Duration,
+}
+
+/// Decides which frames a [`ReplayRecorder`] checkpoints.
+pub trait CheckpointPolicy {
+    /// Whether to save a checkpoint with the frame about to be recorded,
+    /// the `since.frames + 1`th since the last.
+    fn checkpoint(&mut self, since: &SinceCheckpoint) -> bool;
+}
+
+/// Checkpoints every so many frames, as
+/// [`ReplayRecorder::set_checkpoint_interval`] does; zero disables them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedInterval(pub u64);
+
+impl CheckpointPolicy for FixedInterval {
+    fn checkpoint(&mut self, since: &SinceCheckpoint) -> bool {
+        self.0 > 0 && since.frames + 1 >= self.0
+    }
+}
+
+/// Checkpoints more often while a lot is happening and less often when
+/// checkpoints are expensive: after `max_frames` at the latest, and from
+/// `min_frames` on once `max_bytes` of replay or `max_input_changes`
+/// changed frames have built up since the last, as long as the last
+/// checkpoint's cost is within `cost_share` of the time those frames
+/// took to play at `frame_time` each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveCheckpoints {
+    /// Fewest frames between checkpoints (default 15)
+    pub min_frames: u64,
+    /// Most frames between checkpoints (default 600, ten seconds at 60fps)
+    pub max_frames: u64,
+    /// Replay bytes worth a checkpoint (default 16KiB)
+    pub max_bytes: u64,
+    /// Changed frames worth a checkpoint (default 60)
+    pub max_input_changes: u64,
+    /// Play time of one frame (default 1/60s)
+    pub frame_time: Duration,
+    /// Largest share of play time checkpointing may take (default 5%)
+    pub cost_share: f64,
+}
+
+impl Default for AdaptiveCheckpoints {
+    fn default() -> Self {
+        Self {
+            min_frames: 15,
+            max_frames: 600,
+            max_bytes: 16 << 10,
+            max_input_changes: 60,
+            frame_time: Duration::from_secs(1) / 60,
+            cost_share: 0.05,
+        }
+    }
+}
+
+impl CheckpointPolicy for AdaptiveCheckpoints {
+    fn checkpoint(&mut self, since: &SinceCheckpoint) -> bool {
+        let frames = since.frames + 1;
+        if frames < self.min_frames {
+            return false;
+        }
+        if frames >= self.max_frames {
+            return true;
+        }
+        #[expect(clippy::cast_precision_loss)]
+        let budget = self.frame_time.as_secs_f64() * frames as f64 * self.cost_share;
+        since.last_cost.as_secs_f64() <= budget
+            && (since.bytes >= self.max_bytes || since.input_changes >= self.max_input_changes)
+    }
+}
+
+/// Records a replay while a libretro core runs: every input the core polls
+/// becomes an input event, and the core's state is saved as a checkpoint
+/// every [`ReplayRecorder::checkpoint_interval`] frames, or as a
+/// [`CheckpointPolicy`] set with [`ReplayRecorder::set_checkpoint_policy`]
+/// decides.  The header's
+/// statestream settings (block sizes, commit interval and threshold,
+/// compression) are used as given for encoding those checkpoints.
+pub struct ReplayRecorder<'w, W: Write + Seek> {
+    emu: Emulator,
+    encoder: ReplayEncoder<'w, W>,
+    events: Rc<RefCell<Vec<InputData>>>,
+    checkpoint_interval: u64,
+    policy: Option<Box<dyn CheckpointPolicy + 'w>>,
+    since: SinceCheckpoint,
+    /* Where the last frame ended, for counting bytes since a checkpoint */
+    last_pos: u64,
+    frame: Frame,
+    /* Whether to record when the session ends */
+    session: bool,
+}
+
+/* Seconds since the Unix epoch */
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl<'w, W: Write + Seek> ReplayRecorder<'w, W> {
+    /// Starts recording from the emulator's current state, which becomes
+    /// the replay's initial state.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core could not save its state
+    /// Otherwise as [`ReplayEncoder::new`]
+    pub fn new(emu: Emulator, header: Header, rply: &'w mut W) -> Result<Self> {
+        let mut state = vec![0; emu.save_size()];
+        if !emu.save(&mut state) {
+            return Err(ReplayError::CoreState(0));
+        }
+        let mut encoder = ReplayEncoder::new(header, &state, rply)?;
+        Ok(Self {
+            emu,
+            last_pos: encoder.bytes_written()?,
+            encoder,
+            events: Rc::new(RefCell::new(vec![])),
+            checkpoint_interval: 60,
+            policy: None,
+            since: SinceCheckpoint::default(),
+            frame: Frame::default(),
+            session: false,
+        })
+    }
+    /// Starts recording like [`ReplayRecorder::new`], storing `regions` of
+    /// the core's savestate (e.g. where each memory map entry is
+    /// serialized) in the header's metadata so that checkpoint blocks are
+    /// aligned to them.  See [`crate::Metadata::state_regions`].
+    /// # Errors
+    /// As [`ReplayRecorder::new`]
+    pub fn with_state_regions(
+        emu: Emulator,
+        mut header: Header,
+        regions: &[Range<usize>],
+        rply: &'w mut W,
+    ) -> Result<Self> {
+        header.metadata_mut().set_state_regions(regions);
+        Self::new(emu, header, rply)
+    }
+    /// Starts recording like [`ReplayRecorder::new`], and also records the
+    /// session's wall-clock span and, if given, the machine's locale and
+    /// timezone in the header's metadata, for verifiers who cross-check a
+    /// run against when and where it was claimed.  Nothing about the
+    /// session is recorded otherwise, and [`crate::Metadata::anonymize`]
+    /// removes it again.  The span ends when the recorder is finished.
+    /// # Errors
+    /// As [`ReplayRecorder::new`]
+    pub fn with_session(
+        emu: Emulator,
+        mut header: Header,
+        locale: Option<&str>,
+        timezone: Option<&str>,
+        rply: &'w mut W,
+    ) -> Result<Self> {
+        let metadata = header.metadata_mut();
+        let start = now();
+        metadata.set_session(start..start);
+        if let Some(locale) = locale {
+            metadata.set_locale(locale);
+        }
+        if let Some(timezone) = timezone {
+            metadata.set_timezone(timezone);
+        }
+        let mut recorder = Self::new(emu, header, rply)?;
+        recorder.session = true;
+        Ok(recorder)
+    }
+    /// Frames between checkpoints (default 60, one per second at 60fps);
+    /// zero disables checkpoints.  Unused once a policy is set.
+    #[must_use]
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.checkpoint_interval
+    }
+    /// Sets the checkpoint interval, replacing any policy.
+    pub fn set_checkpoint_interval(&mut self, frames: u64) {
+        self.checkpoint_interval = frames;
+        self.policy = None;
+    }
+    /// Decides which frames to checkpoint with `policy` instead of a fixed
+    /// interval, e.g. [`AdaptiveCheckpoints`].
+    pub fn set_checkpoint_policy(&mut self, policy: impl CheckpointPolicy + 'w) {
+        self.policy = Some(Box::new(policy));
+    }
+    #[must_use]
+    pub fn emulator(&self) -> &Emulator {
+        &self.emu
+    }
+    /// Changing the emulator's state other than by running frames will
+    /// desync the replay.
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emu
+    }
+    #[must_use]
+    pub fn encoder(&self) -> &ReplayEncoder<'w, W> {
+        &self.encoder
+    }
+    /// Calls `alert` when a checkpoint comes out much bigger than recent
+    /// ones; see [`ReplayEncoder::set_size_alert`].
+    pub fn set_size_alert(&mut self, threshold: f64, alert: impl FnMut(&SizeJump) + 'w) {
+        self.encoder.set_size_alert(threshold, alert);
+    }
+    /// Tells `events` about each frame and checkpoint recorded; see
+    /// [`ReplayEncoder::set_events`].
+    pub fn set_events(&mut self, events: impl EncoderEvents + 'w) {
+        self.encoder.set_events(events);
+    }
+    /// Calls `hook` with each frame recorded; see
+    /// [`ReplayEncoder::set_frame_hook`].
+    pub fn set_frame_hook(&mut self, hook: impl FnMut(&WrittenFrame) + 'w) {
+        self.encoder.set_frame_hook(hook);
+    }
+    /// Rewrites the header and syncs the replay as often as `every` says,
+    /// so a crash loses at most the frames since; see
+    /// [`ReplayEncoder::set_header_flush`].
+    pub fn set_header_flush(&mut self, every: Option<HeaderFlush>)
+    where
+        W: SyncData,
+    {
+        self.encoder.set_header_flush(every);
+    }
+    /// Frames recorded so far
+    #[must_use]
+    pub fn frame_number(&self) -> u64 {
+        self.encoder.frame_number
+    }
+    /// Runs one frame of emulation, calling `input(port, device, index, id)`
+    /// for each input the core polls, and records the frame.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core could not save a checkpoint
+    /// Otherwise as [`ReplayEncoder::write_frame`]
+    pub fn run_frame(
+        &mut self,
+        mut input: impl FnMut(u32, u32, u32, u32) -> i16 + 'static,
+    ) -> Result<()> {
+        self.events.borrow_mut().clear();
+        let events = Rc::clone(&self.events);
+        self.emu
+            .run_with_button_callback(Box::new(move |port, device, idx, id| {
+                let val = input(port, device, idx, id);
+                // libretro only uses small ports, devices and indices; don't
+                // record polls the format can't represent
+                if let (Ok(port), Ok(device), Ok(idx), Ok(id)) = (
+                    u8::try_from(port),
+                    u8::try_from(device),
+                    u8::try_from(idx),
+                    u16::try_from(id),
+                ) {
+                    events.borrow_mut().push(InputData {
+                        port,
+                        device,
+                        idx,
+                        id,
+                        val,
+                    });
+                }
+                val
+            }));
+        let changed = self.frame.input_events != *self.events.borrow();
+        self.frame.clear();
+        self.frame
+            .input_events
+            .append(&mut self.events.borrow_mut());
+        let frame_number = self.encoder.frame_number + 1;
+        let checkpoint = match &mut self.policy {
+            Some(policy) => policy.checkpoint(&self.since),
+            None => {
+                self.checkpoint_interval > 0
+                    && frame_number.is_multiple_of(self.checkpoint_interval)
+            }
+        };
+        let started = Instant::now();
+        if checkpoint {
+            let state = &mut self.frame.checkpoint_bytes;
+            state.resize(self.emu.save_size(), 0);
+            if !self.emu.save(state) {
+                return Err(ReplayError::CoreState(frame_number));
+            }
+        }
+        self.encoder.write_frame(&self.frame)?;
+        let pos = self.encoder.bytes_written()?;
+        if checkpoint {
+            self.since = SinceCheckpoint {
+                last_cost: started.elapsed(),
+                ..SinceCheckpoint::default()
+            };
+        } else {
+            self.since.frames += 1;
+            self.since.bytes += pos - self.last_pos;
+            self.since.input_changes += u64::from(changed);
+        }
+        self.last_pos = pos;
+        Ok(())
+    }
+    /// Finishes the replay and hands back the emulator.
+    /// # Errors
+    /// As [`ReplayEncoder::finish`]
+    pub fn finish(mut self) -> Result<Emulator> {
+        if self.session {
+            let end = now();
+            self.encoder.update_metadata(|metadata| {
+                let start = metadata.session().map_or(end, |session| session.start);
+                metadata.set_session(start..end);
+            })?;
+        }
+        self.encoder.finish()?;
+        Ok(self.emu)
+    }
+}
+
+/// Plays back each frame's input events exactly as recorded: every input
+/// the core polls gets the value of the matching event, or zero.
+impl Core for Emulator {
+    fn run_frame(&mut self, frame: &Frame) {
+        let inputs: Vec<_> = frame
+            .input_events
+            .iter()
+            .map(|e| {
+                (
+                    (
+                        u32::from(e.port),
+                        u32::from(e.device),
+                        u32::from(e.idx),
+                        u32::from(e.id),
+                    ),
+                    e.val,
+                )
+            })
+            .collect();
+        self.run_with_button_callback(Box::new(move |port, device, idx, id| {
+            inputs
+                .iter()
+                .find(|(key, _)| *key == (port, device, idx, id))
+                .map_or(0, |(_, val)| *val)
+        }));
+    }
+    fn save_state(&mut self, state: &mut Vec<u8>) -> bool {
+        state.resize(self.save_size(), 0);
+        self.save(state)
+    }
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        self.load(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adaptive_checkpoints() {
+        let mut policy = AdaptiveCheckpoints::default();
+        let since = |frames, bytes, input_changes, cost_ms| SinceCheckpoint {
+            frames,
+            bytes,
+            input_changes,
+            last_cost: Duration::from_millis(cost_ms),
+        };
+        assert!(!policy.checkpoint(&since(100, 1000, 10, 1)));
+        assert!(policy.checkpoint(&since(599, 0, 0, 10_000)));
+        assert!(policy.checkpoint(&since(100, 1000, 60, 1)));
+        assert!(!policy.checkpoint(&since(10, 1000, 60, 1)));
+        // 200ms is more than 5% of 101 frames at 60fps, but not of 301
+        assert!(!policy.checkpoint(&since(100, 1 << 20, 0, 200)));
+        assert!(policy.checkpoint(&since(300, 1 << 20, 0, 200)));
+        assert!(!FixedInterval(0).checkpoint(&since(1000, 0, 0, 0)));
+        assert!(FixedInterval(60).checkpoint(&since(59, 0, 0, 0)));
+    }
+}
@@ -0,0 +1,300 @@
+//! [`Encoding::Regions`] checkpoints split the state along the header's
+//! state regions and encode and compress each group of regions that share
+//! settings separately.  The payload is a table of groups, each followed by
+//! its compressed bytes:
+//!
+//! ```text
+//! u8 group count
+//! per group:
+//!   u8 encoding, u8 compression
+//!   u32 region count, then per region u32 offset and u32 length
+//!   u32 compressed size, then the compressed, encoded group
+//! ```
+//!
+//! A group's bytes are its regions concatenated; for statestream groups
+//! each region is padded to a whole number of blocks so that its blocks
+//! line up however it sits in the state.  Integers are little-endian.
+use crate::{
+    Compression, Encoding,
+    checkpoint::{CheckpointCodec, CheckpointContext},
+    compression::Compressors,
+    delta, statestream,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+use std::ops::Range;
+
+/* A region's encoding and compression */
+type Settings = (Encoding, Compression);
+
+pub(crate) struct RegionsCodec {
+    /* Groups get their own statestream tables, apart from whole-state checkpoints' */
+    pub(crate) ctx: statestream::Ctx,
+    pub(crate) compressors: Compressors,
+    regions: Vec<Range<usize>>,
+    encodings: Vec<Settings>,
+    /* Settings for bytes outside the regions and regions without their own */
+    pub(crate) default: Settings,
+}
+
+/* Bytes of `state` in `ranges`, each padded with zeroes to a multiple of `pad_to` */
+fn gather(state: &[u8], ranges: &[Range<usize>], pad_to: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    for range in ranges {
+        let start = bytes.len();
+        bytes.extend_from_slice(state.get(range.clone()).unwrap_or_default());
+        bytes.resize(start + range.len().next_multiple_of(pad_to), 0);
+    }
+    bytes
+}
+
+/* Undoes `gather`, copying `bytes` back into `ranges` of `state` */
+fn scatter(bytes: &[u8], ranges: &[Range<usize>], pad_to: usize, state: &mut [u8]) {
+    let mut from = 0;
+    for range in ranges {
+        state[range.clone()].copy_from_slice(&bytes[from..from + range.len()]);
+        from += range.len().next_multiple_of(pad_to);
+    }
+}
+
+fn invalid(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn to_u32(n: usize) -> std::io::Result<u32> {
+    u32::try_from(n).map_err(|e| std::io::Error::other(crate::ReplayError::CheckpointTooBig(e)))
+}
+
+impl RegionsCodec {
+    pub(crate) fn new(block_size: u32, superblock_size: u32) -> Self {
+        Self {
+            ctx: statestream::Ctx::new(block_size, superblock_size),
+            compressors: Compressors::default(),
+            regions: vec![],
+            encodings: vec![],
+            default: (Encoding::Statestream, Compression::None),
+        }
+    }
+    pub(crate) fn set_regions(&mut self, regions: &[Range<usize>]) {
+        self.regions = regions.to_vec();
+    }
+    pub(crate) fn set_encodings(&mut self, encodings: Vec<Settings>) {
+        self.encodings = encodings;
+    }
+    /* Splits a state of `len` bytes into groups of ranges sharing settings */
+    fn groups(&self, len: usize) -> Vec<(Settings, Vec<Range<usize>>)> {
+        let mut segments: Vec<_> = self
+            .regions
+            .iter()
+            .enumerate()
+            .map(|(i, region)| {
+                let settings = self.encodings.get(i).copied().unwrap_or(self.default);
+                (region.start.min(len)..region.end.min(len), settings)
+            })
+            .collect();
+        segments.sort_by_key(|(region, _)| region.start);
+        let mut groups: Vec<(Settings, Vec<Range<usize>>)> = vec![];
+        let mut add = |range: Range<usize>, settings| {
+            if range.is_empty() {
+                return;
+            }
+            match groups.iter_mut().find(|(s, _)| *s == settings) {
+                Some((_, ranges)) => ranges.push(range),
+                None => groups.push((settings, vec![range])),
+            }
+        };
+        let mut covered = 0;
+        for (region, settings) in segments {
+            // Overlapping regions keep the bytes in the first of them
+            add(covered..region.start.max(covered), self.default);
+            add(region.start.max(covered)..region.end.max(covered), settings);
+            covered = covered.max(region.end);
+        }
+        add(covered..len, self.default);
+        groups
+    }
+    fn padding(&self, encoding: Encoding) -> usize {
+        if encoding == Encoding::Statestream {
+            self.ctx.block_size() as usize
+        } else {
+            1
+        }
+    }
+}
+
+impl CheckpointCodec for RegionsCodec {
+    fn encode(
+        &mut self,
+        writer: &mut dyn Write,
+        checkpoint: &[u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<u32> {
+        let groups = self.groups(checkpoint.len());
+        let mut written = 1;
+        writer
+            .write_u8(u8::try_from(groups.len()).map_err(|_| invalid("too many region groups"))?)?;
+        for ((encoding, compression), ranges) in groups {
+            let pad_to = self.padding(encoding);
+            let bytes = gather(checkpoint, &ranges, pad_to);
+            let compressor = self
+                .compressors
+                .get(compression)
+                .ok_or_else(|| invalid("compression not available for regions"))?;
+            let mut compressed = vec![];
+            let mut compressing = compressor.compress(&mut compressed)?;
+            match encoding {
+                Encoding::Raw => compressing.write_all(&bytes)?,
+                Encoding::Statestream => {
+                    statestream::Encoder::new(&mut compressing, &mut self.ctx)
+                        .encode_checkpoint(&bytes, cx.frame)?;
+                }
+                Encoding::Delta => {
+                    delta::encode(&mut compressing, &gather(cx.previous, &ranges, 1), &bytes)?;
+                }
+                _ => return Err(invalid("encoding not available for regions")),
+            }
+            compressing.finish()?;
+            writer.write_u8(u8::from(encoding))?;
+            writer.write_u8(u8::from(compression))?;
+            writer.write_u32::<LittleEndian>(to_u32(ranges.len())?)?;
+            for range in &ranges {
+                writer.write_u32::<LittleEndian>(to_u32(range.start)?)?;
+                writer.write_u32::<LittleEndian>(to_u32(range.len())?)?;
+            }
+            writer.write_u32::<LittleEndian>(to_u32(compressed.len())?)?;
+            writer.write_all(&compressed)?;
+            written += 10 + 8 * ranges.len() + compressed.len();
+        }
+        to_u32(written)
+    }
+    fn decode(
+        &mut self,
+        reader: &mut dyn Read,
+        checkpoint: &mut [u8],
+        cx: &CheckpointContext,
+    ) -> std::io::Result<()> {
+        for _ in 0..reader.read_u8()? {
+            let encoding = Encoding::try_from(reader.read_u8()?)
+                .map_err(|_| invalid("bad region encoding"))?;
+            let compression = Compression::try_from(reader.read_u8()?)
+                .map_err(|_| invalid("bad region compression"))?;
+            let mut ranges = vec![];
+            for _ in 0..reader.read_u32::<LittleEndian>()? {
+                let start = reader.read_u32::<LittleEndian>()? as usize;
+                let range = start..start + reader.read_u32::<LittleEndian>()? as usize;
+                if range.end > checkpoint.len() {
+                    return Err(invalid("region past the end of the checkpoint"));
+                }
+                ranges.push(range);
+            }
+            let compressed_size = reader.read_u32::<LittleEndian>()?;
+            let compressor = self
+                .compressors
+                .get(compression)
+                .ok_or_else(|| invalid("compression not available for regions"))?;
+            /* Decompressed as it's read, rather than buffered at whatever
+            size the group claims */
+            let mut compressed =
+                std::io::BufReader::new((&mut *reader).take(u64::from(compressed_size)));
+            let mut decompressed = compressor.decompress(&mut compressed)?;
+            let pad_to = self.padding(encoding);
+            let mut bytes = vec![
+                0;
+                ranges
+                    .iter()
+                    .map(|r| r.len().next_multiple_of(pad_to))
+                    .sum()
+            ];
+            match encoding {
+                Encoding::Raw => decompressed.read_exact(&mut bytes)?,
+                Encoding::Statestream => {
+                    let mut ss_decoder =
+                        statestream::Decoder::new(&mut decompressed, &mut self.ctx, bytes.len());
+                    std::io::copy(&mut ss_decoder, &mut std::io::Cursor::new(&mut bytes[..]))?;
+                }
+                Encoding::Delta => {
+                    delta::decode(
+                        &mut decompressed,
+                        &gather(cx.previous, &ranges, 1),
+                        &mut bytes,
+                    )?;
+                }
+                _ => return Err(invalid("encoding not available for regions")),
+            }
+            drop(decompressed);
+            // Up to the next group, past anything the decompressor left
+            std::io::copy(&mut compressed, &mut std::io::sink())?;
+            if compressed.into_inner().limit() > 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            scatter(&bytes, &ranges, pad_to, checkpoint);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_groups() {
+        let mut codec = RegionsCodec::new(16, 4);
+        codec.set_regions(&[40..60, 0..8, 50..70, 100..200]);
+        let raw = (Encoding::Raw, Compression::None);
+        codec.set_encodings(vec![raw, raw]);
+        let ss = codec.default;
+        assert_eq!(
+            codec.groups(150),
+            [
+                (raw, vec![0..8, 40..60]),
+                (ss, vec![8..40, 60..70, 70..100, 100..150])
+            ]
+        );
+        let state: Vec<u8> = (0..150_u8).collect();
+        for (_, ranges) in codec.groups(150) {
+            let mut out = vec![0; 150];
+            scatter(&gather(&state, &ranges, 16), &ranges, 16, &mut out);
+            for range in ranges {
+                assert_eq!(out[range.clone()], state[range]);
+            }
+        }
+    }
+
+    #[test]
+    fn group_sizes() {
+        let mut codec = RegionsCodec::new(16, 4);
+        let cx = CheckpointContext {
+            frame: 1,
+            previous: &[],
+        };
+        // One raw, uncompressed group of bytes 0..4
+        let group = |size: u32| {
+            let mut payload = vec![1, u8::from(Encoding::Raw), u8::from(Compression::None)];
+            for n in [1, 0, 4, size] {
+                payload.extend_from_slice(&u32::to_le_bytes(n));
+            }
+            payload.extend_from_slice(&[1, 2, 3, 4]);
+            payload
+        };
+        let mut state = vec![0; 4];
+        codec
+            .decode(&mut group(4).as_slice(), &mut state, &cx)
+            .unwrap();
+        assert_eq!(state, [1, 2, 3, 4]);
+        // A group claiming far more than is there isn't buffered first
+        let err = codec
+            .decode(&mut group(u32::MAX).as_slice(), &mut state, &cx)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        // Nor does a group's decoding run into the next
+        let mut payload = group(6);
+        payload[0] = 2;
+        payload.extend_from_slice(&group(4)[1..]);
+        assert!(
+            codec
+                .decode(&mut payload.as_slice(), &mut state, &cx)
+                .is_err()
+        );
+    }
+}
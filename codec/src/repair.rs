@@ -0,0 +1,125 @@
+use crate::{Frame, ReplayError, decode};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/* Where version 2 and later headers store the frame count */
+const FRAME_COUNT_OFFSET: u64 = 24;
+
+/// What [`repair`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repair {
+    /// Complete frames in the replay, now also its header's frame count
+    pub frames: u64,
+    /// Where the last complete frame ends; the replay should be cut here
+    pub length: u64,
+    /// Bytes after `length`, from a frame that was only partly written
+    pub cut: u64,
+}
+
+/// Makes a replay whose recording stopped mid-frame (say, because the
+/// emulator crashed) readable again: finds the end of its last complete
+/// frame and writes the number of complete frames to the header, which
+/// the encoder only does when it finishes.  Checkpoints aren't decoded,
+/// so a frame counts as complete once all its bytes are there.
+///
+/// The caller must then cut the replay to [`Repair::length`];
+/// [`repair_file`] does both.  Version 1 replays have no frame count, so
+/// they only need cutting.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: The replay is version 0, which can only be read by running it
+/// [`ReplayError::TooManyFrames`]: The frame count doesn't fit in the header
+/// Any error from decoding the header and initial state, which must be
+/// complete, or from reading a frame other than it being cut off; see
+/// [`crate::ReplayDecoder::read_frame_recovering`] for replays damaged
+/// elsewhere
+pub fn repair<F: Read + Write + Seek>(file: &mut F) -> Result<Repair> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut rply = decode(BufReader::new(&mut *file))?;
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    let has_frame_count = rply.header.capabilities().has_frame_count;
+    // The header's frame count is what's being repaired
+    rply.set_live(true);
+    let mut frame = Frame::default();
+    let mut length = rply.position();
+    while !rply.at_end()? {
+        match rply.read_frame_skipping_checkpoints(&mut frame) {
+            Ok(_) => {}
+            Err(e)
+                if matches!(e.root(), ReplayError::IO(e)
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof) =>
+            {
+                break;
+            }
+            // Cutting here would lose the frames after the damage
+            Err(e) => return Err(e),
+        }
+        length = rply.position();
+    }
+    let frames = rply.frame_number;
+    drop(rply);
+    if has_frame_count {
+        let count = u32::try_from(frames).map_err(ReplayError::TooManyFrames)?;
+        file.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        file.write_all(&count.to_le_bytes())?;
+        file.flush()?;
+    }
+    Ok(Repair {
+        frames,
+        length,
+        cut: len - length,
+    })
+}
+
+/// Repairs the replay at `path` in place, as [`repair`], and cuts it to
+/// its last complete frame.
+/// # Errors
+/// As [`repair`]
+pub fn repair_file(path: impl AsRef<Path>) -> Result<Repair> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let repair = repair(&mut file)?;
+    file.set_len(repair.length)?;
+    Ok(repair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn repair_truncated() {
+        let bytes = replay(None);
+        let offsets = crate::decode(std::io::Cursor::new(bytes.clone()))
+            .unwrap()
+            .build_seek_index()
+            .unwrap()
+            .frame_offsets;
+        // Cut in the middle of the events of frame 501 and of the checkpoint of frame 520
+        for (cut_at, frames) in [(offsets[500] + 9, 500), (offsets[520] - 30, 519)] {
+            let mut file = std::io::Cursor::new(bytes[..usize::try_from(cut_at).unwrap()].to_vec());
+            let repaired = repair(&mut file).unwrap();
+            assert_eq!(
+                (repaired.frames, repaired.length),
+                (frames, offsets[frames as usize])
+            );
+            assert_eq!(repaired.cut, cut_at - repaired.length);
+            let mut file = file.into_inner();
+            file.truncate(usize::try_from(repaired.length).unwrap());
+            let mut rply = crate::decode(file.as_slice()).unwrap();
+            assert_eq!(rply.header.frame_count(), Some(frames));
+            assert_eq!(rply.frames().map(Result::unwrap).count() as u64, frames);
+        }
+        // A complete replay is left as it was
+        let mut file = std::io::Cursor::new(bytes.clone());
+        assert_eq!(repair(&mut file).unwrap().cut, 0);
+        assert_eq!(file.into_inner(), bytes);
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::Write;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum BlockMatch {
+    /// Block was byte-identical to the same position in the previous checkpoint
+    Skipped,
+    /// Block was found in the block index by hash
+    Reused,
+    /// Block was added to the block index
+    New,
+}
+
+impl BlockMatch {
+    fn name(self) -> &'static str {
+        match self {
+            BlockMatch::Skipped => "skip",
+            BlockMatch::Reused => "reuse",
+            BlockMatch::New => "new",
+        }
+    }
+}
+
+/* Reuse distances are bucketed by powers of two: bucket 0 is distance 0,
+ * bucket n holds distances in [2^(n-1), 2^n) */
+const HISTOGRAM_BUCKETS: usize = 65;
+
+/// Statestream instrumentation sink.  Attach one to a
+/// [`crate::ReplayEncoder`] with `set_research_log` to get one CSV row per
+/// encoded block (frame, block position, block index, match kind, entropy)
+/// followed by a reuse distance histogram and match totals when the encoder
+/// finishes.  Only available with the `research` feature.
+pub struct ResearchLog {
+    out: Box<dyn Write + Send>,
+    checkpoints: u64,
+    last_use: Vec<u64>,
+    reuse_histogram: [u64; HISTOGRAM_BUCKETS],
+    skipped: u64,
+    reused: u64,
+    new: u64,
+    superblocks_reused: u64,
+    superblocks_new: u64,
+    entropy_sum: f64,
+    wrote_header: bool,
+}
+
+impl ResearchLog {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Box::new(out),
+            checkpoints: 0,
+            last_use: vec![],
+            reuse_histogram: [0; HISTOGRAM_BUCKETS],
+            skipped: 0,
+            reused: 0,
+            new: 0,
+            superblocks_reused: 0,
+            superblocks_new: 0,
+            entropy_sum: 0.0,
+            wrote_header: false,
+        }
+    }
+    /// Creates a log writing to a new file at the given path.
+    ///
+    /// # Errors
+    /// Any error from creating the file.
+    pub fn create(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        Ok(Self::new(std::io::BufWriter::new(std::fs::File::create(
+            path,
+        )?)))
+    }
+    pub(crate) fn block(
+        &mut self,
+        frame: u64,
+        position: usize,
+        index: u32,
+        bytes: &[u8],
+        kind: BlockMatch,
+    ) -> std::io::Result<()> {
+        if !self.wrote_header {
+            writeln!(self.out, "frame,block,index,match,entropy")?;
+            self.wrote_header = true;
+        }
+        let idx = index as usize;
+        if self.last_use.len() <= idx {
+            self.last_use.resize(idx + 1, u64::MAX);
+        }
+        match kind {
+            BlockMatch::Skipped => self.skipped += 1,
+            BlockMatch::Reused => self.reused += 1,
+            BlockMatch::New => self.new += 1,
+        }
+        if kind != BlockMatch::New && self.last_use[idx] != u64::MAX {
+            let distance = self.checkpoints - self.last_use[idx];
+            self.reuse_histogram[(u64::BITS - distance.leading_zeros()) as usize] += 1;
+        }
+        self.last_use[idx] = self.checkpoints;
+        let entropy = entropy(bytes);
+        self.entropy_sum += entropy;
+        writeln!(
+            self.out,
+            "{frame},{position},{index},{},{entropy:.4}",
+            kind.name()
+        )
+    }
+    pub(crate) fn superblock(&mut self, is_new: bool) {
+        if is_new {
+            self.superblocks_new += 1;
+        } else {
+            self.superblocks_reused += 1;
+        }
+    }
+    pub(crate) fn end_checkpoint(&mut self) {
+        self.checkpoints += 1;
+    }
+    /// Writes the summary section and flushes the output.
+    pub(crate) fn finish(&mut self) -> std::io::Result<()> {
+        let blocks = self.skipped + self.reused + self.new;
+        writeln!(self.out)?;
+        writeln!(self.out, "# checkpoints,{}", self.checkpoints)?;
+        writeln!(self.out, "# blocks,{blocks}")?;
+        writeln!(self.out, "# skipped,{}", self.skipped)?;
+        writeln!(self.out, "# reused,{}", self.reused)?;
+        writeln!(self.out, "# new,{}", self.new)?;
+        writeln!(self.out, "# superblocks_reused,{}", self.superblocks_reused)?;
+        writeln!(self.out, "# superblocks_new,{}", self.superblocks_new)?;
+        #[allow(clippy::cast_precision_loss)]
+        let mean_entropy = if blocks == 0 {
+            0.0
+        } else {
+            self.entropy_sum / blocks as f64
+        };
+        writeln!(self.out, "# mean_entropy,{mean_entropy:.4}")?;
+        writeln!(self.out, "# reuse_distance_lt,count")?;
+        for (bucket, count) in self.reuse_histogram.iter().enumerate() {
+            if *count > 0 {
+                let upper: u128 = 1 << bucket;
+                writeln!(self.out, "# {upper},{count}")?;
+            }
+        }
+        self.out.flush()
+    }
+}
+
+/// Shannon entropy of the bytes, in bits per byte
+#[allow(clippy::cast_precision_loss)]
+fn entropy(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0_u32; 256];
+    for b in bytes {
+        counts[*b as usize] += 1;
+    }
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = f64::from(*c) / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn entropy_bounds() {
+        assert!(entropy(&[0; 64]).abs() < f64::EPSILON);
+        let all: Vec<u8> = (0..=255).collect();
+        assert!((entropy(&all) - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn logs_reencode() {
+        let mut file = std::io::BufReader::new(
+            std::fs::File::open(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../examples/bobl.replay"
+            ))
+            .unwrap(),
+        );
+        let mut rply = crate::decode(&mut file).unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+        let log = Shared::default();
+        enc.set_research_log(ResearchLog::new(log.clone()));
+        let mut frame = crate::Frame::default();
+        for _ in 0..600 {
+            rply.read_frame(&mut frame).unwrap();
+            enc.write_frame(&frame).unwrap();
+        }
+        enc.finish().unwrap();
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(text.starts_with("frame,block,index,match,entropy\n"));
+        assert!(text.contains("# reuse_distance_lt,count"));
+        assert!(text.lines().any(|l| l.contains(",skip,")));
+    }
+}
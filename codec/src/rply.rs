@@ -1,10 +1,16 @@
-use std::io::Write;
-
 use crate::{
     InvalidDeterminant,
-    clock::{self, Timer},
-    statestream,
+    checkpoint::{CheckpointCodec, CheckpointContext, CodecRegistry, Codecs},
+    clock::{Stats, Timer},
+    compression::{CompressionOptions, Compressor, Compressors},
+    counting::CountingReader,
+    encryption::{Cipher, EncryptedSections, SALT_LEN, Section},
+    metadata::Metadata,
+    seekindex::SeekIndex,
+    statestream::BlockHash,
+    trend::{CheckpointSizes, SizeJump, SizeTrend},
 };
+use std::io::Read;
 use thiserror::Error;
 
 // #[repr(usize)]
@@ -24,8 +30,8 @@ use thiserror::Error;
 //     CheckpointConfig = 36,
 //     HeaderLen = 40,
 // }
-// const HEADER_V0V1_LEN_BYTES: usize = HeaderV0V1Part::HeaderLen as usize;
-const HEADERV2_LEN_BYTES: usize = 40;
+const HEADERV1_LEN_BYTES: usize = 24;
+pub(crate) const HEADERV2_LEN_BYTES: usize = 40;
 
 // const VERSION: u32 = 2;
 const MAGIC: u32 = 0x4253_5632;
@@ -60,13 +66,19 @@ impl From<FrameToken> for u8 {
     }
 }
 
-#[repr(u8)]
+/// Checkpoint compression scheme.  Built-in schemes other than `None` are
+/// only usable when the crate feature of the same name is enabled.
 #[non_exhaustive]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Compression {
-    None = 0,
-    Zlib = 1,
-    Zstd = 2,
+    None,
+    Zlib,
+    Zstd,
+    Lz4,
+    Brotli,
+    /// A scheme provided by a registered [`crate::Compressor`]
+    Custom(u8),
 }
 
 impl TryFrom<u8> for Compression {
@@ -77,6 +89,9 @@ impl TryFrom<u8> for Compression {
             0 => Ok(Compression::None),
             1 => Ok(Compression::Zlib),
             2 => Ok(Compression::Zstd),
+            3 => Ok(Compression::Lz4),
+            4 => Ok(Compression::Brotli),
+            CodecRegistry::FIRST_CUSTOM_ID.. => Ok(Compression::Custom(value)),
             _ => Err(InvalidDeterminant(value)),
         }
     }
@@ -88,16 +103,26 @@ impl From<Compression> for u8 {
             Compression::None => 0,
             Compression::Zlib => 1,
             Compression::Zstd => 2,
+            Compression::Lz4 => 3,
+            Compression::Brotli => 4,
+            Compression::Custom(id) => id,
         }
     }
 }
 
-#[repr(u8)]
 #[non_exhaustive]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Encoding {
-    Raw = 0,
-    Statestream = 1,
+    Raw,
+    Statestream,
+    /// Binary diff against the previous checkpoint
+    Delta,
+    /// Each of the header's state regions encoded and compressed with its
+    /// own settings; see [`ReplayEncoder::set_region_encodings`]
+    Regions,
+    /// An encoding provided by a registered [`crate::CheckpointCodec`]
+    Custom(u8),
 }
 
 impl TryFrom<u8> for Encoding {
@@ -107,6 +132,9 @@ impl TryFrom<u8> for Encoding {
         match value {
             0 => Ok(Encoding::Raw),
             1 => Ok(Encoding::Statestream),
+            2 => Ok(Encoding::Delta),
+            3 => Ok(Encoding::Regions),
+            CodecRegistry::FIRST_CUSTOM_ID.. => Ok(Encoding::Custom(value)),
             _ => Err(InvalidDeterminant(value)),
         }
     }
@@ -117,11 +145,114 @@ impl From<Encoding> for u8 {
         match value {
             Encoding::Raw => 0,
             Encoding::Statestream => 1,
+            Encoding::Delta => 2,
+            Encoding::Regions => 3,
+            Encoding::Custom(id) => id,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// What size decoded checkpoints should have.  Each checkpoint records its
+/// own size, which changes when a core's serialized state grows or shrinks
+/// between versions; a core that only loads states of its current size can
+/// get them padded or truncated to that size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StateSize {
+    /// Checkpoints have the size they were recorded with
+    #[default]
+    AsRecorded,
+    /// Checkpoints are zero-padded or truncated to this many bytes
+    Fixed(usize),
+}
+
+/// Caps on what a [`ReplayDecoder`] allocates, for replays from untrusted
+/// sources: their headers and checkpoint records could otherwise claim
+/// gigabytes before any of it is read.  There are no limits by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Largest initial state or decoded checkpoint, and largest statestream
+    /// block or superblock; frame packs may hold this plus 128 KiB of frames
+    pub max_state_bytes: usize,
+    /// Largest version 3 metadata block
+    pub max_metadata_bytes: usize,
+    /// Most key events in one frame
+    pub max_key_events: usize,
+    /// Most input events in one frame
+    pub max_input_events: usize,
+    /// Most blocks plus superblocks a statestream decoder will index; each
+    /// set of tables (whole states, and states split into regions) counts
+    /// on its own
+    pub max_index_entries: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_state_bytes: usize::MAX,
+            max_metadata_bytes: usize::MAX,
+            max_key_events: usize::MAX,
+            max_input_events: usize::MAX,
+            max_index_entries: usize::MAX,
+        }
+    }
+}
+
+impl ReplayError {
+    /// The error without the [`ReplayError::AtFrame`] context around it.
+    #[must_use]
+    pub fn root(&self) -> &Self {
+        match self {
+            Self::AtFrame(_, _, e) => e.root(),
+            e => e,
+        }
+    }
+    /// As [`ReplayError::root`], by value.
+    #[must_use]
+    pub fn into_root(self) -> Self {
+        match self {
+            Self::AtFrame(_, _, e) => e.into_root(),
+            e => e,
+        }
+    }
+    /* Recovers a replay error that a codec, or the frame packs under the
+    decoder, passed up as an I/O error */
+    pub(crate) fn from_io(e: std::io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            *e.into_inner().unwrap().downcast::<Self>().unwrap()
+        } else {
+            Self::IO(e)
+        }
+    }
+}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(e: std::io::Error) -> Self {
+        Self::from_io(e)
+    }
+}
+
+impl DecodeLimits {
+    /* Fails if `size` bytes of `what` are more than `max` */
+    fn check(max: usize, what: &'static str, size: u64) -> Result<()> {
+        if usize::try_from(size).is_ok_and(|size| size <= max) {
+            Ok(())
+        } else {
+            Err(ReplayError::OverLimit(what, size))
+        }
+    }
+}
+
+impl StateSize {
+    /// Pads or truncates `state` according to this policy.
+    pub fn fit(self, state: &mut Vec<u8>) {
+        if let StateSize::Fixed(size) = self {
+            state.resize(size, 0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HeaderBase {
     pub version: u32,
     pub content_crc: u32,
@@ -129,7 +260,8 @@ pub struct HeaderBase {
     pub identifier: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HeaderV2 {
     pub base: HeaderBase,
     pub frame_count: u32,
@@ -138,9 +270,13 @@ pub struct HeaderV2 {
     pub checkpoint_commit_interval: u8,
     pub checkpoint_commit_threshold: u8,
     pub checkpoint_compression: Compression,
+    pub encrypted_sections: EncryptedSections,
+    /// Written only in version 3 replays; the encoder writes version 3 exactly when this is non-empty
+    pub metadata: Metadata,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Header {
     V0V1(HeaderBase),
     V2(HeaderV2),
@@ -156,8 +292,8 @@ pub enum ReplayError {
     Compression(InvalidDeterminant),
     #[error("Unsupported encoding scheme {0}")]
     Encoding(InvalidDeterminant),
-    #[error("I/O Error")]
-    IO(#[from] std::io::Error),
+    #[error("I/O error: {0}")]
+    IO(std::io::Error),
     #[error("Too many frames to {0} fit framecount header")]
     TooManyFrames(std::num::TryFromIntError),
     #[error("Coreless frame read for version 0 not possible")]
@@ -172,16 +308,92 @@ pub enum ReplayError {
     TooManyInputEvents(std::num::TryFromIntError),
     #[error("Invalid frame token {0}")]
     BadFrameToken(u8),
+    #[error("Unsupported encrypted sections {0}")]
+    EncryptedSections(InvalidDeterminant),
+    #[error("Replay has encrypted sections but no key was given")]
+    MissingKey(),
+    #[error("Could not decrypt replay section; wrong key or corrupted data")]
+    Decryption(),
+    #[error("Malformed or oversized metadata block")]
+    Metadata(),
+    #[error("Metadata can't change size once the encoder has written it")]
+    MetadataResized(),
+    #[error("Core failed to save or load its state at frame {0}")]
+    CoreState(u64),
+    #[error("Verification progress belongs to a different replay")]
+    ProgressMismatch(),
+    #[error("Checkpoint depends on skipped checkpoints; seek backward to decode it")]
+    SkippedCheckpoints(),
+    #[error("Seek index is for a different replay or unusable with its checkpoint encodings")]
+    SeekIndex(),
+    #[error("Snapshot is of a different replay or decoder settings")]
+    Snapshot(),
+    #[error("Core could not read memory at {0:#x}")]
+    Memory(usize),
+    #[error("Clip range {0}..{1} is empty or past the end of the replay")]
+    ClipRange(u64, u64),
+    #[error("Version {0} replays can't store {1}")]
+    VersionFeature(u32, &'static str),
+    #[error("Splice desyncs: checkpoint at frame {0} differs from the core's state in {n} byte ranges", n = .1.len())]
+    SpliceDesync(u64, Vec<std::ops::Range<usize>>),
+    #[error("Malformed JSON replay: {0}")]
+    Json(String),
+    #[error("Malformed or unsupported fm2 movie at line {0}: {1}")]
+    Fm2(usize, String),
+    #[error("Not a movie format this crate knows")]
+    UnknownMovieFormat(),
+    #[error("Replays being merged start from different states")]
+    MergeBase(),
+    /// The size in bytes, or the count of events or index entries
+    #[error("Replay's {0} ({1}) is over the decoder's limit")]
+    OverLimit(&'static str, u64),
+    #[error("External verification command failed at frame {0}: {1}")]
+    ExternalCommand(u64, String),
+    /// An error reading a frame, with the frame's number (from 1) and the
+    /// byte offset in the stream the decoder had reached; see
+    /// [`ReplayError::root`] to match on the error itself
+    #[error("{2} (frame {0}, byte offset {1})")]
+    AtFrame(u64, u64, Box<ReplayError>),
+    /// Which of a checkpoint's stored sizes, and the size stored
+    #[error("Checkpoint's {0} size {1} doesn't match its contents")]
+    CheckpointSize(&'static str, u32),
+    #[error("Statestream tables can only be seeded before the first frame")]
+    LateSeed(),
+    #[error("Encoder can't write more frames after a frame failed to be written")]
+    EncoderOutOfStep(),
+    #[error("Invalid header: {0}")]
+    InvalidHeader(&'static str),
 }
 
 type Result<T> = std::result::Result<T, ReplayError>;
 
 pub struct ReplayDecoder<R: std::io::BufRead> {
-    rply: R,
+    rply: CountingReader<R>,
     pub header: Header,
     pub initial_state: Vec<u8>,
     pub frame_number: u64,
-    ss_state: statestream::Ctx,
+    codecs: Codecs,
+    last_checkpoint: Vec<u8>,
+    first_frame_pos: u64,
+    last_frame_pos: Option<u64>,
+    /* Set once a checkpoint decoded against the one before it has been read */
+    chained_checkpoints: bool,
+    cipher: Option<Cipher>,
+    /* Set once a checkpoint that later ones may depend on has been skipped */
+    skipped_checkpoints: bool,
+    /* From a loaded seek index, for jumping straight to frames */
+    frame_offsets: Option<Vec<u64>>,
+    /* Whether the last frame's event padding bytes were all zero */
+    pub(crate) clean_padding: bool,
+    state_size: StateSize,
+    /* Whether the replay is still being recorded */
+    live: bool,
+    /* Recent maxima of key and input events per frame, for pre-sizing new frames */
+    event_capacity: (usize, usize),
+    limits: DecodeLimits,
+    /* What `read_frame_ref` reads events into, and lends checkpoints
+    from when they aren't lent from `last_checkpoint` */
+    frame_buf: Frame,
 }
 
 impl<R: std::io::BufRead> ReplayDecoder<R> {
@@ -192,96 +404,188 @@ impl<R: std::io::BufRead> ReplayDecoder<R> {
     /// [`ReplayError::Magic`]: Invalid magic number at beginning of file
     /// [`ReplayError::Version`]: Version identifier not recognized by parser
     /// [`ReplayError::Compression`]: Unsupported compression scheme for checkpoints
-    pub fn new(mut rply: R) -> Result<ReplayDecoder<R>> {
-        use byteorder::{LittleEndian, ReadBytesExt};
-        let magic = rply.read_u32::<LittleEndian>()?;
-        if magic != MAGIC {
-            return Err(ReplayError::Magic(magic));
-        }
-        let version = rply.read_u32::<LittleEndian>()?;
-        if version > 2 {
-            return Err(ReplayError::Version(version));
-        }
-        let content_crc = rply.read_u32::<LittleEndian>()?;
-        let initial_state_size = rply.read_u32::<LittleEndian>()?;
-        let identifier = rply.read_u64::<LittleEndian>()?;
-        let base = HeaderBase {
-            version,
-            content_crc,
-            initial_state_size,
-            identifier,
-        };
-        let mut initial_state = vec![0; initial_state_size as usize];
-        if version < 2 {
+    /// [`ReplayError::Metadata`]: Malformed version 3 metadata block
+    /// [`ReplayError::EncryptedSections`]: Unrecognized encryption flags
+    /// [`ReplayError::Decryption`]: The encryption key does not match the replay
+    /// [`ReplayError::CheckpointSize`]: The initial state's stored encoded or compressed size is wrong
+    pub fn new(rply: R) -> Result<ReplayDecoder<R>> {
+        Self::with_codecs(rply, CodecRegistry::default())
+    }
+
+    /// Creates a [`ReplayDecoder`] which can also decode checkpoints in the custom encodings of `codecs`.
+    ///
+    /// # Errors
+    /// See [`ReplayDecoder::new`].
+    pub fn with_codecs(rply: R, codecs: CodecRegistry) -> Result<ReplayDecoder<R>> {
+        Self::with_limits(rply, codecs, DecodeLimits::default())
+    }
+
+    /// Creates a [`ReplayDecoder`] like [`ReplayDecoder::with_codecs`] that
+    /// fails rather than allocate past `limits`.
+    ///
+    /// # Errors
+    /// See [`ReplayDecoder::new`], and:
+    /// [`ReplayError::OverLimit`]: The initial state, metadata, or statestream blocks are bigger than `limits` allow
+    pub fn with_limits(
+        rply: R,
+        codecs: CodecRegistry,
+        limits: DecodeLimits,
+    ) -> Result<ReplayDecoder<R>> {
+        let mut rply = CountingReader::new(rply);
+        let mut header = read_header(&mut rply)?;
+        DecodeLimits::check(
+            limits.max_state_bytes,
+            "initial state",
+            u64::from(header.initial_state_size()),
+        )?;
+        let mut initial_state = vec![0; header.initial_state_size() as usize];
+        let Header::V2(v2) = &mut header else {
             rply.read_exact(initial_state.as_mut_slice())?;
             return Ok(ReplayDecoder {
-                header: Header::V0V1(base),
+                header,
+                first_frame_pos: rply.pos,
                 rply,
+                last_checkpoint: initial_state.clone(),
                 initial_state,
                 frame_number: 0,
-                ss_state: statestream::Ctx::new(1, 1),
+                codecs: Codecs::new(1, 1, codecs),
+                last_frame_pos: None,
+                chained_checkpoints: false,
+                cipher: None,
+                skipped_checkpoints: false,
+                frame_offsets: None,
+                clean_padding: true,
+                state_size: StateSize::AsRecorded,
+                live: false,
+                event_capacity: (0, 0),
+                limits,
+                frame_buf: Frame::default(),
             });
+        };
+        if crate::format::capabilities(v2.base.version).has_metadata {
+            v2.metadata = Metadata::read_limited(&mut rply, limits.max_metadata_bytes)?;
         }
-        let frame_count = rply.read_u32::<LittleEndian>()?;
-        let block_size = rply.read_u32::<LittleEndian>()?;
-        let superblock_size = rply.read_u32::<LittleEndian>()?;
-        let cp_config = rply.read_u32::<LittleEndian>()?;
-        let checkpoint_commit_interval = (cp_config >> 24) as u8;
-        let checkpoint_commit_threshold = ((cp_config >> 16) & 0xFF) as u8;
-        let checkpoint_compression = Compression::try_from(((cp_config >> 8) & 0xFF) as u8)
-            .map_err(ReplayError::Compression)?;
+        DecodeLimits::check(
+            limits.max_state_bytes,
+            "block size",
+            u64::from(v2.block_size),
+        )?;
+        DecodeLimits::check(
+            limits.max_state_bytes,
+            "superblock size",
+            u64::from(v2.superblock_size) * 4,
+        )?;
+        let mut codecs = Codecs::new(v2.block_size, v2.superblock_size, codecs);
+        codecs.set_max_index_entries(limits.max_index_entries);
+        codecs.load_zstd_dictionary(&v2.metadata);
+        codecs.load_state_regions(&v2.metadata);
+        if v2.metadata.block_commits() {
+            codecs.set_commit_settings(
+                v2.checkpoint_commit_interval,
+                v2.checkpoint_commit_threshold,
+            );
+        }
+        let cipher = if v2.encrypted_sections.any() {
+            let mut salt = [0; SALT_LEN];
+            rply.read_exact(&mut salt)?;
+            Cipher::with_salt(&codecs, salt)
+        } else {
+            None
+        };
         let mut replay = ReplayDecoder {
             rply,
             initial_state,
-            header: Header::V2(HeaderV2 {
-                base,
-                frame_count,
-                block_size,
-                superblock_size,
-                checkpoint_commit_interval,
-                checkpoint_commit_threshold,
-                checkpoint_compression,
-            }),
+            header,
             frame_number: 0,
-            ss_state: statestream::Ctx::new(block_size, superblock_size),
+            codecs,
+            last_checkpoint: vec![],
+            first_frame_pos: 0,
+            last_frame_pos: None,
+            chained_checkpoints: false,
+            cipher,
+            skipped_checkpoints: false,
+            frame_offsets: None,
+            clean_padding: true,
+            state_size: StateSize::AsRecorded,
+            live: false,
+            event_capacity: (0, 0),
+            limits,
+            frame_buf: Frame::default(),
         };
-        replay.decode_initial_checkpoint()?;
+        if replay.header.initial_state_size() > 0 {
+            replay.decode_initial_checkpoint()?;
+        }
+        replay.first_frame_pos = replay.rply.pos;
+        if let Some(compression) = replay.header.metadata().and_then(Metadata::frame_packing) {
+            if Compressors::default().get(compression).is_none() {
+                return Err(ReplayError::Compression(InvalidDeterminant(u8::from(
+                    compression,
+                ))));
+            }
+            // A pack holds one checkpoint's frame or a run of frames without one
+            replay
+                .rply
+                .start_packing(limits.max_state_bytes.saturating_add(2 * PACK_BYTES));
+        }
         Ok(replay)
     }
 
     pub fn inner(&mut self) -> &mut R {
-        &mut self.rply
+        &mut self.rply.inner
+    }
+
+    /// Consumes the decoder, returning the underlying reader positioned just after the last frame read.
+    pub fn into_inner(self) -> R {
+        self.rply.inner
+    }
+
+    /* Bytes read from the start of the replay; for packed frames, up to
+    the end of the last pack read */
+    pub(crate) fn position(&self) -> u64 {
+        self.rply.physical_pos()
+    }
+
+    /// Registers a codec for checkpoints using [`Encoding::Custom`]`(id)`.
+    /// Use [`ReplayDecoder::with_codecs`] if the initial state may use it.
+    /// # Panics
+    /// If `id` is reserved for a built-in encoding.
+    pub fn register_codec(
+        &mut self,
+        id: u8,
+        codec: Box<dyn CheckpointCodec>,
+    ) -> Option<Box<dyn CheckpointCodec>> {
+        self.codecs.register(id, codec)
+    }
+    /// Registers a custom [`Compressor`] under [`Compression::Custom`]`(id)`.
+    /// # Panics
+    /// If `id` is reserved for a built-in compression scheme.
+    pub fn register_compressor(
+        &mut self,
+        id: u8,
+        compressor: Box<dyn Compressor>,
+    ) -> Option<Box<dyn Compressor>> {
+        self.codecs.register_compressor(id, compressor)
     }
 
     /// Reads keyboard event records at the current input position.  Only really appropriate to explicitly call for v0 replays.
     /// # Errors
     /// [`ReplayError::IO`]: Unexpected end of stream or other I/O error
     pub fn read_key_events(&mut self, frame: &mut Frame) -> Result<()> {
-        use byteorder::{LittleEndian, ReadBytesExt};
-        let rply = &mut self.rply;
-        let key_count = rply.read_u8()? as usize;
-        frame.key_events.resize_with(key_count, Default::default);
-        for ki in 0..key_count {
-            /*
-            down, padding, mod_x2, code_x4, char_x4
-             */
-            let down = rply.read_u8()?;
-            let _ = rply.read_u8()?; // padding
-            let modf = rply.read_u16::<LittleEndian>()?;
-            let code = rply.read_u32::<LittleEndian>()?;
-            let chr = rply.read_u32::<LittleEndian>()?;
-            let key_data = KeyData {
-                down,
-                /* buf[1] is padding */
-                modf,
-                code,
-                chr,
-            };
-            frame.key_events[ki] = key_data;
-        }
+        self.clean_padding = read_key_events(&mut self.rply, frame, &self.limits)?;
         Ok(())
     }
 
+    /// Whether the [`EncryptedSections`] of this replay can't be read
+    /// because no key was given; such sections read as empty.
+    #[must_use]
+    pub fn locked_sections(&self) -> EncryptedSections {
+        if self.cipher.is_some() {
+            EncryptedSections::default()
+        } else {
+            self.header.encrypted_sections()
+        }
+    }
+
     /// Reads an end of frame marker at the current input position.  Only really appropriate to explicitly call for v0 replays.
     /// # Errors
     /// [`ReplayError::IO`]: Unexpected end of stream or other I/O error
@@ -290,6 +594,13 @@ impl<R: std::io::BufRead> ReplayDecoder<R> {
     /// [`ReplayError::BadFrameToken`]: Frame token not recognized or misaligned
     /// [`ReplayError::CheckpointTooBig`]: Tried to read a checkpoint bigger than the address space
     pub fn read_end_of_frame(&mut self, frame: &mut Frame) -> Result<()> {
+        self.read_end_of_frame_lending(frame, false).map(|_| ())
+    }
+
+    /* As `read_end_of_frame`, but if `lend` is set a decoded checkpoint is
+    left in `last_checkpoint` rather than copied into `frame`, returning
+    whether it was */
+    fn read_end_of_frame_lending(&mut self, frame: &mut Frame, lend: bool) -> Result<bool> {
         use byteorder::{LittleEndian, ReadBytesExt};
         let rply = &mut self.rply;
         let tok = rply.read_u8()?;
@@ -302,17 +613,28 @@ impl<R: std::io::BufRead> ReplayDecoder<R> {
             FrameToken::Checkpoint => {
                 frame.checkpoint_compression = Compression::None;
                 frame.checkpoint_encoding = Encoding::Raw;
-                let cp_size = usize::try_from(rply.read_u64::<LittleEndian>()?)
-                    .map_err(ReplayError::CheckpointTooBig)?;
+                let cp_size = rply.read_u64::<LittleEndian>()?;
+                DecodeLimits::check(self.limits.max_state_bytes, "checkpoint", cp_size)?;
+                let cp_size = usize::try_from(cp_size).map_err(ReplayError::CheckpointTooBig)?;
                 frame.checkpoint_bytes.resize(cp_size, 0);
                 rply.read_exact(frame.checkpoint_bytes.as_mut_slice())?;
+                self.state_size.fit(&mut frame.checkpoint_bytes);
             }
             FrameToken::Checkpoint2 => {
-                self.decode_checkpoint(&mut frame.checkpoint_bytes)?;
+                // Fitting changes the bytes, and locked checkpoints aren't decoded
+                let lend = lend
+                    && self.state_size == StateSize::AsRecorded
+                    && !self.locked_sections().checkpoints;
+                let (compression, encoding) =
+                    self.decode_checkpoint(&mut frame.checkpoint_bytes, Section::Checkpoint, lend)?;
+                frame.checkpoint_compression = compression;
+                frame.checkpoint_encoding = encoding;
+                self.state_size.fit(&mut frame.checkpoint_bytes);
+                return Ok(lend);
             }
             _ => return Err(ReplayError::BadFrameToken(tok)),
         }
-        Ok(())
+        Ok(false)
     }
 
     /// Reads a single button value at the current input position.  Only appropriate for v0 replays and only if you are implementing an input callback for a core.
@@ -325,7 +647,8 @@ impl<R: std::io::BufRead> ReplayDecoder<R> {
             .map_err(ReplayError::IO)
     }
 
-    /// Reads a single frame at the current decoder position.
+    /// Reads a single frame at the current decoder position, overwriting
+    /// all of `frame` (see [`Frame`] on reusing it).
     /// # Errors
     /// [`ReplayError::IO`]: Unexpected end of stream or other I/O error
     /// [`ReplayError::Compression`]: Unsupported compression scheme
@@ -333,191 +656,1571 @@ impl<R: std::io::BufRead> ReplayDecoder<R> {
     /// [`ReplayError::BadFrameToken`]: Frame token not recognized or misaligned
     /// [`ReplayError::NoCoreRead`]: Tried to read a frame on a version 0 replay without a loaded core
     /// [`ReplayError::CheckpointTooBig`]: Tried to read a checkpoint bigger than the address space
-    #[allow(clippy::too_many_lines)]
+    /// [`ReplayError::SkippedCheckpoints`]: The checkpoint depends on one skipped by [`ReplayDecoder::read_frame_skipping_checkpoints`]
+    /// [`ReplayError::CheckpointSize`]: The checkpoint's stored encoded or compressed size is wrong
+    ///
+    /// Errors from the frame's contents come wrapped in
+    /// [`ReplayError::AtFrame`] with where they happened.
     pub fn read_frame(&mut self, frame: &mut Frame) -> Result<()> {
-        use byteorder::{LittleEndian, ReadBytesExt};
-        let stopwatch = clock::time(Timer::DecodeFrame);
-        let vsn = self.header.version();
-        if vsn == 0 {
+        let stopwatch = self.codecs.stats.time(Timer::DecodeFrame);
+        if !self.header.capabilities().coreless_frames {
             return Err(ReplayError::NoCoreRead());
         }
-        if vsn > 1 {
-            /* skip over the backref */
-            let _ = self.rply.read_u32::<LittleEndian>()?;
+        let result = self
+            .read_frame_events(frame)
+            .and_then(|()| self.read_end_of_frame(frame));
+        self.in_frame(result)?;
+        self.frame_number += 1;
+        drop(stopwatch);
+        Ok(())
+    }
+
+    /// Reads a single frame like [`ReplayDecoder::read_frame`], lending it
+    /// out until the next read instead of filling a [`Frame`].  Its events
+    /// are parsed into a buffer the decoder reuses, and a decoded checkpoint
+    /// is lent from the buffer the decoder keeps it in anyway as the basis
+    /// for the next, so it is never copied.  Checkpoints fitted to a
+    /// [`StateSize::Fixed`] size are copied to be fitted.
+    /// # Errors
+    /// As [`ReplayDecoder::read_frame`]
+    pub fn read_frame_ref(&mut self) -> Result<FrameRef<'_>> {
+        let stopwatch = self.codecs.stats.time(Timer::DecodeFrame);
+        if !self.header.capabilities().coreless_frames {
+            return Err(ReplayError::NoCoreRead());
         }
-        self.read_key_events(frame)?;
-        let rply = &mut self.rply;
-        let input_count = rply.read_u16::<LittleEndian>()? as usize;
-        frame
-            .input_events
-            .resize_with(input_count, Default::default);
-        for ii in 0..input_count {
-            /* port, device, idx, padding, id_x2, value_x2 */
-            let port = rply.read_u8()?;
-            let device = rply.read_u8()?;
-            let idx = rply.read_u8()?;
-            let _ = rply.read_u8()?;
-            let id = rply.read_u16::<LittleEndian>()?;
-            let val = rply.read_i16::<LittleEndian>()?;
-            let inp_data = InputData {
-                port,
-                device,
-                idx,
-                id,
-                val,
-            };
-            frame.input_events[ii] = inp_data;
+        let mut frame = std::mem::take(&mut self.frame_buf);
+        let result = self
+            .read_frame_events(&mut frame)
+            .and_then(|()| self.read_end_of_frame_lending(&mut frame, true));
+        self.frame_buf = frame;
+        let lent = self.in_frame(result)?;
+        self.frame_number += 1;
+        drop(stopwatch);
+        let frame = &self.frame_buf;
+        Ok(FrameRef {
+            key_events: &frame.key_events,
+            input_events: &frame.input_events,
+            checkpoint_bytes: if lent {
+                &self.last_checkpoint
+            } else {
+                &frame.checkpoint_bytes
+            },
+            checkpoint_compression: frame.checkpoint_compression,
+            checkpoint_encoding: frame.checkpoint_encoding,
+        })
+    }
+
+    /// Reads a single frame at the current decoder position like
+    /// [`ReplayDecoder::read_frame`], but skips over any checkpoint payload
+    /// without decompressing or decoding it.  `frame.checkpoint_bytes` is
+    /// left empty; the checkpoint's compression and encoding are still
+    /// filled in.  Returns the uncompressed size of the skipped checkpoint,
+    /// if the frame had one.
+    ///
+    /// Checkpoints encoded against earlier ones can't be decoded after one
+    /// of those is skipped, so reading them afterwards fails with
+    /// [`ReplayError::SkippedCheckpoints`] until the decoder seeks back.
+    /// # Errors
+    /// As [`ReplayDecoder::read_frame`]
+    pub fn read_frame_skipping_checkpoints(&mut self, frame: &mut Frame) -> Result<Option<u64>> {
+        let stopwatch = self.codecs.stats.time(Timer::DecodeFrame);
+        if !self.header.capabilities().coreless_frames {
+            return Err(ReplayError::NoCoreRead());
         }
-        self.read_end_of_frame(frame)?;
+        let result = self.skip_frame(frame);
+        let size = self.in_frame(result)?;
         self.frame_number += 1;
         drop(stopwatch);
+        Ok(size)
+    }
+
+    /* The body of read_frame_skipping_checkpoints */
+    fn skip_frame(&mut self, frame: &mut Frame) -> Result<Option<u64>> {
+        self.read_frame_events(frame)?;
+        frame.checkpoint_bytes.clear();
+        frame.checkpoint_compression = Compression::None;
+        frame.checkpoint_encoding = Encoding::Raw;
+        let (size, skip) = match read_checkpoint_sizes(&mut self.rply)? {
+            None => (None, 0),
+            Some(sizes) => {
+                frame.checkpoint_compression = sizes.compression;
+                frame.checkpoint_encoding = sizes.encoding;
+                self.skipped_checkpoints |= sizes.encoding != Encoding::Raw;
+                (Some(sizes.uncompressed_size), sizes.compressed_size)
+            }
+        };
+        if std::io::copy(&mut (&mut self.rply).take(skip), &mut std::io::sink())? < skip {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(size)
+    }
+
+    /* Adds the frame being read and the stream position to an error reading it */
+    fn in_frame<T>(&self, result: Result<T>) -> Result<T> {
+        result.map_err(|e| ReplayError::AtFrame(self.frame_number + 1, self.rply.pos, Box::new(e)))
+    }
+
+    /// Sets the size checkpoints are fitted to as frames are read.  The
+    /// initial state is left as recorded; fit a copy of it with
+    /// [`StateSize::fit`] if needed.
+    pub fn set_state_size(&mut self, state_size: StateSize) {
+        self.state_size = state_size;
+    }
+    /// Timer and counter totals for this decoder alone.
+    #[must_use]
+    pub fn stats(&self) -> &Stats {
+        &self.codecs.stats
+    }
+    /// Bytes of blocks and superblocks held in the statestream tables, as
+    /// [`ReplayEncoder::table_bytes`]; a decoder following an encoder's
+    /// evictions holds the same.
+    #[must_use]
+    pub fn table_bytes(&self) -> usize {
+        self.codecs.table_bytes()
+    }
+    #[must_use]
+    pub fn state_size(&self) -> StateSize {
+        self.state_size
+    }
+    /// Reads a replay that is still being recorded.  Its header's frame
+    /// count is only written when recording finishes, so it is ignored and
+    /// only the end of the stream ends the replay.  The last frame may be
+    /// partly written, in which case reading it fails with an unexpected
+    /// end of file.
+    pub fn set_live(&mut self, live: bool) {
+        self.live = live;
+    }
+
+    /// Whether there are no frames left to read: the header's frame count
+    /// has been reached (for v2 replays not read with
+    /// [`ReplayDecoder::set_live`]) or the stream has ended.
+    /// # Errors
+    /// [`ReplayError::IO`]: I/O error while checking for the end of the stream
+    pub fn at_end(&mut self) -> Result<bool> {
+        if !self.live
+            && self
+                .header
+                .frame_count()
+                .is_some_and(|count| self.frame_number >= count)
+        {
+            return Ok(true);
+        }
+        self.stream_ended()
+    }
+
+    /* Whether the stream has no more bytes, whatever the frame count says */
+    pub(crate) fn stream_ended(&mut self) -> Result<bool> {
+        use std::io::BufRead;
+        Ok(self.rply.fill_buf()?.is_empty())
+    }
+
+    /* Reads the backref and events of a frame */
+    fn read_frame_events(&mut self, frame: &mut Frame) -> Result<()> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        self.last_frame_pos = Some(self.rply.pos);
+        if self.header.capabilities().has_backrefs {
+            /* skip over the backref */
+            let _ = self.rply.read_u32::<LittleEndian>()?;
+        }
+        if self.header.encrypted_sections().inputs {
+            let sealed_size = self.rply.read_u32::<LittleEndian>()?;
+            if let Some(cipher) = &self.cipher {
+                let mut sealed = vec![];
+                read_sealed(&mut self.rply, sealed_size, &mut sealed)?;
+                cipher.open(Section::Inputs, self.frame_number, &mut sealed)?;
+                let mut events = sealed.as_slice();
+                self.clean_padding = read_key_events(&mut events, frame, &self.limits)?;
+                self.clean_padding &= read_input_events(&mut events, frame, &self.limits)?;
+            } else {
+                self.rply.skip(u64::from(sealed_size))?;
+                frame.key_events.clear();
+                frame.input_events.clear();
+                self.clean_padding = true;
+            }
+        } else {
+            self.clean_padding = read_key_events(&mut self.rply, frame, &self.limits)?;
+            self.clean_padding &= read_input_events(&mut self.rply, frame, &self.limits)?;
+        }
+        let (keys, inputs) = &mut self.event_capacity;
+        *keys = rolling_max(*keys, frame.key_events.len());
+        *inputs = rolling_max(*inputs, frame.input_events.len());
+        Ok(())
+    }
+
+    /// An empty frame with room for as many events as recent frames had,
+    /// so reading into it rarely has to grow its vectors.
+    #[must_use]
+    pub fn new_frame(&self) -> Frame {
+        Frame::with_capacity(self.event_capacity.0, self.event_capacity.1)
+    }
+
+    /// Seeds the statestream tables with `state` as the encoder's were with
+    /// [`ReplayEncoder::seed_tables`]; replays seeded when encoded can't be
+    /// decoded past their first checkpoint otherwise.
+    /// # Errors
+    /// [`ReplayError::LateSeed`]: A frame was already read
+    pub fn seed_tables(&mut self, state: &[u8]) -> Result<()> {
+        if self.frame_number > 0 {
+            return Err(ReplayError::LateSeed());
+        }
+        self.codecs.seed(state);
         Ok(())
     }
 
+    /// Iterates over the remaining frames, stopping at the header's frame
+    /// count (for v2 replays) or at the end of the stream.  After an error
+    /// is yielded, the iterator ends.
+    pub fn frames(&mut self) -> Frames<'_, R> {
+        Frames {
+            decoder: self,
+            failed: false,
+        }
+    }
+
+    /// Reads on to the first frame for which `matches` is true, skipping
+    /// checkpoints as [`ReplayDecoder::read_frame_skipping_checkpoints`]
+    /// does, and returns its number (counting from 1), or `None` if no
+    /// remaining frame matches.  The decoder is left just after that frame,
+    /// so calling this again finds the next one.  See [`InputPattern`] for
+    /// matching a button or other input.
+    /// # Errors
+    /// As [`ReplayDecoder::read_frame_skipping_checkpoints`]
+    pub fn find_frame(&mut self, mut matches: impl FnMut(&Frame) -> bool) -> Result<Option<u64>> {
+        let mut frame = self.new_frame();
+        while !self.at_end()? {
+            self.read_frame_skipping_checkpoints(&mut frame)?;
+            if matches(&frame) {
+                return Ok(Some(self.frame_number));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the rest of the replay, skipping checkpoints, and returns the
+    /// number (counting from 1) of every frame for which `matches` is true.
+    /// # Errors
+    /// As [`ReplayDecoder::read_frame_skipping_checkpoints`]
+    pub fn find_frames(&mut self, mut matches: impl FnMut(&Frame) -> bool) -> Result<Vec<u64>> {
+        let mut found = vec![];
+        while let Some(frame) = self.find_frame(&mut matches)? {
+            found.push(frame);
+        }
+        Ok(found)
+    }
+
     fn decode_initial_checkpoint(&mut self) -> Result<()> {
         let mut initial_state = std::mem::take(&mut self.initial_state);
-        self.decode_checkpoint(&mut initial_state)?;
+        self.decode_checkpoint(&mut initial_state, Section::InitialState, false)?;
         self.initial_state = initial_state;
         Ok(())
     }
 
-    fn decode_checkpoint(&mut self, checkpoint_bytes: &mut Vec<u8>) -> Result<()> {
+    /* Decodes a checkpoint into `checkpoint_bytes` and keeps it as the
+    basis for the next, or with `lend` swaps it into `last_checkpoint`,
+    leaving the one before in `checkpoint_bytes` */
+    fn decode_checkpoint(
+        &mut self,
+        checkpoint_bytes: &mut Vec<u8>,
+        section: Section,
+        lend: bool,
+    ) -> Result<(Compression, Encoding)> {
         use byteorder::{LittleEndian, ReadBytesExt};
-        let stopwatch = clock::time(Timer::DecodeCheckpoint);
+        let stopwatch = self.codecs.stats.time(Timer::DecodeCheckpoint);
         let rply = &mut self.rply;
         // read a 1 byte compression code
         let compression =
             Compression::try_from(rply.read_u8()?).map_err(ReplayError::Compression)?;
         // read a 1 byte encoding code
-        let encoding = Encoding::try_from(rply.read_u8()?).map_err(ReplayError::Encoding)?;
+        let encoding_byte = rply.read_u8()?;
+        let encoding = Encoding::try_from(encoding_byte).map_err(ReplayError::Encoding)?;
+        // Statestream keyframes can be decoded anyway, and bring the tables back in step
+        let resync = self.skipped_checkpoints && encoding == Encoding::Statestream;
+        if self.skipped_checkpoints && !matches!(encoding, Encoding::Raw | Encoding::Statestream) {
+            return Err(ReplayError::SkippedCheckpoints());
+        }
+        self.codecs.statestream.ctx.set_resync(resync);
+        let (codec, compressor) = self.codecs.get_mut(encoding, compression)?;
         // read a 4 byte uncompressed unencoded size
         let uc_ue_size = rply.read_u32::<LittleEndian>()? as usize;
+        DecodeLimits::check(self.limits.max_state_bytes, "checkpoint", uc_ue_size as u64)?;
         // read a 4 byte uncompressed encoded size
-        #[expect(unused)]
-        let uc_enc_size = rply.read_u32::<LittleEndian>()? as usize;
+        let uc_enc_size = rply.read_u32::<LittleEndian>()?;
         // read a 4 byte compressed encoded size
-        #[expect(unused)]
-        let comp_enc_size = rply.read_u32::<LittleEndian>()? as usize;
+        let comp_enc_size = rply.read_u32::<LittleEndian>()?;
         checkpoint_bytes.resize(uc_ue_size, 0);
-        // maybe decompress
-        match (compression, encoding) {
-            (Compression::None, Encoding::Raw) => {
-                rply.read_exact(checkpoint_bytes.as_mut_slice())?;
-            }
-            (Compression::None, Encoding::Statestream) => {
-                let mut ss_decoder =
-                    statestream::Decoder::new(rply, &mut self.ss_state, uc_ue_size);
-                std::io::copy(
-                    &mut ss_decoder,
-                    &mut std::io::Cursor::new(checkpoint_bytes.as_mut_slice()),
-                )?;
-            }
-            (Compression::Zlib, Encoding::Raw) => {
-                use flate2::bufread::ZlibDecoder;
-                let mut decoder = ZlibDecoder::new(rply);
-                std::io::copy(
-                    &mut decoder,
-                    &mut std::io::Cursor::new(checkpoint_bytes.as_mut_slice()),
-                )?;
-            }
-            (Compression::Zlib, Encoding::Statestream) => {
-                use flate2::bufread::ZlibDecoder;
-                let mut decoder = ZlibDecoder::new(rply);
-                let mut ss_decoder =
-                    statestream::Decoder::new(&mut decoder, &mut self.ss_state, uc_ue_size);
-                std::io::copy(
-                    &mut ss_decoder,
-                    &mut std::io::Cursor::new(checkpoint_bytes.as_mut_slice()),
-                )?;
-            }
-            (Compression::Zstd, Encoding::Raw) => {
-                use zstd::Decoder;
-                let mut decoder = Decoder::with_buffer(rply)?.single_frame();
-                std::io::copy(
-                    &mut decoder,
-                    &mut std::io::Cursor::new(checkpoint_bytes.as_mut_slice()),
-                )?;
-            }
-            (Compression::Zstd, Encoding::Statestream) => {
-                use zstd::Decoder;
-                let mut decoder = Decoder::with_buffer(rply)?.single_frame();
-                let mut ss_decoder =
-                    statestream::Decoder::new(&mut decoder, &mut self.ss_state, uc_ue_size);
-                std::io::copy(
-                    &mut ss_decoder,
-                    &mut std::io::Cursor::new(checkpoint_bytes.as_mut_slice()),
-                )?;
+        let cx = CheckpointContext {
+            frame: self.frame_number,
+            previous: &self.last_checkpoint,
+        };
+        let start = rply.pos;
+        let mut sealed = vec![];
+        let mut compressed: Box<dyn std::io::BufRead> =
+            if self.header.encrypted_sections().checkpoints {
+                let Some(cipher) = &self.cipher else {
+                    rply.skip(u64::from(comp_enc_size))?;
+                    checkpoint_bytes.clear();
+                    self.codecs.statestream.ctx.set_resync(false);
+                    return Ok((compression, encoding));
+                };
+                read_sealed(rply, comp_enc_size, &mut sealed)?;
+                cipher.open(section, self.frame_number, &mut sealed)?;
+                Box::new(sealed.as_slice())
+            } else {
+                // Bound decompressors to this checkpoint, so a bad size can't
+                // make them read into the next frame
+                Box::new((&mut *rply).take(u64::from(comp_enc_size)))
+            };
+        let mut decompressed = CountingReader::new(compressor.decompress(&mut compressed)?);
+        let decoded = codec
+            .decode(&mut decompressed, checkpoint_bytes, &cx)
+            .and_then(|()| std::io::copy(&mut decompressed, &mut std::io::sink()))
+            .map(|_| decompressed.pos);
+        drop(decompressed);
+        // Having reached the end of the decompressed stream, the decompressor
+        // should have consumed all of this checkpoint's bytes
+        let trailing = decoded.as_ref().map_or(Ok(0), |_| {
+            std::io::copy(&mut compressed, &mut std::io::sink())
+        })?;
+        drop(compressed);
+        self.codecs.statestream.ctx.set_resync(false);
+        let consumed = self.rply.pos - start;
+        let encoded = match decoded {
+            Ok(encoded) => encoded,
+            // Decoding ran out of the checkpoint's bytes while the replay goes on
+            Err(e)
+                if ran_out(&e)
+                    && consumed == u64::from(comp_enc_size)
+                    && !std::io::BufRead::fill_buf(&mut self.rply)?.is_empty() =>
+            {
+                return Err(ReplayError::CheckpointSize("compressed", comp_enc_size));
             }
+            Err(e) => return Err(ReplayError::from_io(e)),
+        };
+        if consumed < u64::from(comp_enc_size) {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if trailing > 0 {
+            return Err(ReplayError::CheckpointSize("compressed", comp_enc_size));
+        }
+        if encoded != u64::from(uc_enc_size) {
+            return Err(ReplayError::CheckpointSize("encoded", uc_enc_size));
+        }
+        self.skipped_checkpoints &= !resync;
+        self.chained_checkpoints |= !matches!(encoding, Encoding::Raw | Encoding::Statestream);
+        if lend {
+            std::mem::swap(&mut self.last_checkpoint, checkpoint_bytes);
+        } else {
+            self.last_checkpoint.clone_from(checkpoint_bytes);
         }
         drop(stopwatch);
+        Ok((compression, encoding))
+    }
+}
+
+/* Reads a sealed section of `size` bytes into `sealed`, which grows as they
+arrive rather than to whatever size the replay claims */
+fn read_sealed(
+    rply: &mut impl std::io::Read,
+    size: u32,
+    sealed: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    sealed.clear();
+    rply.take(u64::from(size)).read_to_end(sealed)?;
+    if sealed.len() == size as usize {
         Ok(())
+    } else {
+        Err(std::io::ErrorKind::UnexpectedEof.into())
     }
 }
 
-/// Creates a [`ReplayDecoder`] for the given buffered readable stream.
-///
-/// # Errors
-/// See [`ReplayDecoder::new`].
-pub fn decode<R: std::io::BufRead>(rply: R) -> Result<ReplayDecoder<R>> {
-    ReplayDecoder::new(rply)
+/* Whether `e` comes from reaching the end of a stream, maybe through a codec's own error */
+fn ran_out(e: &std::io::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(e) = source {
+        if e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
 }
 
-pub struct ReplayEncoder<'a, W: std::io::Write + std::io::Seek> {
-    rply: &'a mut W,
-    pub header: Header,
-    pub frame_number: u64,
-    last_pos: u64,
-    ss_state: statestream::Ctx,
-    finished: bool,
+/* Reads a frame token and the sizes of the checkpoint it introduces, up to the checkpoint's payload */
+fn read_checkpoint_sizes<R: std::io::Read>(rply: &mut R) -> Result<Option<CheckpointInfo>> {
+    use byteorder::{LittleEndian, ReadBytesExt};
+    let tok = rply.read_u8()?;
+    match FrameToken::from(tok) {
+        FrameToken::Regular => Ok(None),
+        FrameToken::Checkpoint => {
+            let size = rply.read_u64::<LittleEndian>()?;
+            Ok(Some(CheckpointInfo {
+                frame: 0,
+                offset: 0,
+                compression: Compression::None,
+                encoding: Encoding::Raw,
+                compressed_size: size,
+                uncompressed_size: size,
+            }))
+        }
+        FrameToken::Checkpoint2 => {
+            let compression =
+                Compression::try_from(rply.read_u8()?).map_err(ReplayError::Compression)?;
+            let encoding = Encoding::try_from(rply.read_u8()?).map_err(ReplayError::Encoding)?;
+            let uncompressed_size = rply.read_u32::<LittleEndian>()?;
+            let _encoded_size = rply.read_u32::<LittleEndian>()?;
+            let compressed_size = rply.read_u32::<LittleEndian>()?;
+            Ok(Some(CheckpointInfo {
+                frame: 0,
+                offset: 0,
+                compression,
+                encoding,
+                compressed_size: u64::from(compressed_size),
+                uncompressed_size: u64::from(uncompressed_size),
+            }))
+        }
+        FrameToken::Invalid => Err(ReplayError::BadFrameToken(tok)),
+    }
 }
 
-impl<'w, W: std::io::Write + std::io::Seek> ReplayEncoder<'w, W> {
-    /// Creates a [`ReplayEncoder`] for the given writable and seekable stream.
-    ///
+/* Returns whether all padding bytes were zero */
+fn read_key_events<R: std::io::Read>(
+    rply: &mut R,
+    frame: &mut Frame,
+    limits: &DecodeLimits,
+) -> Result<bool> {
+    use byteorder::{LittleEndian, ReadBytesExt};
+    let key_count = rply.read_u8()? as usize;
+    DecodeLimits::check(limits.max_key_events, "key events", key_count as u64)?;
+    frame.key_events.resize_with(key_count, Default::default);
+    let mut clean_padding = true;
+    for ki in 0..key_count {
+        /*
+        down, padding, mod_x2, code_x4, char_x4
+         */
+        let down = rply.read_u8()?;
+        let padding = rply.read_u8()?;
+        clean_padding &= padding == 0;
+        let modf = rply.read_u16::<LittleEndian>()?;
+        let code = rply.read_u32::<LittleEndian>()?;
+        let chr = rply.read_u32::<LittleEndian>()?;
+        let key_data = KeyData {
+            down,
+            /* buf[1] is padding */
+            modf,
+            code,
+            chr,
+        };
+        frame.key_events[ki] = key_data;
+    }
+    Ok(clean_padding)
+}
+
+/* Returns whether all padding bytes were zero */
+fn read_input_events<R: std::io::Read>(
+    rply: &mut R,
+    frame: &mut Frame,
+    limits: &DecodeLimits,
+) -> Result<bool> {
+    use byteorder::{LittleEndian, ReadBytesExt};
+    let input_count = rply.read_u16::<LittleEndian>()? as usize;
+    DecodeLimits::check(limits.max_input_events, "input events", input_count as u64)?;
+    frame
+        .input_events
+        .resize_with(input_count, Default::default);
+    let mut clean_padding = true;
+    for ii in 0..input_count {
+        /* port, device, idx, padding, id_x2, value_x2 */
+        let port = rply.read_u8()?;
+        let device = rply.read_u8()?;
+        let idx = rply.read_u8()?;
+        clean_padding &= rply.read_u8()? == 0;
+        let id = rply.read_u16::<LittleEndian>()?;
+        let val = rply.read_i16::<LittleEndian>()?;
+        let inp_data = InputData {
+            port,
+            device,
+            idx,
+            id,
+            val,
+        };
+        frame.input_events[ii] = inp_data;
+    }
+    Ok(clean_padding)
+}
+
+fn write_events<W: std::io::Write>(rply: &mut W, frame: &Frame) -> Result<()> {
+    use byteorder::{LittleEndian, WriteBytesExt};
+    rply.write_u8(u8::try_from(frame.key_events.len()).map_err(ReplayError::TooManyKeyEvents)?)?;
+    for evt in &frame.key_events {
+        rply.write_u8(evt.down)?;
+        rply.write_u8(0)?; // padding
+        rply.write_u16::<LittleEndian>(evt.modf)?;
+        rply.write_u32::<LittleEndian>(evt.code)?;
+        rply.write_u32::<LittleEndian>(evt.chr)?;
+    }
+    rply.write_u16::<LittleEndian>(
+        u16::try_from(frame.input_events.len()).map_err(ReplayError::TooManyInputEvents)?,
+    )?;
+    for evt in &frame.input_events {
+        rply.write_u8(evt.port)?;
+        rply.write_u8(evt.device)?;
+        rply.write_u8(evt.idx)?;
+        rply.write_u8(0)?; // padding
+        rply.write_u16::<LittleEndian>(evt.id)?;
+        rply.write_i16::<LittleEndian>(evt.val)?;
+    }
+    Ok(())
+}
+
+impl<R: std::io::BufRead + std::io::Seek> ReplayDecoder<R> {
+    /// Positions the decoder so that the next [`ReplayDecoder::read_frame`]
+    /// reads frame `frame`.  Seeking forward reads the intervening frames;
+    /// seeking backward follows frame backrefs to the nearest checkpoint
+    /// before `frame`, decodes it, and reads forward from there.  With a
+    /// seek index loaded, the decoder jumps straight to the frame.
     /// # Errors
-    /// [`ReplayError::IO`]: Some issue with the write stream, e.g. unexpected end
-    /// [`ReplayError::Version`]: Version identifier not supported by writer
-    /// [`ReplayError::Compression`]: Unsupported compression scheme for checkpoints
-    pub fn new<'s>(
-        header: Header,
-        initial_state: &'s [u8],
-        rply: &'w mut W,
-    ) -> Result<ReplayEncoder<'w, W>> {
-        if header.version() != 2 {
-            return Err(ReplayError::Version(header.version()));
+    /// [`ReplayError::NoCoreRead`]: Tried to seek in a version 0 replay
+    /// Otherwise, any error from [`ReplayDecoder::read_frame`] on the frames read along the way.
+    pub fn seek_to_frame(&mut self, frame: u64) -> Result<()> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        let caps = self.header.capabilities();
+        if !caps.coreless_frames {
+            return Err(ReplayError::NoCoreRead());
         }
-        let ss_state = statestream::Ctx::new(header.block_size(), header.superblock_size());
-        let mut replay = ReplayEncoder {
-            rply,
-            header,
-            frame_number: 0,
-            last_pos: 0,
-            ss_state,
-            finished: false,
-        };
-        replay.write_header()?;
-        if !initial_state.is_empty() {
-            replay.encode_initial_checkpoint(initial_state)?;
+        if let Some(&pos) = self
+            .frame_offsets
+            .as_ref()
+            .and_then(|offsets| offsets.get(usize::try_from(frame).ok()?))
+        {
+            /* The index's tables can decode any checkpoint from here on */
+            self.rply.seek_to(pos)?;
+            self.frame_number = frame;
+            self.last_frame_pos = None;
+            self.skipped_checkpoints = false;
+            return Ok(());
         }
-        replay.last_pos = replay.rply.stream_position()?;
-        Ok(replay)
+        let mut scratch = Frame::default();
+        if frame < self.frame_number {
+            let mut restart = None;
+            /* Version 1 frames have no backrefs, so those always restart from the first frame;
+            so do decoders that skipped checkpoints, to decode everything they missed,
+            decoders that evicted blocks, to see them again, and decoders of checkpoints
+            XORed with or otherwise encoded against the one before, to have the one before */
+            if let (Some(mut pos), true, false, false) = (
+                self.last_frame_pos,
+                caps.has_backrefs,
+                self.skipped_checkpoints,
+                self.codecs.has_evicted() || self.codecs.has_xor() || self.chained_checkpoints,
+            ) {
+                let mut which = self.frame_number - 1;
+                loop {
+                    self.rply.seek_to(pos)?;
+                    let backref = self.rply.read_u32::<LittleEndian>()?;
+                    if which < frame && self.skip_to_end_of_frame()? {
+                        restart = Some((which, pos));
+                        break;
+                    }
+                    if which == 0 {
+                        break;
+                    }
+                    pos -= u64::from(backref);
+                    which -= 1;
+                }
+            }
+            if let Some((which, pos)) = restart {
+                self.rply.seek_to(pos)?;
+                self.frame_number = which;
+            } else {
+                self.rply.seek_to(self.first_frame_pos)?;
+                self.frame_number = 0;
+                self.last_checkpoint.clone_from(&self.initial_state);
+                self.skipped_checkpoints = false;
+            }
+            self.last_frame_pos = None;
+        }
+        while self.frame_number < frame {
+            self.read_frame(&mut scratch)?;
+        }
+        Ok(())
     }
-    fn write_header(&mut self) -> Result<()> {
-        use byteorder::{LittleEndian, WriteBytesExt};
-        self.header
-            .set_frame_count(u32::try_from(self.frame_number).unwrap_or_default());
-        let old_pos = self.rply.stream_position()?;
-        self.rply.seek(std::io::SeekFrom::Start(0))?;
-        self.rply.write_u32::<LittleEndian>(MAGIC)?;
-        self.rply.write_u32::<LittleEndian>(2)?;
-        self.rply
-            .write_u32::<LittleEndian>(self.header.content_crc())?;
-        // state size
-        self.rply
-            .write_u32::<LittleEndian>(self.header.initial_state_size())?;
-        self.rply
-            .write_u64::<LittleEndian>(self.header.identifier())?;
-        self.rply.write_u32::<LittleEndian>(
+
+    /* Skips the events of a frame whose backref has been read, returning whether it ends in a checkpoint */
+    fn skip_to_end_of_frame(&mut self) -> Result<bool> {
+        use byteorder::ReadBytesExt;
+        self.skip_events()?;
+        let tok = self.rply.read_u8()?;
+        match FrameToken::from(tok) {
+            FrameToken::Regular => Ok(false),
+            FrameToken::Checkpoint | FrameToken::Checkpoint2 => Ok(true),
+            FrameToken::Invalid => Err(ReplayError::BadFrameToken(tok)),
+        }
+    }
+
+    fn skip_events(&mut self) -> Result<()> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        if self.header.encrypted_sections().inputs {
+            let sealed_size = self.rply.read_u32::<LittleEndian>()?;
+            self.rply.skip(u64::from(sealed_size))?;
+        } else {
+            // Events are a few bytes a frame; seeking would drop a BufReader's buffer
+            let key_count = self.rply.read_u8()?;
+            self.rply.skip(u64::from(key_count) * 12)?;
+            let input_count = self.rply.read_u16::<LittleEndian>()?;
+            self.rply.skip(u64::from(input_count) * 8)?;
+        }
+        Ok(())
+    }
+
+    /// Lists every checkpoint after the initial state, in order, by
+    /// scanning frame and checkpoint headers without reading any event or
+    /// checkpoint payloads.  The decoder's position is unchanged.
+    /// # Errors
+    /// [`ReplayError::NoCoreRead`]: Tried to scan a version 0 replay
+    /// [`ReplayError::IO`]: Unexpected end of stream or other I/O error
+    /// [`ReplayError::BadFrameToken`]: Frame token not recognized or misaligned
+    /// [`ReplayError::Compression`]: Unsupported compression scheme
+    /// [`ReplayError::Encoding`]: Unsupported encoding scheme
+    pub fn checkpoints(&mut self) -> Result<Vec<CheckpointInfo>> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        let caps = self.header.capabilities();
+        if !caps.coreless_frames {
+            return Err(ReplayError::NoCoreRead());
+        }
+        let (pos, frame_number) = (self.rply.pos, self.frame_number);
+        let mut scan = || {
+            self.rply.seek_to(self.first_frame_pos)?;
+            self.frame_number = 0;
+            let mut checkpoints = vec![];
+            while !self.at_end()? {
+                let offset = self.rply.pos;
+                if caps.has_backrefs {
+                    let _backref = self.rply.read_u32::<LittleEndian>()?;
+                }
+                self.skip_events()?;
+                self.frame_number += 1;
+                if let Some(info) = read_checkpoint_sizes(&mut self.rply)? {
+                    self.rply.seek_to(self.rply.pos + info.compressed_size)?;
+                    checkpoints.push(CheckpointInfo {
+                        frame: self.frame_number,
+                        offset,
+                        ..info
+                    });
+                }
+            }
+            Ok(checkpoints)
+        };
+        let checkpoints = scan();
+        self.rply.seek_to(pos)?;
+        self.frame_number = frame_number;
+        checkpoints
+    }
+
+    /// Lists the checkpoints that can be decoded without reading the replay
+    /// before them: raw ones, and statestream keyframes (see
+    /// [`ReplayEncoder::set_keyframe_interval`]).  Like
+    /// [`ReplayDecoder::checkpoints`], but also reads the start of each
+    /// statestream checkpoint; encrypted ones are never listed.  The
+    /// decoder's position is unchanged.
+    /// # Errors
+    /// As [`ReplayDecoder::checkpoints`]
+    pub fn keyframes(&mut self) -> Result<Vec<CheckpointInfo>> {
+        let checkpoints = self.checkpoints()?;
+        let pos = self.rply.pos;
+        let keyframes = checkpoints
+            .into_iter()
+            .filter_map(|checkpoint| match self.is_keyframe(&checkpoint) {
+                Ok(true) => Some(Ok(checkpoint)),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect();
+        self.rply.seek_to(pos)?;
+        keyframes
+    }
+
+    /* Whether `checkpoint` decodes on its own; moves the decoder */
+    fn is_keyframe(&mut self, checkpoint: &CheckpointInfo) -> Result<bool> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        match checkpoint.encoding {
+            Encoding::Raw => return Ok(true),
+            Encoding::Statestream if !self.header.encrypted_sections().checkpoints => {}
+            _ => return Ok(false),
+        }
+        self.rply.seek_to(checkpoint.offset)?;
+        if self.header.capabilities().has_backrefs {
+            let _backref = self.rply.read_u32::<LittleEndian>()?;
+        }
+        self.skip_events()?;
+        read_checkpoint_sizes(&mut self.rply)?;
+        let (_, compressor) = self
+            .codecs
+            .get_mut(checkpoint.encoding, checkpoint.compression)?;
+        let mut payload = (&mut self.rply).take(checkpoint.compressed_size);
+        Ok(crate::statestream::is_keyframe(
+            &mut compressor.decompress(&mut payload)?,
+        ))
+    }
+
+    /// Positions the decoder so that the next [`ReplayDecoder::read_frame`]
+    /// reads the frame ending in `keyframe`, one of those listed by
+    /// [`ReplayDecoder::keyframes`], without reading anything before it.
+    /// Only a stream's header and the bytes from `keyframe.offset` on are
+    /// needed from then on, so a viewer can start from a partial download.
+    /// Until the keyframe is read, the decoder is out of step with the
+    /// replay's statestream tables, as after
+    /// [`ReplayDecoder::read_frame_skipping_checkpoints`].
+    /// # Errors
+    /// [`ReplayError::NoCoreRead`]: Tried to seek in a version 0 replay
+    /// [`ReplayError::IO`]: The stream couldn't be sought in
+    pub fn seek_to_keyframe(&mut self, keyframe: &CheckpointInfo) -> Result<()> {
+        if !self.header.capabilities().coreless_frames {
+            return Err(ReplayError::NoCoreRead());
+        }
+        self.rply.seek_to(keyframe.offset)?;
+        self.frame_number = keyframe.frame.saturating_sub(1);
+        self.last_frame_pos = None;
+        self.skipped_checkpoints = true;
+        Ok(())
+    }
+
+    /// Reads the whole replay once to build a [`SeekIndex`], then returns
+    /// to the current frame.
+    /// # Errors
+    /// As [`ReplayDecoder::checkpoints`] and [`ReplayDecoder::read_frame`]
+    pub fn build_seek_index(&mut self) -> Result<SeekIndex> {
+        let checkpoints = self.checkpoints()?;
+        let frame_number = self.frame_number;
+        /* The index needs every block; reading from the first frame again without
+        evicting brings back any that were evicted */
+        self.codecs.stop_evicting();
+        self.seek_to_frame(0)?;
+        let mut frame_offsets = vec![];
+        let mut frame = Frame::default();
+        while !self.at_end()? {
+            frame_offsets.push(self.rply.pos);
+            self.read_frame(&mut frame)?;
+        }
+        self.seek_to_frame(frame_number)?;
+        let ctx = &self.codecs.statestream.ctx;
+        Ok(SeekIndex {
+            identifier: self.header.identifier(),
+            frame_offsets,
+            checkpoints,
+            block_size: ctx.block_size(),
+            superblock_size: ctx.superblock_size(),
+            blocks: ctx.blocks().to_vec(),
+            superblocks: ctx.superblocks().to_vec(),
+        })
+    }
+
+    /// Uses a [`SeekIndex`] built for this replay, so that
+    /// [`ReplayDecoder::seek_to_frame`] can jump to any frame without
+    /// reading the frames before it.
+    /// # Errors
+    /// [`ReplayError::SeekIndex`]: The index is for another replay, or the
+    /// replay has checkpoints that depend on the one before them
+    pub fn load_seek_index(&mut self, index: SeekIndex) -> Result<()> {
+        let ctx = &mut self.codecs.statestream.ctx;
+        let usable = index.identifier == self.header.identifier()
+            && self
+                .header
+                .frame_count()
+                .is_none_or(|count| count == index.frame_offsets.len() as u64)
+            && index.block_size == ctx.block_size()
+            && index.superblock_size == ctx.superblock_size()
+            && index
+                .checkpoints
+                .iter()
+                .all(|cp| matches!(cp.encoding, Encoding::Raw | Encoding::Statestream));
+        if !usable || !ctx.restore_tables(&index.blocks, &index.superblocks) {
+            return Err(ReplayError::SeekIndex());
+        }
+        self.frame_offsets = Some(index.frame_offsets);
+        Ok(())
+    }
+
+    /// Takes a [`DecoderSnapshot`] of where the decoder is and what it has
+    /// learned from the frames before, which [`ReplayDecoder::restore`] can
+    /// return to without reading them again.  Seek implementations can keep
+    /// snapshots at checkpoints they may come back to, and parallel workers
+    /// can each open the replay and start from a different one.  Custom
+    /// codecs' own state isn't included.
+    #[must_use]
+    pub fn snapshot(&self) -> DecoderSnapshot {
+        DecoderSnapshot {
+            identifier: self.header.identifier(),
+            pos: self.rply.pos,
+            frame_number: self.frame_number,
+            last_frame_pos: self.last_frame_pos,
+            skipped_checkpoints: self.skipped_checkpoints,
+            last_checkpoint: self.last_checkpoint.clone(),
+            statestream: self.codecs.statestream.ctx.snapshot(),
+            regions: self.codecs.regions.ctx.snapshot(),
+        }
+    }
+
+    /// Returns to a [`DecoderSnapshot`] taken by this decoder or another one
+    /// reading the same replay, so that the next [`ReplayDecoder::read_frame`]
+    /// reads the frame after the snapshot's.
+    /// # Errors
+    /// [`ReplayError::Snapshot`]: The snapshot is of another replay, or one
+    /// with different statestream block sizes
+    /// [`ReplayError::IO`]: The stream couldn't be sought in
+    pub fn restore(&mut self, snapshot: &DecoderSnapshot) -> Result<()> {
+        let ctx = &self.codecs.statestream.ctx;
+        if snapshot.identifier != self.header.identifier()
+            || (ctx.block_size(), ctx.superblock_size())
+                != (
+                    snapshot.statestream.block_size(),
+                    snapshot.statestream.superblock_size(),
+                )
+        {
+            return Err(ReplayError::Snapshot());
+        }
+        self.rply.seek_to(snapshot.pos)?;
+        // Checked above, and the region tables share the same block sizes
+        self.codecs.statestream.ctx.restore(&snapshot.statestream);
+        self.codecs.regions.ctx.restore(&snapshot.regions);
+        self.frame_number = snapshot.frame_number;
+        self.last_frame_pos = snapshot.last_frame_pos;
+        self.skipped_checkpoints = snapshot.skipped_checkpoints;
+        self.last_checkpoint.clone_from(&snapshot.last_checkpoint);
+        Ok(())
+    }
+
+    /// Reads a frame like [`ReplayDecoder::read_frame`], but gets past
+    /// damage instead of failing: a frame whose checkpoint can't be
+    /// decoded is kept without it, and otherwise the decoder scans forward
+    /// for the next plausible frame (one whose event padding is clean,
+    /// whose token and checkpoint sizes are valid, and which is followed by
+    /// the end of the stream or a frame whose backref points back to it)
+    /// and reads that instead.  Each stretch of damage is added to
+    /// `damage`.  Returns false, reading nothing, at the end of the replay.
+    ///
+    /// Frames lost to damage aren't counted in
+    /// [`ReplayDecoder::frame_number`], and checkpoints encoded against
+    /// earlier ones can't be decoded after damage, so they are dropped too
+    /// until the next raw one or statestream keyframe.  Encrypted frames can't be read after
+    /// damage, since their keys depend on the frame number.  Read the
+    /// replay with [`ReplayDecoder::set_live`] if its header's frame count
+    /// may be damaged too.
+    /// # Errors
+    /// [`ReplayError::NoCoreRead`]: Tried to read a version 0 replay
+    /// [`ReplayError::IO`]: The stream couldn't be sought in; errors
+    /// reading frames count as damage, since codecs report corrupt
+    /// checkpoints as I/O errors too
+    pub fn read_frame_recovering(
+        &mut self,
+        frame: &mut Frame,
+        damage: &mut Vec<Damage>,
+    ) -> Result<bool> {
+        if !self.header.capabilities().coreless_frames {
+            return Err(ReplayError::NoCoreRead());
+        }
+        let len = self.stream_len()?;
+        loop {
+            if self.at_end()? {
+                return Ok(false);
+            }
+            let start = self.rply.pos;
+            let error = match self.read_frame(frame) {
+                Ok(()) => return Ok(true),
+                Err(e) => e.into_root(),
+            };
+            let number = self.frame_number + 1;
+            // Only the checkpoint may be bad, if the frame fits between its neighbors
+            if self
+                .frame_end(start, None, len)
+                .is_some_and(|end| self.is_boundary(end, start, len))
+            {
+                self.rply.seek_to(start)?;
+                if self.read_frame_skipping_checkpoints(frame).is_ok() {
+                    damage.push(Damage {
+                        frame: number,
+                        bytes: start..start,
+                        error,
+                    });
+                    return Ok(true);
+                }
+            }
+            let resume = (start + 1..len)
+                .find(|&pos| {
+                    self.frame_end(pos, None, len)
+                        .is_some_and(|end| self.is_boundary(end, pos, len))
+                })
+                .unwrap_or(len);
+            damage.push(Damage {
+                frame: number,
+                bytes: start..resume,
+                error,
+            });
+            self.rply.seek_to(resume)?;
+            self.last_frame_pos = None;
+            self.skipped_checkpoints = true;
+        }
+    }
+
+    /* The length of the stream, in the decoder's positions */
+    fn stream_len(&mut self) -> Result<u64> {
+        Ok(self.rply.stream_len()?)
+    }
+
+    /* Whether `pos`, the end of a frame starting at `prev`, is the end of
+    the stream or the start of a plausible frame */
+    fn is_boundary(&mut self, pos: u64, prev: u64, len: u64) -> bool {
+        pos == len || self.frame_end(pos, Some(prev), len).is_some()
+    }
+
+    /* Where a plausible frame starting at `pos` ends, if one does; its
+    backref must lead to `prev` if given */
+    fn frame_end(&mut self, pos: u64, prev: Option<u64>, len: u64) -> Option<u64> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+        self.rply.seek_to(pos).ok()?;
+        if self.header.capabilities().has_backrefs {
+            let backref = u64::from(self.rply.read_u32::<LittleEndian>().ok()?);
+            let plausible = match prev {
+                Some(prev) => pos - prev == backref,
+                // Only the first frame has no backref
+                None => {
+                    pos.checked_sub(backref)? >= self.first_frame_pos
+                        && (backref > 0 || pos == self.first_frame_pos)
+                }
+            };
+            if !plausible {
+                return None;
+            }
+        }
+        if self.header.encrypted_sections().inputs {
+            let sealed_size = self.rply.read_u32::<LittleEndian>().ok()?;
+            self.rply
+                .seek_to(self.rply.pos + u64::from(sealed_size))
+                .ok()?;
+        } else {
+            let mut scratch = Frame::default();
+            let clean = read_key_events(&mut self.rply, &mut scratch, &self.limits).ok()?
+                && read_input_events(&mut self.rply, &mut scratch, &self.limits).ok()?;
+            if !clean {
+                return None;
+            }
+        }
+        let payload = read_checkpoint_sizes(&mut self.rply)
+            .ok()?
+            .map_or(0, |sizes| sizes.compressed_size);
+        let end = self.rply.pos.checked_add(payload)?;
+        (end <= len).then_some(end)
+    }
+}
+
+/// A stretch of a damaged replay that [`ReplayDecoder::read_frame_recovering`] got past.
+#[derive(Debug)]
+pub struct Damage {
+    /// The frame being read when the damage was found, numbered from 1
+    pub frame: u64,
+    /// The bytes skipped to reach the next plausible frame; empty if the
+    /// frame was kept without its checkpoint
+    pub bytes: std::ops::Range<u64>,
+    /// Why the frame couldn't be read
+    pub error: ReplayError,
+}
+
+/// Where a checkpoint is stored, as listed by [`ReplayDecoder::checkpoints`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointInfo {
+    /// Frames preceding the checkpointed state; after
+    /// [`ReplayDecoder::seek_to_frame`]`(frame)`, the core should be in this state
+    pub frame: u64,
+    /// Stream offset of the frame ending in the checkpoint
+    pub offset: u64,
+    pub compression: Compression,
+    pub encoding: Encoding,
+    /// Size of the stored checkpoint payload
+    pub compressed_size: u64,
+    /// Size of the decoded savestate
+    pub uncompressed_size: u64,
+}
+
+/// A [`ReplayDecoder`]'s position and statestream tables, from
+/// [`ReplayDecoder::snapshot`].  Snapshots can be cloned and sent to other
+/// threads, but hold a copy of every block the decoder knows, so keep only
+/// as many as needed.
+#[derive(Clone)]
+pub struct DecoderSnapshot {
+    identifier: u64,
+    pos: u64,
+    frame_number: u64,
+    last_frame_pos: Option<u64>,
+    skipped_checkpoints: bool,
+    last_checkpoint: Vec<u8>,
+    statestream: crate::statestream::CtxSnapshot,
+    regions: crate::statestream::CtxSnapshot,
+}
+
+impl DecoderSnapshot {
+    /// Frames read before the snapshot was taken
+    #[must_use]
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
+    }
+    /// Bytes of statestream blocks and superblocks the snapshot holds
+    #[must_use]
+    pub fn table_bytes(&self) -> usize {
+        self.statestream.table_bytes() + self.regions.table_bytes()
+    }
+}
+
+/// Iterator over the frames of a [`ReplayDecoder`], created by [`ReplayDecoder::frames`].
+pub struct Frames<'d, R: std::io::BufRead> {
+    decoder: &'d mut ReplayDecoder<R>,
+    failed: bool,
+}
+
+impl<R: std::io::BufRead> Iterator for Frames<'_, R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.decoder.at_end() {
+            Ok(true) => return None,
+            Ok(false) => {}
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        let mut frame = self.decoder.new_frame();
+        let result = self.decoder.read_frame(&mut frame);
+        self.failed = result.is_err();
+        Some(result.map(|()| frame))
+    }
+}
+
+/* The larger of `count` and a slowly decaying `max`, so one unusually busy frame doesn't oversize every later one */
+fn rolling_max(max: usize, count: usize) -> usize {
+    count.max(max - max.div_ceil(64))
+}
+
+/* Reads the fixed-size part of a header, leaving any metadata block unread */
+fn read_header<R: std::io::Read>(rply: &mut R) -> Result<Header> {
+    use byteorder::{LittleEndian, ReadBytesExt};
+    let magic = rply.read_u32::<LittleEndian>()?;
+    if magic != MAGIC {
+        return Err(ReplayError::Magic(magic));
+    }
+    let version = rply.read_u32::<LittleEndian>()?;
+    if !crate::format::capabilities(version).supported {
+        return Err(ReplayError::Version(version));
+    }
+    let content_crc = rply.read_u32::<LittleEndian>()?;
+    let initial_state_size = rply.read_u32::<LittleEndian>()?;
+    let identifier = rply.read_u64::<LittleEndian>()?;
+    let base = HeaderBase {
+        version,
+        content_crc,
+        initial_state_size,
+        identifier,
+    };
+    if version < 2 {
+        return Ok(Header::V0V1(base));
+    }
+    let frame_count = rply.read_u32::<LittleEndian>()?;
+    let block_size = rply.read_u32::<LittleEndian>()?;
+    let superblock_size = rply.read_u32::<LittleEndian>()?;
+    let cp_config = rply.read_u32::<LittleEndian>()?;
+    let checkpoint_commit_interval = (cp_config >> 24) as u8;
+    let checkpoint_commit_threshold = ((cp_config >> 16) & 0xFF) as u8;
+    let checkpoint_compression =
+        Compression::try_from(((cp_config >> 8) & 0xFF) as u8).map_err(ReplayError::Compression)?;
+    let encrypted_sections = EncryptedSections::try_from((cp_config & 0xFF) as u8)
+        .map_err(ReplayError::EncryptedSections)?;
+    Ok(Header::V2(HeaderV2 {
+        base,
+        frame_count,
+        block_size,
+        superblock_size,
+        checkpoint_commit_interval,
+        checkpoint_commit_threshold,
+        checkpoint_compression,
+        encrypted_sections,
+        metadata: Metadata::default(),
+    }))
+}
+
+/// Parses the header at the start of a replay file without allocating or
+/// reading further, for listing many replays quickly.  Only the first 24
+/// bytes are used for version 0 and 1 replays, and a version 3 replay's
+/// metadata, which follows these 40 bytes, is left empty.
+///
+/// # Errors
+/// [`ReplayError::Magic`]: Invalid magic number at beginning of file
+/// [`ReplayError::Version`]: Version identifier not recognized by parser
+/// [`ReplayError::Compression`]: Unsupported compression scheme for checkpoints
+/// [`ReplayError::EncryptedSections`]: Unrecognized encryption flags
+pub fn parse_header(bytes: &[u8; HEADERV2_LEN_BYTES]) -> Result<Header> {
+    read_header(&mut bytes.as_slice())
+}
+
+/// Creates a [`ReplayDecoder`] for the given buffered readable stream.
+///
+/// # Errors
+/// See [`ReplayDecoder::new`].
+pub fn decode<R: std::io::BufRead>(rply: R) -> Result<ReplayDecoder<R>> {
+    ReplayDecoder::new(rply)
+}
+
+/* Called with each checkpoint that jumps in size */
+type SizeAlert<'a> = Box<dyn FnMut(&SizeJump) + 'a>;
+
+/// Hears about each frame and checkpoint a [`ReplayEncoder`] writes, with
+/// their sizes and how long they took, so that recording frontends can
+/// show the compression ratio and bandwidth as they go; see
+/// [`ReplayEncoder::set_events`].  Both methods do nothing by default.
+pub trait EncoderEvents {
+    /// Called after each frame is written, after
+    /// [`EncoderEvents::on_checkpoint`] if it ends in one
+    fn on_frame(&mut self, _frame: &WrittenFrame) {}
+    /// Called after each frame ending in a checkpoint is written; not for
+    /// the initial state, which is written before events can be set
+    fn on_checkpoint(&mut self, _checkpoint: &EncodedCheckpoint) {}
+}
+
+/* Frame events for `ReplayEncoder::set_frame_hook` */
+struct FrameHook<F>(F);
+
+impl<F: FnMut(&WrittenFrame)> EncoderEvents for FrameHook<F> {
+    fn on_frame(&mut self, frame: &WrittenFrame) {
+        (self.0)(frame);
+    }
+}
+
+/// A frame [`ReplayEncoder::write_frame`] has written, as passed to
+/// [`EncoderEvents::on_frame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrittenFrame {
+    /// Its number, counting from 0, which indexes
+    /// [`SeekIndex::frame_offsets`]
+    pub frame: u64,
+    /// Stream offset it starts at, as a decoder counts it; with
+    /// [`Metadata::frame_packing`], an offset into the unpacked frames
+    pub offset: u64,
+    /// Bytes it takes up, counted like `offset`
+    pub len: u64,
+    /// Whether it ends in a checkpoint
+    pub checkpoint: bool,
+    /// How long encoding and writing it took, in microseconds
+    pub micros: u64,
+}
+
+/// A checkpoint [`ReplayEncoder::write_frame`] has written, as passed to
+/// [`EncoderEvents::on_checkpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodedCheckpoint {
+    pub sizes: CheckpointSizes,
+    pub encoding: Encoding,
+    pub compression: Compression,
+    /// How long encoding and compressing it took, in microseconds
+    pub micros: u64,
+}
+
+pub struct ReplayEncoder<'a, W: std::io::Write + std::io::Seek> {
+    rply: &'a mut W,
+    pub header: Header,
+    pub frame_number: u64,
+    last_pos: u64,
+    codecs: Codecs,
+    checkpoint_encoding: Encoding,
+    last_checkpoint: Vec<u8>,
+    finished: bool,
+    cipher: Option<Cipher>,
+    last_sizes: Option<CheckpointSizes>,
+    size_alert: Option<(SizeTrend, SizeAlert<'a>)>,
+    keyframe_interval: Option<u64>,
+    /* Frame of the last statestream keyframe, or 0 for the initial state */
+    last_keyframe: u64,
+    /* Where each frame is built before it's written or packed */
+    scratch: Vec<u8>,
+    packer: Option<FramePacker>,
+    header_flush: Option<HeaderFlushing<W>>,
+    rollback: bool,
+    /* Whether a checkpoint failed to be written without rollback */
+    out_of_step: bool,
+    events: Option<Box<dyn EncoderEvents + 'a>>,
+    /* The checkpoint of the frame being written, for `events` */
+    encoded_checkpoint: Option<EncodedCheckpoint>,
+    frame_offsets: Option<Vec<u64>>,
+    /* Whether frames are written without their checkpoints */
+    input_only: bool,
+}
+
+/* Where a frame being written started: the stream position, and with frame
+packing, how much of the pack being filled came before it */
+#[derive(Clone, Copy)]
+struct WriteMark {
+    pos: u64,
+    packed: usize,
+}
+
+/* What encoding a checkpoint changes, kept so that a frame that fails to
+be written can be taken back (see `ReplayEncoder::set_rollback`) */
+struct CheckpointUndo {
+    statestream: crate::statestream::CtxSnapshot,
+    regions: crate::statestream::CtxSnapshot,
+    last_checkpoint: Vec<u8>,
+    last_sizes: Option<CheckpointSizes>,
+    last_keyframe: u64,
+    trend: Option<SizeTrend>,
+}
+
+/// How often [`ReplayEncoder::set_header_flush`] rewrites the header with
+/// the frames written so far and syncs the replay to disk; either limit
+/// reached starts a flush.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeaderFlush {
+    /// After this many frames since the last flush
+    pub frames: Option<u64>,
+    /// Once this much time has passed since the last flush, checked as
+    /// each frame is written
+    pub interval: Option<std::time::Duration>,
+}
+
+/// Streams that can be made durable, so that what was written to them
+/// survives a crash or power loss; see [`ReplayEncoder::set_header_flush`].
+pub trait SyncData {
+    /// Makes what was written so far durable, like [`std::fs::File::sync_data`].
+    /// # Errors
+    /// If the stream can't be flushed or synced
+    fn sync_data(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncData for std::fs::File {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        std::fs::File::sync_data(self)
+    }
+}
+
+impl<W: std::io::Write + SyncData> SyncData for std::io::BufWriter<W> {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        std::io::Write::flush(self)?;
+        self.get_mut().sync_data()
+    }
+}
+
+/// In memory there is nothing to sync.
+impl<T> SyncData for std::io::Cursor<T> {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: SyncData + ?Sized> SyncData for &mut S {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        (**self).sync_data()
+    }
+}
+
+/* A `HeaderFlush` schedule and where it stands */
+struct HeaderFlushing<W> {
+    every: HeaderFlush,
+    sync: fn(&mut W) -> std::io::Result<()>,
+    last_frame: u64,
+    last_time: std::time::Instant,
+}
+
+/* Unpacked bytes that make a frame pack worth closing, so that a cut-off
+replay loses few frames */
+const PACK_BYTES: usize = 64 * 1024;
+
+/* Frames waiting to be written as one pack, for replays with
+`Metadata::frame_packing`; see `CountingReader::start_packing` */
+struct FramePacker {
+    compressors: Compressors,
+    compression: Compression,
+    /* Offset into the unpacked frames of the first byte of `frames` */
+    start: u64,
+    frames: Vec<u8>,
+    packed: Vec<u8>,
+}
+
+impl FramePacker {
+    fn flush(&mut self, rply: &mut dyn std::io::Write, compression: Compression) -> Result<()> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+        if self.frames.is_empty() {
+            return Ok(());
+        }
+        let payload = if compression == Compression::None {
+            &self.frames
+        } else {
+            let compressor = self
+                .compressors
+                .get(compression)
+                .ok_or(ReplayError::Compression(InvalidDeterminant(u8::from(
+                    compression,
+                ))))?;
+            self.packed.clear();
+            let mut compressing = compressor.compress(&mut self.packed)?;
+            compressing.write_all(&self.frames)?;
+            compressing.finish()?;
+            &self.packed
+        };
+        rply.write_u8(u8::from(compression))?;
+        rply.write_u32::<LittleEndian>(
+            u32::try_from(payload.len()).map_err(ReplayError::FrameTooLong)?,
+        )?;
+        rply.write_u32::<LittleEndian>(
+            u32::try_from(self.frames.len()).map_err(ReplayError::FrameTooLong)?,
+        )?;
+        rply.write_all(payload)?;
+        self.start += self.frames.len() as u64;
+        self.frames.clear();
+        Ok(())
+    }
+}
+
+impl<'w, W: std::io::Write + std::io::Seek> ReplayEncoder<'w, W> {
+    /// Creates a [`ReplayEncoder`] for the given writable and seekable stream.
+    ///
+    /// # Errors
+    /// [`ReplayError::IO`]: Some issue with the write stream, e.g. unexpected end
+    /// [`ReplayError::Version`]: Version identifier not supported by writer
+    /// [`ReplayError::Compression`]: Unsupported compression scheme for checkpoints
+    /// [`ReplayError::MissingKey`]: The header has encrypted sections but no key was given
+    pub fn new<'s>(
+        header: Header,
+        initial_state: &'s [u8],
+        rply: &'w mut W,
+    ) -> Result<ReplayEncoder<'w, W>> {
+        Self::with_codecs(header, initial_state, rply, CodecRegistry::default())
+    }
+    /// Creates a [`ReplayEncoder`] which can also use the codecs and
+    /// compressors in `codecs`, including for the initial state.
+    ///
+    /// # Errors
+    /// See [`ReplayEncoder::new`].
+    pub fn with_codecs<'s>(
+        header: Header,
+        initial_state: &'s [u8],
+        rply: &'w mut W,
+        codecs: CodecRegistry,
+    ) -> Result<ReplayEncoder<'w, W>> {
+        if !(2..=3).contains(&header.version()) {
+            return Err(ReplayError::Version(header.version()));
+        }
+        let version = if header.metadata().is_none_or(Metadata::is_empty) {
+            2
+        } else {
+            3
+        };
+        Self::create(header, initial_state, rply, codecs, version)
+    }
+    /// Creates a [`ReplayEncoder`] which writes exactly the given format
+    /// version, so that tools can target the oldest readers a replay needs.
+    /// Version 1 replays store checkpoints raw, so checkpoints are written
+    /// with [`Encoding::Raw`]; version 2 replays have no metadata; version 3
+    /// replays are written even if the metadata is empty.
+    ///
+    /// # Errors
+    /// See [`ReplayEncoder::new`], and:
+    /// [`ReplayError::VersionFeature`]: The header uses a feature `version` can't store, e.g. compression or encryption in version 1
+    pub fn with_version<'s>(
+        header: Header,
+        initial_state: &'s [u8],
+        rply: &'w mut W,
+        version: u32,
+    ) -> Result<ReplayEncoder<'w, W>> {
+        let caps = crate::format::capabilities(version);
+        let unsupported = if header.metadata().is_some_and(|m| !m.is_empty()) && !caps.has_metadata
+        {
+            Some("metadata")
+        } else if !caps.has_compression && header.checkpoint_compression() != Compression::None {
+            Some("compressed checkpoints")
+        } else if !caps.has_encryption && header.encrypted_sections().any() {
+            Some("encrypted sections")
+        } else {
+            None
+        };
+        if let Some(feature) = unsupported {
+            return Err(ReplayError::VersionFeature(version, feature));
+        }
+        Self::create(
+            header,
+            initial_state,
+            rply,
+            CodecRegistry::default(),
+            version,
+        )
+    }
+    fn create(
+        mut header: Header,
+        initial_state: &[u8],
+        rply: &'w mut W,
+        codecs: CodecRegistry,
+        version: u32,
+    ) -> Result<ReplayEncoder<'w, W>> {
+        if version == 1 {
+            let mut base = match header {
+                Header::V0V1(base) => base,
+                Header::V2(v2) => v2.base,
+            };
+            base.version = 1;
+            base.initial_state_size =
+                u32::try_from(initial_state.len()).map_err(ReplayError::CheckpointTooBig)?;
+            let mut replay = ReplayEncoder {
+                rply,
+                header: Header::V0V1(base),
+                frame_number: 0,
+                last_pos: 0,
+                codecs: Codecs::new(1, 1, codecs),
+                checkpoint_encoding: Encoding::Raw,
+                last_checkpoint: vec![],
+                // Until it's set up, so that failing leaves the stream alone
+                finished: true,
+                cipher: None,
+                last_sizes: None,
+                size_alert: None,
+                keyframe_interval: None,
+                last_keyframe: 0,
+                scratch: vec![],
+                packer: None,
+                header_flush: None,
+                rollback: false,
+                out_of_step: false,
+                events: None,
+                encoded_checkpoint: None,
+                frame_offsets: None,
+                input_only: false,
+            };
+            replay.write_header()?;
+            replay
+                .rply
+                .seek(std::io::SeekFrom::Start(HEADERV1_LEN_BYTES as u64))?;
+            replay.rply.write_all(initial_state)?;
+            replay.last_pos = replay.rply.stream_position()?;
+            replay.finished = false;
+            return Ok(replay);
+        }
+        if !(2..=3).contains(&version) {
+            return Err(ReplayError::Version(version));
+        }
+        header.upgrade().base.version = version;
+        let mut codecs = Codecs::new(header.block_size(), header.superblock_size(), codecs);
+        if let Some(metadata) = header.metadata() {
+            codecs.load_zstd_dictionary(metadata);
+            codecs.load_state_regions(metadata);
+            if metadata.block_commits() {
+                codecs.set_commit_settings(
+                    header.checkpoint_commit_interval(),
+                    header.checkpoint_commit_threshold(),
+                );
+            }
+        }
+        codecs.regions.default.1 = header.checkpoint_compression();
+        let cipher = if header.encrypted_sections().any() {
+            Some(Cipher::generate(&codecs)?.ok_or(ReplayError::MissingKey())?)
+        } else {
+            None
+        };
+        let mut replay = ReplayEncoder {
+            rply,
+            header,
+            frame_number: 0,
+            last_pos: 0,
+            codecs,
+            checkpoint_encoding: Encoding::Statestream,
+            last_checkpoint: vec![],
+            finished: true,
+            cipher,
+            last_sizes: None,
+            size_alert: None,
+            keyframe_interval: None,
+            last_keyframe: 0,
+            scratch: vec![],
+            packer: None,
+            header_flush: None,
+            rollback: false,
+            out_of_step: false,
+            events: None,
+            encoded_checkpoint: None,
+            frame_offsets: None,
+            input_only: false,
+        };
+        replay.write_header()?;
+        replay
+            .rply
+            .seek(std::io::SeekFrom::Start(HEADERV2_LEN_BYTES as u64))?;
+        if let Header::V2(v2) = &replay.header
+            && v2.base.version > 2
+        {
+            v2.metadata.write(replay.rply)?;
+        }
+        if !initial_state.is_empty() || replay.cipher.is_some() {
+            replay.encode_initial_checkpoint(initial_state)?;
+        }
+        replay.last_pos = replay.rply.stream_position()?;
+        if let Some(compression) = replay.header.metadata().and_then(Metadata::frame_packing) {
+            let compressors = Compressors::default();
+            if compressors.get(compression).is_none() {
+                return Err(ReplayError::Compression(InvalidDeterminant(u8::from(
+                    compression,
+                ))));
+            }
+            replay.packer = Some(FramePacker {
+                compressors,
+                compression,
+                start: replay.last_pos,
+                frames: vec![],
+                packed: vec![],
+            });
+        }
+        replay.finished = false;
+        Ok(replay)
+    }
+    /// Changes the metadata already written with the header, e.g. to fill
+    /// in a fixed-size chunk once it's known; the change must keep the
+    /// metadata block the same size, since frames follow it.
+    /// # Errors
+    /// [`ReplayError::MetadataResized`]: The update grew or shrank the metadata, which is left as it was
+    /// [`ReplayError::IO`]: Rewriting the metadata failed
+    pub fn update_metadata(&mut self, update: impl FnOnce(&mut Metadata)) -> Result<()> {
+        let Header::V2(v2) = &mut self.header else {
+            return Err(ReplayError::MetadataResized());
+        };
+        let mut metadata = v2.metadata.clone();
+        update(&mut metadata);
+        if v2.base.version < 3 || metadata.encoded_len() != v2.metadata.encoded_len() {
+            return Err(ReplayError::MetadataResized());
+        }
+        let old_pos = self.rply.stream_position()?;
+        self.rply
+            .seek(std::io::SeekFrom::Start(HEADERV2_LEN_BYTES as u64))?;
+        metadata.write(self.rply)?;
+        self.rply.seek(std::io::SeekFrom::Start(old_pos))?;
+        v2.metadata = metadata;
+        Ok(())
+    }
+    fn write_header(&mut self) -> Result<()> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+        let old_pos = self.rply.stream_position()?;
+        self.rply.seek(std::io::SeekFrom::Start(0))?;
+        self.rply.write_u32::<LittleEndian>(MAGIC)?;
+        self.rply.write_u32::<LittleEndian>(self.header.version())?;
+        self.rply
+            .write_u32::<LittleEndian>(self.header.content_crc())?;
+        // state size
+        self.rply
+            .write_u32::<LittleEndian>(self.header.initial_state_size())?;
+        self.rply
+            .write_u64::<LittleEndian>(self.header.identifier())?;
+        if self.header.version() < 2 {
+            // Version 1 headers end here; there is no frame count to update
+            self.rply.seek(std::io::SeekFrom::Start(old_pos))?;
+            return Ok(());
+        }
+        self.header
+            .set_frame_count(u32::try_from(self.frame_number).unwrap_or_default());
+        self.rply.write_u32::<LittleEndian>(
             u32::try_from(self.header.frame_count().unwrap())
                 .map_err(ReplayError::TooManyFrames)?,
         )?;
@@ -528,97 +2231,118 @@ impl<'w, W: std::io::Write + std::io::Seek> ReplayEncoder<'w, W> {
         let cp_interval = u32::from(self.header.checkpoint_commit_interval());
         let cp_threshold = u32::from(self.header.checkpoint_commit_threshold());
         let cp_compression = u32::from(u8::from(self.header.checkpoint_compression()));
+        let encrypted = u32::from(u8::from(self.header.encrypted_sections()));
         self.rply.write_u32::<LittleEndian>(
-            (cp_interval << 24) | (cp_threshold << 16) | (cp_compression << 8),
+            (cp_interval << 24) | (cp_threshold << 16) | (cp_compression << 8) | encrypted,
         )?;
         self.rply.seek(std::io::SeekFrom::Start(old_pos))?;
         Ok(())
     }
-    fn encode_checkpoint(&mut self, checkpoint: &[u8], frame: u64) -> Result<()> {
+    fn encode_checkpoint(
+        &mut self,
+        out: &mut Vec<u8>,
+        checkpoint: &[u8],
+        frame: u64,
+        section: Section,
+    ) -> Result<()> {
         use byteorder::{LittleEndian, WriteBytesExt};
-        let stopwatch = clock::time(Timer::EncodeCheckpoint);
-        let compression = self.header.checkpoint_compression();
-        let encoding = Encoding::Statestream;
-        self.rply.write_u8(u8::from(compression))?;
-        self.rply.write_u8(u8::from(encoding))?;
+        let stopwatch = self.codecs.stats.time(Timer::EncodeCheckpoint);
+        let started = std::time::Instant::now();
+        let encoding = self.checkpoint_encoding;
+        // Regions compress themselves
+        let compression = if encoding == Encoding::Regions {
+            Compression::None
+        } else {
+            self.header.checkpoint_compression()
+        };
+        // Counted like `CheckpointInfo::frame`, through the checkpoint's frame
+        let through = frame + 1;
+        if encoding == Encoding::Statestream
+            && matches!(section, Section::Checkpoint)
+            && self
+                .keyframe_interval
+                .is_some_and(|interval| through - self.last_keyframe >= interval)
+        {
+            self.codecs.statestream.ctx.request_keyframe();
+            self.last_keyframe = through;
+        }
+        let (codec, compressor) = self.codecs.get_mut(encoding, compression)?;
+        out.write_u8(u8::from(compression))?;
+        out.write_u8(u8::from(encoding))?;
         // write unencoded uncompressed size
         let full_size = u32::try_from(checkpoint.len()).map_err(ReplayError::CheckpointTooBig)?;
-        self.rply.write_u32::<LittleEndian>(full_size)?;
-        let size_pos = self.rply.stream_position()?;
+        out.write_u32::<LittleEndian>(full_size)?;
+        let size_pos = out.len();
         // can't yet write encoded uncompressed size, just write zeros for now
         // write encoded compressed size
-        self.rply.write_u32::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>(0)?;
         // write encoded compressed bytes
-        self.rply.write_u32::<LittleEndian>(0)?;
-        let (encoded_size, compressed_size) = match (compression, encoding) {
-            (Compression::None, Encoding::Raw) => {
-                self.rply.write_all(checkpoint)?;
-                (full_size, full_size)
-            }
-            (Compression::None, Encoding::Statestream) => {
-                let encoder = statestream::Encoder::new(&mut self.rply, &mut self.ss_state);
-                let encoded_size = encoder.encode_checkpoint(checkpoint, frame)?;
-                (encoded_size, encoded_size)
-            }
-            (Compression::Zlib, Encoding::Raw) => {
-                use flate2::write::ZlibEncoder;
-                let here_pos = self.rply.stream_position()?;
-                let mut encoder = ZlibEncoder::new(&mut self.rply, flate2::Compression::default());
-                let encoded_size = full_size;
-                encoder.write_all(checkpoint)?;
-                encoder.finish()?;
-                let compressed_size = u32::try_from(self.rply.stream_position()? - here_pos)
-                    .map_err(ReplayError::CheckpointTooBig)?;
-                (encoded_size, compressed_size)
-            }
-            (Compression::Zlib, Encoding::Statestream) => {
-                use flate2::write::ZlibEncoder;
-                let here_pos = self.rply.stream_position()?;
-                let mut compressor =
-                    ZlibEncoder::new(&mut self.rply, flate2::Compression::default());
-                let encoder = statestream::Encoder::new(&mut compressor, &mut self.ss_state);
-                let encoded_size = encoder.encode_checkpoint(checkpoint, frame)?;
-                compressor.finish()?;
-                let compressed_size = u32::try_from(self.rply.stream_position()? - here_pos)
-                    .map_err(ReplayError::CheckpointTooBig)?;
-                (encoded_size, compressed_size)
-            }
-            (Compression::Zstd, Encoding::Raw) => {
-                let here_pos = self.rply.stream_position()?;
-                let mut encoder = zstd::Encoder::new(&mut self.rply, 16)?;
-                encoder.write_all(checkpoint)?;
-                encoder.finish()?;
-                let encoded_size = full_size;
-                let compressed_size = u32::try_from(self.rply.stream_position()? - here_pos)
-                    .map_err(ReplayError::CheckpointTooBig)?;
-                (encoded_size, compressed_size)
+        out.write_u32::<LittleEndian>(0)?;
+        let cx = CheckpointContext {
+            frame,
+            previous: &self.last_checkpoint,
+        };
+        let here_pos = out.len();
+        let encoded_size = match &self.cipher {
+            Some(cipher) if self.header.encrypted_sections().checkpoints => {
+                let mut sealed = vec![];
+                let mut compressing = compressor.compress(&mut sealed)?;
+                let encoded_size = codec.encode(&mut *compressing, checkpoint, &cx)?;
+                compressing.finish()?;
+                cipher.seal(section, frame, &mut sealed)?;
+                out.extend_from_slice(&sealed);
+                encoded_size
             }
-            (Compression::Zstd, Encoding::Statestream) => {
-                let here_pos = self.rply.stream_position()?;
-                let mut compressor = zstd::Encoder::new(&mut self.rply, 16)?;
-                let encoder = statestream::Encoder::new(&mut compressor, &mut self.ss_state);
-                let encoded_size = encoder.encode_checkpoint(checkpoint, frame)?;
-                compressor.finish()?;
-                let compressed_size = u32::try_from(self.rply.stream_position()? - here_pos)
-                    .map_err(ReplayError::CheckpointTooBig)?;
-                (encoded_size, compressed_size)
+            _ => {
+                let mut compressing = compressor.compress(&mut *out)?;
+                let encoded_size = codec.encode(&mut *compressing, checkpoint, &cx)?;
+                compressing.finish()?;
+                encoded_size
             }
         };
-        let end_pos = self.rply.stream_position()?;
-        self.rply.seek(std::io::SeekFrom::Start(size_pos))?;
+        let compressed_size =
+            u32::try_from(out.len() - here_pos).map_err(ReplayError::CheckpointTooBig)?;
+        self.last_checkpoint.clear();
+        self.last_checkpoint.extend_from_slice(checkpoint);
+        let sizes = CheckpointSizes {
+            // Frames are numbered from 1, after the initial state
+            frame: if matches!(section, Section::InitialState) {
+                0
+            } else {
+                frame + 1
+            },
+            uncompressed: u64::from(full_size),
+            compressed: u64::from(compressed_size),
+        };
+        self.last_sizes = Some(sizes);
+        self.encoded_checkpoint = Some(EncodedCheckpoint {
+            sizes,
+            encoding,
+            compression,
+            micros: u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX),
+        });
+        if let Some((trend, alert)) = &mut self.size_alert
+            && let Some(jump) = trend.observe(sizes)
+        {
+            alert(&jump);
+        }
         // write encoded compressed size
-        self.rply.write_u32::<LittleEndian>(encoded_size)?;
+        out[size_pos..size_pos + 4].copy_from_slice(&encoded_size.to_le_bytes());
         // write encoded compressed bytes
-        self.rply.write_u32::<LittleEndian>(compressed_size)?;
-        self.rply.seek(std::io::SeekFrom::Start(end_pos))?;
+        out[size_pos + 4..size_pos + 8].copy_from_slice(&compressed_size.to_le_bytes());
         drop(stopwatch);
         Ok(())
     }
     fn encode_initial_checkpoint(&mut self, checkpoint: &[u8]) -> Result<()> {
-        self.rply
-            .seek(std::io::SeekFrom::Start(HEADERV2_LEN_BYTES as u64))?;
-        self.encode_checkpoint(checkpoint, 0)?;
-        let encoded_size = self.rply.stream_position()? - HEADERV2_LEN_BYTES as u64;
+        let mut out = std::mem::take(&mut self.scratch);
+        out.clear();
+        if let Some(cipher) = &self.cipher {
+            out.extend_from_slice(cipher.salt());
+        }
+        self.encode_checkpoint(&mut out, checkpoint, 0, Section::InitialState)?;
+        self.rply.write_all(&out)?;
+        let encoded_size = out.len();
+        self.scratch = out;
         self.header.set_initial_state_size(
             u32::try_from(encoded_size).map_err(ReplayError::CheckpointTooBig)?,
         );
@@ -628,51 +2352,496 @@ impl<'w, W: std::io::Write + std::io::Seek> ReplayEncoder<'w, W> {
         Ok(())
     }
 
-    /// Writes a single frame at the current encoder position.
+    /// Adds the blocks of `state`, e.g. a savestate the player's copy of the
+    /// game ships with, to the statestream tables without writing them, so
+    /// that checkpoints resembling it come out small.  The initial state's
+    /// blocks are already in the tables.  Decoders must be given the same
+    /// states with [`ReplayDecoder::seed_tables`] before reading any frame;
+    /// RetroArch can't read such replays.  Checkpoints in
+    /// [`Encoding::Regions`] don't use the seeded blocks.
+    /// # Errors
+    /// [`ReplayError::LateSeed`]: A frame was already written
+    /// [`ReplayError::VersionFeature`]: Version 1 replays have no statestream checkpoints
+    pub fn seed_tables(&mut self, state: &[u8]) -> Result<()> {
+        if self.frame_number > 0 {
+            return Err(ReplayError::LateSeed());
+        }
+        if !self.header.capabilities().has_statestream {
+            return Err(ReplayError::VersionFeature(
+                self.header.version(),
+                "seeded statestream tables",
+            ));
+        }
+        self.codecs.seed(state);
+        Ok(())
+    }
+    /// Chooses how subsequent checkpoints are encoded (the initial state is
+    /// always encoded when the encoder is created, using the default
+    /// [`Encoding::Statestream`]).
+    pub fn set_checkpoint_encoding(&mut self, encoding: Encoding) {
+        self.checkpoint_encoding = encoding;
+    }
+    #[must_use]
+    pub fn checkpoint_encoding(&self) -> Encoding {
+        self.checkpoint_encoding
+    }
+    /// Chooses the encoding and compression of each of the header's
+    /// [`Metadata::state_regions`], in order, for checkpoints encoded with
+    /// [`Encoding::Regions`]: e.g. raw for small register blocks and
+    /// statestream with zstd for RAM.  Regions that share settings are
+    /// encoded together.  Bytes outside the regions, and regions past the
+    /// end of `encodings`, are statestream encoded with the header's
+    /// compression.  Only the built-in encodings other than
+    /// [`Encoding::Regions`] and the built-in compressors can be used.
+    pub fn set_region_encodings(&mut self, encodings: Vec<(Encoding, Compression)>) {
+        self.codecs.regions.set_encodings(encodings);
+    }
+    /// Timer and counter totals for this encoder alone.
+    #[must_use]
+    pub fn stats(&self) -> &Stats {
+        &self.codecs.stats
+    }
+    /// Bytes of blocks and superblocks held in the statestream tables
+    /// (including those of [`Encoding::Regions`]), not counting evicted
+    /// ones.  After each checkpoint this is within the memory budget (see
+    /// [`ReplayEncoder::set_memory_budget`]), except for the objects of the
+    /// initial state and of that checkpoint, which can't be evicted.
+    #[must_use]
+    pub fn table_bytes(&self) -> usize {
+        self.codecs.table_bytes()
+    }
+    /// Bytes written to the stream so far, header included; with
+    /// [`Metadata::frame_packing`], frames not yet packed aren't counted.
+    /// # Errors
+    /// [`ReplayError::IO`]: The stream could not report its position
+    pub fn bytes_written(&mut self) -> Result<u64> {
+        Ok(self.rply.stream_position()?)
+    }
+    /// The sizes of the last checkpoint encoded, including the initial state.
+    #[must_use]
+    pub fn last_checkpoint_sizes(&self) -> Option<CheckpointSizes> {
+        self.last_sizes
+    }
+    /// Calls `alert` as [`ReplayEncoder::write_frame`] encodes a checkpoint
+    /// whose size is over `threshold` times the recent average (see
+    /// [`SizeTrend`]), so a recorder can warn the user or react to a core
+    /// in a pathological state before the replay balloons.
+    pub fn set_size_alert(&mut self, threshold: f64, alert: impl FnMut(&SizeJump) + 'w) {
+        let mut trend = SizeTrend::new(threshold);
+        // The initial state counts towards the average
+        if let Some(sizes) = self.last_sizes {
+            trend.observe(sizes);
+        }
+        self.size_alert = Some((trend, Box::new(alert)));
+    }
+    /// Stops size alerts.
+    pub fn clear_size_alert(&mut self) {
+        self.size_alert = None;
+    }
+    /// Tells `events` about each frame and checkpoint
+    /// [`ReplayEncoder::write_frame`] writes from here on, replacing any
+    /// events or frame hook set before.  With [`Metadata::frame_packing`],
+    /// a frame may still be waiting to be packed when it's reported.
+    pub fn set_events(&mut self, events: impl EncoderEvents + 'w) {
+        self.events = Some(Box::new(events));
+    }
+    /// Calls `hook` with each frame [`ReplayEncoder::write_frame`] writes,
+    /// so a writer can build a seek index or show progress as it goes
+    /// rather than reading the replay again afterwards; shorthand for
+    /// [`ReplayEncoder::set_events`] with only [`EncoderEvents::on_frame`].
+    pub fn set_frame_hook(&mut self, hook: impl FnMut(&WrittenFrame) + 'w) {
+        self.set_events(FrameHook(hook));
+    }
+    /// Stops the events or frame hook.
+    pub fn clear_events(&mut self) {
+        self.events = None;
+    }
+    /// Starts or stops keeping the offset of every frame written from here
+    /// on, the way [`SeekIndex::frame_offsets`] lists them; see
+    /// [`ReplayEncoder::frame_offsets`].  Stopping forgets them.
+    pub fn record_frame_offsets(&mut self, record: bool) {
+        if record != self.frame_offsets.is_some() {
+            self.frame_offsets = record.then(Vec::new);
+        }
+    }
+    /// The offsets kept since [`ReplayEncoder::record_frame_offsets`] was
+    /// turned on, indexed by frame number if it was on from the start;
+    /// still there after [`ReplayEncoder::finish`].
+    #[must_use]
+    pub fn frame_offsets(&self) -> Option<&[u64]> {
+        self.frame_offsets.as_deref()
+    }
+    /// Rewrites the header as frames are written, as often as `every`
+    /// says, and syncs the stream, so that a recording cut off by a crash
+    /// or power loss reads up to the last flush rather than having to be
+    /// repaired (see [`crate::repair`]).  With [`Metadata::frame_packing`],
+    /// each flush also closes the pack being filled.  `None` (the default)
+    /// leaves the header until [`ReplayEncoder::finish`].  Version 1
+    /// headers have no frame count, so only the syncing helps them.
+    ///
+    /// A flush that comes due runs when the next frame is written, before
+    /// any of it, so a flush that fails fails that frame and leaves none
+    /// of it in the stream; writing the frame again retries the flush.
+    pub fn set_header_flush(&mut self, every: Option<HeaderFlush>)
+    where
+        W: SyncData,
+    {
+        self.header_flush = every.map(|every| HeaderFlushing {
+            every,
+            sync: <W as SyncData>::sync_data,
+            last_frame: self.frame_number,
+            last_time: std::time::Instant::now(),
+        });
+    }
+    /// Rewrites the header with the frames written so far and syncs the
+    /// stream now, e.g. when a recording is paused.
+    /// # Errors
+    /// [`ReplayError::IO`]: The stream could not be written or synced
+    /// [`ReplayError::TooManyFrames`]: The frame count doesn't fit in the header
+    pub fn flush_header(&mut self) -> Result<()>
+    where
+        W: SyncData,
+    {
+        self.sync_header()?;
+        if self.header_flush.is_none() {
+            self.rply.sync_data()?;
+        }
+        Ok(())
+    }
+    /* Writes out everything written so far and syncs it, header included */
+    fn sync_header(&mut self) -> Result<()> {
+        self.flush_pack()?;
+        let pos = self.rply.stream_position()?;
+        if let Err(e) = self
+            .write_header()
+            .and_then(|()| self.rply.flush().map_err(ReplayError::from))
+        {
+            // Frames go on from where they left off, not inside the header
+            self.rply.seek(std::io::SeekFrom::Start(pos))?;
+            return Err(e);
+        }
+        if let Some(flushing) = &mut self.header_flush {
+            (flushing.sync)(self.rply)?;
+            flushing.last_frame = self.frame_number;
+            flushing.last_time = std::time::Instant::now();
+        }
+        Ok(())
+    }
+    /// Sets the options of the built-in compressors for subsequent
+    /// checkpoints; to cover the initial state too, set them on the
+    /// [`CodecRegistry`] passed to [`ReplayEncoder::with_codecs`].
+    pub fn set_compression_options(&mut self, options: CompressionOptions) {
+        self.codecs.set_compression_options(options);
+    }
+    /// Caps the memory the statestream encoder's block tables may use, in
+    /// bytes, by evicting the least recently used blocks; `None` (the
+    /// default) leaves them to grow.  The decoder follows the evictions, so
+    /// its tables stay within the same budget.  Replays written with a
+    /// budget can't be read by RetroArch.
+    pub fn set_memory_budget(&mut self, bytes: Option<usize>) {
+        self.codecs.set_memory_budget(bytes);
+    }
+    /// Gives the encoder every checkpoint it will write from here on, in
+    /// order, e.g. when re-encoding a replay it has already read, so that
+    /// when the memory budget forces evictions it evicts the blocks needed
+    /// furthest in the future rather than the least recently used ones.
+    /// Fewer blocks then have to be sent again, and the replay is smaller;
+    /// it reads the same as any other replay written with a budget.
+    /// Without a budget it changes nothing.  Only [`Encoding::Statestream`]
+    /// checkpoints use it, and XOR deltas (see
+    /// [`ReplayEncoder::set_xor_deltas`]) spoil its predictions.
+    pub fn set_lookahead<'s>(&mut self, checkpoints: impl IntoIterator<Item = &'s [u8]>) {
+        self.codecs.set_lookahead(checkpoints);
+    }
+    /// Lets the statestream encoder send long runs of all-zero or repeated
+    /// superblocks as single tokens instead of an entry each, which
+    /// shrinks checkpoints of mostly empty or uniform memory.  Off by
+    /// default, because replays written with it can't be read by
+    /// RetroArch or older versions of this crate.
+    pub fn set_run_tokens(&mut self, runs: bool) {
+        self.codecs.set_runs(runs);
+    }
+    /// Sends each statestream checkpoint but the first and keyframes as its
+    /// XOR with the previous checkpoint, so that memory which changes
+    /// slowly becomes mostly zero blocks.  That usually shrinks replays
+    /// considerably, but decoding such a checkpoint needs the one before
+    /// it, so seeking backwards decodes from the start of the replay (or
+    /// the last keyframe, with [`ReplayDecoder::seek_to_keyframe`]), and a
+    /// [`SeekIndex`] can't jump to the frames between keyframes.  Off
+    /// by default, because RetroArch can't read replays written with it.
+    /// Checkpoints in [`Encoding::Regions`] are never XORed.
+    pub fn set_xor_deltas(&mut self, xor: bool) {
+        self.codecs.set_xor(xor);
+    }
+    /// Chooses the hash the statestream encoder uses to find blocks it has
+    /// seen before (by default [`BlockHash::Xxh3_64`]).  Blocks whose
+    /// hashes match are also compared byte for byte, unless `trust` is
+    /// set and the hash has 128 bits or more; skipping the comparison
+    /// speeds up encoding large states, at a vanishing risk of a wrong
+    /// block.  Replays are readable however they were hashed.
+    pub fn set_block_hash(&mut self, hash: BlockHash, trust: bool) {
+        self.codecs.set_block_hash(hash, trust);
+    }
+    /// Makes a statestream checkpoint a keyframe whenever at least
+    /// `frames` frames have passed since the last one (or the start); `None`
+    /// (the default) writes no keyframes.  A keyframe sends every block its
+    /// state uses, so it costs about as much as a compressed raw checkpoint,
+    /// but decoders can start from it without reading the replay before it
+    /// (see [`ReplayDecoder::seek_to_keyframe`]), which makes random access
+    /// and partial downloads practical.  Checkpoints in other encodings,
+    /// including [`Encoding::Regions`], are never keyframes.  Replays with
+    /// keyframes can't be read by RetroArch.
+    pub fn set_keyframe_interval(&mut self, frames: Option<u64>) {
+        self.keyframe_interval = frames;
+    }
+    /// Registers a codec for [`Encoding::Custom`]`(id)`, which can then be chosen with [`ReplayEncoder::set_checkpoint_encoding`].
+    /// # Panics
+    /// If `id` is reserved for a built-in encoding.
+    pub fn register_codec(
+        &mut self,
+        id: u8,
+        codec: Box<dyn CheckpointCodec>,
+    ) -> Option<Box<dyn CheckpointCodec>> {
+        self.codecs.register(id, codec)
+    }
+    /// Registers a custom [`Compressor`] under [`Compression::Custom`]`(id)`.
+    /// # Panics
+    /// If `id` is reserved for a built-in compression scheme.
+    pub fn register_compressor(
+        &mut self,
+        id: u8,
+        compressor: Box<dyn Compressor>,
+    ) -> Option<Box<dyn Compressor>> {
+        self.codecs.register_compressor(id, compressor)
+    }
+    /// Writes a single frame at the current encoder position.  If it fails,
+    /// none of the frame is left in the stream: the encoder seeks back to
+    /// where the frame started and counts it as unwritten, so that writing
+    /// can go on (say, once the disk has room) or the replay be finished
+    /// without it.  Taking back a checkpoint takes
+    /// [`ReplayEncoder::set_rollback`]; without it, a frame with a
+    /// checkpoint that fails leaves the encoder unable to write more.  So
+    /// does any frame that fails in an encrypted replay, since a different
+    /// frame written in its place would be sealed with the same nonce.
+    ///
+    /// The stream isn't truncated, as `W` needn't support it: bytes of the
+    /// failed frame stay past the encoder's position until overwritten.
+    /// Readers stop at the header's frame count, but live readers (see
+    /// [`ReplayDecoder::set_live`]) may misread them, so truncate files
+    /// to [`ReplayEncoder::bytes_written`] after finishing if that matters.
     /// # Errors
     /// [`ReplayError::FrameTooLong`]: Frame encoded to more than 2^32 bytes, backrefs invalid
     /// [`ReplayError::TooManyKeyEvents`]: More key events than allowed by spec
     /// [`ReplayError::TooManyInputEvents`]: More input events than allowed by spec
     /// [`ReplayError::CheckpointTooBig`]: Checkpoint data takes up more than 2^32 bytes
+    /// [`ReplayError::VersionFeature`]: Writing a version 1 replay with a checkpoint encoding other than [`Encoding::Raw`]
+    /// [`ReplayError::IO`]: The stream could not be written, or a header flush that came due
+    /// (see [`ReplayEncoder::set_header_flush`]) could not be written or synced
+    /// [`ReplayError::EncoderOutOfStep`]: A frame with a checkpoint failed before, without
+    /// rollback, or any frame of an encrypted replay did
     pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        if self.out_of_step {
+            return Err(ReplayError::EncoderOutOfStep());
+        }
+        if let Some(flushing) = &self.header_flush {
+            let due = flushing
+                .every
+                .frames
+                .is_some_and(|frames| self.frame_number - flushing.last_frame >= frames)
+                || flushing
+                    .every
+                    .interval
+                    .is_some_and(|interval| flushing.last_time.elapsed() >= interval);
+            // Before any of the frame is written, so that it can fail whole
+            if due {
+                self.sync_header()?;
+            }
+        }
+        let stopwatch = self.codecs.stats.time(Timer::EncodeFrame);
+        let started = std::time::Instant::now();
+        // Left by the initial state or a frame that failed
+        self.encoded_checkpoint = None;
+        let mut mark = WriteMark {
+            pos: self.rply.stream_position()?,
+            packed: self.packer.as_ref().map_or(0, |packer| packer.frames.len()),
+        };
+        let start_pos = match &self.packer {
+            Some(packer) => packer.start + packer.frames.len() as u64,
+            None => mark.pos,
+        };
+        let checkpoint = !frame.checkpoint_bytes.is_empty() && !self.input_only;
+        let undo = (checkpoint && self.rollback).then(|| self.checkpoint_undo());
+        let mut out = std::mem::take(&mut self.scratch);
+        out.clear();
+        let result = self
+            .encode_frame(&mut out, frame, checkpoint, start_pos)
+            .and_then(|()| self.write_out(&out, checkpoint, &mut mark));
+        let len = out.len() as u64;
+        self.scratch = out;
+        if let Err(e) = result {
+            match undo {
+                Some(undo) => self.undo_checkpoint(undo),
+                None => self.out_of_step = checkpoint,
+            }
+            // Its sealed sections may be on disk, and their nonces can't be reused
+            self.out_of_step |= self.cipher.is_some();
+            if let Some(packer) = &mut self.packer {
+                packer.frames.truncate(mark.packed);
+            }
+            self.rply.seek(std::io::SeekFrom::Start(mark.pos))?;
+            return Err(e);
+        }
+        if let Some(offsets) = &mut self.frame_offsets {
+            offsets.push(start_pos);
+        }
+        let encoded_checkpoint = self.encoded_checkpoint.take();
+        if let Some(events) = &mut self.events {
+            if let Some(encoded) = encoded_checkpoint {
+                events.on_checkpoint(&encoded);
+            }
+            events.on_frame(&WrittenFrame {
+                frame: self.frame_number,
+                offset: start_pos,
+                len,
+                checkpoint,
+                micros: u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX),
+            });
+        }
+        self.frame_number += 1;
+        self.last_pos = start_pos;
+        drop(stopwatch);
+        Ok(())
+    }
+    /* Writes an encoded frame or packs it, moving `mark` past whatever
+    earlier frames get written on the way */
+    fn write_out(&mut self, out: &[u8], checkpoint: bool, mark: &mut WriteMark) -> Result<()> {
+        match &mut self.packer {
+            None => self.rply.write_all(out)?,
+            // Checkpoints are already compressed, and each gets a pack of its
+            // own so that seeking to one doesn't unpack the frames before it
+            Some(packer) if checkpoint => {
+                packer.flush(self.rply, packer.compression)?;
+                *mark = WriteMark {
+                    pos: self.rply.stream_position()?,
+                    packed: 0,
+                };
+                packer.frames.extend_from_slice(out);
+                packer.flush(self.rply, Compression::None)?;
+            }
+            Some(packer) => {
+                packer.frames.extend_from_slice(out);
+                if packer.frames.len() >= PACK_BYTES {
+                    packer.flush(self.rply, packer.compression)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /* Writes the frames waiting to be packed, if any, leaving none of the
+    pack in the stream if that fails */
+    fn flush_pack(&mut self) -> Result<()> {
+        if let Some(packer) = &mut self.packer {
+            let pos = self.rply.stream_position()?;
+            if let Err(e) = packer.flush(self.rply, packer.compression) {
+                self.rply.seek(std::io::SeekFrom::Start(pos))?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// Writes subsequent frames without their checkpoints, so that the
+    /// replay stores only its initial state and inputs: the smallest
+    /// replay that can still be checked, which is what competitive
+    /// submission sites want.  [`crate::Verifier::regenerate`] puts
+    /// checkpoints back by running the inputs through a core.  Off by
+    /// default.
+    pub fn set_input_only(&mut self, input_only: bool) {
+        self.input_only = input_only;
+    }
+    /// Keeps what encoding each checkpoint changes until its frame is
+    /// written, so that [`ReplayEncoder::write_frame`] can take back a
+    /// frame with a checkpoint that fails to be written, as it always does
+    /// other frames.  This copies the statestream tables at every
+    /// checkpoint.  Custom codecs' own state isn't kept.  Off by default.
+    pub fn set_rollback(&mut self, rollback: bool) {
+        self.rollback = rollback;
+    }
+    fn checkpoint_undo(&self) -> CheckpointUndo {
+        CheckpointUndo {
+            statestream: self.codecs.statestream.ctx.snapshot(),
+            regions: self.codecs.regions.ctx.snapshot(),
+            last_checkpoint: self.last_checkpoint.clone(),
+            last_sizes: self.last_sizes,
+            last_keyframe: self.last_keyframe,
+            trend: self.size_alert.as_ref().map(|(trend, _)| trend.clone()),
+        }
+    }
+    fn undo_checkpoint(&mut self, undo: CheckpointUndo) {
+        // Taken from these same contexts, so their block sizes match
+        self.codecs.statestream.ctx.restore(&undo.statestream);
+        self.codecs.regions.ctx.restore(&undo.regions);
+        self.last_checkpoint = undo.last_checkpoint;
+        self.last_sizes = undo.last_sizes;
+        self.last_keyframe = undo.last_keyframe;
+        if let (Some((trend, _)), Some(undone)) = (&mut self.size_alert, undo.trend) {
+            *trend = undone;
+        }
+    }
+    /* Writes the frame's events, then its checkpoint if `checkpoint` is set */
+    fn encode_frame(
+        &mut self,
+        out: &mut Vec<u8>,
+        frame: &Frame,
+        checkpoint: bool,
+        start_pos: u64,
+    ) -> Result<()> {
         use byteorder::{LittleEndian, WriteBytesExt};
-        let stopwatch = clock::time(Timer::EncodeFrame);
-        let start_pos = self.rply.stream_position()?;
-        self.rply.write_u32::<LittleEndian>(
+        if self.header.version() < 2 {
+            write_events(out, frame)?;
+            if !checkpoint {
+                out.write_u8(u8::from(FrameToken::Regular))?;
+            } else if self.checkpoint_encoding != Encoding::Raw {
+                return Err(ReplayError::VersionFeature(1, "encoded checkpoints"));
+            } else {
+                out.write_u8(u8::from(FrameToken::Checkpoint))?;
+                out.write_u64::<LittleEndian>(frame.checkpoint_bytes.len() as u64)?;
+                out.extend_from_slice(&frame.checkpoint_bytes);
+            }
+            return Ok(());
+        }
+        out.write_u32::<LittleEndian>(
             u32::try_from(start_pos - self.last_pos).map_err(ReplayError::FrameTooLong)?,
         )?;
-        self.rply.write_u8(
-            u8::try_from(frame.key_events.len()).map_err(ReplayError::TooManyKeyEvents)?,
-        )?;
-        for evt in &frame.key_events {
-            self.rply.write_u8(evt.down)?;
-            self.rply.write_u8(0)?; // padding
-            self.rply.write_u16::<LittleEndian>(evt.modf)?;
-            self.rply.write_u32::<LittleEndian>(evt.code)?;
-            self.rply.write_u32::<LittleEndian>(evt.chr)?;
-        }
-        self.rply.write_u16::<LittleEndian>(
-            u16::try_from(frame.input_events.len()).map_err(ReplayError::TooManyInputEvents)?,
-        )?;
-        for evt in &frame.input_events {
-            self.rply.write_u8(evt.port)?;
-            self.rply.write_u8(evt.device)?;
-            self.rply.write_u8(evt.idx)?;
-            self.rply.write_u8(0)?; // padding
-            self.rply.write_u16::<LittleEndian>(evt.id)?;
-            self.rply.write_i16::<LittleEndian>(evt.val)?;
-        }
-        if frame.checkpoint_bytes.is_empty() {
-            self.rply.write_u8(u8::from(FrameToken::Regular))?;
+        match &self.cipher {
+            Some(cipher) if self.header.encrypted_sections().inputs => {
+                let mut sealed = vec![];
+                write_events(&mut sealed, frame)?;
+                cipher.seal(Section::Inputs, self.frame_number, &mut sealed)?;
+                out.write_u32::<LittleEndian>(
+                    u32::try_from(sealed.len()).map_err(ReplayError::FrameTooLong)?,
+                )?;
+                out.extend_from_slice(&sealed);
+            }
+            _ => write_events(out, frame)?,
+        }
+        if !checkpoint {
+            out.write_u8(u8::from(FrameToken::Regular))?;
         } else {
-            self.rply.write_u8(u8::from(FrameToken::Checkpoint2))?;
-            self.encode_checkpoint(&frame.checkpoint_bytes, self.frame_number)?;
+            out.write_u8(u8::from(FrameToken::Checkpoint2))?;
+            self.encode_checkpoint(
+                out,
+                &frame.checkpoint_bytes,
+                self.frame_number,
+                Section::Checkpoint,
+            )?;
         }
-        self.frame_number += 1;
-        self.last_pos = start_pos;
-        drop(stopwatch);
         Ok(())
     }
+    /// Attaches a research log which will record statestream block statistics for every subsequent checkpoint.
+    #[cfg(feature = "research")]
+    pub fn set_research_log(&mut self, log: crate::ResearchLog) {
+        self.codecs.statestream.ctx.research = Some(log);
+    }
     /// Finishes the encoding, writing the header in the process
     /// # Errors
     /// [`ReplayError::IO`]: Underlying writer fails to write header
@@ -680,10 +2849,25 @@ impl<'w, W: std::io::Write + std::io::Seek> ReplayEncoder<'w, W> {
         if self.finished {
             return Ok(());
         }
+        self.flush_pack()?;
         self.write_header()?;
+        if let Some(flushing) = &self.header_flush {
+            self.rply.flush()?;
+            (flushing.sync)(self.rply)?;
+        }
+        #[cfg(feature = "research")]
+        if let Some(log) = self.codecs.statestream.ctx.research.as_mut() {
+            log.finish()?;
+        }
         self.finished = true;
         Ok(())
     }
+    /* Leaves the stream as it is when the encoder is dropped, rather than
+    finishing it again after finishing failed */
+    #[cfg(feature = "capi")]
+    pub(crate) fn abandon(&mut self) {
+        self.finished = true;
+    }
 }
 
 impl<W: std::io::Write + std::io::Seek> Drop for ReplayEncoder<'_, W> {
@@ -754,18 +2938,10 @@ impl Header {
     }
     pub fn upgrade(&mut self) -> &mut HeaderV2 {
         if let Header::V0V1(base) = self {
-            *self = Header::V2(HeaderV2 {
-                base: base.clone(),
-                frame_count: 0,
-                block_size: 256,
-                superblock_size: 256,
-                checkpoint_commit_interval: 8,
-                checkpoint_commit_threshold: 4,
-                checkpoint_compression: Compression::None,
-            });
+            *self = Header::V2(HeaderV2::with_defaults(base.clone()));
         }
         let Header::V2(v2) = self else { unreachable!() };
-        v2.base.version = 2;
+        v2.base.version = v2.base.version.max(2);
         v2
     }
     #[must_use]
@@ -804,6 +2980,8 @@ impl Header {
             Header::V2(header_v2) => header_v2.checkpoint_commit_threshold,
         }
     }
+    /// Sets the commit interval and threshold, which only evict blocks in
+    /// replays with [`Metadata::block_commits`].
     pub fn set_checkpoint_commit_settings(&mut self, interval: u8, threshold: u8) {
         let v2 = self.upgrade();
         v2.checkpoint_commit_interval = interval;
@@ -820,15 +2998,146 @@ impl Header {
         let v2 = self.upgrade();
         v2.checkpoint_compression = compression;
     }
+    #[must_use]
+    pub fn encrypted_sections(&self) -> EncryptedSections {
+        match self {
+            Header::V0V1(_) => EncryptedSections::default(),
+            Header::V2(header_v2) => header_v2.encrypted_sections,
+        }
+    }
+    pub fn set_encrypted_sections(&mut self, sections: EncryptedSections) {
+        let v2 = self.upgrade();
+        v2.encrypted_sections = sections;
+    }
+    /// Metadata chunks; only version 3 replays have any.
+    #[must_use]
+    pub fn metadata(&self) -> Option<&Metadata> {
+        match self {
+            Header::V0V1(_) => None,
+            Header::V2(header_v2) => Some(&header_v2.metadata),
+        }
+    }
+    /// Mutable access to the metadata chunks, upgrading the header if needed.
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.upgrade().metadata
+    }
+    /// Replaces the metadata chunks, e.g. with a map collected into a
+    /// [`Metadata`], before handing the header to an encoder.  Non-empty
+    /// metadata makes the encoder write a version 3 replay.
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.upgrade().metadata = metadata;
+    }
+}
+
+impl HeaderV2 {
+    /// Starts a version 2 header for the content with this CRC, with the
+    /// replay's `identifier`; see [`HeaderBuilder`] for the defaults.
+    #[must_use]
+    pub fn builder(identifier: u64, content_crc: u32) -> HeaderBuilder {
+        HeaderBuilder(HeaderV2::with_defaults(HeaderBase {
+            version: 2,
+            content_crc,
+            initial_state_size: 0,
+            identifier,
+        }))
+    }
+    fn with_defaults(base: HeaderBase) -> HeaderV2 {
+        HeaderV2 {
+            base,
+            frame_count: 0,
+            block_size: 256,
+            superblock_size: 256,
+            checkpoint_commit_interval: 8,
+            checkpoint_commit_threshold: 4,
+            checkpoint_compression: Compression::None,
+            encrypted_sections: EncryptedSections::default(),
+            metadata: Metadata::default(),
+        }
+    }
 }
-#[derive(Debug, Default)]
+
+/// Builds a [`Header`] for an encoder, checking its settings together.
+/// Unless set, blocks and superblocks are 256 long, the commit interval
+/// and threshold are 8 and 4 (which evict nothing without
+/// [`Metadata::block_commits`]), and nothing is compressed, encrypted, or
+/// stored as metadata.
+#[derive(Debug, Clone)]
+pub struct HeaderBuilder(HeaderV2);
+
+impl HeaderBuilder {
+    #[must_use]
+    pub fn block_size(mut self, sz: u32) -> Self {
+        self.0.block_size = sz;
+        self
+    }
+    #[must_use]
+    pub fn superblock_size(mut self, sz: u32) -> Self {
+        self.0.superblock_size = sz;
+        self
+    }
+    /// Sets the block and superblock sizes [`crate::tune_block_sizes`]
+    /// picks for replays starting from `initial_state`.
+    #[must_use]
+    pub fn tuned_for(mut self, initial_state: &[u8]) -> Self {
+        (self.0.block_size, self.0.superblock_size) = crate::tune_block_sizes(initial_state);
+        self
+    }
+    /// Sets the commit interval and threshold; see
+    /// [`Header::set_checkpoint_commit_settings`].
+    #[must_use]
+    pub fn checkpoint_commit_settings(mut self, interval: u8, threshold: u8) -> Self {
+        self.0.checkpoint_commit_interval = interval;
+        self.0.checkpoint_commit_threshold = threshold;
+        self
+    }
+    #[must_use]
+    pub fn checkpoint_compression(mut self, compression: Compression) -> Self {
+        self.0.checkpoint_compression = compression;
+        self
+    }
+    #[must_use]
+    pub fn encrypted_sections(mut self, sections: EncryptedSections) -> Self {
+        self.0.encrypted_sections = sections;
+        self
+    }
+    /// Non-empty metadata makes the encoder write a version 3 replay.
+    #[must_use]
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.0.metadata = metadata;
+        self
+    }
+    /// The header, if its settings make sense together.
+    ///
+    /// # Errors
+    /// [`ReplayError::InvalidHeader`]: A block or superblock size is zero
+    /// (the initial state is always statestream encoded, so both are used),
+    /// or the commit threshold is over the commit interval
+    pub fn build(self) -> Result<Header> {
+        let v2 = self.0;
+        if v2.block_size == 0 {
+            return Err(ReplayError::InvalidHeader("block size is zero"));
+        }
+        if v2.superblock_size == 0 {
+            return Err(ReplayError::InvalidHeader("superblock size is zero"));
+        }
+        if v2.checkpoint_commit_threshold > v2.checkpoint_commit_interval {
+            return Err(ReplayError::InvalidHeader(
+                "commit threshold is over the commit interval",
+            ));
+        }
+        Ok(Header::V2(v2))
+    }
+}
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeyData {
     pub down: u8,
     pub modf: u16,
     pub code: u32,
     pub chr: u32,
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InputData {
     pub port: u8,
     pub device: u8,
@@ -837,7 +3146,87 @@ pub struct InputData {
     pub val: i16,
 }
 
-#[derive(Debug)]
+/* RETRO_DEVICE_JOYPAD, and the id under which it reports all its buttons as one bitmask */
+pub(crate) const JOYPAD: u8 = 1;
+pub(crate) const JOYPAD_MASK: u16 = 256;
+
+impl InputData {
+    /* The buttons held, by id, if this is a joypad bitmask event */
+    pub(crate) fn joypad_mask(&self) -> Option<u16> {
+        (self.device == JOYPAD && self.id == JOYPAD_MASK).then(|| self.val.cast_unsigned())
+    }
+}
+
+/// Which input events to look for with [`ReplayDecoder::find_frame`],
+/// e.g. `InputPattern::pressed(0, 1, 3)` for the first player's joypad
+/// Start button.  Fields left `None` match anything.  A joypad button's id
+/// also matches its bit in joypad bitmask events (id 256), as cores that
+/// poll the whole joypad at once report it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InputPattern {
+    pub port: Option<u8>,
+    pub device: Option<u8>,
+    pub idx: Option<u8>,
+    pub id: Option<u16>,
+    /// The value to look for, or `None` for any but 0, i.e. pressed
+    pub val: Option<i16>,
+}
+
+impl InputPattern {
+    /// Input `id` of `device` on `port` held down, whatever its index.
+    #[must_use]
+    pub fn pressed(port: u8, device: u8, id: u16) -> Self {
+        Self {
+            port: Some(port),
+            device: Some(device),
+            id: Some(id),
+            ..Self::default()
+        }
+    }
+    /// Whether `input` is one of the events this looks for
+    #[must_use]
+    pub fn matches_input(&self, input: &InputData) -> bool {
+        self.port.is_none_or(|port| port == input.port)
+            && self.device.is_none_or(|device| device == input.device)
+            && self.idx.is_none_or(|idx| idx == input.idx)
+            && match (self.id, input.joypad_mask()) {
+                (Some(id), Some(mask)) if id < 16 => {
+                    let held = mask & (1 << id) != 0;
+                    self.val.map_or(held, |val| val == i16::from(held))
+                }
+                _ => {
+                    self.id.is_none_or(|id| id == input.id)
+                        && self.val.map_or(input.val != 0, |val| val == input.val)
+                }
+            }
+    }
+    /// Whether any of `frame`'s input events is one this looks for, for
+    /// passing to [`ReplayDecoder::find_frame`] as `|f| pattern.matches(f)`
+    #[must_use]
+    pub fn matches(&self, frame: &Frame) -> bool {
+        frame
+            .input_events
+            .iter()
+            .any(|input| self.matches_input(input))
+    }
+}
+
+/// One frame's input events and the checkpoint at its end, if any.
+///
+/// Frames are meant to be reused: [`ReplayDecoder::read_frame`] and its
+/// relatives overwrite every field of the frame they're given, growing its
+/// vectors only when they're too small, and neither they nor
+/// [`ReplayEncoder::write_frame`] keep any reference to it afterwards, so
+/// one frame can be read into and written from over a whole replay.  After
+/// a read fails, the frame's contents are unspecified but safe to reuse.
+/// Tools scanning a replay can instead borrow each frame from the decoder
+/// with [`ReplayDecoder::read_frame_ref`], which doesn't copy checkpoints,
+/// or skip checkpoints with
+/// [`ReplayDecoder::read_frame_skipping_checkpoints`].
+/// [`Frame::clear`], [`Frame::reset_to`] and [`Frame::shrink_to_fit`] keep
+/// or release its allocations in between.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Frame {
     pub key_events: Vec<KeyData>,
     pub input_events: Vec<InputData>,
@@ -847,6 +3236,16 @@ pub struct Frame {
 }
 
 impl Frame {
+    /// An empty frame with room for `keys` key events and `inputs` input
+    /// events.
+    #[must_use]
+    pub fn with_capacity(keys: usize, inputs: usize) -> Self {
+        Self {
+            key_events: Vec::with_capacity(keys),
+            input_events: Vec::with_capacity(inputs),
+            ..Self::default()
+        }
+    }
     #[must_use]
     pub fn inputs(&self) -> String {
         use std::fmt::Write;
@@ -860,16 +3259,91 @@ impl Frame {
         }
         output
     }
+    /// Removes the checkpoint, keeping its allocation.
     pub fn drop_checkpoint(&mut self) {
         self.checkpoint_bytes.clear();
         self.checkpoint_compression = Compression::None;
         self.checkpoint_encoding = Encoding::Raw;
     }
+    /// Empties the frame of events and checkpoint, keeping its allocations,
+    /// so it equals [`Frame::default`].
     pub fn clear(&mut self) {
         self.key_events.clear();
         self.input_events.clear();
         self.drop_checkpoint();
     }
+    /// Makes this frame a copy of `other`, reusing its allocations where
+    /// they're big enough.
+    pub fn reset_to(&mut self, other: &Frame) {
+        self.key_events.clone_from(&other.key_events);
+        self.input_events.clone_from(&other.input_events);
+        self.checkpoint_bytes.clone_from(&other.checkpoint_bytes);
+        self.checkpoint_compression = other.checkpoint_compression;
+        self.checkpoint_encoding = other.checkpoint_encoding;
+    }
+    /// Releases the vectors' spare capacity, e.g. after reading a frame with
+    /// unusually many events or a big checkpoint into a long-lived frame.
+    pub fn shrink_to_fit(&mut self) {
+        self.key_events.shrink_to_fit();
+        self.input_events.shrink_to_fit();
+        self.checkpoint_bytes.shrink_to_fit();
+    }
+}
+
+/// A [`Frame`] borrowed from its owner, e.g. from the decoder by
+/// [`ReplayDecoder::read_frame_ref`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRef<'a> {
+    pub key_events: &'a [KeyData],
+    pub input_events: &'a [InputData],
+    pub checkpoint_bytes: &'a [u8],
+    pub checkpoint_compression: Compression,
+    pub checkpoint_encoding: Encoding,
+}
+
+impl FrameRef<'_> {
+    /// Copies the frame into `frame`, reusing its allocations.
+    pub fn copy_to(&self, frame: &mut Frame) {
+        frame.key_events.clear();
+        frame.key_events.extend_from_slice(self.key_events);
+        frame.input_events.clear();
+        frame.input_events.extend_from_slice(self.input_events);
+        frame.checkpoint_bytes.clear();
+        frame
+            .checkpoint_bytes
+            .extend_from_slice(self.checkpoint_bytes);
+        frame.checkpoint_compression = self.checkpoint_compression;
+        frame.checkpoint_encoding = self.checkpoint_encoding;
+    }
+    #[must_use]
+    pub fn to_frame(&self) -> Frame {
+        let mut frame = Frame::default();
+        self.copy_to(&mut frame);
+        frame
+    }
+}
+
+impl<'a> From<&'a Frame> for FrameRef<'a> {
+    fn from(frame: &'a Frame) -> Self {
+        Self {
+            key_events: &frame.key_events,
+            input_events: &frame.input_events,
+            checkpoint_bytes: &frame.checkpoint_bytes,
+            checkpoint_compression: frame.checkpoint_compression,
+            checkpoint_encoding: frame.checkpoint_encoding,
+        }
+    }
+}
+
+impl Clone for Frame {
+    fn clone(&self) -> Self {
+        let mut frame = Frame::default();
+        frame.reset_to(self);
+        frame
+    }
+    fn clone_from(&mut self, source: &Self) {
+        self.reset_to(source);
+    }
 }
 
 impl Default for Frame {
@@ -0,0 +1,249 @@
+use crate::rply::HEADERV2_LEN_BYTES;
+use crate::{
+    CodecRegistry, Compression, DecodeLimits, Frame, Header, HeaderV2, Metadata, ReplayDecoder,
+    ReplayEncoder, ReplayError, parse_header,
+};
+use std::io::{BufRead, Read, Seek, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// How much of an untrusted replay [`sanitize`] will read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SanitizeLimits {
+    /// Largest file accepted (default 1GiB)
+    pub max_bytes: u64,
+    /// Most frames accepted (default 5184000, a day at 60fps)
+    pub max_frames: u64,
+    /// Largest initial state, checkpoint, and metadata block, and most
+    /// events and statestream index entries (default 64MiB states, 1MiB of
+    /// metadata, 4096 inputs a frame, and 16M index entries)
+    pub decode: DecodeLimits,
+}
+
+impl Default for SanitizeLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            max_frames: 60 * 60 * 60 * 24,
+            decode: DecodeLimits {
+                max_state_bytes: 64 << 20,
+                max_metadata_bytes: 1 << 20,
+                max_input_events: 4096,
+                max_index_entries: 1 << 24,
+                ..DecodeLimits::default()
+            },
+        }
+    }
+}
+
+/* The parts of `header` a sanitized replay keeps, in a fresh header */
+fn sanitized_header(header: &Header) -> Result<Header> {
+    let mut clean = HeaderV2::builder(header.identifier(), header.content_crc());
+    // Custom schemes would need the uploader's code to read back
+    if !matches!(header.checkpoint_compression(), Compression::Custom(_)) {
+        clean = clean.checkpoint_compression(header.checkpoint_compression());
+    }
+    let mut clean = clean.build()?;
+    let Some(metadata) = header.metadata() else {
+        return Ok(clean);
+    };
+    let out = clean.metadata_mut();
+    for (text, set) in [
+        (
+            metadata.title(),
+            Metadata::set_title as fn(&mut Metadata, &str),
+        ),
+        (metadata.core_name(), Metadata::set_core_name),
+        (metadata.core_version(), Metadata::set_core_version),
+        (metadata.author(), Metadata::set_author),
+        (metadata.license(), Metadata::set_license),
+        (metadata.locale(), Metadata::set_locale),
+        (metadata.timezone(), Metadata::set_timezone),
+    ] {
+        if let Some(text) = text {
+            set(out, text);
+        }
+    }
+    if let Some(created) = metadata.created() {
+        out.set_created(created);
+    }
+    if let Some(session) = metadata.session() {
+        out.set_session(session);
+    }
+    if let Some(hash) = metadata.rom_hash() {
+        out.set_rom_hash(hash);
+    }
+    if let Some(uses) = metadata.allowed_uses() {
+        out.set_allowed_uses(uses);
+    }
+    if let Some(regions) = metadata.state_regions() {
+        out.set_state_regions(&regions);
+    }
+    if let Some(compat) = metadata.compat()? {
+        out.set_compat(&compat)?;
+    }
+    Ok(clean)
+}
+
+/// Decodes an untrusted replay from `input` within `limits` and encodes it
+/// again to `out`, for services that accept uploads.  Nothing is copied
+/// through as bytes: the output has a fresh header holding only the
+/// metadata this crate understands (a ROM hash, title, and so on, but no
+/// zstd dictionary or unknown chunks), then the decoded frames, with
+/// checkpoints re-encoded with this crate's default settings.
+///
+/// Returns the number of frames written.  `out` may hold part of a replay
+/// on error, so write to a buffer or temporary file first.
+/// # Errors
+/// [`ReplayError::OverLimit`]: The replay is bigger than `limits` allow
+/// [`ReplayError::NoCoreRead`]: The replay is version 0, which can only be read by running it
+/// [`ReplayError::MissingKey`]: The replay has encrypted sections
+/// Any error from decoding the replay, which must be well-formed throughout
+pub fn sanitize<R: BufRead, W: Write + Seek>(
+    input: R,
+    out: &mut W,
+    limits: &SanitizeLimits,
+) -> Result<u64> {
+    let mut input = input.take(limits.max_bytes);
+    let result = reencode(&mut input, out, limits);
+    // Whether it failed or looks finished, it may only be because the file was cut off
+    if input.limit() == 0 && !input.get_mut().fill_buf()?.is_empty() {
+        return Err(ReplayError::OverLimit("file", limits.max_bytes + 1));
+    }
+    result
+}
+
+fn reencode<R: BufRead, W: Write + Seek>(
+    mut input: R,
+    out: &mut W,
+    limits: &SanitizeLimits,
+) -> Result<u64> {
+    /* Replays that can't be sanitized are refused from their header alone,
+    before the decoder reads their initial state */
+    let mut head = vec![];
+    (&mut input)
+        .take(HEADERV2_LEN_BYTES as u64)
+        .read_to_end(&mut head)?;
+    let mut fixed = [0; HEADERV2_LEN_BYTES];
+    fixed[..head.len()].copy_from_slice(&head);
+    let header = parse_header(&fixed)?;
+    if !header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    if header.encrypted_sections().any() {
+        return Err(ReplayError::MissingKey());
+    }
+    let input = head.as_slice().chain(input);
+    let mut rply = ReplayDecoder::with_limits(input, CodecRegistry::default(), limits.decode)?;
+    let header = sanitized_header(&rply.header)?;
+    let mut enc = ReplayEncoder::new(header, &rply.initial_state, out)?;
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        if rply.frame_number >= limits.max_frames {
+            return Err(ReplayError::OverLimit("frames", rply.frame_number + 1));
+        }
+        rply.read_frame(&mut frame)?;
+        enc.write_frame(&frame)?;
+    }
+    enc.finish()?;
+    Ok(enc.frame_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn sanitize_replay() {
+        let bytes = replay(None);
+        let mut dirty = crate::decode(bytes.as_slice()).unwrap();
+        let mut header = dirty.header.clone();
+        header.metadata_mut().set_title("bobl");
+        header.metadata_mut().set(*b"JUNK", vec![0xcc; 100]);
+        let mut with_junk = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(header, &dirty.initial_state, &mut with_junk).unwrap();
+        for frame in dirty.frames() {
+            enc.write_frame(&frame.unwrap()).unwrap();
+        }
+        enc.finish().unwrap();
+        drop(enc);
+
+        let limits = SanitizeLimits::default();
+        let mut out = std::io::Cursor::new(vec![]);
+        assert_eq!(
+            sanitize(with_junk.get_ref().as_slice(), &mut out, &limits).unwrap(),
+            1000
+        );
+        let mut clean = crate::decode(out.get_ref().as_slice()).unwrap();
+        let metadata = clean.header.metadata().unwrap();
+        assert_eq!(metadata.title(), Some("bobl"));
+        assert_eq!(metadata.unknown_chunks().count(), 0);
+        let mut dirty = crate::decode(bytes.as_slice()).unwrap();
+        assert!(
+            clean
+                .frames()
+                .map(Result::unwrap)
+                .eq(dirty.frames().map(Result::unwrap))
+        );
+
+        let tight = |limits: SanitizeLimits| {
+            sanitize(bytes.as_slice(), &mut std::io::Cursor::new(vec![]), &limits)
+        };
+        for (limits, what) in [
+            (
+                SanitizeLimits {
+                    max_frames: 999,
+                    ..limits
+                },
+                "frames",
+            ),
+            (
+                SanitizeLimits {
+                    max_bytes: bytes.len() as u64 - 1,
+                    ..limits
+                },
+                "file",
+            ),
+            (
+                SanitizeLimits {
+                    decode: DecodeLimits {
+                        max_state_bytes: 99,
+                        ..limits.decode
+                    },
+                    ..limits
+                },
+                "checkpoint",
+            ),
+        ] {
+            assert!(
+                matches!(tight(limits).map_err(ReplayError::into_root), Err(ReplayError::OverLimit(w, _)) if w == what),
+                "{what}"
+            );
+        }
+        assert!(
+            tight(SanitizeLimits {
+                max_bytes: bytes.len() as u64,
+                max_frames: 1000,
+                ..limits
+            })
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn refuses_from_header() {
+        // Just a header, with encrypted checkpoints and no initial state after it
+        let mut bytes = replay(None)[..HEADERV2_LEN_BYTES].to_vec();
+        bytes[36] = u8::from(crate::EncryptedSections {
+            checkpoints: true,
+            inputs: false,
+        });
+        let mut out = std::io::Cursor::new(vec![]);
+        assert!(matches!(
+            sanitize(bytes.as_slice(), &mut out, &SanitizeLimits::default()),
+            Err(ReplayError::MissingKey())
+        ));
+        assert!(out.get_ref().is_empty());
+    }
+}
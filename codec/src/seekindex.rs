@@ -0,0 +1,161 @@
+use crate::{CheckpointInfo, Compression, Encoding, ReplayError};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+const INDEX_MAGIC: u32 = 0x5844_4952; // RIDX
+
+/// Everything needed to jump straight to any frame of a replay: the
+/// offset of every frame, where each checkpoint is, and every statestream
+/// block the replay's checkpoints use.  Built once with
+/// [`crate::ReplayDecoder::build_seek_index`], usually saved to a
+/// `<replay>.idx` sidecar, and handed to
+/// [`crate::ReplayDecoder::load_seek_index`] by later readers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekIndex {
+    pub(crate) identifier: u64,
+    pub(crate) frame_offsets: Vec<u64>,
+    pub(crate) checkpoints: Vec<CheckpointInfo>,
+    pub(crate) block_size: u32,
+    pub(crate) superblock_size: u32,
+    pub(crate) blocks: Vec<Box<[u8]>>,
+    pub(crate) superblocks: Vec<Box<[u32]>>,
+}
+
+impl SeekIndex {
+    /// Identifier of the indexed replay
+    #[must_use]
+    pub fn identifier(&self) -> u64 {
+        self.identifier
+    }
+    /// Stream offset of each frame, indexed by frame number
+    #[must_use]
+    pub fn frame_offsets(&self) -> &[u64] {
+        &self.frame_offsets
+    }
+    #[must_use]
+    pub fn checkpoints(&self) -> &[CheckpointInfo] {
+        &self.checkpoints
+    }
+
+    /// # Errors
+    /// [`ReplayError::Magic`]: Not a seek index
+    /// [`ReplayError::Compression`]: Unsupported compression scheme
+    /// [`ReplayError::Encoding`]: Unsupported encoding scheme
+    /// [`ReplayError::IO`]: Error reading the index
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != INDEX_MAGIC {
+            return Err(ReplayError::Magic(magic));
+        }
+        let identifier = reader.read_u64::<LittleEndian>()?;
+        /* Frame offsets are stored as a start and frame lengths */
+        let frame_count = reader.read_u64::<LittleEndian>()?;
+        let mut frame_offsets = Vec::with_capacity(frame_count.min(1 << 20) as usize);
+        if frame_count > 0 {
+            let mut offset = reader.read_u64::<LittleEndian>()?;
+            frame_offsets.push(offset);
+            for _ in 1..frame_count {
+                offset += u64::from(reader.read_u32::<LittleEndian>()?);
+                frame_offsets.push(offset);
+            }
+        }
+        let checkpoint_count = reader.read_u32::<LittleEndian>()?;
+        let mut checkpoints = vec![];
+        for _ in 0..checkpoint_count {
+            checkpoints.push(CheckpointInfo {
+                frame: reader.read_u64::<LittleEndian>()?,
+                offset: reader.read_u64::<LittleEndian>()?,
+                compression: Compression::try_from(reader.read_u8()?)
+                    .map_err(ReplayError::Compression)?,
+                encoding: Encoding::try_from(reader.read_u8()?).map_err(ReplayError::Encoding)?,
+                compressed_size: reader.read_u64::<LittleEndian>()?,
+                uncompressed_size: reader.read_u64::<LittleEndian>()?,
+            });
+        }
+        let block_size = reader.read_u32::<LittleEndian>()?;
+        let superblock_size = reader.read_u32::<LittleEndian>()?;
+        let mut blocks = vec![];
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let mut block = vec![0; block_size as usize].into_boxed_slice();
+            reader.read_exact(&mut block)?;
+            blocks.push(block);
+        }
+        let mut superblocks = vec![];
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let mut superblock = vec![0; superblock_size as usize].into_boxed_slice();
+            reader.read_u32_into::<LittleEndian>(&mut superblock)?;
+            superblocks.push(superblock);
+        }
+        Ok(Self {
+            identifier,
+            frame_offsets,
+            checkpoints,
+            block_size,
+            superblock_size,
+            blocks,
+            superblocks,
+        })
+    }
+    /// # Errors
+    /// [`ReplayError::FrameTooLong`]: A frame is too long for the index
+    /// [`ReplayError::IO`]: Error writing the index
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<LittleEndian>(INDEX_MAGIC)?;
+        writer.write_u64::<LittleEndian>(self.identifier)?;
+        writer.write_u64::<LittleEndian>(self.frame_offsets.len() as u64)?;
+        if let Some(first) = self.frame_offsets.first() {
+            writer.write_u64::<LittleEndian>(*first)?;
+        }
+        for pair in self.frame_offsets.windows(2) {
+            writer.write_u32::<LittleEndian>(
+                u32::try_from(pair[1] - pair[0]).map_err(ReplayError::FrameTooLong)?,
+            )?;
+        }
+        writer.write_u32::<LittleEndian>(
+            u32::try_from(self.checkpoints.len()).map_err(ReplayError::TooManyFrames)?,
+        )?;
+        for cp in &self.checkpoints {
+            writer.write_u64::<LittleEndian>(cp.frame)?;
+            writer.write_u64::<LittleEndian>(cp.offset)?;
+            writer.write_u8(cp.compression.into())?;
+            writer.write_u8(cp.encoding.into())?;
+            writer.write_u64::<LittleEndian>(cp.compressed_size)?;
+            writer.write_u64::<LittleEndian>(cp.uncompressed_size)?;
+        }
+        writer.write_u32::<LittleEndian>(self.block_size)?;
+        writer.write_u32::<LittleEndian>(self.superblock_size)?;
+        writer.write_u32::<LittleEndian>(
+            u32::try_from(self.blocks.len()).map_err(ReplayError::CheckpointTooBig)?,
+        )?;
+        for block in &self.blocks {
+            writer.write_all(block)?;
+        }
+        writer.write_u32::<LittleEndian>(
+            u32::try_from(self.superblocks.len()).map_err(ReplayError::CheckpointTooBig)?,
+        )?;
+        for superblock in &self.superblocks {
+            for idx in superblock {
+                writer.write_u32::<LittleEndian>(*idx)?;
+            }
+        }
+        Ok(())
+    }
+    /// Loads an index sidecar, or returns `None` if there isn't one.
+    /// # Errors
+    /// As [`SeekIndex::read`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::read(&mut std::io::BufReader::new(file)).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// # Errors
+    /// As [`SeekIndex::write`]
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        crate::verify::save_sidecar(path.as_ref(), |file| self.write(file))
+    }
+}
@@ -0,0 +1,442 @@
+use crate::{ReplayDecoder, ReplayError};
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/* Bytes a SourceReader reads at once */
+const READ_SIZE: usize = 64 * 1024;
+
+/// Where a replay's bytes are stored: a file, a memory map, a server, or
+/// memory.  Wrapped in a [`SourceReader`], any source can be decoded,
+/// seeked and verified like a file.
+pub trait ReplaySource {
+    /// Reads bytes starting at `offset` into `buf`, returning how many were
+    /// read; fewer than asked only at the end of the replay.
+    /// # Errors
+    /// Any error from the storage
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+    /// The replay's size in bytes.
+    /// # Errors
+    /// Any error from the storage
+    fn len(&self) -> std::io::Result<u64>;
+    /// # Errors
+    /// Any error from the storage
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Hints that `range` will be read soon, so slow storage can start
+    /// fetching it.  Does nothing by default.
+    fn prefetch(&self, _range: Range<u64>) {}
+}
+
+impl ReplaySource for [u8] {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = usize::try_from(offset).map_or(self.len(), |o| o.min(self.len()));
+        let n = buf.len().min(self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+impl ReplaySource for Vec<u8> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.as_slice().read_at(offset, buf)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl ReplaySource for std::fs::File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Positioned reads may stop short anywhere, not just at the end
+        let mut read = 0;
+        while read < buf.len() {
+            #[cfg(unix)]
+            let n =
+                std::os::unix::fs::FileExt::read_at(self, &mut buf[read..], offset + read as u64);
+            #[cfg(windows)]
+            let n = std::os::windows::fs::FileExt::seek_read(
+                self,
+                &mut buf[read..],
+                offset + read as u64,
+            );
+            match n {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl<S: ReplaySource + ?Sized> ReplaySource for &S {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        (**self).len()
+    }
+    fn prefetch(&self, range: Range<u64>) {
+        (**self).prefetch(range);
+    }
+}
+
+impl<S: ReplaySource + ?Sized> ReplaySource for Box<S> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        (**self).len()
+    }
+    fn prefetch(&self, range: Range<u64>) {
+        (**self).prefetch(range);
+    }
+}
+
+impl<S: ReplaySource + ?Sized> ReplaySource for std::sync::Arc<S> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        (**self).len()
+    }
+    fn prefetch(&self, range: Range<u64>) {
+        (**self).prefetch(range);
+    }
+}
+
+/// A replay file mapped into memory, so that reads are copies and the
+/// operating system pages it in as needed.  Only available with the
+/// `mmap` feature.
+#[cfg(feature = "mmap")]
+pub struct MmapSource(memmap2::Mmap);
+
+#[cfg(feature = "mmap")]
+impl MmapSource {
+    /// Maps the file at `path`.  Changing the file while it's mapped
+    /// changes what decoders read from it, so replays still being written
+    /// should be read some other way.
+    /// # Errors
+    /// Any error opening or mapping the file
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        #[allow(unsafe_code)]
+        // Safety: the caller is told not to change the file while it's mapped
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Self(map))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ReplaySource for MmapSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0[..].read_at(offset, buf)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+    fn prefetch(&self, range: Range<u64>) {
+        #[cfg(unix)]
+        if let (Ok(start), Ok(end)) = (usize::try_from(range.start), usize::try_from(range.end)) {
+            let end = end.min(self.0.len());
+            if start < end {
+                // Only a hint, so failing to give it is no loss
+                let _ = self
+                    .0
+                    .advise_range(memmap2::Advice::WillNeed, start, end - start);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = range;
+    }
+}
+
+/// A replay on a web server that supports HTTP range requests, fetched a
+/// piece at a time as decoders read it.  Each read fetches at least
+/// 256KiB and keeps the latest piece, so reading straight through makes
+/// few requests; [`ReplaySource::prefetch`] fetches a whole range at once.
+/// Only available with the `http` feature.
+#[cfg(feature = "http")]
+pub struct HttpSource {
+    agent: ureq::Agent,
+    url: String,
+    len: u64,
+    /* The latest piece fetched, and where in the replay it starts */
+    piece: std::sync::Mutex<(u64, Vec<u8>)>,
+}
+
+#[cfg(feature = "http")]
+impl HttpSource {
+    /* Least a read fetches */
+    const PIECE_SIZE: u64 = 256 * 1024;
+
+    /// Finds the size of the replay at `url` with a one-byte range request.
+    /// # Errors
+    /// Any error from the request, or if the server ignores ranges
+    pub fn open(url: &str) -> std::io::Result<Self> {
+        let agent = ureq::Agent::new_with_defaults();
+        let response = agent
+            .get(url)
+            .header("Range", "bytes=0-0")
+            .call()
+            .map_err(std::io::Error::other)?;
+        // "bytes 0-0/SIZE"
+        let len = response
+            .headers()
+            .get("Content-Range")
+            .and_then(|range| range.to_str().ok())
+            .filter(|_| response.status() == 206)
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, len)| len.parse().ok())
+            .ok_or_else(|| std::io::Error::other("server doesn't support range requests"))?;
+        Ok(Self {
+            agent,
+            url: url.to_owned(),
+            len,
+            piece: std::sync::Mutex::new((0, vec![])),
+        })
+    }
+    /* Fetches `range`, which must be within the replay */
+    fn fetch(&self, range: Range<u64>) -> std::io::Result<Vec<u8>> {
+        let mut response = self
+            .agent
+            .get(&self.url)
+            .header("Range", format!("bytes={}-{}", range.start, range.end - 1))
+            .call()
+            .map_err(std::io::Error::other)?;
+        if response.status() != 206 {
+            return Err(std::io::Error::other("server ignored a range request"));
+        }
+        let mut bytes = Vec::with_capacity(usize::try_from(range.end - range.start).unwrap_or(0));
+        response
+            .body_mut()
+            .as_reader()
+            .take(range.end - range.start)
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(feature = "http")]
+impl ReplaySource for HttpSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let mut piece = self
+            .piece
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let (start, bytes) = &*piece;
+        if offset < *start || offset >= start + bytes.len() as u64 {
+            let end = (offset + (buf.len() as u64).max(Self::PIECE_SIZE)).min(self.len);
+            *piece = (offset, self.fetch(offset..end)?);
+        }
+        let (start, bytes) = &*piece;
+        bytes.read_at(offset - start, buf)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.len)
+    }
+    fn prefetch(&self, range: Range<u64>) {
+        let range = range.start..range.end.min(self.len);
+        if range.is_empty() {
+            return;
+        }
+        // A failed prefetch leaves the read to fetch it again and report why
+        if let Ok(bytes) = self.fetch(range.clone()) {
+            *self
+                .piece
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner) = (range.start, bytes);
+        }
+    }
+}
+
+/// Reads, buffers and seeks a [`ReplaySource`], for decoders and anything
+/// else that wants a file.
+pub struct SourceReader<S: ReplaySource> {
+    source: S,
+    buf: Box<[u8]>,
+    /* Where in the source buf starts, and how much of it is read and used */
+    start: u64,
+    filled: usize,
+    cursor: usize,
+}
+
+impl<S: ReplaySource> SourceReader<S> {
+    #[must_use]
+    pub fn new(source: S) -> Self {
+        Self::with_capacity(READ_SIZE, source)
+    }
+    /// A reader that reads `capacity` bytes from `source` at once
+    #[must_use]
+    pub fn with_capacity(capacity: usize, source: S) -> Self {
+        Self {
+            source,
+            buf: vec![0; capacity.max(1)].into_boxed_slice(),
+            start: 0,
+            filled: 0,
+            cursor: 0,
+        }
+    }
+    #[must_use]
+    pub fn get_ref(&self) -> &S {
+        &self.source
+    }
+    #[must_use]
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+    /// Passes a [`ReplaySource::prefetch`] hint on to the source.
+    pub fn prefetch(&self, range: Range<u64>) {
+        self.source.prefetch(range);
+    }
+}
+
+impl<S: ReplaySource> Read for SourceReader<S> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<S: ReplaySource> BufRead for SourceReader<S> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.cursor == self.filled {
+            self.start += self.cursor as u64;
+            self.cursor = 0;
+            self.filled = 0;
+            self.filled = self.source.read_at(self.start, &mut self.buf)?;
+        }
+        Ok(&self.buf[self.cursor..self.filled])
+    }
+    fn consume(&mut self, amount: usize) {
+        self.cursor = (self.cursor + amount).min(self.filled);
+    }
+}
+
+impl<S: ReplaySource> Seek for SourceReader<S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => return Ok(self.seek_to(offset)),
+            SeekFrom::Current(delta) => (self.start + self.cursor as u64, delta),
+            SeekFrom::End(delta) => (self.source.len()?, delta),
+        };
+        let offset = base.checked_add_signed(delta).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the replay",
+            )
+        })?;
+        Ok(self.seek_to(offset))
+    }
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.start + self.cursor as u64)
+    }
+}
+
+impl<S: ReplaySource> SourceReader<S> {
+    /* Keeps the buffer if `offset` is within it */
+    fn seek_to(&mut self, offset: u64) -> u64 {
+        match offset.checked_sub(self.start) {
+            Some(within) if within <= self.filled as u64 => {
+                self.cursor = usize::try_from(within).unwrap();
+            }
+            _ => {
+                self.start = offset;
+                self.filled = 0;
+                self.cursor = 0;
+            }
+        }
+        offset
+    }
+}
+
+/// Decodes the replay stored in `source`, as [`crate::decode`] does a file.
+/// # Errors
+/// As [`crate::decode`]
+pub fn decode_source<S: ReplaySource>(
+    source: S,
+) -> Result<ReplayDecoder<SourceReader<S>>, ReplayError> {
+    crate::decode(SourceReader::new(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Frame;
+    use crate::verify::tests::replay;
+
+    /* Decodes every frame, then seeks back to the middle and decodes it
+    again, returning the frame count and the checkpoint in the middle */
+    fn read_through<S: ReplaySource>(source: S) -> (u64, Vec<u8>) {
+        let mut rply = decode_source(source).unwrap();
+        let mut frame = Frame::default();
+        let mut middle = None;
+        while !rply.at_end().unwrap() {
+            rply.read_frame(&mut frame).unwrap();
+            if rply.frame_number == 520 {
+                middle = Some(frame.checkpoint_bytes.clone());
+            }
+        }
+        let frames = rply.frame_number;
+        rply.seek_to_frame(519).unwrap();
+        rply.read_frame(&mut frame).unwrap();
+        assert_eq!(Some(&frame.checkpoint_bytes), middle.as_ref());
+        (frames, middle.unwrap())
+    }
+
+    #[test]
+    fn sources() {
+        let bytes = replay(None);
+        let expected = read_through(bytes.as_slice());
+        assert_eq!(expected.0, 1000);
+        assert_eq!(read_through(bytes.clone()), expected);
+        assert_eq!(
+            read_through(std::sync::Arc::new(bytes.clone()) as std::sync::Arc<dyn ReplaySource>),
+            expected
+        );
+
+        let path = std::env::temp_dir().join(format!("rply-source-{}.replay", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        assert_eq!(ReplaySource::len(&file).unwrap(), bytes.len() as u64);
+        assert_eq!(read_through(file), expected);
+        #[cfg(feature = "mmap")]
+        assert_eq!(read_through(MmapSource::open(&path).unwrap()), expected);
+        std::fs::remove_file(&path).unwrap();
+
+        // Tiny reads and seeks within and around the buffer
+        let mut reader = SourceReader::with_capacity(7, bytes.as_slice());
+        let mut head = [0; 20];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(head, bytes[..20]);
+        assert_eq!(reader.seek(SeekFrom::Current(-3)).unwrap(), 17);
+        assert_eq!(reader.fill_buf().unwrap(), &bytes[17..21]);
+        assert_eq!(
+            reader.seek(SeekFrom::End(-2)).unwrap(),
+            bytes.len() as u64 - 2
+        );
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, bytes[bytes.len() - 2..]);
+        assert!(
+            reader
+                .seek(SeekFrom::Current(-(bytes.len() as i64) - 1))
+                .is_err()
+        );
+    }
+}
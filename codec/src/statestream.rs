@@ -1,10 +1,56 @@
+//! Statestream checkpoints: states split into fixed-size blocks, grouped
+//! into superblocks, with each distinct block and superblock sent once.
+//!
+//! A state whose size isn't a multiple of the superblock size is padded.
+//! Its partial last block is filled out to the block size; the filler
+//! bytes are ignored by decoders, and RetroArch leaves whatever was in its
+//! buffer there, but this encoder always writes zeros so that its output is
+//! deterministic.  Its partial last superblock is filled out with block 0,
+//! the all-zero block, and no superblocks follow it.  The encoder counts
+//! its padding in [`Counter::EncPaddingBytes`] and
+//! [`Counter::EncPaddingBlocks`]; the decoder counts the padding it sees,
+//! and counts padding that breaks these rules in [`Counter::DecBadPadding`].
+//!
+//! Blocks and superblocks that are no longer needed are evicted from the
+//! tables, either implicitly by the commit protocol that encoder and
+//! decoder both run, or explicitly by an [`SSToken::Evict`] when the
+//! encoder's tables outgrow their memory budget.  Evicted indices are never
+//! reused.
+//!
+//! A keyframe checkpoint starts with an [`SSToken::Keyframe`] giving the
+//! sizes of the encoder's tables, and sends every block and superblock it
+//! uses, known or not; until the next keyframe, the checkpoints after it
+//! also send each object older than it the first time they use one.  A
+//! decoder that has lost step with the encoder, by skipping checkpoints or
+//! starting partway through the stream, can pick up again at a keyframe:
+//! it keeps what it knows, treats the indices it hasn't seen as evicted,
+//! and resumes committing from there.  Decoders in step just see blocks
+//! they already have.
+//!
+//! Savestates are often mostly zeros, or the same memory pattern over and
+//! over.  Block 0 and superblock 0 are always all zeros, so such states
+//! take few table entries, but each superblock of them still costs an
+//! entry in every checkpoint's superblock sequence.  On request, the
+//! encoder sends long runs of superblock 0 as an [`SSToken::ZeroRun`] and
+//! long runs of one superblock repeating the one before them as an
+//! [`SSToken::RepeatRun`], and leaves those entries out of the sequence.
+//!
+//! Also on request, a checkpoint can be sent as its XOR with the previous
+//! checkpoint, marked by an [`SSToken::Xor`] with a hash of that previous
+//! checkpoint.  Memory that changes slowly XORs to mostly zero blocks,
+//! which dedupe and compress far better than the memory itself.  Keyframes
+//! are never XORed, and a decoder whose previous checkpoint isn't the one
+//! the hash names reports [`crate::ReplayError::SkippedCheckpoints`].
 mod blockindex;
 use crate::{
     InvalidDeterminant,
     clock::{self, Counter, Timer},
 };
+pub use blockindex::BlockHash;
 use blockindex::BlockIndex;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Write;
+use xxhash_rust::xxh3::xxh3_64;
 
 #[repr(u8)]
 #[non_exhaustive]
@@ -14,6 +60,11 @@ pub enum SSToken {
     NewBlock = 1,
     NewSuperblock = 2,
     SuperblockSeq = 3,
+    Evict = 4,
+    Keyframe = 5,
+    ZeroRun = 6,
+    RepeatRun = 7,
+    Xor = 8,
 }
 impl TryFrom<u8> for SSToken {
     type Error = InvalidDeterminant;
@@ -24,6 +75,11 @@ impl TryFrom<u8> for SSToken {
             1 => Ok(SSToken::NewBlock),
             2 => Ok(SSToken::NewSuperblock),
             3 => Ok(SSToken::SuperblockSeq),
+            4 => Ok(SSToken::Evict),
+            5 => Ok(SSToken::Keyframe),
+            6 => Ok(SSToken::ZeroRun),
+            7 => Ok(SSToken::RepeatRun),
+            8 => Ok(SSToken::Xor),
             _ => Err(InvalidDeterminant(value)),
         }
     }
@@ -36,10 +92,135 @@ impl From<SSToken> for u8 {
             SSToken::NewBlock => 1,
             SSToken::NewSuperblock => 2,
             SSToken::SuperblockSeq => 3,
+            SSToken::Evict => 4,
+            SSToken::Keyframe => 5,
+            SSToken::ZeroRun => 6,
+            SSToken::RepeatRun => 7,
+            SSToken::Xor => 8,
         }
     }
 }
 
+#[derive(Clone)]
+struct Addition {
+    when: u64,       // Checkpoint on which some objects were added
+    block: u32,      // Lowest block index added on this checkpoint
+    superblock: u32, // Lowest superblock index added on this checkpoint
+}
+
+/* Shortest run worth a run token, which costs at least three bytes */
+const MIN_RUN: usize = 4;
+
+/* Entries `start..start + len` of a superblock sequence, all superblock 0
+or all the same as the entry before them */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Run {
+    start: usize,
+    len: usize,
+    zero: bool,
+}
+
+/* XORs `basis` into `state`, as if `basis` were zero-extended or cut to
+the state's length */
+pub(crate) fn xor_into(state: &mut [u8], basis: &[u8]) {
+    for (byte, basis) in state.iter_mut().zip(basis) {
+        *byte ^= basis;
+    }
+}
+
+/* The runs of `superseq` worth sending as run tokens, in order */
+fn find_runs(superseq: &[u32]) -> Vec<Run> {
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < superseq.len() {
+        let zero = superseq[i] == 0;
+        let len = if zero || (i > 0 && superseq[i] == superseq[i - 1]) {
+            superseq[i..]
+                .iter()
+                .take_while(|&&s| s == superseq[i])
+                .count()
+        } else {
+            0
+        };
+        if len >= MIN_RUN {
+            runs.push(Run {
+                start: i,
+                len,
+                zero,
+            });
+        }
+        i += len.max(1);
+    }
+    runs
+}
+
+/* The checkpoints an encoder will be given, so that it can evict the
+blocks needed furthest in the future */
+struct Lookahead {
+    hasher: BlockHash,
+    /* The checkpoints, counted from 0, each block turns up in, in order */
+    uses: HashMap<u128, Vec<u32>>,
+    /* Checkpoints encoded since the lookahead was given */
+    encoded: u32,
+}
+
+impl Lookahead {
+    /* The next checkpoint after the one being encoded to use `block`, if any */
+    fn next_use(&self, block: &[u8]) -> Option<u32> {
+        let uses = self.uses.get(&self.hasher.hash(block))?;
+        uses.get(uses.partition_point(|&k| k <= self.encoded))
+            .copied()
+    }
+}
+
+/// What a [`Ctx`] has learned from the stream so far, apart from its
+/// settings, for carrying on from the same point later or elsewhere.
+#[derive(Clone)]
+pub(crate) struct CtxSnapshot {
+    block_size: u32,
+    superblock_size: u32,
+    last_state: Vec<u8>,
+    last_superseq: Vec<u32>,
+    block_index: BlockIndex<u8>,
+    superblock_index: BlockIndex<u32>,
+    checkpoints: u64,
+    counted_through: Option<u64>,
+    additions: VecDeque<Addition>,
+    counted_blocks: u32,
+    counted_superblocks: u32,
+    initial_blocks: u32,
+    initial_superblocks: u32,
+    keep_all: bool,
+    resync: bool,
+    keyframe_next: bool,
+    since_keyframe: Option<SinceKeyframe>,
+    /* An encoder's place in what it was told is to come */
+    lookahead_encoded: Option<u32>,
+}
+
+impl CtxSnapshot {
+    pub(crate) fn block_size(&self) -> u32 {
+        self.block_size
+    }
+    pub(crate) fn superblock_size(&self) -> u32 {
+        self.superblock_size
+    }
+    /// Bytes of blocks and superblocks held, as [`Ctx::table_bytes`]
+    pub(crate) fn table_bytes(&self) -> usize {
+        self.block_index.live_bytes() + self.superblock_index.live_bytes()
+    }
+}
+
+/* What a decoder that starts at the encoder's latest keyframe knows: the
+ * objects added since then, and the older ones sent again since then */
+#[derive(Clone)]
+struct SinceKeyframe {
+    blocks: u32,      // Block table size at the keyframe
+    superblocks: u32, // Superblock table size at the keyframe
+    sent_blocks: HashSet<u32>,
+    sent_superblocks: HashSet<u32>,
+}
+
 pub(crate) struct Ctx {
     block_size: u32,
     superblock_size: u32,
@@ -48,6 +229,43 @@ pub(crate) struct Ctx {
     block_index: BlockIndex<u8>,
     superblock_index: BlockIndex<u32>,
     use_encode_state_comparisons: bool,
+    commit_interval: u8,
+    commit_threshold: u8,
+    /* Checkpoints counted so far */
+    checkpoints: u64,
+    /* Frame of the last checkpoint counted; decoding it again after seeking backwards doesn't count */
+    counted_through: Option<u64>,
+    /* Objects added by counted checkpoints that haven't been committed yet */
+    additions: VecDeque<Addition>,
+    counted_blocks: u32,
+    counted_superblocks: u32,
+    /* Objects from the initial state, which are never evicted */
+    initial_blocks: u32,
+    initial_superblocks: u32,
+    memory_budget: Option<usize>,
+    /* Most blocks plus superblocks a decoder may index */
+    max_entries: usize,
+    keep_all: bool,
+    /* Whether the encoder's next checkpoint is a keyframe */
+    keyframe_next: bool,
+    since_keyframe: Option<SinceKeyframe>,
+    /* Whether the decoder is out of step with the encoder until a keyframe */
+    resync: bool,
+    /* Whether the encoder may send runs of the superblock sequence as tokens */
+    runs: bool,
+    /* Whether the encoder XORs checkpoints with the previous one */
+    xor: bool,
+    /* Whether the decoder has seen a checkpoint XORed with the previous one */
+    seen_xor: bool,
+    hash: BlockHash,
+    trust_hashes: bool,
+    lookahead: Option<Lookahead>,
+    /* Sorted offsets at which the state's regions start or end */
+    boundaries: Vec<usize>,
+    #[cfg(feature = "research")]
+    pub(crate) research: Option<crate::research::ResearchLog>,
+    /* Shared with the encoder or decoder this belongs to */
+    pub(crate) stats: clock::Stats,
 }
 
 impl Ctx {
@@ -60,7 +278,484 @@ impl Ctx {
             block_index: BlockIndex::new(block_size as usize),
             superblock_index: BlockIndex::new(superblock_size as usize),
             use_encode_state_comparisons: true,
+            commit_interval: 0,
+            commit_threshold: 0,
+            checkpoints: 0,
+            counted_through: None,
+            additions: VecDeque::new(),
+            counted_blocks: 1,
+            counted_superblocks: 1,
+            initial_blocks: 1,
+            initial_superblocks: 1,
+            memory_budget: None,
+            max_entries: usize::MAX,
+            keep_all: false,
+            keyframe_next: false,
+            since_keyframe: None,
+            resync: false,
+            runs: false,
+            xor: false,
+            seen_xor: false,
+            hash: BlockHash::default(),
+            trust_hashes: false,
+            lookahead: None,
+            boundaries: vec![],
+            stats: clock::Stats::default(),
+            #[cfg(feature = "research")]
+            research: None,
+        }
+    }
+}
+
+impl Ctx {
+    pub(crate) fn block_size(&self) -> u32 {
+        self.block_size
+    }
+    pub(crate) fn superblock_size(&self) -> u32 {
+        self.superblock_size
+    }
+    pub(crate) fn blocks(&self) -> &[Box<[u8]>] {
+        self.block_index.objects()
+    }
+    pub(crate) fn superblocks(&self) -> &[Box<[u32]>] {
+        self.superblock_index.objects()
+    }
+    /// Sets the header's checkpoint commit interval and threshold.  Every
+    /// time a block is used counts, including each of its positions in
+    /// every checkpoint.  A block added on some checkpoint is committed
+    /// `interval` checkpoints later, counting that one: if it was used
+    /// fewer than `threshold` times by then, it's evicted, along with the
+    /// superblocks containing it, unless the latest checkpoint uses it.
+    /// This matches RetroArch's encoder, and the encoder and decoder commit
+    /// in lockstep, so the encoder never refers to an evicted block; one
+    /// that turns up again is sent again under a new index.  Blocks of the
+    /// initial state are never evicted.  An interval of zero never evicts
+    /// anything.
+    pub(crate) fn set_commit_settings(&mut self, interval: u8, threshold: u8) {
+        self.commit_interval = interval;
+        self.commit_threshold = threshold;
+    }
+    /// Aligns blocks to the given regions of the state: each region, and
+    /// each gap between regions, starts a new block, with the block before
+    /// it padded out with zeroes.  Encoder and decoder must agree on the
+    /// regions, and decoders that don't know them (such as RetroArch's)
+    /// can't read the stream.
+    pub(crate) fn set_regions(&mut self, regions: &[std::ops::Range<usize>]) {
+        self.boundaries = regions
+            .iter()
+            .flat_map(|region| [region.start, region.end])
+            .filter(|&offset| offset > 0)
+            .collect();
+        self.boundaries.sort_unstable();
+        self.boundaries.dedup();
+    }
+    pub(crate) fn has_regions(&self) -> bool {
+        !self.boundaries.is_empty()
+    }
+    /* The pieces of a state of `len` bytes that each start a block */
+    fn segments(&self, len: usize) -> impl Iterator<Item = std::ops::Range<usize>> {
+        let ends = self
+            .boundaries
+            .iter()
+            .copied()
+            .filter(move |&offset| offset < len)
+            .chain(std::iter::once(len));
+        std::iter::once(0)
+            .chain(ends.clone())
+            .zip(ends)
+            .map(|(a, b)| a..b)
+    }
+    /// Size of a state of `len` bytes once aligned by [`Ctx::align`].
+    pub(crate) fn aligned_len(&self, len: usize) -> usize {
+        let block_size = self.block_size as usize;
+        self.segments(len)
+            .map(|segment| segment.len().next_multiple_of(block_size))
+            .sum()
+    }
+    /// Writes `state` to `aligned` with each region starting a block.
+    pub(crate) fn align(&self, state: &[u8], aligned: &mut Vec<u8>) {
+        let block_size = self.block_size as usize;
+        aligned.clear();
+        for segment in self.segments(state.len()) {
+            aligned.extend_from_slice(&state[segment]);
+            aligned.resize(aligned.len().next_multiple_of(block_size), 0);
+        }
+    }
+    /// Undoes [`Ctx::align`], filling `state` from `aligned`.
+    pub(crate) fn unalign(&self, aligned: &[u8], state: &mut [u8]) {
+        let block_size = self.block_size as usize;
+        let mut from = 0;
+        for segment in self.segments(state.len()) {
+            let len = segment.len();
+            state[segment].copy_from_slice(&aligned[from..from + len]);
+            from += len.next_multiple_of(block_size);
+        }
+    }
+    /// Caps the bytes the encoder's block and superblock tables may take
+    /// up.  After each checkpoint, if they take up more, the least recently
+    /// used blocks are evicted until they fit, along with the superblocks
+    /// containing them; blocks of the initial state and the latest
+    /// checkpoint are always kept.  Unlike commits, these evictions are
+    /// written to the stream as [`SSToken::Evict`] so the decoder can
+    /// follow them without knowing the budget, but decoders that don't know
+    /// the token (such as RetroArch's) can't read the stream.
+    pub(crate) fn set_memory_budget(&mut self, bytes: Option<usize>) {
+        self.memory_budget = bytes;
+    }
+    /// Caps the blocks and superblocks a decoder indexes, for streams from
+    /// untrusted sources.
+    pub(crate) fn set_max_index_entries(&mut self, max: usize) {
+        self.max_entries = max;
+    }
+    /* Makes room for indices up to the encoder's table sizes at a keyframe,
+     * treating those not yet known as evicted */
+    fn pad_tables(&mut self, blocks: usize, superblocks: usize) -> std::io::Result<()> {
+        let entries =
+            blocks.max(self.block_index.len()) + superblocks.max(self.superblock_index.len());
+        if entries > self.max_entries {
+            return Err(std::io::Error::other(crate::ReplayError::OverLimit(
+                "statestream index entries",
+                entries as u64,
+            )));
+        }
+        self.block_index.pad_to(blocks);
+        self.superblock_index.pad_to(superblocks);
+        Ok(())
+    }
+    /* Fails once indexing another object would go over the cap */
+    fn check_entries(&self) -> std::io::Result<()> {
+        let entries = self.block_index.len() + self.superblock_index.len();
+        if entries < self.max_entries {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(crate::ReplayError::OverLimit(
+                "statestream index entries",
+                entries as u64 + 1,
+            )))
+        }
+    }
+    pub(crate) fn snapshot(&self) -> CtxSnapshot {
+        CtxSnapshot {
+            block_size: self.block_size,
+            superblock_size: self.superblock_size,
+            last_state: self.last_state.clone(),
+            last_superseq: self.last_superseq.clone(),
+            block_index: self.block_index.clone(),
+            superblock_index: self.superblock_index.clone(),
+            checkpoints: self.checkpoints,
+            counted_through: self.counted_through,
+            additions: self.additions.clone(),
+            counted_blocks: self.counted_blocks,
+            counted_superblocks: self.counted_superblocks,
+            initial_blocks: self.initial_blocks,
+            initial_superblocks: self.initial_superblocks,
+            keep_all: self.keep_all,
+            resync: self.resync,
+            keyframe_next: self.keyframe_next,
+            since_keyframe: self.since_keyframe.clone(),
+            lookahead_encoded: self.lookahead.as_ref().map(|l| l.encoded),
+        }
+    }
+    /// Puts back what `snapshot` had learned, keeping this context's
+    /// settings; returns false, changing nothing, if its block sizes differ.
+    pub(crate) fn restore(&mut self, snapshot: &CtxSnapshot) -> bool {
+        if (snapshot.block_size, snapshot.superblock_size)
+            != (self.block_size, self.superblock_size)
+        {
+            return false;
+        }
+        self.last_state.clone_from(&snapshot.last_state);
+        self.last_superseq.clone_from(&snapshot.last_superseq);
+        self.block_index.clone_from(&snapshot.block_index);
+        self.superblock_index.clone_from(&snapshot.superblock_index);
+        self.block_index.set_hash(self.hash, self.trust_hashes);
+        self.superblock_index.set_hash(self.hash, self.trust_hashes);
+        self.checkpoints = snapshot.checkpoints;
+        self.counted_through = snapshot.counted_through;
+        self.additions.clone_from(&snapshot.additions);
+        self.counted_blocks = snapshot.counted_blocks;
+        self.counted_superblocks = snapshot.counted_superblocks;
+        self.initial_blocks = snapshot.initial_blocks;
+        self.initial_superblocks = snapshot.initial_superblocks;
+        self.keep_all = snapshot.keep_all;
+        self.resync = snapshot.resync;
+        self.keyframe_next = snapshot.keyframe_next;
+        self.since_keyframe.clone_from(&snapshot.since_keyframe);
+        if let (Some(lookahead), Some(encoded)) = (&mut self.lookahead, snapshot.lookahead_encoded)
+        {
+            lookahead.encoded = encoded;
+        }
+        true
+    }
+    /// Bytes taken up by blocks and superblocks that aren't evicted
+    pub(crate) fn table_bytes(&self) -> usize {
+        self.block_index.live_bytes() + self.superblock_index.live_bytes()
+    }
+    /// Whether some objects are evicted, so that old checkpoints may no
+    /// longer decode without reading the stream from its start again.
+    pub(crate) fn has_evicted(&self) -> bool {
+        self.block_index.evicted() > 0 || self.superblock_index.evicted() > 0
+    }
+    /// Stops evicting, so that decoding the stream again from its start
+    /// restores evicted objects and keeps every object from then on.
+    pub(crate) fn stop_evicting(&mut self) {
+        self.keep_all = true;
+    }
+    /// Lets the encoder send long runs of zero or repeated superblocks as
+    /// [`SSToken::ZeroRun`] and [`SSToken::RepeatRun`].
+    pub(crate) fn set_runs(&mut self, runs: bool) {
+        self.runs = runs;
+    }
+    /// Makes [`Encoder::encode_checkpoint_against`] send checkpoints as
+    /// their XOR with the previous one.
+    pub(crate) fn set_xor(&mut self, xor: bool) {
+        self.xor = xor;
+    }
+    /// Whether any checkpoint decoded so far needed the one before it
+    pub(crate) fn has_xor(&self) -> bool {
+        self.seen_xor
+    }
+    /// Hashes blocks and superblocks with `hash` to find ones seen before;
+    /// with `trust` and a hash of 128 bits or more, a matching hash is
+    /// taken as a match without comparing the bytes.
+    pub(crate) fn set_block_hash(&mut self, hash: BlockHash, trust: bool) {
+        self.hash = hash;
+        self.trust_hashes = trust;
+        self.block_index.set_hash(hash, trust);
+        self.superblock_index.set_hash(hash, trust);
+    }
+    /// Adds the blocks and superblocks of `state` to the tables without
+    /// sending them, as if they had come with the initial state: they are
+    /// never evicted, and checkpoints resembling `state` just refer to
+    /// them.  Encoder and decoder must be seeded with the same states in
+    /// the same order, between the same two checkpoints.
+    pub(crate) fn seed(&mut self, state: &[u8]) {
+        let block_size = self.block_size as usize;
+        let superblock_size = self.superblock_size as usize;
+        let mut padded = vec![0; block_size];
+        let mut contents = vec![0_u32; superblock_size];
+        for superblock in state.chunks(block_size * superblock_size) {
+            contents.fill(0);
+            for (block_i, block) in superblock.chunks(block_size).enumerate() {
+                padded.fill(0);
+                padded[..block.len()].copy_from_slice(block);
+                contents[block_i] = self.block_index.insert(&padded, 0).index;
+            }
+            self.superblock_index.insert(&contents, 0);
         }
+        let blocks = u32::try_from(self.block_index.len()).unwrap();
+        let superblocks = u32::try_from(self.superblock_index.len()).unwrap();
+        self.initial_blocks = blocks;
+        self.initial_superblocks = superblocks;
+        self.counted_blocks = blocks;
+        self.counted_superblocks = superblocks;
+    }
+    /// Tells the encoder which checkpoints it will encode next, in order,
+    /// so that when its tables outgrow the memory budget it evicts the
+    /// blocks whose next use is furthest away (or never comes) instead of
+    /// the least recently used ones, and has to send fewer of them again.
+    /// The stream is the same kind either way; only which blocks are
+    /// evicted changes.  Blocks are predicted from the states as given, so
+    /// XORed checkpoints make for poor predictions.
+    pub(crate) fn set_lookahead<'s>(&mut self, states: impl IntoIterator<Item = &'s [u8]>) {
+        let block_size = self.block_size as usize;
+        let mut uses: HashMap<u128, Vec<u32>> = HashMap::new();
+        let mut aligned = vec![];
+        let mut padded = vec![0; block_size];
+        for (k, state) in states.into_iter().enumerate() {
+            let k = u32::try_from(k).unwrap();
+            let state = if self.has_regions() {
+                self.align(state, &mut aligned);
+                &aligned[..]
+            } else {
+                state
+            };
+            for block in state.chunks(block_size) {
+                padded.fill(0);
+                padded[..block.len()].copy_from_slice(block);
+                let checkpoints = uses.entry(self.hash.hash(&padded)).or_default();
+                if checkpoints.last() != Some(&k) {
+                    checkpoints.push(k);
+                }
+            }
+        }
+        self.lookahead = Some(Lookahead {
+            hasher: self.hash,
+            uses,
+            encoded: 0,
+        });
+    }
+    /// Makes the next checkpoint encoded a keyframe.
+    pub(crate) fn request_keyframe(&mut self) {
+        self.keyframe_next = true;
+    }
+    /// Whether the decoder's tables are out of step with the encoder's, so
+    /// that it can only decode keyframes; decoding one puts it back in step.
+    pub(crate) fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+    /* Evicts the least recently used blocks, or with a lookahead those
+     * needed furthest in the future, and the superblocks containing them
+     * until the tables fit in the memory budget, returning their indices in
+     * ascending order */
+    fn evict_over_budget(&mut self) -> (Vec<u32>, Vec<u32>) {
+        let Some(budget) = self.memory_budget.filter(|_| !self.keep_all) else {
+            return (vec![], vec![]);
+        };
+        let now = self.checkpoints;
+        for &sb in &self.last_superseq {
+            self.superblock_index.touch(sb, now);
+            for &b in self.superblock_index.get(sb) {
+                self.block_index.touch(b, now);
+            }
+        }
+        let mut over = self.table_bytes().saturating_sub(budget);
+        if over == 0 {
+            return (vec![], vec![]);
+        }
+        let blocks_len = u32::try_from(self.block_index.len()).unwrap();
+        let mut candidates: Vec<u32> = (self.initial_blocks..blocks_len)
+            .filter(|&b| !self.block_index.get(b).is_empty() && self.block_index.last_used(b) < now)
+            .collect();
+        match &self.lookahead {
+            Some(lookahead) => candidates.sort_by_cached_key(|&b| {
+                let next = lookahead.next_use(self.block_index.get(b));
+                (
+                    std::cmp::Reverse(next.unwrap_or(u32::MAX)),
+                    self.block_index.last_used(b),
+                )
+            }),
+            None => candidates.sort_by_key(|&b| self.block_index.last_used(b)),
+        }
+        let mut blocks = vec![];
+        for b in candidates {
+            if over == 0 {
+                break;
+            }
+            blocks.push(b);
+            over = over.saturating_sub(self.block_size as usize);
+        }
+        blocks.sort_unstable();
+        let superblocks_len = u32::try_from(self.superblock_index.len()).unwrap();
+        let superblocks: Vec<u32> = (self.initial_superblocks..superblocks_len)
+            .filter(|&sb| {
+                self.superblock_index
+                    .get(sb)
+                    .iter()
+                    .any(|b| blocks.binary_search(b).is_ok())
+            })
+            .collect();
+        self.evict(&blocks, &superblocks);
+        (blocks, superblocks)
+    }
+    fn evict(&mut self, blocks: &[u32], superblocks: &[u32]) {
+        for &sb in superblocks {
+            self.superblock_index.evict(sb);
+        }
+        for &b in blocks {
+            self.block_index.evict(b);
+        }
+    }
+    /* Counts the uses of the checkpoint in `last_superseq` and commits the
+     * objects that are due, returning the number of blocks and superblocks
+     * evicted */
+    fn end_checkpoint(&mut self, frame: u64) -> (u64, u64) {
+        if std::mem::take(&mut self.resync) {
+            /* Back in step with the encoder after a keyframe: everything
+             * known so far is kept, and commits resume with the objects
+             * the next checkpoint adds */
+            self.counted_through = Some(frame);
+            self.checkpoints += 1;
+            self.additions.clear();
+            self.counted_blocks = u32::try_from(self.block_index.len()).unwrap();
+            self.counted_superblocks = u32::try_from(self.superblock_index.len()).unwrap();
+            return (0, 0);
+        }
+        if self.keep_all || self.counted_through.is_some_and(|f| frame <= f) {
+            return (0, 0);
+        }
+        self.counted_through = Some(frame);
+        let when = self.checkpoints;
+        self.checkpoints += 1;
+        let blocks_len = u32::try_from(self.block_index.len()).unwrap();
+        let superblocks_len = u32::try_from(self.superblock_index.len()).unwrap();
+        if frame == 0 {
+            self.initial_blocks = blocks_len;
+            self.initial_superblocks = superblocks_len;
+        } else if self.commit_interval > 0 {
+            self.additions.push_back(Addition {
+                when,
+                block: self.counted_blocks,
+                superblock: self.counted_superblocks,
+            });
+        }
+        self.counted_blocks = blocks_len;
+        self.counted_superblocks = superblocks_len;
+        if self.commit_interval == 0 {
+            return (0, 0);
+        }
+        let mut in_use = vec![];
+        for &sb in &self.last_superseq {
+            for &b in self.superblock_index.get(sb) {
+                self.block_index.record_use(b);
+                in_use.push(b);
+            }
+        }
+        in_use.sort_unstable();
+        in_use.dedup();
+        let threshold = u32::from(self.commit_threshold);
+        let (mut blocks, mut superblocks) = (0, 0);
+        while let Some(addition) = self.additions.front()
+            && addition.when + u64::from(self.commit_interval) <= self.checkpoints
+        {
+            let end = self.additions.get(1).map_or(blocks_len, |next| next.block);
+            let evicted: Vec<u32> = (addition.block..end)
+                .filter(|&b| {
+                    self.block_index.uses(b) < threshold
+                        && in_use.binary_search(&b).is_err()
+                        && !self.block_index.get(b).is_empty()
+                })
+                .collect();
+            /* Superblocks containing those blocks were all added since */
+            if !evicted.is_empty() {
+                for sb in addition.superblock..superblocks_len {
+                    let contents = self.superblock_index.get(sb);
+                    if contents.iter().any(|b| evicted.binary_search(b).is_ok()) {
+                        self.superblock_index.evict(sb);
+                        superblocks += 1;
+                    }
+                }
+            }
+            for &b in &evicted {
+                self.block_index.evict(b);
+            }
+            blocks += evicted.len() as u64;
+            self.additions.pop_front();
+        }
+        (blocks, superblocks)
+    }
+    /// Adds blocks and superblocks learned elsewhere, e.g. from a seek
+    /// index; returns false if they contradict ones already known.  The
+    /// tables are complete from then on, so nothing is evicted.
+    pub(crate) fn restore_tables(
+        &mut self,
+        blocks: &[Box<[u8]>],
+        superblocks: &[Box<[u32]>],
+    ) -> bool {
+        self.stop_evicting();
+        blocks.iter().enumerate().all(|(idx, block)| {
+            block.len() == self.block_size as usize
+                && self
+                    .block_index
+                    .insert_exact(u32::try_from(idx).unwrap(), block.clone(), 0)
+        }) && superblocks.iter().enumerate().all(|(idx, superblock)| {
+            superblock.len() == self.superblock_size as usize
+                && self.superblock_index.insert_exact(
+                    u32::try_from(idx).unwrap(),
+                    superblock.clone(),
+                    0,
+                )
+        })
     }
 }
 
@@ -70,6 +765,8 @@ pub(crate) struct Decoder<'r, 'c, R: std::io::Read> {
     state_size: usize,
     finished: bool,
     readout_cursor: usize,
+    /* Hash of the previous checkpoint, if this one is XORed with it */
+    basis: Option<u64>,
 }
 
 impl<'r, 'c, R: std::io::Read> Decoder<'r, 'c, R> {
@@ -80,8 +777,40 @@ impl<'r, 'c, R: std::io::Read> Decoder<'r, 'c, R> {
             finished: false,
             readout_cursor: 0,
             state_size,
+            basis: None,
         }
     }
+    /// Once the checkpoint is read, the hash of the previous checkpoint
+    /// its bytes must be XORed with, if any
+    pub(crate) fn basis(&self) -> Option<u64> {
+        self.basis
+    }
+    /* Counts the padding at the end of the state described by `superseq` */
+    fn check_padding(&self, superseq: &[u32]) {
+        let block_size = self.ctx.block_size as usize;
+        let superblock_size = self.ctx.superblock_size as usize;
+        let state_blocks = self.state_size.div_ceil(block_size);
+        let (mut bytes, mut blocks, mut bad) = (0, 0, 0);
+        if superseq.len() > state_blocks.div_ceil(superblock_size) {
+            bad += 1;
+        }
+        if let Some(&last) = superseq.last() {
+            let first_block = (superseq.len() - 1) * superblock_size;
+            let used = self.state_size % block_size;
+            for (i, &block_id) in self.ctx.superblock_index.get(last).iter().enumerate() {
+                let which = first_block + i;
+                if which >= state_blocks {
+                    blocks += 1;
+                    bad += u64::from(block_id != 0);
+                } else if which + 1 == state_blocks && used != 0 {
+                    bytes += (block_size - used) as u64;
+                }
+            }
+        }
+        self.ctx.stats.count(Counter::DecPaddingBytes, bytes);
+        self.ctx.stats.count(Counter::DecPaddingBlocks, blocks);
+        self.ctx.stats.count(Counter::DecBadPadding, bad);
+    }
     fn readout(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
         match buf.write(&self.ctx.last_state[self.readout_cursor..]) {
             Err(e) => Err(e),
@@ -115,6 +844,18 @@ enum SSError {
     BadBlockInsert(u64, u32),
     #[error("Couldn't insert superblock at {1} on frame {0}")]
     BadSuperblockInsert(u64, u32),
+    #[error("Block {1} used on frame {0} was evicted")]
+    EvictedBlock(u64, u32),
+    #[error("Superblock {1} used on frame {0} was evicted")]
+    EvictedSuperblock(u64, u32),
+    #[error("Can't evict {1} on frame {0}")]
+    BadEviction(u64, u32),
+    #[error("Bad run of superblocks at {1} on frame {0}")]
+    BadRun(u64, u64),
+    #[error("Block {1} used on frame {0} doesn't exist")]
+    MissingBlock(u64, u32),
+    #[error("Superblock {1} used on frame {0} doesn't exist")]
+    MissingSuperblock(u64, u32),
 }
 
 impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
@@ -131,11 +872,15 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
             }
             return self.readout(outbuf);
         }
-        let stopwatch = clock::time(Timer::DecodeStatestream);
+        let stopwatch = self.ctx.stats.time(Timer::DecodeStatestream);
         let mut frame = 0;
         let mut state = State::WaitForStart;
         let mut buf = vec![0_u8; self.ctx.block_size as usize];
         let mut superblock = vec![0_u32; self.ctx.superblock_size as usize];
+        let mut evicted_blocks = vec![];
+        let mut evicted_superblocks = vec![];
+        let mut runs: Vec<Run> = vec![];
+        let mut keyframe = false;
         loop {
             let tok: u8 = r::read_int(self.reader).map_err(std::io::Error::other)?;
             match (
@@ -148,6 +893,25 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
                     state = State::WaitForSuperblockSeq;
                 }
                 (_, SSToken::Start) => return Err(std::io::Error::other(SSError::TooManyStarts())),
+                (State::WaitForSuperblockSeq, tok)
+                    if self.ctx.resync && !keyframe && !matches!(tok, SSToken::Keyframe) =>
+                {
+                    /* Out of step, only a keyframe can be decoded */
+                    return Err(std::io::Error::other(
+                        crate::ReplayError::SkippedCheckpoints(),
+                    ));
+                }
+                (State::WaitForSuperblockSeq, SSToken::Keyframe) => {
+                    let blocks: u32 = r::read_int(self.reader).map_err(std::io::Error::other)?;
+                    let superblocks: u32 =
+                        r::read_int(self.reader).map_err(std::io::Error::other)?;
+                    self.ctx.pad_tables(blocks as usize, superblocks as usize)?;
+                    keyframe = true;
+                }
+                (State::WaitForSuperblockSeq, SSToken::Xor) => {
+                    self.basis = Some(r::read_int(self.reader).map_err(std::io::Error::other)?);
+                    self.ctx.seen_xor = true;
+                }
                 (State::WaitForSuperblockSeq, SSToken::NewBlock) => {
                     let idx = r::read_int(self.reader).map_err(std::io::Error::other)?;
                     let bin_len = r::read_bin_len(self.reader).map_err(std::io::Error::other)?;
@@ -156,6 +920,9 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
                     }
                     self.reader.read_exact(&mut buf)?;
                     // hashes += 1;
+                    if idx as usize >= self.ctx.block_index.len() {
+                        self.ctx.check_entries()?;
+                    }
                     if !self
                         .ctx
                         .block_index
@@ -173,8 +940,18 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
                     for superblock_elt in &mut superblock {
                         *superblock_elt =
                             r::read_int(self.reader).map_err(std::io::Error::other)?;
+                        // Its blocks come before it
+                        if *superblock_elt as usize >= self.ctx.block_index.len() {
+                            return Err(std::io::Error::other(SSError::MissingBlock(
+                                frame,
+                                *superblock_elt,
+                            )));
+                        }
                     }
                     // hashes += 1;
+                    if idx as usize >= self.ctx.superblock_index.len() {
+                        self.ctx.check_entries()?;
+                    }
                     if !self.ctx.superblock_index.insert_exact(
                         idx,
                         Box::from(superblock.clone()),
@@ -185,21 +962,90 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
                         )));
                     }
                 }
+                (State::WaitForSuperblockSeq, SSToken::Evict) => {
+                    for (evicted, len) in [
+                        (&mut evicted_blocks, self.ctx.block_index.len()),
+                        (&mut evicted_superblocks, self.ctx.superblock_index.len()),
+                    ] {
+                        let count =
+                            r::read_array_len(self.reader).map_err(std::io::Error::other)?;
+                        for _ in 0..count {
+                            let idx: u32 =
+                                r::read_int(self.reader).map_err(std::io::Error::other)?;
+                            if idx == 0 || idx as usize >= len {
+                                return Err(std::io::Error::other(SSError::BadEviction(
+                                    frame, idx,
+                                )));
+                            }
+                            evicted.push(idx);
+                        }
+                    }
+                }
+                (State::WaitForSuperblockSeq, tok @ (SSToken::ZeroRun | SSToken::RepeatRun)) => {
+                    let start: u64 = r::read_int(self.reader).map_err(std::io::Error::other)?;
+                    let len: u64 = r::read_int(self.reader).map_err(std::io::Error::other)?;
+                    let zero = matches!(tok, SSToken::ZeroRun);
+                    // Runs come in order, and can't run past the end of the state
+                    let superblock_bytes =
+                        self.ctx.block_size as usize * self.ctx.superblock_size as usize;
+                    let max_end = self.state_size.div_ceil(superblock_bytes) as u64;
+                    let after = runs.last().map_or(0, |run| (run.start + run.len) as u64);
+                    if len == 0
+                        || start < after
+                        || (!zero && start == 0)
+                        || start.saturating_add(len) > max_end
+                    {
+                        return Err(std::io::Error::other(SSError::BadRun(frame, start)));
+                    }
+                    runs.push(Run {
+                        start: usize::try_from(start).unwrap(),
+                        len: usize::try_from(len).unwrap(),
+                        zero,
+                    });
+                }
                 (State::WaitForSuperblockSeq, SSToken::SuperblockSeq) => {
-                    let arr_len =
+                    let listed =
                         r::read_array_len(self.reader).map_err(std::io::Error::other)? as usize;
+                    let arr_len = listed + runs.iter().map(|run| run.len).sum::<usize>();
+                    if let Some(run) = runs.last().filter(|run| run.start + run.len > arr_len) {
+                        return Err(std::io::Error::other(SSError::BadRun(
+                            frame,
+                            run.start as u64,
+                        )));
+                    }
+                    let mut runs = runs.iter().peekable();
                     let last_state_valid = self.ctx.last_superseq.len() >= arr_len
                         && self.ctx.last_state.len() >= self.state_size;
                     let block_byte_size = self.ctx.block_size as usize;
                     let superblock_byte_size = self.ctx.superblock_size as usize * block_byte_size;
-                    let mut superseq = vec![0; arr_len];
+                    // The length comes from the stream, so only trust it as far as the state goes
+                    let mut superseq = Vec::with_capacity(
+                        arr_len.min(self.state_size.div_ceil(superblock_byte_size)),
+                    );
                     self.ctx.last_state.resize(self.state_size, 0);
                     let mut skipped_superblocks = 0;
                     let mut skipped_blocks = 0;
-                    for (superblock_i, superseq_sblk) in superseq.iter_mut().enumerate() {
-                        let superblock_idx =
-                            r::read_int(self.reader).map_err(std::io::Error::other)?;
-                        *superseq_sblk = superblock_idx;
+                    for superblock_i in 0..arr_len {
+                        let superblock_idx = match runs.peek() {
+                            Some(&&run) if run.start <= superblock_i => {
+                                if superblock_i + 1 == run.start + run.len {
+                                    runs.next();
+                                }
+                                if run.zero {
+                                    0
+                                } else {
+                                    superseq[superblock_i - 1]
+                                }
+                            }
+                            _ => r::read_int(self.reader).map_err(std::io::Error::other)?,
+                        };
+                        if superblock_idx as usize >= self.ctx.superblock_index.len() {
+                            return Err(std::io::Error::other(SSError::MissingSuperblock(
+                                frame,
+                                superblock_idx,
+                            )));
+                        }
+                        superseq.push(superblock_idx);
                         if last_state_valid
                             && self.ctx.last_superseq[superblock_i] == superblock_idx
                         {
@@ -208,6 +1054,12 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
                             continue;
                         }
                         let superblock_data = self.ctx.superblock_index.get(superblock_idx);
+                        if superblock_data.is_empty() {
+                            return Err(std::io::Error::other(SSError::EvictedSuperblock(
+                                frame,
+                                superblock_idx,
+                            )));
+                        }
                         for (block_i, block_id) in superblock_data.iter().copied().enumerate() {
                             if last_state_valid
                                 && self
@@ -229,13 +1081,39 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
                                 // This can happen in the last superblock if it was padded with extra blocks
                                 break;
                             }
+                            if block_bytes.is_empty() {
+                                return Err(std::io::Error::other(SSError::EvictedBlock(
+                                    frame, block_id,
+                                )));
+                            }
                             self.ctx.last_state[block_start..block_end]
                                 .copy_from_slice(&block_bytes[0..(block_end - block_start)]);
                         }
                     }
-                    clock::count(Counter::DecSkippedSuperblocks, skipped_superblocks);
-                    clock::count(Counter::DecSkippedBlocks, skipped_blocks);
+                    self.ctx
+                        .stats
+                        .count(Counter::DecSkippedSuperblocks, skipped_superblocks);
+                    self.ctx
+                        .stats
+                        .count(Counter::DecSkippedBlocks, skipped_blocks);
+                    self.check_padding(&superseq);
                     self.ctx.last_superseq = superseq;
+                    /* Evictions take effect once the checkpoint is decoded */
+                    if !self.ctx.keep_all {
+                        self.ctx.evict(&evicted_blocks, &evicted_superblocks);
+                        self.ctx
+                            .stats
+                            .count(Counter::DecEvictedBlocks, evicted_blocks.len() as u64);
+                        self.ctx.stats.count(
+                            Counter::DecEvictedSuperblocks,
+                            evicted_superblocks.len() as u64,
+                        );
+                    }
+                    let (blocks, superblocks) = self.ctx.end_checkpoint(frame);
+                    self.ctx.stats.count(Counter::DecEvictedBlocks, blocks);
+                    self.ctx
+                        .stats
+                        .count(Counter::DecEvictedSuperblocks, superblocks);
                     state = State::Finished;
                     self.finished = true;
                     break;
@@ -249,6 +1127,48 @@ impl<R: std::io::Read> std::io::Read for Decoder<'_, '_, R> {
     }
 }
 
+/// Whether the statestream checkpoint read from `reader` is a keyframe,
+/// reading only as far as it takes to tell.
+pub(crate) fn is_keyframe<R: std::io::Read>(reader: &mut R) -> bool {
+    use rmp::decode as r;
+    r::read_int::<u8, _>(reader).ok() == Some(u8::from(SSToken::Start))
+        && r::read_int::<u64, _>(reader).is_ok()
+        && r::read_int::<u8, _>(reader).ok() == Some(u8::from(SSToken::Keyframe))
+}
+
+/* Smallest state whose blocks are hashed on several threads */
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_BYTES: usize = 256 * 1024;
+
+/* For each block of `checkpoint`, None if it matches `last_state`, or else its hash, padded
+with zeros to a whole block */
+#[cfg(feature = "parallel")]
+fn prehash(
+    checkpoint: &[u8],
+    last_state: Option<&[u8]>,
+    block_size: usize,
+    hasher: BlockHash,
+) -> Vec<Option<u128>> {
+    use rayon::prelude::*;
+    checkpoint
+        .par_chunks(block_size)
+        .enumerate()
+        .map(|(i, block)| {
+            let start = i * block_size;
+            if last_state.is_some_and(|last| block == &last[start..start + block.len()]) {
+                return None;
+            }
+            Some(if block.len() < block_size {
+                let mut padded = block.to_vec();
+                padded.resize(block_size, 0);
+                hasher.hash(&padded)
+            } else {
+                hasher.hash(block)
+            })
+        })
+        .collect()
+}
+
 pub(crate) struct Encoder<'w, 'c, W: std::io::Write> {
     writer: &'w mut W,
     ctx: &'c mut Ctx,
@@ -277,26 +1197,81 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
     pub(crate) fn new(writer: &'w mut W, ctx: &'c mut Ctx) -> Self {
         Self { writer, ctx }
     }
+    pub fn encode_checkpoint(self, checkpoint: &[u8], frame: u64) -> std::io::Result<u32> {
+        self.encode(checkpoint, None, frame)
+    }
+    /// Encodes `checkpoint`, as its XOR with `previous` if XOR deltas are
+    /// on, `previous` isn't empty, and this isn't a keyframe.
+    pub(crate) fn encode_checkpoint_against(
+        self,
+        checkpoint: &[u8],
+        previous: &[u8],
+        frame: u64,
+    ) -> std::io::Result<u32> {
+        let xor = self.ctx.xor && !self.ctx.keyframe_next && !previous.is_empty();
+        self.encode(checkpoint, xor.then_some(previous), frame)
+    }
     #[allow(clippy::too_many_lines)]
-    pub fn encode_checkpoint(mut self, checkpoint: &[u8], frame: u64) -> std::io::Result<u32> {
+    fn encode(
+        mut self,
+        checkpoint: &[u8],
+        basis: Option<&[u8]>,
+        frame: u64,
+    ) -> std::io::Result<u32> {
         use rmp::encode as r;
-        let stopwatch = clock::time(Timer::EncodeStatestream);
-        clock::count(Counter::EncTotalKBsIn, (checkpoint.len() / 1024) as u64);
+        let stopwatch = self.ctx.stats.time(Timer::EncodeStatestream);
+        self.ctx
+            .stats
+            .count(Counter::EncTotalKBsIn, (checkpoint.len() / 1024) as u64);
         let mut bytes_out = 0;
         bytes_out += rmp_size(r::write_uint(
             &mut self.writer,
             u64::from(u8::from(SSToken::Start)),
         )?);
         bytes_out += rmp_size(r::write_uint(&mut self.writer, frame)?);
+        /* A keyframe sends every object it uses once, whether new or not,
+        and so do the checkpoints after it, until the next keyframe, for
+        objects older than it that a decoder starting there hasn't seen */
+        if std::mem::take(&mut self.ctx.keyframe_next) {
+            bytes_out += rmp_size(r::write_uint(
+                &mut self.writer,
+                u64::from(u8::from(SSToken::Keyframe)),
+            )?);
+            for len in [self.ctx.block_index.len(), self.ctx.superblock_index.len()] {
+                bytes_out += rmp_size(r::write_uint(&mut self.writer, len as u64)?);
+            }
+            self.ctx.since_keyframe = Some(SinceKeyframe {
+                blocks: u32::try_from(self.ctx.block_index.len()).unwrap(),
+                superblocks: u32::try_from(self.ctx.superblock_index.len()).unwrap(),
+                sent_blocks: HashSet::new(),
+                sent_superblocks: HashSet::new(),
+            });
+        }
+        let delta;
+        let checkpoint = if let Some(basis) = basis {
+            bytes_out += rmp_size(r::write_uint(
+                &mut self.writer,
+                u64::from(u8::from(SSToken::Xor)),
+            )?);
+            bytes_out += rmp_size(r::write_uint(&mut self.writer, xxh3_64(basis))?);
+            let mut xored = checkpoint.to_vec();
+            xor_into(&mut xored, basis);
+            delta = xored;
+            &delta[..]
+        } else {
+            checkpoint
+        };
         let block_size = self.ctx.block_size as usize;
         let mut padded_block = vec![0; block_size];
         let superblock_size = self.ctx.superblock_size as usize;
         let superblock_size_bytes = block_size * superblock_size;
-        let superblock_count = ((checkpoint.len() - 1) / superblock_size_bytes) + 1;
-        clock::count(Counter::EncTotalSuperblocks, superblock_count as u64);
-        clock::count(
+        let superblock_count = checkpoint.len().div_ceil(superblock_size_bytes);
+        self.ctx
+            .stats
+            .count(Counter::EncTotalSuperblocks, superblock_count as u64);
+        self.ctx.stats.count(
             Counter::EncTotalBlocks,
-            (((checkpoint.len() - 1) / block_size) + 1) as u64,
+            checkpoint.len().div_ceil(block_size) as u64,
         );
         let mut reused_blocks = 0;
         let mut reused_superblocks = 0;
@@ -315,6 +1290,19 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
             self.ctx.last_state.truncate(checkpoint.len());
             self.ctx.use_encode_state_comparisons
         };
+        /* Whether each block matches the last state, or else its hash, worked out up front on
+        every thread for big states; indices are still assigned in order below */
+        #[cfg(feature = "parallel")]
+        let prehashed = (checkpoint.len() >= PARALLEL_MIN_BYTES).then(|| {
+            prehash(
+                checkpoint,
+                can_compare_saves.then_some(&self.ctx.last_state[..]),
+                block_size,
+                self.ctx.hash,
+            )
+        });
+        #[cfg(not(feature = "parallel"))]
+        let prehashed: Option<Vec<Option<u128>>> = None;
         for (superblock_i, (superblock_bytes, last_state_superblock_bytes)) in (checkpoint
             .chunks(superblock_size_bytes)
             .zip(self.ctx.last_state.chunks(superblock_size_bytes)))
@@ -322,10 +1310,12 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
         {
             /* maybe: skip superblocks */
             if superblock_bytes.len() < superblock_size_bytes {
-                let block_count = (superblock_bytes.len() - 1) / block_size + 1;
-                if block_count + 1 < superblock_size {
-                    superblock_contents[(block_count + 1)..].fill(0);
-                }
+                let block_count = superblock_bytes.len().div_ceil(block_size);
+                self.ctx.stats.count(
+                    Counter::EncPaddingBlocks,
+                    (superblock_size - block_count) as u64,
+                );
+                superblock_contents[block_count..].fill(0);
             }
             for (block_i, (block_bytes, last_state_block_bytes)) in (superblock_bytes
                 .chunks(block_size)
@@ -333,9 +1323,18 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
             .enumerate()
             {
                 memcmps += u64::from(can_compare_saves);
-                let found_block = if can_compare_saves
-                    && block_bytes[..] == last_state_block_bytes[..block_bytes.len()]
-                {
+                let (same, hash) = match &prehashed {
+                    Some(prehashed) => {
+                        let hash = prehashed[superblock_i * superblock_size + block_i];
+                        (hash.is_none(), hash)
+                    }
+                    None => (
+                        can_compare_saves
+                            && block_bytes[..] == last_state_block_bytes[..block_bytes.len()],
+                        None,
+                    ),
+                };
+                let found_block = if same {
                     skipped_blocks += 1;
                     blockindex::Insertion {
                         index: self
@@ -345,16 +1344,58 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
                         is_new: false,
                     }
                 } else if block_bytes.len() < block_size {
+                    self.ctx.stats.count(
+                        Counter::EncPaddingBytes,
+                        (block_size - block_bytes.len()) as u64,
+                    );
                     padded_block[block_bytes.len()..].fill(0);
                     padded_block[..block_bytes.len()].copy_from_slice(block_bytes);
                     hashes += 1;
-                    self.ctx.block_index.insert(&padded_block, frame)
+                    match hash {
+                        Some(hash) => {
+                            self.ctx
+                                .block_index
+                                .insert_hashed(&padded_block, hash, frame)
+                        }
+                        None => self.ctx.block_index.insert(&padded_block, frame),
+                    }
                 } else {
                     hashes += 1;
-                    self.ctx.block_index.insert(block_bytes, frame)
+                    match hash {
+                        Some(hash) => self.ctx.block_index.insert_hashed(block_bytes, hash, frame),
+                        None => self.ctx.block_index.insert(block_bytes, frame),
+                    }
                 };
                 superblock_contents[block_i] = found_block.index;
-                if found_block.is_new {
+                #[cfg(feature = "research")]
+                if let Some(log) = self.ctx.research.as_mut() {
+                    use crate::research::BlockMatch;
+                    let kind = if found_block.is_new {
+                        BlockMatch::New
+                    } else if can_compare_saves
+                        && block_bytes[..] == last_state_block_bytes[..block_bytes.len()]
+                    {
+                        BlockMatch::Skipped
+                    } else {
+                        BlockMatch::Reused
+                    };
+                    log.block(
+                        frame,
+                        superblock_i * superblock_size + block_i,
+                        found_block.index,
+                        block_bytes,
+                        kind,
+                    )?;
+                }
+                let send = found_block.is_new
+                    || self.ctx.since_keyframe.as_mut().is_some_and(|since| {
+                        found_block.index < since.blocks
+                            && since.sent_blocks.insert(found_block.index)
+                    });
+                if !found_block.is_new {
+                    reused_blocks += 1;
+                }
+                if send {
                     let block_out_bytes = self.ctx.block_index.get(found_block.index);
                     bytes_out += rmp_size(r::write_uint(
                         self.writer,
@@ -365,8 +1406,6 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
                     bytes_out += rmp_size(r::write_bin_len(self.writer, self.ctx.block_size)?);
                     self.writer.write_all(block_out_bytes)?;
                     bytes_out += block_out_bytes.len();
-                } else {
-                    reused_blocks += 1;
                 }
             }
             hashes += 1;
@@ -375,7 +1414,19 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
                 .superblock_index
                 .insert(&superblock_contents, frame);
             self.ctx.last_superseq[superblock_i] = found_superblock.index;
-            if found_superblock.is_new {
+            #[cfg(feature = "research")]
+            if let Some(log) = self.ctx.research.as_mut() {
+                log.superblock(found_superblock.is_new);
+            }
+            let send = found_superblock.is_new
+                || self.ctx.since_keyframe.as_mut().is_some_and(|since| {
+                    found_superblock.index < since.superblocks
+                        && since.sent_superblocks.insert(found_superblock.index)
+                });
+            if !found_superblock.is_new {
+                reused_superblocks += 1;
+            }
+            if send {
                 bytes_out += rmp_size(r::write_uint(
                     self.writer,
                     u64::from(u8::from(SSToken::NewSuperblock)),
@@ -388,31 +1439,467 @@ impl<'w, 'c, W: std::io::Write> Encoder<'w, 'c, W> {
                 for blkid in &superblock_contents {
                     bytes_out += rmp_size(r::write_uint(self.writer, u64::from(*blkid))?);
                 }
-            } else {
-                reused_superblocks += 1;
             }
         }
-        clock::count(Counter::EncReusedBlocks, reused_blocks);
-        clock::count(Counter::EncReusedSuperblocks, reused_superblocks);
-        clock::count(Counter::EncSkippedBlocks, skipped_blocks);
-        clock::count(Counter::EncMemCmps, memcmps);
-        clock::count(Counter::EncHashes, hashes);
+        self.ctx
+            .stats
+            .count(Counter::EncReusedBlocks, reused_blocks);
+        self.ctx
+            .stats
+            .count(Counter::EncReusedSuperblocks, reused_superblocks);
+        self.ctx
+            .stats
+            .count(Counter::EncSkippedBlocks, skipped_blocks);
+        self.ctx.stats.count(Counter::EncMemCmps, memcmps);
+        self.ctx.stats.count(Counter::EncHashes, hashes);
         self.ctx.last_superseq.truncate(superblock_count);
+        let (evicted_blocks, evicted_superblocks) = self.ctx.evict_over_budget();
+        if let Some(lookahead) = &mut self.ctx.lookahead {
+            lookahead.encoded += 1;
+        }
+        if !evicted_blocks.is_empty() {
+            bytes_out += rmp_size(r::write_uint(
+                self.writer,
+                u64::from(u8::from(SSToken::Evict)),
+            )?);
+            for evicted in [&evicted_blocks, &evicted_superblocks] {
+                bytes_out += rmp_size(r::write_array_len(
+                    self.writer,
+                    u32::try_from(evicted.len()).unwrap(),
+                )?);
+                for idx in evicted {
+                    bytes_out += rmp_size(r::write_uint(self.writer, u64::from(*idx))?);
+                }
+            }
+        }
+        self.ctx
+            .stats
+            .count(Counter::EncEvictedBlocks, evicted_blocks.len() as u64);
+        self.ctx.stats.count(
+            Counter::EncEvictedSuperblocks,
+            evicted_superblocks.len() as u64,
+        );
+        /* The next checkpoint's skip comparisons are against this one */
+        self.ctx.last_state.clear();
+        self.ctx.last_state.extend_from_slice(checkpoint);
+        /* Runs go before the sequence, which lists the entries outside them */
+        let runs = if self.ctx.runs {
+            find_runs(&self.ctx.last_superseq)
+        } else {
+            vec![]
+        };
+        let mut listed = vec![true; superblock_count];
+        for run in &runs {
+            let token = if run.zero {
+                SSToken::ZeroRun
+            } else {
+                SSToken::RepeatRun
+            };
+            bytes_out += rmp_size(r::write_uint(self.writer, u64::from(u8::from(token)))?);
+            bytes_out += rmp_size(r::write_uint(self.writer, run.start as u64)?);
+            bytes_out += rmp_size(r::write_uint(self.writer, run.len as u64)?);
+            listed[run.start..run.start + run.len].fill(false);
+        }
+        let run_entries = runs.iter().map(|run| run.len).sum::<usize>();
+        self.ctx
+            .stats
+            .count(Counter::EncRunSuperblocks, run_entries as u64);
         bytes_out += rmp_size(r::write_uint(
             self.writer,
             u64::from(u8::from(SSToken::SuperblockSeq)),
         )?);
         bytes_out += rmp_size(r::write_array_len(
             self.writer,
-            u32::try_from(superblock_count)
+            u32::try_from(superblock_count - run_entries)
                 .map_err(|e| std::io::Error::other(crate::ReplayError::CheckpointTooBig(e)))?,
         )?);
-        for super_id in &self.ctx.last_superseq {
+        for (super_id, _) in self
+            .ctx
+            .last_superseq
+            .iter()
+            .zip(&listed)
+            .filter(|(_, listed)| **listed)
+        {
             bytes_out += rmp_size(r::write_uint(self.writer, u64::from(*super_id))?);
         }
+        let (evicted_blocks, evicted_superblocks) = self.ctx.end_checkpoint(frame);
+        self.ctx
+            .stats
+            .count(Counter::EncEvictedBlocks, evicted_blocks);
+        self.ctx
+            .stats
+            .count(Counter::EncEvictedSuperblocks, evicted_superblocks);
+        #[cfg(feature = "research")]
+        if let Some(log) = self.ctx.research.as_mut() {
+            log.end_checkpoint();
+        }
         drop(stopwatch);
-        clock::count(Counter::EncTotalKBsOut, (bytes_out / 1024) as u64);
+        self.ctx
+            .stats
+            .count(Counter::EncTotalKBsOut, (bytes_out / 1024) as u64);
         u32::try_from(bytes_out)
             .map_err(|e| std::io::Error::other(crate::ReplayError::CheckpointTooBig(e)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_changing_states() {
+        let mut enc_ctx = Ctx::new(16, 4);
+        let mut dec_ctx = Ctx::new(16, 4);
+        let mut state: Vec<u8> = (0..300_u32).map(|i| (i % 7) as u8).collect();
+        for (frame, edit) in [0_usize, 17, 250, 299, 0, 64, 5, 3].into_iter().enumerate() {
+            state[edit] = state[edit].wrapping_add(1);
+            match frame {
+                4 => state.truncate(190),
+                // Grow within the last block, then past the last superblock
+                6 => state.extend_from_slice(&[9; 2]),
+                7 => state.extend_from_slice(&[7; 100]),
+                _ => {}
+            }
+            let mut out = vec![];
+            let size = Encoder::new(&mut out, &mut enc_ctx)
+                .encode_checkpoint(&state, frame as u64)
+                .unwrap();
+            assert_eq!(size as usize, out.len());
+            let mut decoded = vec![];
+            let mut reader = out.as_slice();
+            std::io::Read::read_to_end(
+                &mut Decoder::new(&mut reader, &mut dec_ctx, state.len()),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, state);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn padding() {
+        let mut enc_ctx = Ctx::new(16, 4);
+        let mut dec_ctx = Ctx::new(16, 4);
+        /* 100 bytes: 7 blocks in 2 superblocks, so 12 padding bytes and 1 padding block */
+        let state = vec![3_u8; 100];
+        let (enc_bytes, enc_blocks) = (
+            clock::counts(Counter::EncPaddingBytes),
+            clock::counts(Counter::EncPaddingBlocks),
+        );
+        let mut out = vec![];
+        Encoder::new(&mut out, &mut enc_ctx)
+            .encode_checkpoint(&state, 0)
+            .unwrap();
+        // Other tests may be encoding at the same time
+        assert!(clock::counts(Counter::EncPaddingBytes) >= enc_bytes + 12);
+        assert!(clock::counts(Counter::EncPaddingBlocks) > enc_blocks);
+        let bad = clock::counts(Counter::DecBadPadding);
+        let mut decoded = vec![];
+        std::io::Read::read_to_end(
+            &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, state);
+        assert_eq!(clock::counts(Counter::DecBadPadding), bad);
+
+        // Reading it as a shorter state finds nonzero blocks where padding should be
+        let mut dec_ctx = Ctx::new(16, 4);
+        std::io::Read::read_to_end(
+            &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, 40),
+            &mut decoded,
+        )
+        .unwrap();
+        assert!(clock::counts(Counter::DecBadPadding) > bad);
+    }
+
+    #[test]
+    fn commits() {
+        let mut enc_ctx = Ctx::new(16, 4);
+        let mut dec_ctx = Ctx::new(16, 4);
+        enc_ctx.set_commit_settings(2, 2);
+        dec_ctx.set_commit_settings(2, 2);
+        // Decoders that keep every block can read the stream too
+        let mut keep_ctx = Ctx::new(16, 4);
+        let base = vec![1_u8; 64];
+        let mut once = base.clone();
+        once[..16].fill(2);
+        let mut twice = base.clone();
+        twice[16..32].fill(3);
+        let mut blocks = vec![];
+        for (frame, state) in [&base, &once, &twice, &twice, &base, &once]
+            .into_iter()
+            .enumerate()
+        {
+            let mut out = vec![];
+            Encoder::new(&mut out, &mut enc_ctx)
+                .encode_checkpoint(state, frame as u64)
+                .unwrap();
+            for ctx in [&mut dec_ctx, &mut keep_ctx] {
+                let mut decoded = vec![];
+                std::io::Read::read_to_end(
+                    &mut Decoder::new(&mut out.as_slice(), ctx, state.len()),
+                    &mut decoded,
+                )
+                .unwrap();
+                assert_eq!(&decoded, state);
+            }
+            blocks.push(enc_ctx.block_index.len());
+        }
+        /* The block only `once` uses is evicted once `twice` is committed,
+         * so it's sent again under a new index; `twice`'s block is kept */
+        assert_eq!(blocks, [2, 3, 4, 4, 4, 5]);
+        assert_eq!(enc_ctx.block_index.evicted(), 1);
+        assert_eq!(dec_ctx.block_index.evicted(), 1);
+        assert_eq!(dec_ctx.superblock_index.evicted(), 1);
+        assert!(dec_ctx.block_index.get(2).is_empty());
+        assert_eq!(keep_ctx.block_index.evicted(), 0);
+    }
+
+    #[test]
+    fn keyframes() {
+        let mut enc_ctx = Ctx::new(16, 4);
+        let base = vec![1_u8; 64];
+        let mut old = base.clone();
+        old[..16].fill(2);
+        let mut checkpoints = vec![];
+        for (frame, state) in [&base, &old, &base, &old].into_iter().enumerate() {
+            if frame == 2 {
+                enc_ctx.request_keyframe();
+            }
+            let mut out = vec![];
+            Encoder::new(&mut out, &mut enc_ctx)
+                .encode_checkpoint(state, frame as u64)
+                .unwrap();
+            checkpoints.push(out);
+        }
+        /* A decoder starting at the keyframe never saw `old`'s block or
+         * superblock, which the keyframe doesn't use, so the checkpoint
+         * after it sends them again */
+        let mut dec_ctx = Ctx::new(16, 4);
+        dec_ctx.set_resync(true);
+        for (out, state) in checkpoints[2..].iter().zip([&base, &old]) {
+            let mut decoded = vec![];
+            std::io::Read::read_to_end(
+                &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(&decoded, state);
+        }
+        assert_eq!(enc_ctx.block_index.len(), 3);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let mut enc_ctx = Ctx::new(16, 4);
+        let mut dec_ctx = Ctx::new(16, 4);
+        let budget = 1024;
+        enc_ctx.set_memory_budget(Some(budget));
+        let mut state = vec![0_u8; 256];
+        for frame in 0..100_u64 {
+            // Every checkpoint brings a few new blocks
+            for (i, byte) in state.iter_mut().enumerate().step_by(50) {
+                *byte = byte.wrapping_add(u8::try_from(frame % 7 + i as u64 % 3).unwrap());
+            }
+            let mut out = vec![];
+            Encoder::new(&mut out, &mut enc_ctx)
+                .encode_checkpoint(&state, frame)
+                .unwrap();
+            let mut decoded = vec![];
+            std::io::Read::read_to_end(
+                &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, state);
+            /* The initial state and latest checkpoint are always kept */
+            assert!(enc_ctx.table_bytes() <= budget.max(2 * 256 + 64));
+            assert_eq!(dec_ctx.table_bytes(), enc_ctx.table_bytes());
+        }
+        assert!(enc_ctx.has_evicted());
+    }
+
+    #[test]
+    fn runs() {
+        // Zeros, a repeated pattern, and a little of everything else
+        let mut state = vec![0_u8; 64 * 20];
+        state[64 * 8..64 * 16].fill(0xff);
+        state[64 * 19 + 5] = 1;
+        let (mut plain_ctx, mut enc_ctx, mut dec_ctx) =
+            (Ctx::new(16, 4), Ctx::new(16, 4), Ctx::new(16, 4));
+        enc_ctx.set_runs(true);
+        for frame in 0..3 {
+            state[64 * 18] = frame as u8;
+            let (mut plain, mut out) = (vec![], vec![]);
+            Encoder::new(&mut plain, &mut plain_ctx)
+                .encode_checkpoint(&state, frame)
+                .unwrap();
+            Encoder::new(&mut out, &mut enc_ctx)
+                .encode_checkpoint(&state, frame)
+                .unwrap();
+            assert!(out.len() < plain.len());
+            let mut decoded = vec![];
+            std::io::Read::read_to_end(
+                &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, state);
+        }
+        assert_eq!(
+            find_runs(&enc_ctx.last_superseq),
+            [
+                Run {
+                    start: 0,
+                    len: 8,
+                    zero: true
+                },
+                Run {
+                    start: 9,
+                    len: 7,
+                    zero: false
+                },
+            ]
+        );
+
+        // A run past the end of the state is an error
+        let mut out = vec![];
+        for n in [0, 0, 6, 0, 100, 3, 0] {
+            rmp::encode::write_uint(&mut out, n).unwrap();
+        }
+        let mut dec_ctx = Ctx::new(16, 4);
+        assert!(
+            std::io::Read::read_to_end(
+                &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+                &mut vec![],
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn missing_objects() {
+        for (stream, missing) in [
+            // A sequence naming superblock 5 when only the zero one exists
+            (&[0, 0, 3, 0x91, 5][..], "Superblock 5"),
+            // A superblock made of block 7, which was never sent
+            (&[0, 0, 2, 1, 0x94, 0, 0, 0, 7, 3, 0x91, 1], "Block 7"),
+        ] {
+            let mut dec_ctx = Ctx::new(16, 4);
+            let err = std::io::Read::read_to_end(
+                &mut Decoder::new(&mut &stream[..], &mut dec_ctx, 64),
+                &mut vec![],
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("{missing} used on frame 0 doesn't exist")
+            );
+        }
+    }
+
+    #[test]
+    fn block_hashes() {
+        let mut hashes = vec![(BlockHash::Xxh3_64, true), (BlockHash::Xxh3_128, false)];
+        hashes.push((BlockHash::Xxh3_128, true));
+        #[cfg(feature = "blake3")]
+        hashes.push((BlockHash::Blake3, true));
+        let mut state: Vec<u8> = (0..64 * 20).map(|i| (i % 7) as u8).collect();
+        let mut plain_ctx = Ctx::new(16, 4);
+        let mut ctxs: Vec<_> = hashes.iter().map(|_| Ctx::new(16, 4)).collect();
+        for frame in 0..6 {
+            state[frame as usize * 50] ^= 0x55;
+            // Switching hashes partway through rehashes the tables
+            if frame == 2 {
+                for (ctx, &(hash, trust)) in ctxs.iter_mut().zip(&hashes) {
+                    ctx.set_block_hash(hash, trust);
+                }
+            }
+            let mut plain = vec![];
+            Encoder::new(&mut plain, &mut plain_ctx)
+                .encode_checkpoint(&state, frame)
+                .unwrap();
+            // Only the encoder's lookups change, not what it writes
+            for ctx in &mut ctxs {
+                let mut out = vec![];
+                Encoder::new(&mut out, ctx)
+                    .encode_checkpoint(&state, frame)
+                    .unwrap();
+                assert_eq!(out, plain);
+            }
+        }
+        assert!(!BlockHash::Xxh3_64.is_wide() && BlockHash::Xxh3_128.is_wide());
+    }
+
+    #[test]
+    fn lookahead() {
+        // Blocks come back in a cycle too long for the budget, where
+        // evicting the least recently used block is always wrong
+        let states: Vec<Vec<u8>> = (0..60_u8)
+            .map(|k| (0..4_u8).flat_map(|j| [(k + j) % 10 + 1; 16]).collect())
+            .collect();
+        let budget = 8 * 16 + 8 * 16;
+        let encode = |lookahead: bool| {
+            let mut enc_ctx = Ctx::new(16, 4);
+            let mut dec_ctx = Ctx::new(16, 4);
+            enc_ctx.set_memory_budget(Some(budget));
+            if lookahead {
+                enc_ctx.set_lookahead(states.iter().map(Vec::as_slice));
+            }
+            let mut total = 0;
+            for (frame, state) in states.iter().enumerate() {
+                let mut out = vec![];
+                Encoder::new(&mut out, &mut enc_ctx)
+                    .encode_checkpoint(state, frame as u64)
+                    .unwrap();
+                let mut decoded = vec![];
+                std::io::Read::read_to_end(
+                    &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+                    &mut decoded,
+                )
+                .unwrap();
+                assert_eq!(&decoded, state);
+                total += out.len();
+            }
+            assert!(enc_ctx.has_evicted());
+            total
+        };
+        let (lru, lookahead) = (encode(false), encode(true));
+        assert!(lookahead < lru, "{lookahead} vs {lru}");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_hashing() {
+        // Big enough to hash on several threads, and ending in a partial block
+        let mut state: Vec<u8> = (0..PARALLEL_MIN_BYTES + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let (mut enc_ctx, mut dec_ctx) = (Ctx::new(64, 16), Ctx::new(64, 16));
+        for frame in 0..4 {
+            state[frame as usize * 4096] ^= 0xff;
+            let last = enc_ctx.last_state.clone();
+            let basis = (last.len() == state.len()).then_some(&last[..]);
+            let prehashed = prehash(&state, basis, 64, BlockHash::Xxh3_64);
+            for (i, (block, hash)) in state.chunks(64).zip(&prehashed).enumerate() {
+                let mut padded = block.to_vec();
+                padded.resize(64, 0);
+                let same = basis.is_some_and(|last| block == &last[i * 64..i * 64 + block.len()]);
+                assert_eq!(*hash, (!same).then(|| BlockHash::Xxh3_64.hash(&padded)));
+            }
+            let mut out = vec![];
+            Encoder::new(&mut out, &mut enc_ctx)
+                .encode_checkpoint(&state, frame)
+                .unwrap();
+            let mut decoded = vec![];
+            std::io::Read::read_to_end(
+                &mut Decoder::new(&mut out.as_slice(), &mut dec_ctx, state.len()),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, state);
+        }
+    }
+}
@@ -1,21 +1,64 @@
 use nohash_hasher::NoHashHasher;
 use smallvec::{SmallVec, smallvec};
 use std::{collections::HashMap, hash::BuildHasherDefault};
-use xxhash_rust::xxh3::xxh3_64 as xxh;
+use xxhash_rust::xxh3::{xxh3_64, xxh3_128};
 
-// struct Addition {
-//     when:u64, // Frame on which some objects were added
-//     index:u32, // Lowest index added on this frame
-// }
+/// Hash function the statestream encoder uses to recognize blocks and
+/// superblocks it has seen before.  Indices are written explicitly, so
+/// the choice doesn't affect the stream, only how fast the encoder is
+/// and how much it can trust a matching hash.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlockHash {
+    #[default]
+    Xxh3_64,
+    Xxh3_128,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl BlockHash {
+    /// Whether the hash has 128 bits or more, so that trusting a match
+    /// without comparing the bytes is reasonable
+    #[must_use]
+    pub fn is_wide(self) -> bool {
+        self != BlockHash::Xxh3_64
+    }
+    pub(crate) fn hash<T: bytemuck::AnyBitPattern + bytemuck::NoUninit>(self, val: &[T]) -> u128 {
+        let bytes = bytemuck::cast_slice(val);
+        match self {
+            BlockHash::Xxh3_64 => u128::from(xxh3_64(bytes)),
+            BlockHash::Xxh3_128 => xxh3_128(bytes),
+            #[cfg(feature = "blake3")]
+            BlockHash::Blake3 => {
+                let digest = blake3::hash(bytes);
+                u128::from_le_bytes(digest.as_bytes()[..16].try_into().unwrap())
+            }
+        }
+    }
+}
+
+/* The index is keyed by the low 64 bits of each hash */
+#[expect(clippy::cast_possible_truncation)]
+fn key(hash: u128) -> u64 {
+    hash as u64
+}
 
+#[derive(Clone)]
 pub(crate) struct BlockIndex<
     T: bytemuck::Zeroable + bytemuck::AnyBitPattern + bytemuck::NoUninit + PartialEq,
 > {
     index: HashMap<u64, SmallVec<[u32; 4]>, BuildHasherDefault<NoHashHasher<u64>>>,
     objects: Vec<Box<[T]>>,
-    hashes: Vec<u64>,
-    //additions: Vec<Addition>,
+    hashes: Vec<u128>,
+    /// Times each object has been used since it was added
+    uses: Vec<u32>,
+    /// Checkpoint on which each object was last used
+    last_used: Vec<u64>,
+    evicted: usize,
     object_size: usize,
+    hasher: BlockHash,
+    /* Whether a matching wide hash is taken as a match without comparing bytes */
+    trust: bool,
 }
 
 pub(crate) struct Insertion {
@@ -23,35 +66,63 @@ pub(crate) struct Insertion {
     pub is_new: bool,
 }
 
-fn hash<T: bytemuck::AnyBitPattern + bytemuck::NoUninit>(val: &[T]) -> u64 {
-    xxh(bytemuck::cast_slice(val))
-}
-
 impl<T: bytemuck::Zeroable + bytemuck::AnyBitPattern + bytemuck::NoUninit + PartialEq>
     BlockIndex<T>
 {
     pub fn new(object_size: usize) -> Self {
         let mut index = HashMap::with_capacity_and_hasher(4096, BuildHasherDefault::default());
         let zeros = (vec![T::zeroed(); object_size]).into_boxed_slice();
-        let zero_hash = hash(&zeros);
-        index.insert(zero_hash, smallvec![0]);
+        let zero_hash = BlockHash::default().hash(&zeros);
+        index.insert(key(zero_hash), smallvec![0]);
         Self {
             index,
             object_size,
             objects: vec![zeros],
             hashes: vec![zero_hash],
+            uses: vec![0],
+            last_used: vec![0],
+            evicted: 0,
+            hasher: BlockHash::default(),
+            trust: false,
+        }
+    }
+    /// Switches hash functions, hashing every object again.  With `trust`
+    /// and a wide hash, objects with the same hash are taken to be equal.
+    pub fn set_hash(&mut self, hasher: BlockHash, trust: bool) {
+        self.trust = trust && hasher.is_wide();
+        if hasher == self.hasher {
+            return;
         }
+        self.hasher = hasher;
+        self.index.clear();
+        for (idx, obj) in self.objects.iter().enumerate() {
+            if obj.is_empty() {
+                continue;
+            }
+            let hash = hasher.hash(obj);
+            self.hashes[idx] = hash;
+            self.index
+                .entry(key(hash))
+                .or_default()
+                .push(u32::try_from(idx).unwrap());
+        }
+    }
+    pub fn insert(&mut self, obj: &[T], frame: u64) -> Insertion {
+        self.insert_hashed(obj, self.hasher.hash(obj), frame)
     }
-    pub fn insert(&mut self, obj: &[T], _frame: u64) -> Insertion {
+    /// As [`BlockIndex::insert`], for an object already hashed with this index's [`BlockHash`]
+    pub fn insert_hashed(&mut self, obj: &[T], hash: u128, _frame: u64) -> Insertion {
         assert_eq!(obj.len(), self.object_size);
-        let hash = hash(obj);
-        match self.index.entry(hash) {
+        match self.index.entry(key(hash)) {
             std::collections::hash_map::Entry::Occupied(mut e) => {
-                if let Some(found) = e
-                    .get()
-                    .iter()
-                    .find(|o| obj == &*self.objects[(**o) as usize])
-                {
+                if let Some(found) = e.get().iter().find(|o| {
+                    let o = (**o) as usize;
+                    if self.trust {
+                        self.hashes[o] == hash
+                    } else {
+                        obj == &*self.objects[o]
+                    }
+                }) {
                     Insertion {
                         index: *found,
                         is_new: false,
@@ -61,6 +132,8 @@ impl<T: bytemuck::Zeroable + bytemuck::AnyBitPattern + bytemuck::NoUninit + Part
                     let idx = u32::try_from(self.objects.len()).unwrap();
                     self.objects.push(copy);
                     self.hashes.push(hash);
+                    self.uses.push(0);
+                    self.last_used.push(0);
                     e.get_mut().push(idx);
                     Insertion {
                         index: idx,
@@ -73,6 +146,8 @@ impl<T: bytemuck::Zeroable + bytemuck::AnyBitPattern + bytemuck::NoUninit + Part
                 let idx = u32::try_from(self.objects.len()).unwrap();
                 self.objects.push(copy);
                 self.hashes.push(hash);
+                self.uses.push(0);
+                self.last_used.push(0);
                 e.insert(smallvec![idx]);
                 Insertion {
                     index: idx,
@@ -83,15 +158,35 @@ impl<T: bytemuck::Zeroable + bytemuck::AnyBitPattern + bytemuck::NoUninit + Part
     }
     pub fn insert_exact(&mut self, idx: u32, obj: Box<[T]>, _frame: u64) -> bool {
         assert_eq!(obj.len(), self.object_size);
+        if (idx as usize) < self.objects.len() {
+            if self.objects[idx as usize].is_empty() {
+                // Evicted, but needed again after seeking backwards, or
+                // never seen before a keyframe
+                let hash = self.hasher.hash(&obj);
+                self.index.entry(key(hash)).or_default().push(idx);
+                self.hashes[idx as usize] = hash;
+                self.objects[idx as usize] = obj;
+                self.evicted -= 1;
+                return true;
+            }
+            // Already known, e.g. when decoding a checkpoint again after seeking backwards
+            return self.objects[idx as usize] == obj;
+        }
         if self.objects.len() != idx as usize {
             return false;
         }
-        let hash = hash(&obj);
-        self.index.entry(hash).or_default().push(idx);
+        let hash = self.hasher.hash(&obj);
+        self.index.entry(key(hash)).or_default().push(idx);
         self.objects.push(obj);
         self.hashes.push(hash);
+        self.uses.push(0);
+        self.last_used.push(0);
         true
     }
+    pub fn objects(&self) -> &[Box<[T]>] {
+        &self.objects
+    }
+    /// Empty if the object was evicted
     pub fn get(&self, which: u32) -> &[T] {
         &self.objects[which as usize]
     }
@@ -100,11 +195,61 @@ impl<T: bytemuck::Zeroable + bytemuck::AnyBitPattern + bytemuck::NoUninit + Part
         self.index.clear();
         self.objects.truncate(1);
         self.hashes.truncate(1);
-        self.index.insert(self.hashes[0], smallvec![0]);
+        self.uses.truncate(1);
+        self.last_used.truncate(1);
+        self.evicted = 0;
+        self.index.insert(key(self.hashes[0]), smallvec![0]);
+    }
+    /// Adds evicted objects up to index `len`, for indices the encoder
+    /// assigned before this table started following it.
+    pub fn pad_to(&mut self, len: usize) {
+        let missing = len.saturating_sub(self.objects.len());
+        self.objects
+            .resize_with(len.max(self.objects.len()), Box::default);
+        self.hashes.resize(self.objects.len(), 0);
+        self.uses.resize(self.objects.len(), 0);
+        self.last_used.resize(self.objects.len(), 0);
+        self.evicted += missing;
     }
-    #[expect(unused)]
     pub fn len(&self) -> usize {
         self.objects.len()
     }
-    // remove_after, commit?
+    /// Number of objects evicted and not since restored
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+    pub fn record_use(&mut self, which: u32) {
+        self.uses[which as usize] += 1;
+    }
+    pub fn uses(&self, which: u32) -> u32 {
+        self.uses[which as usize]
+    }
+    pub fn touch(&mut self, which: u32, when: u64) {
+        self.last_used[which as usize] = when;
+    }
+    pub fn last_used(&self, which: u32) -> u64 {
+        self.last_used[which as usize]
+    }
+    /// Bytes taken up by objects that aren't evicted
+    pub fn live_bytes(&self) -> usize {
+        (self.objects.len() - self.evicted) * self.object_size * size_of::<T>()
+    }
+    /// Frees an object and forgets its hash, so that inserting it again
+    /// gives it a new index; its own index is never reused.
+    pub fn evict(&mut self, which: u32) {
+        let i = which as usize;
+        if self.objects[i].is_empty() {
+            return;
+        }
+        if let std::collections::hash_map::Entry::Occupied(mut e) =
+            self.index.entry(key(self.hashes[i]))
+        {
+            e.get_mut().retain(|o| *o != which);
+            if e.get().is_empty() {
+                e.remove();
+            }
+        }
+        self.objects[i] = Box::default();
+        self.evicted += 1;
+    }
 }
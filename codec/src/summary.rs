@@ -0,0 +1,110 @@
+use crate::{Frame, ReplayDecoder, ReplayError};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Seek};
+use std::time::Duration;
+
+/// Totals over a whole replay, from [`summarize`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Summary {
+    /// Frames in the stream
+    pub frames: u64,
+    /// Bytes from the start of the replay through its last frame
+    pub replay_bytes: u64,
+    /// Size of the initial state
+    pub initial_state_bytes: u64,
+    /// Checkpoints after the initial state
+    pub checkpoints: u64,
+    /// Stored (compressed and encoded) size of every checkpoint
+    pub checkpoint_bytes: u64,
+    /// Stored size of the largest checkpoint
+    pub max_checkpoint_bytes: u64,
+    /// Decoded size of every checkpoint
+    pub uncompressed_checkpoint_bytes: u64,
+    /// Key events in every frame
+    pub key_events: u64,
+    /// Input events in every frame, by port
+    pub inputs_per_port: BTreeMap<u8, u64>,
+}
+
+#[allow(clippy::cast_precision_loss)]
+impl Summary {
+    /// Average stored size of a checkpoint, if there are any
+    #[must_use]
+    pub fn average_checkpoint_bytes(&self) -> Option<f64> {
+        (self.checkpoints > 0).then(|| self.checkpoint_bytes as f64 / self.checkpoints as f64)
+    }
+    /// How many times bigger the replay would be with every checkpoint
+    /// stored as plain state
+    #[must_use]
+    pub fn compression_ratio(&self) -> f64 {
+        let uncompressed =
+            self.replay_bytes - self.checkpoint_bytes + self.uncompressed_checkpoint_bytes;
+        uncompressed as f64 / self.replay_bytes.max(1) as f64
+    }
+    /// How long the replay plays for at `fps` frames per second
+    #[must_use]
+    pub fn duration(&self, fps: f64) -> Duration {
+        Duration::from_secs_f64(self.frames as f64 / fps)
+    }
+}
+
+/// Reads `rply` from the start, skipping checkpoint payloads, and totals
+/// its frames, checkpoints, and events.  The decoder is left at the end.
+/// # Errors
+/// [`ReplayError::NoCoreRead`]: Tried to summarize a version 0 replay
+/// Any error from [`ReplayDecoder::checkpoints`] or reading frames
+pub fn summarize<R: BufRead + Seek>(rply: &mut ReplayDecoder<R>) -> Result<Summary, ReplayError> {
+    let checkpoints = rply.checkpoints()?;
+    let mut summary = Summary {
+        initial_state_bytes: rply.initial_state.len() as u64,
+        checkpoints: checkpoints.len() as u64,
+        ..Summary::default()
+    };
+    for checkpoint in &checkpoints {
+        summary.checkpoint_bytes += checkpoint.compressed_size;
+        summary.uncompressed_checkpoint_bytes += checkpoint.uncompressed_size;
+        summary.max_checkpoint_bytes = summary.max_checkpoint_bytes.max(checkpoint.compressed_size);
+    }
+    rply.seek_to_frame(0)?;
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame_skipping_checkpoints(&mut frame)?;
+        summary.key_events += frame.key_events.len() as u64;
+        for input in &frame.input_events {
+            *summary.inputs_per_port.entry(input.port).or_default() += 1;
+        }
+    }
+    summary.frames = rply.frame_number;
+    summary.replay_bytes = rply.position();
+    Ok(summary)
+}
+
+#[cfg(test)]
+#[allow(clippy::cast_precision_loss)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn summarizes() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let summary = summarize(&mut rply).unwrap();
+        assert_eq!(summary.frames, 1000);
+        assert_eq!(summary.replay_bytes, bytes.len() as u64);
+        assert_eq!(summary.initial_state_bytes, 100);
+        assert_eq!(summary.checkpoints, 25);
+        assert_eq!(summary.uncompressed_checkpoint_bytes, 2500);
+        assert!(summary.max_checkpoint_bytes >= summary.average_checkpoint_bytes().unwrap() as u64);
+        let plain = bytes.len() as u64 - summary.checkpoint_bytes + 2500;
+        assert_eq!(
+            summary.compression_ratio(),
+            plain as f64 / bytes.len() as f64
+        );
+        assert_eq!(summary.key_events, 0);
+        assert_eq!(summary.inputs_per_port, BTreeMap::from([(0, 1000)]));
+        assert_eq!(summary.duration(50.0), Duration::from_secs(20));
+        assert!(rply.at_end().unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{Frame, Header, ReplayEncoder, ReplayError};
+use std::io::{Seek, SeekFrom, Write};
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// Anything a replay can be encoded to.
+pub trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// Writes and seeks several sinks in step, so that one encoder's output
+/// ends up in all of them.  Seeks report the first sink's position.
+pub struct Tee<'s> {
+    sinks: Vec<&'s mut dyn WriteSeek>,
+}
+
+impl<'s> Tee<'s> {
+    /// # Panics
+    /// If `sinks` is empty
+    #[must_use]
+    pub fn new(sinks: Vec<&'s mut dyn WriteSeek>) -> Self {
+        assert!(!sinks.is_empty(), "a tee needs at least one sink");
+        Self { sinks }
+    }
+}
+
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for sink in &mut self.sinks {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for Tee<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let mut at = None;
+        for sink in &mut self.sinks {
+            let here = sink.seek(pos)?;
+            if *at.get_or_insert(here) != here {
+                return Err(std::io::Error::other("tee sinks are out of step"));
+            }
+        }
+        Ok(at.unwrap_or_default())
+    }
+}
+
+/// Encodes one stream of frames to several sinks at once: every sink of
+/// `full` gets the same bytes from a single encoder, so each checkpoint is
+/// encoded and compressed once however many sinks there are, and each sink
+/// of `inputs_only` gets the same replay without checkpoints (e.g. a small
+/// sidecar for sharing or searching inputs).  Sinks that can't seek, like
+/// sockets, can be fed from a [`std::io::Cursor`] after each frame, since
+/// the encoder only seeks back within the frame it is writing until
+/// [`TeeEncoder::finish`] rewrites the header.
+pub struct TeeEncoder<'w, 's> {
+    full: ReplayEncoder<'w, Tee<'s>>,
+    inputs_only: Option<ReplayEncoder<'w, Tee<'s>>>,
+    /* The frame without its checkpoint, for `inputs_only` */
+    scratch: Frame,
+}
+
+impl<'w, 's> TeeEncoder<'w, 's> {
+    /// Starts both replays with `header` and `initial_state`.
+    /// # Errors
+    /// As [`ReplayEncoder::new`]
+    pub fn new(
+        header: Header,
+        initial_state: &[u8],
+        full: &'w mut Tee<'s>,
+        inputs_only: Option<&'w mut Tee<'s>>,
+    ) -> Result<Self> {
+        let inputs_only = inputs_only
+            .map(|tee| ReplayEncoder::new(header.clone(), initial_state, tee))
+            .transpose()?;
+        Ok(Self {
+            full: ReplayEncoder::new(header, initial_state, full)?,
+            inputs_only,
+            scratch: Frame::default(),
+        })
+    }
+    /// The encoder of the full replay, for its settings and statistics
+    #[must_use]
+    pub fn encoder(&self) -> &ReplayEncoder<'w, Tee<'s>> {
+        &self.full
+    }
+    /// As [`TeeEncoder::encoder`]; checkpoint settings only matter to it,
+    /// but changes to the header's metadata should be made to both.
+    pub fn encoder_mut(&mut self) -> &mut ReplayEncoder<'w, Tee<'s>> {
+        &mut self.full
+    }
+    /// Frames written so far
+    #[must_use]
+    pub fn frame_number(&self) -> u64 {
+        self.full.frame_number
+    }
+    /// Writes `frame` to every sink.
+    /// # Errors
+    /// As [`ReplayEncoder::write_frame`]
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.full.write_frame(frame)?;
+        if let Some(inputs_only) = &mut self.inputs_only {
+            self.scratch.key_events.clone_from(&frame.key_events);
+            self.scratch.input_events.clone_from(&frame.input_events);
+            inputs_only.write_frame(&self.scratch)?;
+        }
+        Ok(())
+    }
+    /// Finishes every replay, writing their headers.
+    /// # Errors
+    /// As [`ReplayEncoder::finish`]
+    pub fn finish(&mut self) -> Result<()> {
+        self.full.finish()?;
+        if let Some(inputs_only) = &mut self.inputs_only {
+            inputs_only.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+    use std::io::Cursor;
+
+    #[test]
+    fn tee_encoder() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(bytes.as_slice()).unwrap();
+        let (mut a, mut b, mut side) = (
+            Cursor::new(vec![]),
+            Cursor::new(vec![]),
+            Cursor::new(vec![]),
+        );
+        let mut full = Tee::new(vec![&mut a, &mut b]);
+        let mut inputs = Tee::new(vec![&mut side]);
+        let mut enc = TeeEncoder::new(
+            rply.header.clone(),
+            &rply.initial_state,
+            &mut full,
+            Some(&mut inputs),
+        )
+        .unwrap();
+        for frame in rply.frames() {
+            enc.write_frame(&frame.unwrap()).unwrap();
+        }
+        enc.finish().unwrap();
+        assert_eq!(enc.frame_number(), 1000);
+        drop(enc);
+        drop((full, inputs));
+
+        assert_eq!(a.get_ref(), b.get_ref());
+        let mut full = crate::decode(a.get_ref().as_slice()).unwrap();
+        let mut side = crate::decode(side.get_ref().as_slice()).unwrap();
+        assert_eq!(side.initial_state, full.initial_state);
+        let mut checkpoints = 0;
+        for (full, side) in full.frames().zip(side.frames()) {
+            let (full, side) = (full.unwrap(), side.unwrap());
+            assert_eq!(full.inputs(), side.inputs());
+            assert!(side.checkpoint_bytes.is_empty());
+            checkpoints += usize::from(!full.checkpoint_bytes.is_empty());
+        }
+        assert_eq!((side.frame_number, checkpoints), (1000, 25));
+    }
+}
@@ -0,0 +1,424 @@
+//! Canonical byte sequences for each part of the replay format, with the
+//! values they parse to, for testing other implementations against this
+//! one.  Every vector is written out by hand rather than by this crate's
+//! encoder, and this crate's tests check that it both reads and writes
+//! them exactly.
+//!
+//! Integers in headers and frames are little-endian; statestream tokens and
+//! their arguments are MessagePack, whose multibyte integers are big-endian.
+use crate::{
+    Compression, Encoding, Frame, Header, HeaderBase, HeaderV2, InputData, KeyData, Metadata,
+};
+
+/// A whole replay and what it decodes to.
+#[derive(Debug)]
+pub struct ReplayVector {
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+    pub header: Header,
+    pub initial_state: Vec<u8>,
+    pub frames: Vec<Frame>,
+}
+
+/// A sequence of statestream checkpoints, each encoded against the block
+/// and superblock tables left by the ones before it.
+#[derive(Debug)]
+pub struct StatestreamVector {
+    pub name: &'static str,
+    pub block_size: u32,
+    pub superblock_size: u32,
+    /// Memory budget the encoder evicted blocks to stay within, if any
+    pub memory_budget: Option<usize>,
+    pub checkpoints: Vec<StatestreamCheckpoint>,
+}
+
+#[derive(Debug)]
+pub struct StatestreamCheckpoint {
+    /// Frame written in the checkpoint's start token
+    pub frame: u64,
+    pub bytes: Vec<u8>,
+    pub state: Vec<u8>,
+}
+
+/// Bytes a reader must reject, and why.
+#[derive(Debug)]
+pub struct RejectVector {
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+    pub reason: &'static str,
+}
+
+fn cat(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+const MAGIC: &[u8] = b"2VSB";
+const CRC: u32 = 0x0102_0304;
+const IDENTIFIER: u64 = 0x1122_3344_5566_7788;
+
+fn base(version: u32, initial_state_size: u32) -> HeaderBase {
+    HeaderBase {
+        version,
+        content_crc: CRC,
+        initial_state_size,
+        identifier: IDENTIFIER,
+    }
+}
+
+fn base_bytes(version: u32, initial_state_size: u32) -> Vec<u8> {
+    cat(&[
+        MAGIC,
+        &version.to_le_bytes(),
+        &CRC.to_le_bytes(),
+        &initial_state_size.to_le_bytes(),
+        &IDENTIFIER.to_le_bytes(),
+    ])
+}
+
+/* Frame count, block size 16, superblock size 4, commit interval 4 and
+ * threshold 2, no compression or encryption */
+fn v2_bytes(version: u32, frame_count: u32) -> Vec<u8> {
+    cat(&[
+        &base_bytes(version, 0),
+        &frame_count.to_le_bytes(),
+        &16_u32.to_le_bytes(),
+        &4_u32.to_le_bytes(),
+        &[0, 0, 2, 4],
+    ])
+}
+
+fn v2_header(version: u32, frame_count: u32, metadata: Metadata) -> Header {
+    Header::V2(HeaderV2 {
+        base: base(version, 0),
+        frame_count,
+        block_size: 16,
+        superblock_size: 4,
+        checkpoint_commit_interval: 4,
+        checkpoint_commit_threshold: 2,
+        checkpoint_compression: Compression::None,
+        encrypted_sections: crate::EncryptedSections::default(),
+        metadata,
+    })
+}
+
+fn frame(key_events: Vec<KeyData>, input_events: Vec<InputData>, checkpoint: &[u8]) -> Frame {
+    Frame {
+        key_events,
+        input_events,
+        checkpoint_bytes: checkpoint.to_vec(),
+        checkpoint_compression: Compression::None,
+        checkpoint_encoding: Encoding::Raw,
+    }
+}
+
+/* No key events, no input events */
+const NO_EVENTS: &[u8] = &[0, 0, 0];
+
+/// Replays of each version, covering every frame token.
+#[must_use]
+pub fn replays() -> Vec<ReplayVector> {
+    let events = cat(&[
+        // Key event count, then down, padding, modifiers, keycode, character
+        &[1],
+        &[1, 0],
+        &2_u16.to_le_bytes(),
+        &0x61_u32.to_le_bytes(),
+        &0x61_u32.to_le_bytes(),
+        // Input event count, then port, device, index, padding, id, value
+        &2_u16.to_le_bytes(),
+        &[0, 1, 0, 0],
+        &8_u16.to_le_bytes(),
+        &1_i16.to_le_bytes(),
+        &[1, 2, 0, 0],
+        &0_u16.to_le_bytes(),
+        &(-300_i16).to_le_bytes(),
+    ]);
+    let events_frame = frame(
+        vec![KeyData {
+            down: 1,
+            modf: 2,
+            code: 0x61,
+            chr: 0x61,
+        }],
+        vec![
+            InputData {
+                port: 0,
+                device: 1,
+                idx: 0,
+                id: 8,
+                val: 1,
+            },
+            InputData {
+                port: 1,
+                device: 2,
+                idx: 0,
+                id: 0,
+                val: -300,
+            },
+        ],
+        &[],
+    );
+    let title = Metadata::from_iter([(*b"TITL", b"hello".to_vec())]);
+    vec![
+        ReplayVector {
+            name: "version 1: raw initial state, regular and checkpoint frames",
+            bytes: cat(&[
+                &base_bytes(1, 4),
+                &[9, 8, 7, 6],
+                // 'f': a regular frame
+                NO_EVENTS,
+                b"f",
+                // 'c': a checkpoint frame with a u64 size and raw bytes
+                NO_EVENTS,
+                b"c",
+                &4_u64.to_le_bytes(),
+                &[1, 2, 3, 4],
+            ]),
+            header: Header::V0V1(base(1, 4)),
+            initial_state: vec![9, 8, 7, 6],
+            frames: vec![
+                frame(vec![], vec![], &[]),
+                frame(vec![], vec![], &[1, 2, 3, 4]),
+            ],
+        },
+        ReplayVector {
+            name: "version 2: backrefs, events and a raw checkpoint",
+            bytes: cat(&[
+                &v2_bytes(2, 2),
+                // Backref to the previous frame (none yet), events, 'f'
+                &0_u32.to_le_bytes(),
+                &events,
+                b"f",
+                // Backref: the previous frame was 4 + 31 + 1 bytes long
+                &36_u32.to_le_bytes(),
+                NO_EVENTS,
+                // 'C': compression, encoding, then the unencoded,
+                // encoded and compressed sizes
+                b"C",
+                &[0, 0],
+                &4_u32.to_le_bytes(),
+                &4_u32.to_le_bytes(),
+                &4_u32.to_le_bytes(),
+                &[1, 2, 3, 4],
+            ]),
+            header: v2_header(2, 2, Metadata::default()),
+            initial_state: vec![],
+            frames: vec![events_frame, frame(vec![], vec![], &[1, 2, 3, 4])],
+        },
+        ReplayVector {
+            name: "version 3: metadata block",
+            bytes: cat(&[
+                &v2_bytes(3, 1),
+                // Block length, then each chunk's tag, length and payload
+                &13_u32.to_le_bytes(),
+                b"TITL",
+                &5_u32.to_le_bytes(),
+                b"hello",
+                &0_u32.to_le_bytes(),
+                NO_EVENTS,
+                b"f",
+            ]),
+            header: v2_header(3, 1, title),
+            initial_state: vec![],
+            frames: vec![frame(vec![], vec![], &[])],
+        },
+    ]
+}
+
+/// Statestream checkpoints with 4-byte blocks in 2-block superblocks,
+/// covering every statestream token.  Commits are off.
+#[must_use]
+pub fn statestream() -> Vec<StatestreamVector> {
+    const START: u8 = 0;
+    const NEW_BLOCK: u8 = 1;
+    const NEW_SUPERBLOCK: u8 = 2;
+    const SUPERBLOCK_SEQ: u8 = 3;
+    const EVICT: u8 = 4;
+    // MessagePack markers
+    const BIN8: u8 = 0xc4;
+    const FIXARRAY_1: u8 = 0x91;
+    const FIXARRAY_2: u8 = 0x92;
+    vec![StatestreamVector {
+        name: "new, reused, padded and evicted blocks",
+        block_size: 4,
+        superblock_size: 2,
+        memory_budget: Some(40),
+        checkpoints: vec![
+            StatestreamCheckpoint {
+                frame: 0,
+                bytes: cat(&[
+                    &[START, 0],
+                    &[NEW_BLOCK, 1, BIN8, 4, 1, 2, 3, 4],
+                    &[NEW_BLOCK, 2, BIN8, 4, 5, 6, 7, 8],
+                    &[NEW_SUPERBLOCK, 1, FIXARRAY_2, 1, 2],
+                    // The last superblock is padded with block 0
+                    &[NEW_SUPERBLOCK, 2, FIXARRAY_2, 1, 0],
+                    &[SUPERBLOCK_SEQ, FIXARRAY_2, 1, 2],
+                ]),
+                state: vec![1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4],
+            },
+            StatestreamCheckpoint {
+                frame: 60,
+                bytes: cat(&[
+                    &[START, 60],
+                    &[NEW_BLOCK, 3, BIN8, 4, 9, 9, 9, 9],
+                    &[NEW_SUPERBLOCK, 3, FIXARRAY_2, 1, 3],
+                    &[SUPERBLOCK_SEQ, FIXARRAY_2, 3, 2],
+                ]),
+                state: vec![1, 2, 3, 4, 9, 9, 9, 9, 1, 2, 3, 4],
+            },
+            StatestreamCheckpoint {
+                frame: 120,
+                bytes: cat(&[
+                    &[START, 120],
+                    // Over budget: block 3 and the superblock using it go
+                    &[EVICT, FIXARRAY_1, 3, FIXARRAY_1, 3],
+                    &[SUPERBLOCK_SEQ, FIXARRAY_2, 1, 2],
+                ]),
+                state: vec![1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4],
+            },
+        ],
+    }]
+}
+
+/// Malformed replays that readers must reject.
+#[must_use]
+pub fn rejects() -> Vec<RejectVector> {
+    let mut future = v2_bytes(2, 0);
+    future[4] = 4;
+    let mut compression = v2_bytes(2, 0);
+    compression[37] = 0x7f;
+    vec![
+        RejectVector {
+            name: "big-endian magic",
+            bytes: cat(&[b"BSV2", &v2_bytes(2, 0)[4..]]),
+            reason: "the magic number is little-endian",
+        },
+        RejectVector {
+            name: "future version",
+            bytes: future,
+            reason: "version 4 is unknown",
+        },
+        RejectVector {
+            name: "truncated header",
+            bytes: v2_bytes(2, 0)[..30].to_vec(),
+            reason: "the header ends early",
+        },
+        RejectVector {
+            name: "reserved compression",
+            bytes: compression,
+            reason: "compression 0x7f is neither built in nor custom",
+        },
+        RejectVector {
+            name: "bad frame token",
+            bytes: cat(&[&v2_bytes(2, 1), &0_u32.to_le_bytes(), NO_EVENTS, b"x"]),
+            reason: "'x' is not a frame token",
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statestream::{Ctx, Decoder, Encoder};
+    use crate::{ReplayEncoder, ReplayError};
+
+    #[test]
+    fn replays_roundtrip() {
+        for vector in replays() {
+            let mut dec = crate::decode(vector.bytes.as_slice()).unwrap();
+            assert_eq!(dec.header, vector.header, "{}", vector.name);
+            assert_eq!(dec.initial_state, vector.initial_state, "{}", vector.name);
+            let frames = dec.frames().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(frames, vector.frames, "{}", vector.name);
+
+            let mut out = std::io::Cursor::new(vec![]);
+            {
+                let mut enc = ReplayEncoder::with_version(
+                    vector.header.clone(),
+                    &vector.initial_state,
+                    &mut out,
+                    vector.header.version(),
+                )
+                .unwrap();
+                enc.set_checkpoint_encoding(Encoding::Raw);
+                for frame in &vector.frames {
+                    enc.write_frame(frame).unwrap();
+                }
+                enc.finish().unwrap();
+            }
+            assert_eq!(out.into_inner(), vector.bytes, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn headers_parse() {
+        for vector in replays() {
+            let mut bytes = [0; 40];
+            let len = vector.bytes.len().min(40);
+            bytes[..len].copy_from_slice(&vector.bytes[..len]);
+            let mut header = vector.header.clone();
+            if let Header::V2(v2) = &mut header {
+                v2.metadata = Metadata::default();
+            }
+            assert_eq!(
+                crate::parse_header(&bytes).unwrap(),
+                header,
+                "{}",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn statestream_roundtrip() {
+        for vector in statestream() {
+            let mut enc_ctx = Ctx::new(vector.block_size, vector.superblock_size);
+            enc_ctx.set_memory_budget(vector.memory_budget);
+            let mut dec_ctx = Ctx::new(vector.block_size, vector.superblock_size);
+            for checkpoint in &vector.checkpoints {
+                let mut out = vec![];
+                Encoder::new(&mut out, &mut enc_ctx)
+                    .encode_checkpoint(&checkpoint.state, checkpoint.frame)
+                    .unwrap();
+                assert_eq!(
+                    out, checkpoint.bytes,
+                    "{} frame {}",
+                    vector.name, checkpoint.frame
+                );
+                let mut state = vec![];
+                std::io::Read::read_to_end(
+                    &mut Decoder::new(
+                        &mut checkpoint.bytes.as_slice(),
+                        &mut dec_ctx,
+                        checkpoint.state.len(),
+                    ),
+                    &mut state,
+                )
+                .unwrap();
+                assert_eq!(state, checkpoint.state);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_fail() {
+        for vector in rejects() {
+            let result = crate::decode(vector.bytes.as_slice())
+                .and_then(|mut dec| {
+                    let mut frame = Frame::default();
+                    dec.read_frame(&mut frame)
+                })
+                .map_err(ReplayError::into_root);
+            let expected = match vector.name {
+                "big-endian magic" => matches!(result, Err(ReplayError::Magic(_))),
+                "future version" => matches!(result, Err(ReplayError::Version(4))),
+                "truncated header" => matches!(result, Err(ReplayError::IO(_))),
+                "reserved compression" => matches!(result, Err(ReplayError::Compression(_))),
+                "bad frame token" => matches!(result, Err(ReplayError::BadFrameToken(b'x'))),
+                _ => false,
+            };
+            assert!(expected, "{}: {result:?}", vector.name);
+        }
+    }
+}
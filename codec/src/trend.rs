@@ -0,0 +1,135 @@
+/// The sizes of one encoded checkpoint, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointSizes {
+    /// The frame the checkpoint was saved after, numbered from 1; 0 for the
+    /// initial state
+    pub frame: u64,
+    /// The savestate's own size
+    pub uncompressed: u64,
+    /// The size written to the replay, after encoding and compression
+    pub compressed: u64,
+}
+
+/// A checkpoint much bigger than the ones before it, which often means the
+/// core has got into a pathological state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeJump {
+    pub sizes: CheckpointSizes,
+    /// Recent average of [`CheckpointSizes::uncompressed`]
+    pub average_uncompressed: f64,
+    /// Recent average of [`CheckpointSizes::compressed`]
+    pub average_compressed: f64,
+}
+
+impl SizeJump {
+    /// How many times the recent average the bigger of the two sizes is
+    #[must_use]
+    pub fn ratio(&self) -> f64 {
+        #[expect(clippy::cast_precision_loss)]
+        let (uncompressed, compressed) =
+            (self.sizes.uncompressed as f64, self.sizes.compressed as f64);
+        (uncompressed / self.average_uncompressed.max(1.0))
+            .max(compressed / self.average_compressed.max(1.0))
+    }
+}
+
+/// Keeps a moving average of checkpoint sizes and spots checkpoints whose
+/// uncompressed or compressed size jumps past `threshold` times it.  The
+/// first few checkpoints only set the average, and every checkpoint moves
+/// it, so a new steady size stops being reported after a while.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeTrend {
+    threshold: f64,
+    seen: u32,
+    average_uncompressed: f64,
+    average_compressed: f64,
+}
+
+/* Checkpoints averaged before any can count as a jump */
+const WARMUP: u32 = 4;
+/* Weight of each new checkpoint in the moving average */
+const ALPHA: f64 = 1.0 / 8.0;
+
+impl SizeTrend {
+    #[must_use]
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            seen: 0,
+            average_uncompressed: 0.0,
+            average_compressed: 0.0,
+        }
+    }
+    #[must_use]
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+    /// Adds a checkpoint to the trend, returning it as a jump if it is
+    /// over the threshold.
+    pub fn observe(&mut self, sizes: CheckpointSizes) -> Option<SizeJump> {
+        #[expect(clippy::cast_precision_loss)]
+        let (uncompressed, compressed) = (sizes.uncompressed as f64, sizes.compressed as f64);
+        let jump = SizeJump {
+            sizes,
+            average_uncompressed: self.average_uncompressed,
+            average_compressed: self.average_compressed,
+        };
+        let alpha = if self.seen < WARMUP {
+            1.0 / f64::from(self.seen + 1)
+        } else {
+            ALPHA
+        };
+        self.average_uncompressed += (uncompressed - self.average_uncompressed) * alpha;
+        self.average_compressed += (compressed - self.average_compressed) * alpha;
+        self.seen = self.seen.saturating_add(1);
+        (self.seen > WARMUP && jump.ratio() > self.threshold).then_some(jump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_jumps() {
+        let mut trend = SizeTrend::new(2.0);
+        let sizes = |frame, compressed| CheckpointSizes {
+            frame,
+            uncompressed: 1000,
+            compressed,
+        };
+        for frame in 0..10 {
+            assert_eq!(trend.observe(sizes(frame, 100 + frame % 3)), None);
+        }
+        let jump = trend.observe(sizes(10, 500)).unwrap();
+        assert_eq!(jump.sizes.frame, 10);
+        assert!((4.8..5.0).contains(&jump.ratio()), "{}", jump.ratio());
+        // The average catches up with a sustained new size
+        let jumps = (11..40)
+            .filter_map(|frame| trend.observe(sizes(frame, 500)))
+            .count();
+        assert!((1..10).contains(&jumps), "{jumps}");
+        assert_eq!(trend.observe(sizes(40, 500)), None);
+    }
+
+    #[test]
+    fn encoder_size_alert() {
+        let bytes = crate::verify::tests::replay(None);
+        let mut rply = crate::decode(bytes.as_slice()).unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut jumps = vec![];
+        let mut enc = crate::encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+        enc.set_size_alert(4.0, |jump| jumps.push(jump.sizes.frame));
+        for frame in rply.frames() {
+            let mut frame = frame.unwrap();
+            if enc.frame_number == 399 {
+                frame.checkpoint_bytes = (0..4000u32).map(|i| (i * 7919 % 251) as u8).collect();
+            }
+            enc.write_frame(&frame).unwrap();
+        }
+        let sizes = enc.last_checkpoint_sizes().unwrap();
+        assert_eq!((sizes.frame, sizes.uncompressed), (1000, 100));
+        drop(enc);
+        assert_eq!(jumps, [400]);
+    }
+}
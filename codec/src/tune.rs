@@ -0,0 +1,97 @@
+use crate::statestream::{Ctx, Encoder};
+
+/* Sizes tried for both blocks and superblocks */
+const SIZES: [u32; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// Picks the block and superblock sizes, in that order, that should make
+/// replays starting from `initial_state` smallest.
+///
+/// Each pair of sizes is scored by encoding the state with it, which
+/// favours blocks that line up with its repeated regions, plus the
+/// superblock sequence that every later checkpoint sends again.  The
+/// encoding counts for more the higher the state's byte entropy, since
+/// padding and repeated regions rarely change from one checkpoint to the
+/// next.  An empty state gets the [`crate::HeaderBuilder`] defaults.
+#[must_use]
+pub fn tune_block_sizes(initial_state: &[u8]) -> (u32, u32) {
+    if initial_state.is_empty() {
+        return (256, 256);
+    }
+    let live = (entropy(initial_state) / 8.0).max(1.0 / 8.0);
+    let mut best = (f64::INFINITY, (256, 256));
+    // Larger sizes come first so that ties keep the smaller tables
+    for block_size in SIZES.into_iter().rev() {
+        for superblock_size in SIZES.into_iter().rev() {
+            let mut ctx = Ctx::new(block_size, superblock_size);
+            let Ok(encoded) =
+                Encoder::new(&mut std::io::sink(), &mut ctx).encode_checkpoint(initial_state, 0)
+            else {
+                continue;
+            };
+            let superblocks = initial_state
+                .len()
+                .div_ceil(block_size as usize * superblock_size as usize);
+            let superseq = superblocks * uint_len(ctx.superblocks().len());
+            #[allow(clippy::cast_precision_loss)]
+            let score = live * f64::from(encoded) + superseq as f64;
+            if score < best.0 {
+                best = (score, (block_size, superblock_size));
+            }
+        }
+    }
+    best.1
+}
+
+/* Shannon entropy of the bytes, in bits per byte */
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0_u64; 256];
+    for &b in bytes {
+        counts[b as usize] += 1;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let total = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            #[allow(clippy::cast_precision_loss)]
+            let p = n as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/* Bytes msgpack takes to write the index `n` */
+fn uint_len(n: usize) -> usize {
+    match n {
+        0..128 => 1,
+        128..256 => 2,
+        256..65536 => 3,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_sizes() {
+        let file = std::fs::File::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../examples/bobl.replay"
+        ))
+        .unwrap();
+        let rply = crate::decode(std::io::BufReader::new(file)).unwrap();
+        // Re-encoded with these, the whole example is within 1% of its
+        // smallest over all the pairs tried (32 and 16), and 26% smaller
+        // than with the defaults
+        assert_eq!(tune_block_sizes(&rply.initial_state), (32, 32));
+        // Noise can't be deduplicated, so only the superblock sequence matters
+        let noise: Vec<u8> = (0..1_u32 << 20)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        assert_eq!(tune_block_sizes(&noise).1, 1024);
+        assert_eq!(tune_block_sizes(&[]), (256, 256));
+    }
+}
@@ -0,0 +1,690 @@
+use crate::{Frame, ReplayDecoder, ReplayEncoder, ReplayError};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{BufRead, Read, Seek, Write};
+use std::ops::Range;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// An emulator core that can replay frames, for checking that a replay's
+/// inputs reproduce its checkpoints.
+pub trait Core {
+    /// Runs one frame of emulation with the frame's inputs.
+    fn run_frame(&mut self, frame: &Frame);
+    /// Serializes the core's state into `state`, replacing its contents.
+    /// Returns false if the core could not save.
+    fn save_state(&mut self, state: &mut Vec<u8>) -> bool;
+    /// Returns false if the core could not load `state`.
+    fn load_state(&mut self, state: &[u8]) -> bool;
+}
+
+const PROGRESS_MAGIC: u32 = 0x5646_5952; // RYFV
+
+/// How far a verification has got: enough to resume it later without
+/// replaying from the start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyProgress {
+    /// Identifier of the replay being verified
+    pub identifier: u64,
+    /// Frames verified so far
+    pub frame: u64,
+    /// Checkpoints verified so far
+    pub checkpoints: u64,
+    /// Core state after `frame` frames
+    pub state: Vec<u8>,
+}
+
+impl VerifyProgress {
+    /// Reads progress written by [`VerifyProgress::write`].
+    /// # Errors
+    /// [`ReplayError::Magic`]: Not a progress sidecar
+    /// [`ReplayError::IO`]: Error reading the sidecar
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != PROGRESS_MAGIC {
+            return Err(ReplayError::Magic(magic));
+        }
+        let identifier = reader.read_u64::<LittleEndian>()?;
+        let frame = reader.read_u64::<LittleEndian>()?;
+        let checkpoints = reader.read_u64::<LittleEndian>()?;
+        let state_size = reader.read_u32::<LittleEndian>()?;
+        // Grown as the state is read, so a bad size can't claim 4 GiB up front
+        let mut state = vec![];
+        reader.take(u64::from(state_size)).read_to_end(&mut state)?;
+        if state.len() != state_size as usize {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Self {
+            identifier,
+            frame,
+            checkpoints,
+            state,
+        })
+    }
+    /// # Errors
+    /// [`ReplayError::CheckpointTooBig`]: State too large for the sidecar
+    /// [`ReplayError::IO`]: Error writing the sidecar
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<LittleEndian>(PROGRESS_MAGIC)?;
+        writer.write_u64::<LittleEndian>(self.identifier)?;
+        writer.write_u64::<LittleEndian>(self.frame)?;
+        writer.write_u64::<LittleEndian>(self.checkpoints)?;
+        writer.write_u32::<LittleEndian>(
+            u32::try_from(self.state.len()).map_err(ReplayError::CheckpointTooBig)?,
+        )?;
+        writer.write_all(&self.state)?;
+        Ok(())
+    }
+    /// Loads a progress sidecar, or returns `None` if there isn't one.
+    /// # Errors
+    /// As [`VerifyProgress::read`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::read(&mut std::io::BufReader::new(file)).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Saves a progress sidecar.  The old sidecar is only replaced once the
+    /// new one is completely written, so an interruption mid-save loses at
+    /// most this update.
+    /// # Errors
+    /// As [`VerifyProgress::write`]
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        save_sidecar(path.as_ref(), |file| self.write(file))
+    }
+}
+
+/* Writes a sidecar next to its replay, replacing any old one only once the new one is complete */
+pub(crate) fn save_sidecar(
+    path: &Path,
+    write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<()>,
+) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    write(&mut file)?;
+    file.into_inner()
+        .map_err(std::io::IntoInnerError::into_error)?
+        .sync_all()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// The result of a verification run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Verification {
+    /// Frames verified, including any verified before resuming
+    pub frames: u64,
+    /// Checkpoints that matched the core's state
+    pub checkpoints: u64,
+    /// The first frame whose checkpoint did not match the core's state, if any
+    pub desync: Option<u64>,
+    /// Byte ranges where that checkpoint and the core's state differ,
+    /// including any bytes only one of them has
+    pub differences: Vec<Range<usize>>,
+}
+
+/* Byte ranges where `a` and `b` differ, including a range for any length difference */
+pub(crate) fn differences(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let len = a.len().max(b.len());
+    for i in (0..len).filter(|&i| a.get(i) != b.get(i)) {
+        match ranges.last_mut() {
+            Some(range) if range.end == i => range.end += 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+/// Verifies `rply` from the start with `core`, stopping at the first
+/// checkpoint that doesn't match the core's state.  This is
+/// [`Verifier::verify`] without progress reports.
+/// # Errors
+/// As [`Verifier::verify`]
+pub fn verify<R: BufRead + Seek, C: Core + ?Sized>(
+    rply: &mut ReplayDecoder<R>,
+    core: &mut C,
+) -> Result<Verification> {
+    Verifier::new(core).verify(rply, None, |_| Ok(()))
+}
+
+impl<C: Core + ?Sized> Core for &mut C {
+    fn run_frame(&mut self, frame: &Frame) {
+        (**self).run_frame(frame);
+    }
+    fn save_state(&mut self, state: &mut Vec<u8>) -> bool {
+        (**self).save_state(state)
+    }
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        (**self).load_state(state)
+    }
+}
+
+/// Replays a replay's inputs through a [`Core`], comparing the core's state
+/// against every checkpoint, and reporting progress periodically so that
+/// long verifications can be resumed.
+pub struct Verifier<C: Core> {
+    pub core: C,
+    progress_interval: u64,
+    state: Vec<u8>,
+}
+
+impl<C: Core> Verifier<C> {
+    pub fn new(core: C) -> Self {
+        Self {
+            core,
+            progress_interval: 36_000,
+            state: vec![],
+        }
+    }
+    /// Sets how many frames pass between progress reports (default 36000,
+    /// ten minutes at 60fps).
+    pub fn set_progress_interval(&mut self, frames: u64) {
+        self.progress_interval = frames.max(1);
+    }
+    #[must_use]
+    pub fn progress_interval(&self) -> u64 {
+        self.progress_interval
+    }
+    /// Verifies `rply` from the start, or from `resume` if given, calling
+    /// `on_progress` every [`Verifier::progress_interval`] frames.  Stops
+    /// at the first desync.
+    /// # Errors
+    /// [`ReplayError::ProgressMismatch`]: `resume` is for a different replay
+    /// [`ReplayError::CoreState`]: The core failed to save or load a state
+    /// Any error from decoding the replay or from `on_progress`
+    pub fn verify<R: BufRead + Seek>(
+        &mut self,
+        rply: &mut ReplayDecoder<R>,
+        resume: Option<&VerifyProgress>,
+        mut on_progress: impl FnMut(&VerifyProgress) -> Result<()>,
+    ) -> Result<Verification> {
+        let identifier = rply.header.identifier();
+        let mut checkpoints = 0;
+        if let Some(progress) = resume {
+            if progress.identifier != identifier {
+                return Err(ReplayError::ProgressMismatch());
+            }
+            rply.seek_to_frame(progress.frame)?;
+            if !self.core.load_state(&progress.state) {
+                return Err(ReplayError::CoreState(progress.frame));
+            }
+            checkpoints = progress.checkpoints;
+        } else {
+            rply.seek_to_frame(0)?;
+            if !rply.initial_state.is_empty() && !self.core.load_state(&rply.initial_state) {
+                return Err(ReplayError::CoreState(0));
+            }
+        }
+        let start = rply.frame_number;
+        let mut desync = None;
+        let mut diffs = vec![];
+        for (i, frame) in rply.frames().enumerate() {
+            let frame = frame?;
+            let frame_number = start + i as u64 + 1;
+            self.core.run_frame(&frame);
+            if !frame.checkpoint_bytes.is_empty() {
+                if !self.core.save_state(&mut self.state) {
+                    return Err(ReplayError::CoreState(frame_number));
+                }
+                if self.state != frame.checkpoint_bytes {
+                    desync = Some(frame_number);
+                    diffs = differences(&self.state, &frame.checkpoint_bytes);
+                    break;
+                }
+                checkpoints += 1;
+            }
+            if frame_number.is_multiple_of(self.progress_interval) {
+                if !self.core.save_state(&mut self.state) {
+                    return Err(ReplayError::CoreState(frame_number));
+                }
+                let progress = VerifyProgress {
+                    identifier,
+                    frame: frame_number,
+                    checkpoints,
+                    state: std::mem::take(&mut self.state),
+                };
+                on_progress(&progress)?;
+                self.state = progress.state;
+            }
+        }
+        Ok(Verification {
+            frames: rply.frame_number,
+            checkpoints,
+            desync,
+            differences: diffs,
+        })
+    }
+}
+
+impl<C: Core> Verifier<C> {
+    /// Plays `rply` from the start through the core, writing each frame to
+    /// `out` with the core's state as its checkpoint every `every` frames
+    /// (none if zero), e.g. to put back the checkpoints of a replay written
+    /// with [`crate::ReplayEncoder::set_input_only`].  `out` should start
+    /// from `rply`'s header and initial state.  Checkpoints `rply` already
+    /// has are checked as in [`Verifier::verify`] and kept; writing stops
+    /// at the first desync, before its frame.
+    /// # Errors
+    /// [`ReplayError::CoreState`]: The core failed to save or load a state
+    /// Any error from decoding the replay or writing `out`
+    pub fn regenerate<R: BufRead + Seek, W: Write + Seek>(
+        &mut self,
+        rply: &mut ReplayDecoder<R>,
+        every: u64,
+        out: &mut ReplayEncoder<'_, W>,
+    ) -> Result<Verification> {
+        rply.seek_to_frame(0)?;
+        if !rply.initial_state.is_empty() && !self.core.load_state(&rply.initial_state) {
+            return Err(ReplayError::CoreState(0));
+        }
+        let mut checkpoints = 0;
+        let mut desync = None;
+        let mut diffs = vec![];
+        let mut frame = Frame::default();
+        while !rply.at_end()? {
+            rply.read_frame(&mut frame)?;
+            let frame_number = rply.frame_number;
+            self.core.run_frame(&frame);
+            let recorded = !frame.checkpoint_bytes.is_empty();
+            if recorded || (every > 0 && frame_number.is_multiple_of(every)) {
+                if !self.core.save_state(&mut self.state) {
+                    return Err(ReplayError::CoreState(frame_number));
+                }
+                if !recorded {
+                    std::mem::swap(&mut frame.checkpoint_bytes, &mut self.state);
+                } else if self.state == frame.checkpoint_bytes {
+                    checkpoints += 1;
+                } else {
+                    desync = Some(frame_number);
+                    diffs = differences(&self.state, &frame.checkpoint_bytes);
+                    break;
+                }
+            }
+            out.write_frame(&frame)?;
+        }
+        Ok(Verification {
+            frames: rply.frame_number,
+            checkpoints,
+            desync,
+            differences: diffs,
+        })
+    }
+}
+
+/* A desynced checkpoint's frame and differing byte ranges */
+type Desync = (u64, Vec<Range<usize>>);
+
+/* The frames from one checkpoint (or the initial state) through the next */
+struct Interval {
+    start_state: Vec<u8>,
+    frames: Vec<Frame>,
+    end_frame: u64,
+}
+
+/// Verifies `rply` from the start with `threads` cores at once.  Since
+/// every checkpoint is a known state, each stretch of frames between
+/// consecutive checkpoints is verified independently on whichever core is
+/// free, starting from the earlier checkpoint.  Unlike
+/// [`Verifier::verify`], this checks every checkpoint rather than stopping
+/// at the first desync, and reports the earliest one.
+///
+/// Each thread makes its own core with `make_core`, which also makes a
+/// fresh core for replays with no initial state.
+/// # Errors
+/// [`ReplayError::CoreState`]: A core failed to save or load a state
+/// Any error from decoding the replay
+pub fn verify_parallel<R, C, F>(
+    rply: &mut ReplayDecoder<R>,
+    threads: usize,
+    make_core: F,
+) -> Result<Verification>
+where
+    R: BufRead + Seek,
+    C: Core,
+    F: Fn() -> C + Sync,
+{
+    rply.seek_to_frame(0)?;
+    let threads = threads.max(1);
+    let (send, recv) = std::sync::mpsc::sync_channel::<Interval>(threads * 2);
+    let recv = std::sync::Arc::new(std::sync::Mutex::new(recv));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let recv = std::sync::Arc::clone(&recv);
+                let make_core = &make_core;
+                scope.spawn(move || -> Result<(u64, Option<Desync>)> {
+                    let mut core = make_core();
+                    let mut state = vec![];
+                    let (mut checkpoints, mut desync) = (0, None);
+                    loop {
+                        /* The lock is only held while waiting for the next
+                        interval; a `while let` would hold it through the body */
+                        let interval = recv.lock().unwrap().recv();
+                        let Ok(interval) = interval else { break };
+                        if interval.start_state.is_empty() {
+                            core = make_core();
+                        } else if !core.load_state(&interval.start_state) {
+                            return Err(ReplayError::CoreState(
+                                interval.end_frame - interval.frames.len() as u64,
+                            ));
+                        }
+                        for frame in &interval.frames {
+                            core.run_frame(frame);
+                        }
+                        if !core.save_state(&mut state) {
+                            return Err(ReplayError::CoreState(interval.end_frame));
+                        }
+                        let expected = &interval.frames.last().unwrap().checkpoint_bytes;
+                        if state == *expected {
+                            checkpoints += 1;
+                        } else if desync.as_ref().is_none_or(|(d, _)| interval.end_frame < *d) {
+                            desync = Some((interval.end_frame, differences(&state, expected)));
+                        }
+                    }
+                    Ok((checkpoints, desync))
+                })
+            })
+            .collect();
+        drop(recv);
+        let mut start_state = rply.initial_state.clone();
+        let mut frames = vec![];
+        let mut decoded = Ok(());
+        for (i, frame) in rply.frames().enumerate() {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    decoded = Err(e);
+                    break;
+                }
+            };
+            let has_checkpoint = !frame.checkpoint_bytes.is_empty();
+            frames.push(frame);
+            if has_checkpoint {
+                let frames = std::mem::take(&mut frames);
+                let next_state = frames.last().unwrap().checkpoint_bytes.clone();
+                let interval = Interval {
+                    start_state: std::mem::replace(&mut start_state, next_state),
+                    end_frame: i as u64 + 1,
+                    frames,
+                };
+                /* Only fails if every worker has stopped with an error */
+                if send.send(interval).is_err() {
+                    break;
+                }
+            }
+        }
+        drop(send);
+        let mut verification = Verification {
+            frames: 0,
+            checkpoints: 0,
+            desync: None,
+            differences: vec![],
+        };
+        for worker in workers {
+            let (checkpoints, desync) = worker.join().unwrap()?;
+            verification.checkpoints += checkpoints;
+            if let Some((frame, differences)) = desync
+                && verification.desync.is_none_or(|d| frame < d)
+            {
+                verification.desync = Some(frame);
+                verification.differences = differences;
+            }
+        }
+        decoded?;
+        Ok(verification)
+    })
+    .map(|verification| Verification {
+        frames: rply.frame_number,
+        ..verification
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Compression, Header, HeaderBase, HeaderV2, InputData};
+
+    /* A deterministic stand-in for an emulator: counts frames and inputs */
+    #[derive(Clone)]
+    pub(crate) struct Counter(pub(crate) Vec<u8>);
+    impl Core for Counter {
+        fn run_frame(&mut self, frame: &Frame) {
+            self.0[0] = self.0[0].wrapping_add(1);
+            for input in &frame.input_events {
+                let slot = 1 + input.val.unsigned_abs() as usize % (self.0.len() - 1);
+                self.0[slot] = self.0[slot].wrapping_add(1);
+            }
+        }
+        fn save_state(&mut self, state: &mut Vec<u8>) -> bool {
+            state.clone_from(&self.0);
+            true
+        }
+        fn load_state(&mut self, state: &[u8]) -> bool {
+            self.0 = state.to_vec();
+            true
+        }
+    }
+
+    pub(crate) fn replay(corrupt_at: Option<u64>) -> Vec<u8> {
+        let header = Header::V2(HeaderV2 {
+            base: HeaderBase {
+                version: 2,
+                content_crc: 0,
+                initial_state_size: 0,
+                identifier: 1234,
+            },
+            frame_count: 0,
+            block_size: 16,
+            superblock_size: 4,
+            checkpoint_commit_interval: 4,
+            checkpoint_commit_threshold: 2,
+            checkpoint_compression: Compression::None,
+            encrypted_sections: crate::EncryptedSections::default(),
+            metadata: crate::Metadata::default(),
+        });
+        let mut core = Counter(vec![0; 100]);
+        let mut out = std::io::Cursor::new(vec![]);
+        {
+            let mut enc = crate::encode(header, &core.0, &mut out).unwrap();
+            for n in 1..=1000_u64 {
+                let mut frame = Frame::default();
+                frame.input_events.push(InputData {
+                    val: i16::try_from(n * 7 % 300).unwrap(),
+                    ..InputData::default()
+                });
+                core.run_frame(&frame);
+                if n % 40 == 0 {
+                    core.save_state(&mut frame.checkpoint_bytes);
+                    if corrupt_at == Some(n) {
+                        frame.checkpoint_bytes[50] ^= 1;
+                    }
+                }
+                enc.write_frame(&frame).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        out.into_inner()
+    }
+
+    #[test]
+    fn resumable_verify() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut verifier = Verifier::new(Counter(vec![]));
+        verifier.set_progress_interval(300);
+        let full = verifier.verify(&mut rply, None, |_| Ok(())).unwrap();
+        assert_eq!(
+            full,
+            Verification {
+                frames: 1000,
+                checkpoints: 25,
+                desync: None,
+                differences: vec![],
+            }
+        );
+
+        // Interrupt after the second progress report, then resume from it
+        let mut saved = vec![];
+        let err = verifier.verify(&mut rply, None, |progress| {
+            progress.write(&mut saved)?;
+            if progress.frame == 600 {
+                return Err(std::io::Error::other("interrupted").into());
+            }
+            saved.clear();
+            Ok(())
+        });
+        assert!(matches!(err, Err(ReplayError::IO(_))));
+        let progress = VerifyProgress::read(&mut saved.as_slice()).unwrap();
+        assert_eq!((progress.frame, progress.checkpoints), (600, 15));
+        // A sidecar cut off in its state, however big it claims to be
+        for state_size in [progress.state.len() as u32 + 1, u32::MAX] {
+            let mut cut = saved.clone();
+            cut[28..32].copy_from_slice(&state_size.to_le_bytes());
+            assert!(matches!(
+                VerifyProgress::read(&mut cut.as_slice()),
+                Err(ReplayError::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+            ));
+        }
+        let mut verifier = Verifier::new(Counter(vec![]));
+        verifier.set_progress_interval(300);
+        let resumed = verifier
+            .verify(&mut rply, Some(&progress), |_| Ok(()))
+            .unwrap();
+        assert_eq!(resumed, full);
+
+        let other = VerifyProgress {
+            identifier: 99,
+            ..progress
+        };
+        assert!(matches!(
+            verifier.verify(&mut rply, Some(&other), |_| Ok(())),
+            Err(ReplayError::ProgressMismatch())
+        ));
+
+        let bytes = replay(Some(480));
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let desynced = verifier.verify(&mut rply, None, |_| Ok(())).unwrap();
+        assert_eq!(desynced.desync, Some(480));
+        assert_eq!(desynced.checkpoints, 11);
+        assert_eq!(desynced.differences, vec![50..51]);
+        let mut core = Counter(vec![]);
+        assert_eq!(verify(&mut rply, &mut core).unwrap(), desynced);
+    }
+
+    #[test]
+    fn parallel_verify() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let verification = verify_parallel(&mut rply, 4, || Counter(vec![])).unwrap();
+        assert_eq!(
+            verification,
+            Verification {
+                frames: 1000,
+                checkpoints: 25,
+                desync: None,
+                differences: vec![],
+            }
+        );
+
+        let bytes = replay(Some(480));
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let verification = verify_parallel(&mut rply, 3, || Counter(vec![])).unwrap();
+        assert_eq!(verification.desync, Some(480));
+        assert_eq!(verification.differences, vec![50..51]);
+        /* The checkpoint after the corrupted one starts from the corrupted state */
+        assert_eq!(verification.checkpoints, 23);
+    }
+
+    /* Cores arrived so far and whether any gave up waiting, and their signal */
+    type Meeting = std::sync::Arc<(std::sync::Mutex<(usize, bool)>, std::sync::Condvar)>;
+
+    /* A Counter whose first frame waits for another core's first frame,
+    recording whether it gave up waiting after a few seconds */
+    struct Rendezvous {
+        counter: Counter,
+        meeting: Option<Meeting>,
+    }
+    impl Core for Rendezvous {
+        fn run_frame(&mut self, frame: &Frame) {
+            if let Some(meeting) = self.meeting.take() {
+                let (lock, arrived) = &*meeting;
+                let mut arrivals = lock.lock().unwrap();
+                arrivals.0 += 1;
+                arrived.notify_all();
+                let (mut arrivals, wait) = arrived
+                    .wait_timeout_while(arrivals, std::time::Duration::from_secs(5), |a| a.0 < 2)
+                    .unwrap();
+                arrivals.1 |= wait.timed_out();
+            }
+            self.counter.run_frame(frame);
+        }
+        fn save_state(&mut self, state: &mut Vec<u8>) -> bool {
+            self.counter.save_state(state)
+        }
+        fn load_state(&mut self, state: &[u8]) -> bool {
+            self.counter.load_state(state)
+        }
+    }
+
+    #[test]
+    fn parallel_verify_overlaps() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let meeting = Meeting::default();
+        let verification = verify_parallel(&mut rply, 2, || Rendezvous {
+            counter: Counter(vec![]),
+            meeting: Some(std::sync::Arc::clone(&meeting)),
+        })
+        .unwrap();
+        assert_eq!(verification.checkpoints, 25);
+        // Both cores were running an interval at once, rather than one
+        // waiting for the other's to finish
+        assert!(!meeting.0.lock().unwrap().1);
+    }
+
+    #[test]
+    fn input_only() {
+        let bytes = replay(None);
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut stripped = std::io::Cursor::new(vec![]);
+        {
+            let mut enc =
+                crate::encode(rply.header.clone(), &rply.initial_state, &mut stripped).unwrap();
+            enc.set_input_only(true);
+            for frame in rply.frames() {
+                enc.write_frame(&frame.unwrap()).unwrap();
+            }
+            enc.finish().unwrap();
+        }
+        let stripped = stripped.into_inner();
+        assert!(stripped.len() < bytes.len());
+        let mut rply = crate::decode(std::io::Cursor::new(&stripped)).unwrap();
+        assert!(
+            rply.frames()
+                .all(|f| f.unwrap().checkpoint_bytes.is_empty())
+        );
+        // The core puts back exactly the checkpoints the original had
+        let mut regenerated = std::io::Cursor::new(vec![]);
+        let mut verifier = Verifier::new(Counter(vec![]));
+        let verification = {
+            let mut enc =
+                crate::encode(rply.header.clone(), &rply.initial_state, &mut regenerated).unwrap();
+            verifier.regenerate(&mut rply, 40, &mut enc).unwrap()
+        };
+        assert_eq!((verification.frames, verification.checkpoints), (1000, 0));
+        assert_eq!(regenerated.into_inner(), bytes);
+
+        // Checkpoints already there are checked
+        let bytes = replay(Some(480));
+        let mut rply = crate::decode(std::io::Cursor::new(&bytes)).unwrap();
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = crate::encode(rply.header.clone(), &rply.initial_state, &mut out).unwrap();
+        let verification = verifier.regenerate(&mut rply, 40, &mut enc).unwrap();
+        assert_eq!(verification.desync, Some(480));
+        assert_eq!(verification.checkpoints, 11);
+    }
+}
@@ -0,0 +1,188 @@
+//! wasm-bindgen wrapper for viewing replays in a browser.  Build it with
+//! `cargo build -p rply-codec --target wasm32-unknown-unknown --no-default-features --features wasm,zlib,ruzstd`
+//! and run `wasm-bindgen` on the result; the zstd feature needs a C
+//! toolchain for wasm32, so use ruzstd to read zstd checkpoints instead.
+//!
+//! ```js
+//! const viewer = new ReplayViewer(new Uint8Array(await response.arrayBuffer()));
+//! for (let frame; (frame = viewer.nextFrame()); ) {
+//!     for (let i = 0; i < frame.inputCount; i++) { draw(frame.input(i)); }
+//! }
+//! ```
+#![allow(unsafe_code)]
+use crate::{Frame, ReplayDecoder, decode};
+use std::io::Cursor;
+use wasm_bindgen::prelude::*;
+
+/// A replay being read from an in-memory buffer, one frame at a time.
+#[wasm_bindgen]
+pub struct ReplayViewer {
+    rply: ReplayDecoder<Cursor<Vec<u8>>>,
+}
+
+#[wasm_bindgen]
+impl ReplayViewer {
+    /// Reads the header and initial state of the replay in `bytes`.
+    /// # Errors
+    /// Any error from decoding the header
+    #[wasm_bindgen(constructor)]
+    pub fn new(bytes: Vec<u8>) -> Result<ReplayViewer, JsError> {
+        Ok(Self {
+            rply: decode(Cursor::new(bytes))?,
+        })
+    }
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn version(&self) -> u32 {
+        self.rply.header.version()
+    }
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn identifier(&self) -> u64 {
+        self.rply.header.identifier()
+    }
+    #[wasm_bindgen(getter, js_name = contentCrc)]
+    #[must_use]
+    pub fn content_crc(&self) -> u32 {
+        self.rply.header.content_crc()
+    }
+    /// Frames in the replay, if the header says (version 2 and up, once the
+    /// recorder finished)
+    #[wasm_bindgen(getter, js_name = frameCount)]
+    #[must_use]
+    pub fn frame_count(&self) -> Option<u64> {
+        self.rply.header.frame_count()
+    }
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn title(&self) -> Option<String> {
+        self.metadata(crate::Metadata::title)
+    }
+    #[wasm_bindgen(getter, js_name = coreName)]
+    #[must_use]
+    pub fn core_name(&self) -> Option<String> {
+        self.metadata(crate::Metadata::core_name)
+    }
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn author(&self) -> Option<String> {
+        self.metadata(crate::Metadata::author)
+    }
+    /// The savestate the replay starts from
+    #[wasm_bindgen(getter, js_name = initialState)]
+    #[must_use]
+    pub fn initial_state(&self) -> Vec<u8> {
+        self.rply.initial_state.clone()
+    }
+    /// Frames read so far
+    #[wasm_bindgen(getter, js_name = frameNumber)]
+    #[must_use]
+    pub fn frame_number(&self) -> u64 {
+        self.rply.frame_number
+    }
+    /// The next frame, or `undefined` at the end of the replay.
+    /// # Errors
+    /// Any error from decoding the frame
+    #[wasm_bindgen(js_name = nextFrame)]
+    pub fn next_frame(&mut self) -> Result<Option<ViewerFrame>, JsError> {
+        if self.rply.at_end()? {
+            return Ok(None);
+        }
+        let mut frame = Frame::default();
+        self.rply.read_frame(&mut frame)?;
+        Ok(Some(ViewerFrame(frame)))
+    }
+    fn metadata(&self, field: fn(&crate::Metadata) -> Option<&str>) -> Option<String> {
+        self.rply
+            .header
+            .metadata()
+            .and_then(field)
+            .map(String::from)
+    }
+}
+
+/// One frame read by [`ReplayViewer::next_frame`].
+#[wasm_bindgen]
+pub struct ViewerFrame(Frame);
+
+#[wasm_bindgen]
+impl ViewerFrame {
+    #[wasm_bindgen(getter, js_name = inputCount)]
+    #[must_use]
+    pub fn input_count(&self) -> usize {
+        self.0.input_events.len()
+    }
+    #[must_use]
+    pub fn input(&self, i: usize) -> Option<ViewerInput> {
+        let input = self.0.input_events.get(i)?;
+        Some(ViewerInput {
+            port: input.port,
+            device: input.device,
+            idx: input.idx,
+            id: input.id,
+            val: input.val,
+        })
+    }
+    #[wasm_bindgen(getter, js_name = keyCount)]
+    #[must_use]
+    pub fn key_count(&self) -> usize {
+        self.0.key_events.len()
+    }
+    #[must_use]
+    pub fn key(&self, i: usize) -> Option<ViewerKey> {
+        let key = self.0.key_events.get(i)?;
+        Some(ViewerKey {
+            down: key.down != 0,
+            modifiers: key.modf,
+            code: key.code,
+            character: key.chr,
+        })
+    }
+    /// The core's state at the end of the frame; empty if it has no checkpoint
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn checkpoint(&self) -> Vec<u8> {
+        self.0.checkpoint_bytes.clone()
+    }
+}
+
+/// One input event, as `retro_input_state_t` was called
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewerInput {
+    pub port: u8,
+    pub device: u8,
+    pub idx: u8,
+    pub id: u16,
+    pub val: i16,
+}
+
+/// One key event, as `retro_keyboard_event_t` was called
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewerKey {
+    pub down: bool,
+    pub modifiers: u16,
+    pub code: u32,
+    pub character: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::replay;
+
+    #[test]
+    fn viewer_frames() {
+        let mut viewer = ReplayViewer::new(replay(None)).unwrap();
+        assert_eq!(viewer.initial_state().len(), 100);
+        let mut checkpoints = 0;
+        while let Some(frame) = viewer.next_frame().unwrap() {
+            assert_eq!(frame.input_count(), 1);
+            assert_eq!(frame.input(0).unwrap().id, 0);
+            assert!(frame.input(1).is_none());
+            checkpoints += usize::from(!frame.checkpoint().is_empty());
+        }
+        assert_eq!((viewer.frame_number(), checkpoints), (1000, 25));
+    }
+}
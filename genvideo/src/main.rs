@@ -6,8 +6,13 @@ use ffmpeg_next::{
 };
 use retro_rs::Emulator;
 use ringbuf::traits::{Consumer, Observer, RingBuffer};
-use rply_codec::{Frame, decode};
-use std::{error::Error, path::Path};
+use rply_codec::{decode, decode_any, diff};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Clone, Copy)]
 struct ToI32Err();
@@ -137,7 +142,20 @@ impl VideoState {
             self.encoded_video.write_interleaved(output).unwrap();
         }
     }
-    fn send_frame(&mut self, emu: &Emulator, frame_num: u64, output: &mut FFOut) {
+    /* Paints a red square in the top right corner of the converted frame */
+    fn mark(&mut self) {
+        let w = self.out_vframe.width() as usize;
+        let size = (self.out_vframe.height() as usize / 8).max(2) & !1;
+        // Red in YUV; the chroma planes are half size
+        for (plane, value, scale) in [(0, 81, 1), (1, 90, 2), (2, 240, 2)] {
+            let stride = self.out_vframe.stride(plane);
+            let data = self.out_vframe.data_mut(plane);
+            for y in 0..size / scale {
+                data[y * stride + (w - size) / scale..y * stride + w / scale].fill(value);
+            }
+        }
+    }
+    fn send_frame(&mut self, emu: &Emulator, frame_num: u64, marked: bool, output: &mut FFOut) {
         // output one frame of video/audio, set_pts
         // copy video to out_vframe
         if self.native_pixel_format {
@@ -159,6 +177,9 @@ impl VideoState {
         self.converter
             .run(&self.out_rgbframe, &mut self.out_vframe)
             .unwrap();
+        if marked {
+            self.mark();
+        }
         let frame_num = i64::try_from(frame_num).unwrap();
         let frame_pts = frame_num.rescale(self.emu_time_base, self.out_video_enc.time_base());
         self.out_vframe.set_pts(Some(frame_pts));
@@ -171,6 +192,124 @@ impl VideoState {
     }
 }
 
+/* A commentary track, decoded only as far as the video has got and resampled
+to the core's rate so it mixes straight into the emulator's samples */
+struct Commentary {
+    input: ffmpeg_next::format::context::Input,
+    stream: usize,
+    decoder: ffmpeg_next::decoder::Audio,
+    resampler: Option<ffmpeg_next::software::resampling::Context>,
+    rate: u32,
+    /* Decoded stereo samples, interleaved, from pair `first` of the track on */
+    samples: VecDeque<i16>,
+    first: usize,
+    draining: bool,
+    finished: bool,
+    start_frame: i64,
+    pairs_per_frame: f64,
+}
+
+impl Commentary {
+    fn open(
+        path: &Path,
+        start_frame: i64,
+        rate: u32,
+        fps: f64,
+    ) -> Result<Self, ffmpeg_next::Error> {
+        let input = ffmpeg_next::format::input(&path)?;
+        let (stream, decoder) = {
+            let stream = input
+                .streams()
+                .best(ffmpeg_next::media::Type::Audio)
+                .ok_or(ffmpeg_next::Error::StreamNotFound)?;
+            let context =
+                ffmpeg_next::codec::context::Context::from_parameters(stream.parameters())?;
+            (stream.index(), context.decoder().audio()?)
+        };
+        Ok(Self {
+            input,
+            stream,
+            decoder,
+            resampler: None,
+            rate,
+            samples: VecDeque::new(),
+            first: 0,
+            draining: false,
+            finished: false,
+            start_frame,
+            pairs_per_frame: f64::from(rate) / fps,
+        })
+    }
+    /* Decodes until pair `end` of the track is buffered or the track ends */
+    fn fill(&mut self, end: usize) {
+        while self.first + self.samples.len() / 2 < end && !self.finished {
+            let mut decoded = FFAFrame::empty();
+            if self.decoder.receive_frame(&mut decoded).is_ok() {
+                self.resample(&decoded);
+            } else if self.draining {
+                self.finished = true;
+            } else if let Some((stream, packet)) = self.input.packets().next() {
+                // A corrupt packet only leaves a gap in the track
+                if stream.index() == self.stream {
+                    let _ = self.decoder.send_packet(&packet);
+                }
+            } else {
+                self.decoder.send_eof().unwrap();
+                self.draining = true;
+            }
+        }
+    }
+    fn resample(&mut self, decoded: &FFAFrame) {
+        let format = ffmpeg_next::format::Sample::I16(ffmpeg_next::format::sample::Type::Packed);
+        let resampler = self.resampler.get_or_insert_with(|| {
+            decoded
+                .resampler(format, ffmpeg_next::ChannelLayout::STEREO, self.rate)
+                .unwrap()
+        });
+        // Room for every sample the input could turn into, and some the
+        // resampler held back from the last frame
+        let capacity = decoded.samples() * self.rate as usize / decoded.rate().max(1) as usize;
+        let mut resampled =
+            FFAFrame::new(format, capacity + 256, ffmpeg_next::ChannelLayout::STEREO);
+        resampled.set_rate(self.rate);
+        resampler.run(decoded, &mut resampled).unwrap();
+        let bytes = &resampled.data(0)[..resampled.samples() * 4];
+        self.samples.extend(
+            bytes
+                .chunks_exact(2)
+                .map(|b| i16::from_ne_bytes([b[0], b[1]])),
+        );
+    }
+    /* Mixes the track into stereo `samples`, the audio of the `frame_number`th
+    frame.  Each frame starts where the frame count says the track should be,
+    so the track stays in sync however many samples the core makes a frame. */
+    fn mix(&mut self, frame_number: u64, samples: &mut [i16]) {
+        let frames_in = i64::try_from(frame_number).unwrap() - 1 - self.start_frame;
+        let at = (frames_in as f64 * self.pairs_per_frame).round() as i64;
+        let pairs = samples.len() / 2;
+        // Pairs of this frame played before the track starts
+        let skip = usize::try_from(-at).unwrap_or(0).min(pairs);
+        let at = usize::try_from(at).unwrap_or(0);
+        self.fill(at + pairs - skip);
+        let played = (at - self.first).min(self.samples.len() / 2);
+        self.samples.drain(..played * 2);
+        self.first += played;
+        for (pair, i) in samples
+            .chunks_exact_mut(2)
+            .skip(skip)
+            .zip(at - self.first..)
+        {
+            let (Some(&left), Some(&right)) =
+                (self.samples.get(2 * i), self.samples.get(2 * i + 1))
+            else {
+                break;
+            };
+            pair[0] = pair[0].saturating_add(left);
+            pair[1] = pair[1].saturating_add(right);
+        }
+    }
+}
+
 struct AudioState {
     out_audio_enc: ffmpeg_next::encoder::audio::Encoder,
     out_aframe: FFAFrame,
@@ -180,6 +319,10 @@ struct AudioState {
     audio_frame_out: i64,
     audio_frame_in: i64,
     resampler: ffmpeg_next::software::resampling::Context,
+    /* Input samples (per channel) of beep still to mix in, and how many were */
+    beep_left: usize,
+    beep_done: usize,
+    commentary: Option<Commentary>,
 }
 
 impl AudioState {
@@ -240,6 +383,31 @@ impl AudioState {
             audio_frame_in: 0,
             resampler,
             in_aframe,
+            beep_left: 0,
+            beep_done: 0,
+            commentary: None,
+        }
+    }
+    /* Starts a tenth of a second beep */
+    fn beep(&mut self) {
+        self.beep_left = self.in_aframe.rate() as usize / 10;
+        self.beep_done = 0;
+    }
+    /* Mixes the rest of the beep into stereo `samples` */
+    fn mix_beep(&mut self, samples: &mut [i16]) {
+        // An 880Hz square wave
+        let half_period = (self.in_aframe.rate() as usize / 1760).max(1);
+        for pair in samples.chunks_exact_mut(2).take(self.beep_left) {
+            let tone = if (self.beep_done / half_period) % 2 == 0 {
+                4000
+            } else {
+                -4000
+            };
+            for sample in pair {
+                *sample = sample.saturating_add(tone);
+            }
+            self.beep_done += 1;
+            self.beep_left -= 1;
         }
     }
     fn writeout(&mut self, output: &mut FFOut) {
@@ -285,10 +453,19 @@ impl AudioState {
             self.writeout(output);
         }
     }
-    fn send_frames(&mut self, emu: &Emulator, output: &mut FFOut) {
+    fn send_frames(&mut self, emu: &Emulator, frame_number: u64, output: &mut FFOut) {
         #[allow(unused_must_use)]
         emu.peek_audio_sample(|samples| {
-            self.audio_buf.push_slice_overwrite(samples);
+            if self.beep_left > 0 || self.commentary.is_some() {
+                let mut samples = samples.to_vec();
+                self.mix_beep(&mut samples);
+                if let Some(commentary) = &mut self.commentary {
+                    commentary.mix(frame_number, &mut samples);
+                }
+                self.audio_buf.push_slice_overwrite(&samples);
+            } else {
+                self.audio_buf.push_slice_overwrite(samples);
+            }
             while self.audio_buf.occupied_len() >= self.in_aframe.samples() * 2 {
                 let (_, toconvert, _) = unsafe { self.in_aframe.data_mut(0).align_to_mut::<i16>() };
                 assert_eq!(self.audio_buf.pop_slice(toconvert), toconvert.len());
@@ -311,13 +488,51 @@ impl AudioState {
 
 // bobl example: cargo run --bin genvideo examples/bobl.replay examples/bobl.mp4 cores/fceumm_libretro roms/bobl.nes
 // ff3 example: cargo run --bin genvideo examples/ff3v2.replay examples/ff3.mp4 cores/snes9x_libretro roms/ff3.nes
+// Exits with status 0 on success and 3 on any error, like rply.  With --json, prints
+// {"frames": N} when done, or {"error": MESSAGE}, instead of the header and progress.
+// --diff OTHER.replay marks the frames where OTHER's inputs differ from the replay with a red
+// square in the corner, and beeps where each run of them starts, for reviewing edits between
+// two versions of a run; JSON adds "differences": [[first, last], ...].
+// A replay whose metadata names a commentary track (see rply convert --commentary) gets it
+// mixed into the game audio, starting after the recorded number of frames; --commentary
+// AUDIO mixes in another file instead, and --commentary-start FRAME moves its start.
 
 fn main() {
+    let json = std::env::args().any(|a| a == "--json");
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if json {
+            let message = info.payload_as_str().unwrap_or("panic");
+            println!("{}", serde_json::json!({ "error": message }));
+        } else {
+            default_hook(info);
+        }
+        std::process::exit(3);
+    }));
     ffmpeg_next::init().unwrap();
     ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Warning);
-    let args: Vec<_> = std::env::args().collect();
-    let file =
-        std::fs::File::open(args.get(1).unwrap_or(&"examples/ff3v2.replay".to_string())).unwrap();
+    let mut args: Vec<_> = std::env::args().filter(|a| a != "--json").collect();
+    let mut take_flag = |flag: &str| {
+        args.iter().position(|a| a == flag).map(|i| {
+            let value = args
+                .get(i + 1)
+                .unwrap_or_else(|| panic!("{flag} needs a value"));
+            let value = value.clone();
+            args.drain(i..=i + 1);
+            value
+        })
+    };
+    let other = take_flag("--diff");
+    let commentary_path = take_flag("--commentary");
+    let commentary_start = take_flag("--commentary-start").map(|f| {
+        f.parse::<i64>()
+            .expect("--commentary-start needs a frame number")
+    });
+    let replay = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| "examples/ff3v2.replay".to_string());
+    let file = std::fs::File::open(&replay).unwrap();
     let outfile = std::path::PathBuf::from(args.get(2).unwrap_or(&"examples/ff3.mp4".to_string()));
     let corefile = args
         .get(3)
@@ -325,28 +540,45 @@ fn main() {
         .clone();
     let romfile = args.get(4).unwrap_or(&"roms/ff3.sfc".to_string()).clone();
     let mut emu = Emulator::create(Path::new(&corefile), Path::new(&romfile));
-    let file = std::io::BufReader::new(file);
-    let mut rply = decode(file).unwrap();
-    let header = &rply.header;
-    println!("Header in: {header:?}");
-    if header.version() == 0 {
-        println!("Only use this program for v1+ replays!");
-        std::process::exit(-1);
-    }
     // run emu a tick to make sure we have right frame sizes, etc
     emu.run([retro_rs::Buttons::default(); 2]);
     let (w, h) = emu.framebuffer_size();
     let pixel_format = emu.pixel_format();
-    assert!(emu.load(&rply.initial_state));
+    let emu_fps = emu.get_video_fps();
+    let emu_video_framerate = emu_fps.to_i32().unwrap();
+    let audio_sample_rate = emu.get_audio_sample_rate().to_i32().unwrap();
+    let aspect_ratio = Rational::from(f64::from(emu.get_aspect_ratio()));
+    let diffing = other.is_some();
+    let differences: Vec<Range<u64>> = other.map_or_else(Vec::new, |other| {
+        let open = |path: &str| decode(std::io::BufReader::new(std::fs::File::open(path).unwrap()));
+        diff(&mut open(&replay).unwrap(), &mut open(&other).unwrap()).unwrap()
+    });
+    let file = std::io::BufReader::new(file);
+    // Runs every frame on emu, even for version 0 replays
+    let mut rply = decode_any(file, Some(&mut emu)).unwrap();
+    if !json {
+        println!("Header in: {:?}", rply.header);
+    }
 
     let mut output = ffmpeg_next::format::output(&outfile).unwrap();
-    let emu_video_framerate = emu.get_video_fps().to_i32().unwrap();
     let emu_time_base = Rational::new(1, emu_video_framerate);
-    let audio_sample_rate = emu.get_audio_sample_rate().to_i32().unwrap();
-    let aspect_ratio = Rational::from(f64::from(emu.get_aspect_ratio()));
     let mut video_state =
         VideoState::new(emu_time_base, aspect_ratio, w, h, pixel_format, &mut output);
     let mut audio_state = AudioState::new(audio_sample_rate, &mut output);
+    // A path from the metadata is relative to the replay, one given here to the working directory
+    let commentary = commentary_path
+        .map(|path| (PathBuf::from(path), 0))
+        .or_else(|| {
+            let commentary = rply.header.metadata()?.commentary()?;
+            let dir = Path::new(&replay).parent().unwrap_or(Path::new(""));
+            Some((dir.join(commentary.path), commentary.start_frame))
+        });
+    let rate = audio_state.in_aframe.rate();
+    audio_state.commentary = commentary.map(|(path, start_frame)| {
+        let start_frame = commentary_start.unwrap_or(start_frame);
+        Commentary::open(&path, start_frame, rate, emu_fps)
+            .unwrap_or_else(|e| panic!("Commentary {}: {e}", path.display()))
+    });
     output.write_header().unwrap();
     // video_state
     //     .encoded_video
@@ -355,36 +587,29 @@ fn main() {
     //     .encoded_audio
     //     .set_time_base(audio_stream_time_base);
 
-    let mut frame = Frame::default();
-    while let Ok(()) = rply
-        .read_frame(&mut frame)
-        .inspect_err(|e| println!("Err: {e}"))
-    {
-        let buttons = frame_to_buttons(&frame);
-        emu.run(buttons);
-        video_state.send_frame(&emu, rply.frame_number, &mut output);
-        audio_state.send_frames(&emu, &mut output);
+    while let Some(frame) = rply.next() {
+        let frame = frame.unwrap_or_else(|e| panic!("Frame {}: {e}", rply.frame_number + 1));
+        let frame_number = rply.frame_number;
+        let emu = rply.emulator().unwrap();
+        if differences.iter().any(|d| d.start == frame_number) {
+            audio_state.beep();
+        }
+        let marked = differences.iter().any(|d| d.contains(&frame_number));
+        video_state.send_frame(emu, frame_number, marked, &mut output);
+        audio_state.send_frames(emu, frame_number, &mut output);
         if !frame.checkpoint_bytes.is_empty() {
             assert!(emu.load(&frame.checkpoint_bytes));
         }
-
-        if Some(rply.frame_number) == rply.header.frame_count() {
-            break;
-        }
     }
     audio_state.drain(&mut output);
     video_state.drain(&mut output);
     output.write_trailer().unwrap();
-}
-
-fn frame_to_buttons(frame: &Frame) -> [retro_rs::Buttons; 2] {
-    use retro_rs::Buttons;
-    let mut buttons = [0_i16; 2];
-    for inp in &frame.input_events {
-        let port = usize::from(inp.port);
-        if port < buttons.len() && inp.device == 1 {
-            buttons[port] |= inp.val;
+    if json {
+        let mut out = serde_json::json!({ "frames": rply.frame_number });
+        if diffing {
+            let ranges: Vec<_> = differences.iter().map(|d| [d.start, d.end - 1]).collect();
+            out["differences"] = serde_json::json!(ranges);
         }
+        println!("{out}");
     }
-    [Buttons::from(buttons[0]), Buttons::from(buttons[1])]
 }
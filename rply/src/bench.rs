@@ -0,0 +1,185 @@
+use crate::convert::parse_compression;
+use crate::{arg, fail, open, parse_flag, take_flag, take_switch, usage};
+use rply_codec::{
+    CodecRegistry, Compression, CompressionOptions, Frame, Header, ReplayEncoder, ReplayError,
+    decode,
+};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/* Matrix tried unless the flags say otherwise */
+const BLOCK_SIZES: &str = "32,64,128,256";
+const SUPERBLOCK_SIZES: &str = "16,64,256";
+const COMPRESSIONS: &str = "none,zlib,zstd";
+
+/* One cell of the matrix */
+struct Config {
+    block_size: u32,
+    superblock_size: u32,
+    compression: Compression,
+    /* Name as given, level included */
+    compression_name: String,
+    level: Option<i32>,
+}
+
+/* What encoding and decoding with a config took */
+struct Run {
+    bytes: u64,
+    encode: Duration,
+    decode: Duration,
+}
+
+fn list<T: std::str::FromStr>(list: &str) -> Vec<T> {
+    list.split(',')
+        .map(|item| item.parse().unwrap_or_else(|_| usage()))
+        .collect()
+}
+
+/* Encodes the frames with `config`, then decodes them back, timing each */
+fn run(
+    header: &Header,
+    initial_state: &[u8],
+    frames: &[Frame],
+    config: &Config,
+) -> Result<Run, ReplayError> {
+    let mut header = header.clone();
+    header.set_block_size(config.block_size);
+    header.set_superblock_size(config.superblock_size);
+    header.set_checkpoint_compression(config.compression);
+    let mut codecs = CodecRegistry::default();
+    codecs.set_compression_options(CompressionOptions {
+        level: config.level,
+        ..CompressionOptions::default()
+    });
+    let mut out = std::io::Cursor::new(vec![]);
+    let start = Instant::now();
+    let mut enc = ReplayEncoder::with_codecs(header, initial_state, &mut out, codecs)?;
+    for frame in frames {
+        enc.write_frame(frame)?;
+    }
+    enc.finish()?;
+    drop(enc);
+    let encode = start.elapsed();
+    let out = out.into_inner();
+    let start = Instant::now();
+    let mut rply = decode(std::io::Cursor::new(&out))?;
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+    }
+    Ok(Run {
+        bytes: out.len() as u64,
+        encode,
+        decode: start.elapsed(),
+    })
+}
+
+pub(crate) fn bench_command(mut args: Vec<String>) {
+    let block_sizes: Vec<u32> =
+        list(&take_flag(&mut args, "--block-sizes").unwrap_or(BLOCK_SIZES.into()));
+    let superblock_sizes: Vec<u32> =
+        list(&take_flag(&mut args, "--superblock-sizes").unwrap_or(SUPERBLOCK_SIZES.into()));
+    let compressions: Vec<String> =
+        list(&take_flag(&mut args, "--compressions").unwrap_or(COMPRESSIONS.into()));
+    let max_frames = parse_flag::<usize>(&mut args, "--frames");
+    let json = take_switch(&mut args, "--json");
+    let replay = arg(&args, 1);
+    let mut rply = open(replay);
+    if !rply.header.capabilities().coreless_frames {
+        fail("Version 0 replays must be converted with a core first");
+    }
+    let frames: Vec<Frame> = rply
+        .frames()
+        .take(max_frames.unwrap_or(usize::MAX))
+        .map(Result::unwrap)
+        .collect();
+    let mut header = rply.header.clone();
+    header.upgrade();
+    let mut configs = vec![];
+    for compression_name in &compressions {
+        let (name, level) = match compression_name.split_once(':') {
+            Some((name, level)) => (name, Some(level.parse().unwrap_or_else(|_| usage()))),
+            None => (compression_name.as_str(), None),
+        };
+        for &block_size in &block_sizes {
+            for &superblock_size in &superblock_sizes {
+                configs.push(Config {
+                    block_size,
+                    superblock_size,
+                    compression: parse_compression(name),
+                    compression_name: compression_name.clone(),
+                    level,
+                });
+            }
+        }
+    }
+    if !json {
+        println!(
+            "{:>5} {:>10} {:<11} {:>12} {:>10} {:>10}",
+            "block", "superblock", "compression", "bytes", "encode ms", "decode ms"
+        );
+    }
+    let mut runs = vec![];
+    for config in &configs {
+        let result = run(&header, &rply.initial_state, &frames, config);
+        if !json {
+            let cell = format!(
+                "{:>5} {:>10} {:<11}",
+                config.block_size, config.superblock_size, config.compression_name
+            );
+            match &result {
+                Ok(run) => println!(
+                    "{cell} {:>12} {:>10.1} {:>10.1}",
+                    run.bytes,
+                    run.encode.as_secs_f64() * 1000.0,
+                    run.decode.as_secs_f64() * 1000.0
+                ),
+                Err(e) => println!("{cell} {e}"),
+            }
+        }
+        runs.push(result);
+    }
+    let smallest = runs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, run)| Some((i, run.as_ref().ok()?.bytes)))
+        .min_by_key(|(_, bytes)| *bytes)
+        .map(|(i, _)| i);
+    if json {
+        let runs: Vec<_> = configs
+            .iter()
+            .zip(&runs)
+            .map(|(config, run)| {
+                let mut entry = json!({
+                    "block_size": config.block_size,
+                    "superblock_size": config.superblock_size,
+                    "compression": config.compression_name,
+                });
+                match run {
+                    Ok(run) => {
+                        entry["bytes"] = json!(run.bytes);
+                        entry["encode_secs"] = json!(run.encode.as_secs_f64());
+                        entry["decode_secs"] = json!(run.decode.as_secs_f64());
+                    }
+                    Err(e) => entry["error"] = json!(e.to_string()),
+                }
+                entry
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({ "frames": frames.len(), "runs": runs, "smallest": smallest })
+        );
+        return;
+    }
+    if let Some(i) = smallest {
+        let config = &configs[i];
+        println!(
+            "{} frames; smallest with block size {}, superblock size {}, and {} compression",
+            frames.len(),
+            config.block_size,
+            config.superblock_size,
+            config.compression_name
+        );
+    }
+}
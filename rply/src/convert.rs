@@ -0,0 +1,455 @@
+use crate::{
+    EXIT_CHECK_FAILED, arg, create, emulator, fail, open, parse_flag, report_frames, take_flag,
+    take_switch, usage,
+};
+use rply_codec::{
+    BlockHash, Commentary, Compression, Counter, Encoding, Frame, HeaderV2, Movie, MovieRegistry,
+    ReplayDecoder, ReplayEncoder, ReplayError, SanitizeLimits, SizeJump, Stats, Timer, Verifier,
+    decode_any, encode, import_bsv1, read_fm2, repair_file, sanitize, thin_checkpoints,
+    tune_block_sizes, write_fm2, write_trace,
+};
+use std::io::{BufRead, Seek, Write};
+
+pub(crate) fn parse_compression(name: &str) -> Compression {
+    match name {
+        "none" => Compression::None,
+        "zlib" => Compression::Zlib,
+        "zstd" => Compression::Zstd,
+        "lz4" => Compression::Lz4,
+        "brotli" => Compression::Brotli,
+        id => id
+            .parse::<u8>()
+            .ok()
+            .and_then(|id| Compression::try_from(id).ok())
+            .unwrap_or_else(|| usage()),
+    }
+}
+
+fn parse_encoding(name: &str) -> Encoding {
+    match name {
+        "raw" => Encoding::Raw,
+        "statestream" => Encoding::Statestream,
+        "delta" => Encoding::Delta,
+        "regions" => Encoding::Regions,
+        id => id
+            .parse::<u8>()
+            .ok()
+            .and_then(|id| Encoding::try_from(id).ok())
+            .unwrap_or_else(|| usage()),
+    }
+}
+
+fn parse_block_hash(name: &str) -> BlockHash {
+    match name {
+        "xxh3-64" => BlockHash::Xxh3_64,
+        "xxh3-128" => BlockHash::Xxh3_128,
+        #[cfg(feature = "blake3")]
+        "blake3" => BlockHash::Blake3,
+        _ => usage(),
+    }
+}
+
+fn print_stats(label: &str, stats: &Stats) {
+    for timer in [
+        Timer::DecodeFrame,
+        Timer::DecodeCheckpoint,
+        Timer::DecodeStatestream,
+        Timer::EncodeFrame,
+        Timer::EncodeCheckpoint,
+        Timer::EncodeStatestream,
+    ] {
+        let times = stats.stats(timer);
+        if times.count > 0 {
+            #[allow(clippy::cast_precision_loss)]
+            let avg_time = (times.micros as f64 / times.count as f64) / 1000.0;
+            println!("{label} {timer:?}: {} ({avg_time:.8}ms avg)", times.count);
+        }
+    }
+    for counter in [
+        Counter::DecSkippedSuperblocks,
+        Counter::DecSkippedBlocks,
+        Counter::EncReusedBlocks,
+        Counter::EncReusedSuperblocks,
+        Counter::EncSkippedBlocks,
+        Counter::EncMemCmps,
+        Counter::EncHashes,
+        Counter::EncTotalBlocks,
+        Counter::EncTotalSuperblocks,
+        Counter::EncTotalKBsIn,
+        Counter::EncTotalKBsOut,
+        Counter::EncPaddingBytes,
+        Counter::EncPaddingBlocks,
+        Counter::DecPaddingBytes,
+        Counter::DecPaddingBlocks,
+        Counter::DecBadPadding,
+        Counter::EncEvictedBlocks,
+        Counter::EncEvictedSuperblocks,
+        Counter::DecEvictedBlocks,
+        Counter::DecEvictedSuperblocks,
+        Counter::EncRunSuperblocks,
+    ] {
+        let count = stats.counts(counter);
+        if count > 0 {
+            println!("{label} {counter:?}: {count}");
+        }
+    }
+}
+
+/* Copies every frame of `rply` to `out` */
+fn copy_frames<R: BufRead, W: Write + Seek>(
+    rply: &mut ReplayDecoder<R>,
+    out: &mut ReplayEncoder<'_, W>,
+) {
+    for frame in rply.frames() {
+        out.write_frame(&frame.unwrap()).unwrap();
+    }
+    out.finish().unwrap();
+}
+
+pub(crate) fn reencode_command(mut args: Vec<String>) {
+    let auto = take_switch(&mut args, "--auto");
+    let block_size = parse_flag(&mut args, "--block-size");
+    let superblock_size = parse_flag(&mut args, "--superblock-size");
+    let size_alert = parse_flag::<f64>(&mut args, "--size-alert");
+    let keyframes = parse_flag(&mut args, "--keyframes");
+    let runs = take_switch(&mut args, "--runs");
+    let xor = take_switch(&mut args, "--xor");
+    let input_only = take_switch(&mut args, "--input-only");
+    let thin = parse_flag(&mut args, "--thin");
+    let checkpoint_every = parse_flag(&mut args, "--checkpoint-every");
+    let emu = emulator(
+        take_flag(&mut args, "--core"),
+        take_flag(&mut args, "--rom"),
+    );
+    let hash = take_flag(&mut args, "--hash").map(|h| parse_block_hash(&h));
+    let trust_hashes = take_switch(&mut args, "--trust-hashes");
+    let pack_frames = take_flag(&mut args, "--pack-frames").map(|c| parse_compression(&c));
+    let compression = take_flag(&mut args, "--compression").map(|c| parse_compression(&c));
+    let encoding = take_flag(&mut args, "--encoding").map(|e| parse_encoding(&e));
+    let memory_budget = parse_flag(&mut args, "--memory-budget");
+    let lookahead = take_switch(&mut args, "--lookahead");
+    let stats = take_switch(&mut args, "--stats");
+    let trace = take_flag(&mut args, "--trace");
+    let json = take_switch(&mut args, "--json");
+    #[cfg(feature = "research")]
+    let research = take_flag(&mut args, "--research");
+    let (replay, outfile) = (arg(&args, 1), arg(&args, 2));
+    if (thin.is_some() || checkpoint_every.is_some()) && lookahead {
+        fail("--lookahead can't be combined with --thin or --checkpoint-every");
+    }
+    let mut rply = open(replay);
+    if !rply.header.capabilities().coreless_frames {
+        fail("Version 0 replays must be converted with a core first");
+    }
+    let mut header = rply.header.clone();
+    header.upgrade();
+    if auto {
+        let (block_size, superblock_size) = tune_block_sizes(&rply.initial_state);
+        header.set_block_size(block_size);
+        header.set_superblock_size(superblock_size);
+    }
+    if let Some(block_size) = block_size {
+        header.set_block_size(block_size);
+    }
+    if let Some(superblock_size) = superblock_size {
+        header.set_superblock_size(superblock_size);
+    }
+    if let Some(compression) = compression {
+        header.set_checkpoint_compression(compression);
+    }
+    if let Some(compression) = pack_frames {
+        let packing = (compression != Compression::None).then_some(compression);
+        header.metadata_mut().set_frame_packing(packing);
+    }
+    let mut jumps = vec![];
+    let mut outfile = create(outfile);
+    let mut out = encode(header, &rply.initial_state, &mut outfile).unwrap();
+    if let Some(encoding) = encoding {
+        out.set_checkpoint_encoding(encoding);
+    }
+    out.set_keyframe_interval(keyframes);
+    out.set_run_tokens(runs);
+    out.set_xor_deltas(xor);
+    out.set_input_only(input_only);
+    out.set_block_hash(hash.unwrap_or_default(), trust_hashes);
+    out.set_memory_budget(memory_budget);
+    if trace.is_some() {
+        rply.stats().set_tracing(true);
+        out.stats().set_tracing(true);
+    }
+    #[cfg(feature = "research")]
+    if let Some(research) = research {
+        out.set_research_log(rply_codec::ResearchLog::create(research).unwrap());
+    }
+    if let Some(threshold) = size_alert {
+        out.set_size_alert(threshold, |jump: &SizeJump| {
+            if !json {
+                eprintln!(
+                    "Checkpoint at frame {} is {} bytes ({} compressed), {:.1}x recent ones",
+                    jump.sizes.frame,
+                    jump.sizes.uncompressed,
+                    jump.sizes.compressed,
+                    jump.ratio()
+                );
+            }
+            jumps.push(jump.sizes.frame);
+        });
+    }
+    if lookahead {
+        let frames: Vec<Frame> = rply.frames().map(Result::unwrap).collect();
+        let checkpoints = frames.iter().map(|frame| &frame.checkpoint_bytes);
+        out.set_lookahead(checkpoints.filter(|c| !c.is_empty()).map(Vec::as_slice));
+        for frame in &frames {
+            out.write_frame(frame).unwrap();
+        }
+        out.finish().unwrap();
+    } else if let Some(n) = thin {
+        thin_checkpoints(&mut rply, n, &mut out).unwrap();
+        out.finish().unwrap();
+    } else if let Some(every) = checkpoint_every {
+        let Some(core) = emu else {
+            fail("--checkpoint-every needs --core and --rom");
+        };
+        let verification = Verifier::new(core)
+            .regenerate(&mut rply, every, &mut out)
+            .unwrap();
+        out.finish().unwrap();
+        if let Some(frame) = verification.desync {
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "frames": out.frame_number, "desync": frame })
+                );
+            } else {
+                println!(
+                    "Desync at frame {frame}; wrote the {} frames before it",
+                    out.frame_number
+                );
+            }
+            std::process::exit(EXIT_CHECK_FAILED);
+        }
+    } else {
+        copy_frames(&mut rply, &mut out);
+    }
+    let frames = out.frame_number;
+    if stats && !json {
+        print_stats("Decoder", rply.stats());
+        print_stats("Encoder", out.stats());
+    }
+    if let Some(trace) = trace {
+        let tracks = [("Decoder", rply.stats()), ("Encoder", out.stats())];
+        write_trace(create(&trace), &tracks).unwrap();
+    }
+    drop(out);
+    if json && size_alert.is_some() {
+        println!(
+            "{}",
+            serde_json::json!({ "frames": frames, "size_jumps": jumps })
+        );
+    } else {
+        report_frames(json, frames);
+    }
+}
+
+pub(crate) fn convert_command(mut args: Vec<String>) {
+    let version = parse_flag(&mut args, "--version");
+    let compression = take_flag(&mut args, "--compression").map(|c| parse_compression(&c));
+    let encoding = take_flag(&mut args, "--encoding").map(|e| parse_encoding(&e));
+    let json = take_switch(&mut args, "--json");
+    let anonymize = take_switch(&mut args, "--anonymize");
+    let commentary_start = parse_flag(&mut args, "--commentary-start");
+    let commentary = take_flag(&mut args, "--commentary").map(|path| Commentary {
+        path,
+        start_frame: commentary_start.unwrap_or(0),
+    });
+    let mut emu = emulator(
+        take_flag(&mut args, "--core"),
+        take_flag(&mut args, "--rom"),
+    );
+    let (replay, outfile) = (arg(&args, 1), arg(&args, 2));
+    let mut file = std::io::BufReader::new(std::fs::File::open(replay).unwrap());
+    let movies = MovieRegistry::default();
+    // Anything no movie format recognizes had better be a replay
+    let rply = if movies.sniff(file.fill_buf().unwrap()).is_some() {
+        movies.open(file).unwrap()
+    } else {
+        let rply = match decode_any(file, emu.as_mut()) {
+            Err(ReplayError::NoCoreRead()) => fail(
+                "Version 0 replays can only be converted by running them: pass --core and --rom",
+            ),
+            rply => rply.unwrap(),
+        };
+        Movie {
+            header: rply.header.clone(),
+            initial_state: rply.initial_state.clone(),
+            frames: Box::new(rply),
+        }
+    };
+    let mut header = rply.header.clone();
+    if let Some(compression) = compression {
+        header.set_checkpoint_compression(compression);
+    }
+    if anonymize && header.metadata().is_some() {
+        header.metadata_mut().anonymize();
+    }
+    if let Some(commentary) = commentary {
+        header.metadata_mut().set_commentary(&commentary);
+    }
+    let mut outfile = create(outfile);
+    let mut out = match version {
+        Some(version) => {
+            ReplayEncoder::with_version(header, &rply.initial_state, &mut outfile, version)
+        }
+        None => encode(header, &rply.initial_state, &mut outfile),
+    }
+    .unwrap();
+    if let Some(encoding) = encoding {
+        out.set_checkpoint_encoding(encoding);
+    }
+    for frame in rply.frames {
+        out.write_frame(&frame.unwrap()).unwrap();
+    }
+    out.finish().unwrap();
+    report_frames(json, out.frame_number);
+}
+
+pub(crate) fn import_bsv1_command(mut args: Vec<String>) {
+    let compression = take_flag(&mut args, "--compression").map(|c| parse_compression(&c));
+    let json = take_switch(&mut args, "--json");
+    let Some(emu) = emulator(
+        take_flag(&mut args, "--core"),
+        take_flag(&mut args, "--rom"),
+    ) else {
+        usage();
+    };
+    let (movie, outfile) = (arg(&args, 1), arg(&args, 2));
+    // BSV1 movies have no identifier, so stamp the replay with the time it was made
+    let identifier = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut header = HeaderV2::builder(identifier, 0);
+    if let Some(compression) = compression {
+        header = header.checkpoint_compression(compression);
+    }
+    let header = header.build().unwrap();
+    let movie = std::io::BufReader::new(std::fs::File::open(movie).unwrap());
+    let (frames, _) = import_bsv1(movie, emu, header, &mut create(outfile)).unwrap();
+    report_frames(json, frames);
+}
+
+pub(crate) fn import_fm2_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let (movie, outfile) = (arg(&args, 1), arg(&args, 2));
+    let movie = std::io::BufReader::new(std::fs::File::open(movie).unwrap());
+    let frames = read_fm2(movie, &mut create(outfile)).unwrap();
+    report_frames(json, frames);
+}
+
+pub(crate) fn export_fm2_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let (replay, movie) = (arg(&args, 1), arg(&args, 2));
+    let frames = write_fm2(&mut open(replay), &mut create(movie)).unwrap();
+    report_frames(json, frames);
+}
+
+pub(crate) fn salvage_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let (replay, outfile) = (arg(&args, 1), arg(&args, 2));
+    let mut rply = open(replay);
+    // The header's frame count counts frames that may be lost
+    rply.set_live(true);
+    let mut outfile = create(outfile);
+    let mut out = encode(rply.header.clone(), &rply.initial_state, &mut outfile).unwrap();
+    let (mut frame, mut damage) = (Frame::default(), vec![]);
+    while rply
+        .read_frame_recovering(&mut frame, &mut damage)
+        .unwrap_or_else(|e| fail(&format!("{replay}: {e}")))
+    {
+        out.write_frame(&frame).unwrap();
+    }
+    out.finish().unwrap();
+    if json {
+        let damage: Vec<_> = damage
+            .iter()
+            .map(|d| {
+                serde_json::json!({
+                    "frame": d.frame,
+                    "start": d.bytes.start,
+                    "end": d.bytes.end,
+                    "error": d.error.to_string(),
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({ "frames": out.frame_number, "damage": damage })
+        );
+    } else {
+        for d in &damage {
+            if d.bytes.is_empty() {
+                println!("Frame {}: dropped checkpoint: {}", d.frame, d.error);
+            } else {
+                println!(
+                    "Frame {}: skipped bytes {}..{}: {}",
+                    d.frame, d.bytes.start, d.bytes.end, d.error
+                );
+            }
+        }
+        report_frames(json, out.frame_number);
+    }
+    if !damage.is_empty() {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+pub(crate) fn repair_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let replay = arg(&args, 1);
+    let repaired = repair_file(replay).unwrap_or_else(|e| fail(&format!("{replay}: {e}")));
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "frames": repaired.frames, "cut_bytes": repaired.cut })
+        );
+    } else if repaired.cut == 0 {
+        println!("{replay} has {} complete frames", repaired.frames);
+    } else {
+        println!(
+            "Cut {} bytes of an incomplete frame; {replay} has {} frames",
+            repaired.cut, repaired.frames
+        );
+    }
+}
+
+pub(crate) fn sanitize_command(mut args: Vec<String>) {
+    let mut limits = SanitizeLimits::default();
+    if let Some(bytes) = parse_flag(&mut args, "--max-bytes") {
+        limits.max_bytes = bytes;
+    }
+    if let Some(frames) = parse_flag(&mut args, "--max-frames") {
+        limits.max_frames = frames;
+    }
+    if let Some(bytes) = parse_flag(&mut args, "--max-state-bytes") {
+        limits.decode.max_state_bytes = bytes;
+    }
+    let json = take_switch(&mut args, "--json");
+    let (upload, outfile) = (arg(&args, 1), arg(&args, 2));
+    let input = std::io::BufReader::new(std::fs::File::open(upload).unwrap());
+    /* Buffered so a rejected upload doesn't leave a partial file behind */
+    let mut out = std::io::Cursor::new(vec![]);
+    match sanitize(input, &mut out, &limits) {
+        Ok(frames) => {
+            std::fs::write(outfile, out.into_inner()).unwrap();
+            report_frames(json, frames);
+        }
+        Err(e) => {
+            if json {
+                println!("{}", serde_json::json!({ "rejected": e.to_string() }));
+            } else {
+                eprintln!("{upload} rejected: {e}");
+            }
+            std::process::exit(EXIT_CHECK_FAILED);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{arg, create, fail, open, parse_flag, take_switch};
+use rply_codec::{
+    BlockUse, Frame, Header, InputStats, JsonOptions, Summary, block_map, input_stats, read_json,
+    summarize, write_json,
+};
+use serde_json::{Value, json};
+
+fn print_header(header: &Header) {
+    println!("Version: {}", header.version());
+    println!("Identifier: {:#x}", header.identifier());
+    println!("Content CRC: {:#010x}", header.content_crc());
+    println!("Initial state: {} bytes", header.initial_state_size());
+    if let Header::V2(v2) = header {
+        println!("Frames: {}", v2.frame_count);
+        println!(
+            "Blocks: {} bytes, {} per superblock",
+            v2.block_size, v2.superblock_size
+        );
+        println!(
+            "Commits: every {} checkpoints, {} uses{}",
+            v2.checkpoint_commit_interval,
+            v2.checkpoint_commit_threshold,
+            if v2.metadata.block_commits() {
+                ""
+            } else {
+                " (not applied)"
+            }
+        );
+        println!("Compression: {:?}", v2.checkpoint_compression);
+        if v2.encrypted_sections.any() {
+            println!("Encrypted: {:?}", v2.encrypted_sections);
+        }
+    }
+    let Some(metadata) = header.metadata().filter(|m| !m.is_empty()) else {
+        return;
+    };
+    for (label, value) in [
+        ("Title", metadata.title()),
+        ("Core", metadata.core_name()),
+        ("Core version", metadata.core_version()),
+        ("Author", metadata.author()),
+        ("Locale", metadata.locale()),
+        ("Timezone", metadata.timezone()),
+    ] {
+        if let Some(value) = value {
+            println!("{label}: {value}");
+        }
+    }
+    if let Some(created) = metadata.created() {
+        println!("Created: {created} (Unix time)");
+    }
+    if let Some(session) = metadata.session() {
+        println!(
+            "Session: {}..{} (Unix time, {}s)",
+            session.start,
+            session.end,
+            session.end.saturating_sub(session.start)
+        );
+    }
+    if let Some(commentary) = metadata.commentary() {
+        println!(
+            "Commentary: {} from frame {}",
+            commentary.path, commentary.start_frame
+        );
+    }
+    if let Some(hash) = metadata.rom_hash() {
+        let hex: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+        println!("ROM hash: {hex}");
+    }
+    println!("License: {}", metadata.license().unwrap_or("(unspecified)"));
+    if let Some(uses) = metadata.allowed_uses() {
+        println!("Allowed uses: {uses}");
+    }
+    if let Some(dictionary) = metadata.zstd_dictionary() {
+        println!("Zstd dictionary: {} bytes", dictionary.len());
+    }
+    for (tag, data) in metadata.unknown_chunks() {
+        println!(
+            "Chunk {}: {} bytes",
+            String::from_utf8_lossy(tag),
+            data.len()
+        );
+    }
+    if let Ok(Some(compat)) = metadata.compat() {
+        for entry in compat.entries() {
+            println!(
+                "Verified with {} {} [{}]: {}",
+                entry.core,
+                entry.version,
+                entry.options,
+                if entry.synced() { "syncs" } else { "desyncs" }
+            );
+        }
+    }
+}
+
+/* The header fields print_header prints, for --json */
+fn header_json(header: &Header) -> Value {
+    let mut out = json!({
+        "version": header.version(),
+        "identifier": header.identifier(),
+        "content_crc": header.content_crc(),
+        "initial_state_size": header.initial_state_size(),
+    });
+    if let Header::V2(v2) = header {
+        out["frame_count"] = json!(v2.frame_count);
+        out["block_size"] = json!(v2.block_size);
+        out["superblock_size"] = json!(v2.superblock_size);
+        out["checkpoint_compression"] = json!(v2.checkpoint_compression);
+        out["encrypted"] = json!(v2.encrypted_sections.any());
+    }
+    if let Some(metadata) = header.metadata() {
+        out["title"] = json!(metadata.title());
+        out["core_name"] = json!(metadata.core_name());
+        out["core_version"] = json!(metadata.core_version());
+        out["author"] = json!(metadata.author());
+        out["created"] = json!(metadata.created());
+        out["session"] = json!(metadata.session());
+        out["locale"] = json!(metadata.locale());
+        out["timezone"] = json!(metadata.timezone());
+        out["rom_hash"] = json!(
+            metadata
+                .rom_hash()
+                .map(|hash| { hash.iter().map(|b| format!("{b:02x}")).collect::<String>() })
+        );
+        out["license"] = json!(metadata.license());
+        out["commentary"] = json!(
+            metadata
+                .commentary()
+                .map(|c| { json!({ "path": c.path, "start_frame": c.start_frame }) })
+        );
+        out["compat"] = json!(
+            metadata
+                .compat()
+                .ok()
+                .flatten()
+                .map(|c| c.entries().to_vec())
+        );
+    }
+    out
+}
+
+fn print_summary(summary: &Summary, fps: f64) {
+    println!("Frames read: {}", summary.frames);
+    let duration = summary.duration(fps).as_secs();
+    println!(
+        "Duration: {}:{:02}:{:02} at {fps} fps",
+        duration / 3600,
+        duration / 60 % 60,
+        duration % 60
+    );
+    println!("Checkpoints: {}", summary.checkpoints);
+    if let Some(average) = summary.average_checkpoint_bytes() {
+        println!(
+            "Checkpoint size: {average:.1} bytes average, {} max",
+            summary.max_checkpoint_bytes
+        );
+    }
+    println!(
+        "Size: {} bytes, {:.2}x smaller than with plain checkpoints",
+        summary.replay_bytes,
+        summary.compression_ratio()
+    );
+    println!("Key events: {}", summary.key_events);
+    for (port, inputs) in &summary.inputs_per_port {
+        println!("Port {port} inputs: {inputs}");
+    }
+}
+
+fn print_input_stats(stats: &InputStats) {
+    for b in &stats.buttons {
+        println!(
+            "Port {} device {} index {} id {}: {} presses, held {} frames, {} at most",
+            b.port, b.device, b.idx, b.id, b.presses, b.held_frames, b.longest_hold
+        );
+    }
+    for stretch in &stats.idle {
+        println!(
+            "Idle for frames {}..={} ({} frames)",
+            stretch.start,
+            stretch.end - 1,
+            stretch.end - stretch.start
+        );
+    }
+    let seconds = stats.presses_per_second.len().max(1);
+    let total: u64 = stats.presses_per_second.iter().sum();
+    #[allow(clippy::cast_precision_loss)]
+    let average = total as f64 / seconds as f64;
+    if let Some((second, most)) = stats
+        .presses_per_second
+        .iter()
+        .enumerate()
+        .max_by_key(|(second, presses)| (**presses, std::cmp::Reverse(*second)))
+    {
+        println!("Presses per second: {average:.1} average, {most} at most (second {second})");
+    }
+}
+
+pub(crate) fn info_command(mut args: Vec<String>) {
+    let fps = parse_flag(&mut args, "--fps").unwrap_or(60.0);
+    let inputs = take_switch(&mut args, "--inputs");
+    let json = take_switch(&mut args, "--json");
+    let mut rply = open(arg(&args, 1));
+    let coreless_frames = rply.header.capabilities().coreless_frames;
+    if json {
+        let summary = coreless_frames.then(|| summarize(&mut rply).unwrap());
+        let duration = summary.as_ref().map(|s| s.duration(fps).as_secs_f64());
+        let mut out = json!({
+            "header": header_json(&rply.header),
+            "summary": summary,
+            "duration_secs": duration,
+        });
+        if inputs {
+            out["inputs"] = json!(coreless_frames.then(|| {
+                rply.seek_to_frame(0).unwrap();
+                input_stats(&mut rply, fps).unwrap()
+            }));
+        }
+        println!("{out}");
+        return;
+    }
+    print_header(&rply.header);
+    if coreless_frames {
+        print_summary(&summarize(&mut rply).unwrap(), fps);
+        if inputs {
+            rply.seek_to_frame(0).unwrap();
+            print_input_stats(&input_stats(&mut rply, fps).unwrap());
+        }
+    }
+}
+
+pub(crate) fn dump_command(mut args: Vec<String>) {
+    let ndjson = take_switch(&mut args, "--ndjson");
+    let json = take_switch(&mut args, "--json") || ndjson;
+    let checkpoint_bytes = take_switch(&mut args, "--checkpoint-bytes");
+    let mut rply = open(arg(&args, 1));
+    if json {
+        let options = JsonOptions {
+            ndjson,
+            checkpoint_bytes,
+        };
+        let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+        write_json(&mut rply, &mut stdout, &options).unwrap();
+        return;
+    }
+    print_header(&rply.header);
+    if !rply.header.capabilities().coreless_frames {
+        println!("Version 0 frames can only be read by running them in a core");
+        return;
+    }
+    // dump never looks inside checkpoints, so don't spend time decoding them
+    let mut frame = Frame::default();
+    while !rply.at_end().unwrap() {
+        let checkpoint = rply.read_frame_skipping_checkpoints(&mut frame).unwrap();
+        println!(
+            " {}{:08} {}",
+            if checkpoint.is_some() { "*" } else { " " },
+            rply.frame_number,
+            frame.inputs(),
+        );
+    }
+}
+
+pub(crate) fn undump_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let (dump, outfile) = (arg(&args, 1), arg(&args, 2));
+    let input = std::io::BufReader::new(std::fs::File::open(dump).unwrap());
+    let frames = read_json(input, &mut create(outfile)).unwrap();
+    crate::report_frames(json, frames);
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn blockmap_command(mut args: Vec<String>) {
+    let block_size = parse_flag(&mut args, "--block-size");
+    let json = take_switch(&mut args, "--json");
+    let (replay, image) = (arg(&args, 1), arg(&args, 2));
+    let mut rply = open(replay);
+    if !rply.header.capabilities().coreless_frames {
+        fail("Version 0 replays can only be read by running them");
+    }
+    let map = block_map(&mut rply, block_size).unwrap();
+    map.write_png(create(image)).unwrap();
+    let (new, reused, zero) = (
+        map.count(BlockUse::New),
+        map.count(BlockUse::Reused),
+        map.count(BlockUse::Zero),
+    );
+    if json {
+        println!(
+            "{}",
+            json!({
+                "checkpoints": map.checkpoints.len(),
+                "positions": map.positions(),
+                "block_size": map.block_size,
+                "new": new,
+                "reused": reused,
+                "zero": zero,
+            })
+        );
+        return;
+    }
+    let blocks = (new + reused + zero).max(1) as f64;
+    println!(
+        "{} checkpoints of up to {} blocks of {} bytes: {:.1}% new, {:.1}% reused, {:.1}% zero",
+        map.checkpoints.len(),
+        map.positions(),
+        map.block_size,
+        new as f64 * 100.0 / blocks,
+        reused as f64 * 100.0 / blocks,
+        zero as f64 * 100.0 / blocks
+    );
+}
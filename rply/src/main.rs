@@ -0,0 +1,605 @@
+use retro_rs::Emulator;
+use rply_codec::{
+    Core, LintIssue, LintOptions, ReplayDecoder, ReplayError, ReplaySource, SourceReader, clip,
+    decode_source, diff, diverge, lint, merge, splice,
+};
+use serde_json::json;
+use std::io::BufWriter;
+use std::path::Path;
+
+mod bench;
+mod convert;
+mod info;
+mod plan;
+mod soak;
+mod verify;
+mod watch;
+
+// Every subcommand exits with status 0 on success, 1 if the replay failed a check (lint
+// issues, a verify or splice desync, a rejected upload), 2 on bad arguments, and 3 if
+// anything else went wrong, e.g. an unreadable or malformed file.  Every subcommand also
+// takes --json, which replaces its output with a single JSON object (one per line for lint,
+// dump --ndjson and watch), and errors with {"error": MESSAGE}; the object's fields are
+// listed with each subcommand.  Built with the http feature, subcommands that read a replay
+// without writing over it also take an http:// or https:// URL, fetched with range requests.
+//
+// rply info examples/bobl.replay [--fps FPS] [--inputs]
+// Prints the header and metadata, then totals read from the frames: checkpoint sizes,
+// compression, events per port, and the duration at FPS (default 60).  --inputs adds each
+// input's presses and holds, the longest stretches with nothing held, and presses per second.
+// JSON: {"header": {...}, "summary": {...} or null for version 0, "duration_secs"}, and with
+// --inputs "inputs": {"frames", "buttons", "idle", "presses_per_second", "fps"} or null
+//
+// rply dump examples/bobl.replay [--json | --ndjson] [--checkpoint-bytes]
+// Also prints every frame's inputs, marking frames that end in a checkpoint.  With --json
+// or --ndjson, prints the whole replay as one JSON document or one line per frame instead;
+// --checkpoint-bytes includes each checkpoint's state in base64.
+//
+// rply undump bobl.json out.replay
+// Encodes JSON written by dump back into a replay.  Checkpoints dumped without their bytes
+// are left out.
+// JSON (for this and every subcommand that writes a replay or movie): {"frames": N}
+//
+// rply blockmap examples/bobl.replay blocks.png [--block-size N]
+// Draws how statestream stores each checkpoint: a column per checkpoint (the initial state
+// first) and a row per block, red where the block is new, green where it was seen before, and
+// grey where it is all zeros.  Blocks are the header's size (256 bytes for version 1) unless
+// --block-size is given, and are sorted by content whatever the replay's encoding.
+// JSON: {"checkpoints", "positions", "block_size", "new", "reused", "zero"}, counts of blocks
+//
+// rply lint examples/bobl.replay [--json] [--max-checkpoint-gap FRAMES]
+// With --json, prints one JSON object per issue for submission pipelines:
+// {"file", "code", "error": true for errors rather than warnings, "frame" or null, "message"}
+// Exits with status 1 if any issue was found.
+//
+// rply clip examples/bobl.replay boss.replay --from A --to B [--core CORE --rom ROM]
+// Without a core, the clip starts at the last checkpoint at or before A.
+// rply trim is clip without a core.
+// JSON: {"from", "to"}, the frames of the original replay that were written
+//
+// rply splice first.replay second.replay out.replay [--core CORE --rom ROM]
+// Appends second's frames to first's.  With a core, the junction is simulated up to second's
+// first checkpoint, and nothing is written if the core's state doesn't match it.
+// JSON: {"frames", "checked": the verifying checkpoint's frame or null}, or on a desync
+// {"desync", "differences": [{"start", "end"}...]}
+//
+// rply diff a.replay b.replay
+// Prints the frame ranges (numbered from 1) where the replays' inputs differ, then the first
+// frame after which both have a checkpoint but the checkpoints differ: the differing byte
+// ranges, blocks (of a's block size), and indices of a's state regions.  Checkpoints that
+// differ before the inputs do mean the core recorded them nondeterministically.  Exits with
+// status 1 if the inputs or any checkpoints differ.
+// JSON: {"differences": [{"start", "end"}...], "first_frame": frame or null, "checkpoint":
+// {"frame", "differences", "block_size", "blocks", "regions"} or null}
+//
+// rply merge base.replay ours.replay theirs.replay out.replay
+// Three-way merges the input edits of two replays made from base: each frame takes whichever
+// side's inputs changed.  Frames both sides changed differently are conflicts and get ours;
+// the merge is written anyway, and exits with status 1.  Checkpoints are kept only up to the
+// first frame that doesn't match their side, so verify the merge with a core afterwards.
+// JSON: {"frames", "conflicts": [frame...], "checkpoints"}
+//
+// rply reencode examples/bobl.replay small.replay [--auto] [--block-size N] [--superblock-size N]
+//   [--stats] [--size-alert RATIO] [--keyframes FRAMES] [--runs] [--xor] [--hash H [--trust-hashes]]
+//   [--pack-frames C] [--memory-budget BYTES [--lookahead]] [--trace TRACE.json] [--input-only]
+//   [--thin N | --checkpoint-every FRAMES --core CORE --rom ROM] [--compression C] [--encoding E]
+// Re-encodes every checkpoint with new statestream settings.  With the research feature,
+// --research LOG.csv also writes one row per encoded block.  --stats is left out of JSON.
+// --auto picks the block and superblock sizes from the replay's initial state; --block-size
+// and --superblock-size override its picks.
+// --size-alert warns of checkpoints over RATIO times the size of recent ones, which often
+// means the core got into a bad state; JSON: {"frames", "size_jumps": [frame, ...]}
+// --keyframes makes a statestream checkpoint at least every FRAMES frames decodable without
+// the ones before it, so players can start from the middle of a partly downloaded replay.
+// --runs sends long runs of zero or repeated superblocks as single tokens, and --xor sends
+// each checkpoint as its XOR with the one before; RetroArch can't read the result of either.
+// --hash finds repeated blocks with xxh3-64 (the default), xxh3-128, or, with the blake3
+// feature, blake3; --trust-hashes skips comparing the bytes of blocks whose 128-bit hashes match.
+// --pack-frames compresses runs of frames between checkpoints with C, which makes a version 3
+// replay RetroArch can't read; --pack-frames none unpacks a packed replay.
+// --compression and --encoding switch the checkpoints to another compression (e.g. zlib to
+// zstd) or encoding (e.g. raw to statestream), as convert does; inputs are copied as they are.
+// --input-only drops every checkpoint but the initial state, for the smallest replay that can
+// still be checked; verify --regenerate puts checkpoints back.
+// --thin keeps only every Nth checkpoint, starting with the first.  --checkpoint-every runs the
+// core over the replay to add its state as a checkpoint every FRAMES frames, keeping the
+// checkpoints already there; it checks those as verify does, and on a desync writes the frames
+// before it and exits with status 1 (JSON: {"frames", "desync"}).  Neither combines with
+// --lookahead.
+// --memory-budget caps the statestream tables, evicting the least recently used blocks, or
+// with --lookahead (which reads the whole replay first) those needed furthest in the future.
+// --trace writes how long decoding and encoding each frame, checkpoint, and statestream took
+// as a Chrome tracing file, for viewing in Perfetto (ui.perfetto.dev) or chrome://tracing.
+//
+// rply convert in.replay out.replay [--version V] [--compression C] [--encoding E] [--anonymize]
+//   [--commentary AUDIO [--commentary-start FRAME]] [--core CORE --rom ROM]
+// Rewrites a replay in another format version or checkpoint compression or encoding (raw,
+// statestream, delta, or regions, which encodes each of the header's state regions on its own);
+// version 0 replays can only be read by running them, so they need a core.  The input may
+// also be a movie in any format the codec can import (currently fm2), recognized by its
+// contents rather than its name.  --anonymize leaves out the author and any recording session
+// times, locale, and timezone.  --commentary records an audio file (relative to the output
+// replay) for genvideo to mix in, starting once FRAME frames have run (default 0, negative
+// to skip the start of the audio).
+//
+// rply sanitize upload.replay clean.replay [--max-bytes N] [--max-frames N] [--max-state-bytes N]
+// Decodes an untrusted replay within limits (by default 1GiB, a day of frames at 60fps, and
+// 64MiB states) and re-encodes it with a fresh header, keeping only metadata the codec
+// understands.  Exits with status 1, writing nothing, if the replay is malformed, over a
+// limit, encrypted, or version 0.
+// JSON: {"frames"}, or {"rejected": MESSAGE}
+//
+// rply salvage damaged.replay out.replay
+// Copies every frame it can read from a damaged replay, skipping past corrupt stretches to the
+// next plausible frame and dropping checkpoints that can't be decoded.  Prints each stretch of
+// damage, and exits with status 1 if there was any.
+// JSON: {"frames", "damage": [{"frame", "start", "end", "error"}, ...]}
+//
+// rply repair crashed.replay
+// Fixes a replay in place whose recording stopped mid-frame: cuts it after its last complete
+// frame and writes the frame count to its header.  Replays damaged anywhere but their end
+// are left alone; salvage those instead.
+// JSON: {"frames", "cut_bytes"}
+//
+// rply import-bsv1 movie.bsv out.replay --core CORE --rom ROM [--compression C]
+// Converts an old RetroArch BSV1 movie by running it in the core, which splits its inputs
+// into frames; checkpoints are saved every 60 frames.
+//
+// rply import-fm2 movie.fm2 out.replay
+// rply export-fm2 in.replay movie.fm2
+// Converts FCEUX movies to and from replays, with NES gamepads as RetroPad bitmasks.  Imported
+// replays start from power-on and have no checkpoints; exports drop checkpoints.
+//
+// rply verify examples/bobl.replay --core CORE --rom ROM [--jobs N] [--restart]
+//   [--regenerate OUT [--checkpoint-every FRAMES]]
+//   [--core-name NAME] [--core-version VERSION] [--core-options OPTIONS]
+// Progress is kept in <replay>.verify; run again to resume, or pass --restart to start over.
+// With --jobs N, checkpoint intervals are verified on N cores at once (no progress is kept).
+// --regenerate also writes the replay to OUT with the core's state as a checkpoint every
+// FRAMES frames (default 60), e.g. to restore an input-only replay; it keeps no progress and
+// stops writing at a desync.
+// Results are recorded in <replay>.compat under the core's name (default: the core file's
+// name), --core-version, and --core-options.  Exits with status 1 on a desync.
+//
+// rply verify examples/bobl.replay --command "PROGRAM ARGS" [--core-name NAME] ...
+// Verifies with an external program instead of a libretro core: it is run as
+// `PROGRAM ARGS replay from to` with a savestate on stdin and must print a hash of the state
+// after running frames from+1..=to (none when from equals to).  The core's name defaults to
+// the program's name.
+// JSON: {"core", "version", "options", "result": {"frames", "checkpoints", "desync" or null,
+// "differences": [{"start", "end"}...]}}; progress isn't reported.
+//
+// rply watch recording.replay [--interval SECS]
+// Re-reads a replay as it is recorded, printing the recording rate, bytes per frame,
+// checkpoint cadence, and how much bigger its savestates are than the file, until the
+// recorder finishes it: the header's frame count matches the frames, and the file hasn't
+// changed since the last read (recorders may flush the header before they finish).
+// JSON: one {"frames", "bytes", "checkpoints", "last_checkpoint", "state_bytes", "finished"}
+// per read; reads before the file is readable are skipped.
+//
+// rply soak [--iterations N] [--seed S] [--bytes N] [--max-rss BYTES] [--dir DIR]
+// Soak-tests the codec's bounded memory use: each run encodes a synthetic replay with N bytes
+// of savestates (default 4GiB) under settings drawn at random from its seed, to a temporary
+// file in DIR, then decodes it and restarts from one of its keyframes.  Fails, exiting with
+// status 1, if a state doesn't decode, the statestream tables outgrow the run's memory budget,
+// or the process's peak RSS goes over BYTES (default 1GiB; checked on Linux only).  Runs until
+// a run fails or N runs have passed; run i uses seed S+i, S being the time by default, so
+// `--seed` with `--iterations 1` repeats a run.
+// JSON: one {"seed", "settings": {...}, "frames", "checkpoints", "state_bytes", "bytes",
+// "encoder_table_bytes", "decoder_table_bytes", "keyframe", "peak_rss", "secs"} per run, or
+// {"seed", "failure": MESSAGE}
+//
+// rply plan archive/ [--sample CHECKPOINTS] [--min-savings PERCENT]
+// Plans re-encoding every .replay under a directory: re-encodes the start of each replay, up to
+// its CHECKPOINTSth checkpoint (default 16), in memory with other checkpoint compressions,
+// block sizes, --runs, and --pack-frames zstd, plus the reencode settings that helped together,
+// and scales the sizes up to the whole file.  Prints the replays whose best estimate saves at
+// least PERCENT (default 5) of their size, those saving the most first, each with the command
+// that re-encodes it; a compression and reencode settings can't be made in one step, so plan
+// again after migrating to find the next.  Replays that can't be read (including version 0)
+// are skipped.
+// JSON: {"plan": [{"file", "bytes", "estimated_bytes", "command", "args": [flag...]}...],
+// "unchanged": [file...], "skipped": [{"file", "error"}...], "bytes", "estimated_savings"};
+// each step runs as `rply COMMAND FILE OUT ARGS...`.
+//
+// rply bench examples/bobl.replay [--block-sizes N,...] [--superblock-sizes N,...]
+//   [--compressions C[:LEVEL],...] [--frames N]
+// Re-encodes the replay in memory with every combination of the given block sizes (default
+// 32,64,128,256), superblock sizes (default 16,64,256), and checkpoint compressions, each
+// optionally at a level (default none,zlib,zstd at their default levels), then decodes each
+// result, printing its size and how long encoding and decoding took.  --frames benchmarks only
+// the first N frames.  Combinations that fail to encode, e.g. a level out of range or a scheme
+// built without, are reported and skipped.
+// JSON: {"frames", "runs": [{"block_size", "superblock_size", "compression", "bytes",
+// "encode_secs", "decode_secs"} or {..., "error"}...], "smallest": index into runs or null}
+
+const USAGE: &str = "Usage (every subcommand also takes --json):
+  rply info <replay> [--fps FPS] [--inputs]
+  rply dump <replay> [--json | --ndjson] [--checkpoint-bytes]
+  rply undump <json> <out>
+  rply blockmap <replay> <out.png> [--block-size N]
+  rply lint <replay> [--json] [--max-checkpoint-gap FRAMES]
+  rply clip <replay> <out> --from A --to B [--core CORE --rom ROM]
+  rply trim <replay> <out> --from A --to B
+  rply splice <first> <second> <out> [--core CORE --rom ROM]
+  rply diff <a> <b>
+  rply merge <base> <ours> <theirs> <out>
+  rply reencode <replay> <out> [--auto] [--block-size N] [--superblock-size N] [--stats]
+                [--size-alert RATIO] [--keyframes FRAMES] [--runs] [--xor] [--hash H [--trust-hashes]]
+                [--pack-frames C] [--memory-budget BYTES [--lookahead]] [--trace TRACE.json]
+                [--input-only] [--thin N | --checkpoint-every FRAMES --core CORE --rom ROM]
+                [--compression C] [--encoding E]
+  rply convert <replay or movie> <out> [--version V] [--compression C] [--encoding E] [--anonymize]
+               [--commentary AUDIO [--commentary-start FRAME]] [--core CORE --rom ROM]
+  rply sanitize <replay> <out> [--max-bytes N] [--max-frames N] [--max-state-bytes N]
+  rply salvage <replay> <out>
+  rply repair <replay>
+  rply import-bsv1 <movie.bsv> <out> --core CORE --rom ROM [--compression C]
+  rply import-fm2 <movie.fm2> <out>
+  rply export-fm2 <replay> <movie.fm2>
+  rply verify <replay> --core CORE --rom ROM [--jobs N] [--restart]
+              [--regenerate OUT [--checkpoint-every FRAMES]]
+              [--core-name NAME] [--core-version VERSION] [--core-options OPTIONS]
+  rply verify <replay> --command \"PROGRAM ARGS\" [--core-name NAME] ...
+  rply watch <replay> [--interval SECS]
+  rply soak [--iterations N] [--seed S] [--bytes N] [--max-rss BYTES] [--dir DIR]
+  rply plan <dir> [--sample CHECKPOINTS] [--min-savings PERCENT]
+  rply bench <replay> [--block-sizes N,...] [--superblock-sizes N,...]
+             [--compressions C[:LEVEL],...] [--frames N]";
+
+/* Removes `name` and its value from `args` */
+fn take_flag(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == name)?;
+    if i + 1 == args.len() {
+        bad_argument(&format!("{name} needs a value"));
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+/* Removes `name` and its value from `args`, parsed as a `T` */
+fn parse_flag<T: std::str::FromStr>(args: &mut Vec<String>, name: &str) -> Option<T>
+where
+    T::Err: std::fmt::Display,
+{
+    take_flag(args, name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|e| bad_argument(&format!("Bad {name} {value:?}: {e}")))
+    })
+}
+
+/* Removes `name` from `args`, returning whether it was there */
+fn take_switch(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|a| a != name);
+    args.len() != len
+}
+
+/* Exit statuses; see the top of this file */
+const EXIT_CHECK_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(EXIT_USAGE);
+}
+
+/* Reports bad arguments, saying what was wrong with them, and exits */
+fn bad_argument(message: &str) -> ! {
+    if json_output() {
+        println!("{}", json!({ "error": message }));
+    } else {
+        eprintln!("{message}\n{USAGE}");
+    }
+    std::process::exit(EXIT_USAGE);
+}
+
+/* Whether --json was passed */
+fn json_output() -> bool {
+    std::env::args().any(|a| a == "--json")
+}
+
+/* Reports an error that isn't a failed check and exits */
+fn fail(message: &str) -> ! {
+    if json_output() {
+        println!("{}", json!({ "error": message }));
+    } else {
+        eprintln!("{message}");
+    }
+    std::process::exit(EXIT_ERROR);
+}
+
+/* The `i`th positional argument after the subcommand */
+fn arg(args: &[String], i: usize) -> &str {
+    args.get(i).map_or_else(|| usage(), String::as_str)
+}
+
+/* Where open reads replays from */
+type Source = SourceReader<Box<dyn ReplaySource>>;
+
+fn open(replay: &str) -> ReplayDecoder<Source> {
+    let source: Box<dyn ReplaySource> =
+        if replay.starts_with("http://") || replay.starts_with("https://") {
+            #[cfg(feature = "http")]
+            {
+                Box::new(rply_codec::HttpSource::open(replay).unwrap())
+            }
+            #[cfg(not(feature = "http"))]
+            fail("Reading replays from URLs needs the http feature")
+        } else {
+            Box::new(std::fs::File::open(replay).unwrap())
+        };
+    decode_source(source).unwrap()
+}
+
+fn create(outfile: &str) -> BufWriter<std::fs::File> {
+    BufWriter::new(std::fs::File::create(outfile).unwrap())
+}
+
+/* Reports how many frames a subcommand wrote */
+fn report_frames(json: bool, frames: u64) {
+    if json {
+        println!("{}", json!({ "frames": frames }));
+    } else {
+        println!("Wrote {frames} frames");
+    }
+}
+
+/* Loads `core` with `rom` if both were given */
+fn emulator(core: Option<String>, rom: Option<String>) -> Option<Emulator> {
+    core.map(|core| {
+        let rom = rom.unwrap_or_else(|| bad_argument("--core needs --rom"));
+        let mut emu = Emulator::create(Path::new(&core), Path::new(&rom));
+        // run emu a tick so the core is fully initialized before loading states
+        emu.run([retro_rs::Buttons::default(); 2]);
+        emu
+    })
+}
+
+fn main() {
+    // Errors mostly surface as panics from unwrapping; give them their own status
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if json_output() {
+            println!(
+                "{}",
+                json!({ "error": info.payload_as_str().unwrap_or("panic") })
+            );
+        } else {
+            default_hook(info);
+        }
+        std::process::exit(EXIT_ERROR);
+    }));
+    let mut args: Vec<_> = std::env::args().collect();
+    if args.len() < 2 {
+        usage();
+    }
+    let command = args.remove(1);
+    match command.as_str() {
+        "info" => info::info_command(args),
+        "dump" => info::dump_command(args),
+        "undump" => info::undump_command(args),
+        "blockmap" => info::blockmap_command(args),
+        "lint" => lint_command(args),
+        "clip" => clip_command(args, true),
+        "trim" => clip_command(args, false),
+        "splice" => splice_command(args),
+        "diff" => diff_command(args),
+        "merge" => merge_command(args),
+        "reencode" => convert::reencode_command(args),
+        "convert" => convert::convert_command(args),
+        "sanitize" => convert::sanitize_command(args),
+        "salvage" => convert::salvage_command(args),
+        "repair" => convert::repair_command(args),
+        "import-bsv1" => convert::import_bsv1_command(args),
+        "import-fm2" => convert::import_fm2_command(args),
+        "export-fm2" => convert::export_fm2_command(args),
+        "verify" => verify::verify_command(args),
+        "watch" => watch::watch_command(args),
+        "soak" => soak::soak_command(args),
+        "plan" => plan::plan_command(args),
+        "bench" => bench::bench_command(args),
+        _ => usage(),
+    }
+}
+
+fn lint_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let mut options = LintOptions::default();
+    if let Some(gap) = parse_flag(&mut args, "--max-checkpoint-gap") {
+        options.max_checkpoint_gap = gap;
+    }
+    let replay = arg(&args, 1);
+    let mut rply = open(replay);
+    let issues = lint(&mut rply, &options).unwrap();
+    for issue in &issues {
+        if json {
+            println!("{}", issue_json(replay, issue));
+        } else {
+            println!("{replay}: {}: {issue}", issue.code());
+        }
+    }
+    if !issues.is_empty() {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+fn clip_command(mut args: Vec<String>, with_core: bool) {
+    let json = take_switch(&mut args, "--json");
+    let from = parse_flag(&mut args, "--from").unwrap_or(0);
+    let to = parse_flag(&mut args, "--to").unwrap_or(u64::MAX);
+    let mut emu = if with_core {
+        emulator(
+            take_flag(&mut args, "--core"),
+            take_flag(&mut args, "--rom"),
+        )
+    } else {
+        None
+    };
+    let (replay, outfile) = (arg(&args, 1), arg(&args, 2));
+    let mut rply = open(replay);
+    let mut out = create(outfile);
+    let start = clip(
+        &mut rply,
+        from,
+        to,
+        &mut out,
+        emu.as_mut().map(|emu| emu as &mut dyn Core),
+    )
+    .unwrap();
+    let end = rply.frame_number.min(to);
+    if json {
+        println!("{}", json!({ "from": start, "to": end }));
+    } else {
+        println!("Wrote frames {start}..{end} to {outfile}");
+    }
+}
+
+fn splice_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let mut emu = emulator(
+        take_flag(&mut args, "--core"),
+        take_flag(&mut args, "--rom"),
+    );
+    let (first, second, outfile) = (arg(&args, 1), arg(&args, 2), arg(&args, 3));
+    let (mut first, mut second) = (open(first), open(second));
+    /* Buffered so a failed splice doesn't leave a partial file behind */
+    let mut out = std::io::Cursor::new(vec![]);
+    match splice(
+        &mut first,
+        &mut second,
+        &mut out,
+        emu.as_mut().map(|emu| emu as &mut dyn Core),
+    ) {
+        Ok(checked) => {
+            std::fs::write(outfile, out.into_inner()).unwrap();
+            let frames = first.frame_number + second.frame_number;
+            if json {
+                println!("{}", json!({ "frames": frames, "checked": checked }));
+                return;
+            }
+            match checked {
+                Some(frame) => println!("Junction verified by the checkpoint at frame {frame}"),
+                None if emu.is_some() => println!("No checkpoint after the junction to verify"),
+                None => {}
+            }
+            println!("Wrote {frames} frames to {outfile}");
+        }
+        Err(ReplayError::SpliceDesync(frame, differences)) => {
+            if json {
+                println!("{}", json!({ "desync": frame, "differences": differences }));
+            } else {
+                eprintln!("Splice desyncs at frame {frame}; nothing written");
+                for range in &differences {
+                    eprintln!("  bytes {range:?} differ");
+                }
+            }
+            std::process::exit(EXIT_CHECK_FAILED);
+        }
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+fn diff_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let (a, b) = (arg(&args, 1), arg(&args, 2));
+    let differences = diff(&mut open(a), &mut open(b)).unwrap();
+    let divergence = diverge(&mut open(a), &mut open(b)).unwrap();
+    let checkpoint = divergence.checkpoint.as_ref();
+    if json {
+        let checkpoint = checkpoint.map(|c| {
+            json!({
+                "frame": c.frame,
+                "differences": c.differences,
+                "block_size": c.block_size,
+                "blocks": c.blocks,
+                "regions": c.regions,
+            })
+        });
+        println!(
+            "{}",
+            json!({
+                "differences": differences,
+                "first_frame": divergence.frame,
+                "checkpoint": checkpoint,
+            })
+        );
+    } else {
+        for range in &differences {
+            println!("frames {}..={} differ", range.start, range.end - 1);
+        }
+        if let Some(c) = checkpoint {
+            let ranges = |ranges: &[std::ops::Range<usize>]| {
+                ranges
+                    .iter()
+                    .map(|r| format!("{}..={}", r.start, r.end - 1))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            println!(
+                "checkpoints after frame {} differ in bytes {}",
+                c.frame,
+                ranges(&c.differences)
+            );
+            println!("  blocks of {} bytes: {}", c.block_size, ranges(&c.blocks));
+            if !c.regions.is_empty() {
+                let regions: Vec<_> = c.regions.iter().map(ToString::to_string).collect();
+                println!("  state regions: {}", regions.join(", "));
+            }
+            if divergence.frame.is_none_or(|frame| c.frame < frame) {
+                println!("  before any inputs differ, so the recording was nondeterministic");
+            }
+        }
+    }
+    if !differences.is_empty() || checkpoint.is_some() {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+fn merge_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let (base, ours, theirs) = (arg(&args, 1), arg(&args, 2), arg(&args, 3));
+    let outfile = arg(&args, 4);
+    let mut out = std::io::Cursor::new(vec![]);
+    let merged = merge(
+        &mut open(base),
+        &mut open(ours),
+        &mut open(theirs),
+        &mut out,
+    )
+    .unwrap_or_else(|e| fail(&e.to_string()));
+    std::fs::write(outfile, out.into_inner()).unwrap();
+    if json {
+        println!(
+            "{}",
+            json!({
+                "frames": merged.frames,
+                "conflicts": merged.conflicts,
+                "checkpoints": merged.checkpoints,
+            })
+        );
+    } else {
+        for frame in &merged.conflicts {
+            eprintln!("Conflict at frame {frame}: both sides changed it; kept ours");
+        }
+        println!(
+            "Wrote {} frames with {} checkpoints to {outfile}",
+            merged.frames, merged.checkpoints
+        );
+    }
+    if !merged.conflicts.is_empty() {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+fn issue_json(replay: &str, issue: &LintIssue) -> serde_json::Value {
+    json!({
+        "file": replay,
+        "code": issue.code(),
+        "error": issue.is_error(),
+        "frame": issue.frame(),
+        "message": issue.to_string(),
+    })
+}
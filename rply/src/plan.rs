@@ -0,0 +1,297 @@
+use crate::convert::parse_compression;
+use crate::{arg, parse_flag, take_switch};
+use rply_codec::{Frame, Header, ReplayError, decode, encode};
+use serde_json::json;
+use std::io::Seek;
+use std::path::{Path, PathBuf};
+
+/* Checkpoints of each replay re-encoded by default */
+const SAMPLE_CHECKPOINTS: usize = 16;
+/* Block sizes tried besides the replay's own */
+const BLOCK_SIZES: [u32; 4] = [64, 128, 256, 512];
+
+/* A re-encoding the planner tries: a new checkpoint compression, which
+convert makes, or new statestream settings, which reencode makes */
+#[derive(Clone, Default)]
+struct Candidate {
+    compression: Option<&'static str>,
+    block_size: Option<u32>,
+    runs: bool,
+    pack_frames: Option<&'static str>,
+}
+
+impl Candidate {
+    /* The subcommand that makes it and the flags to pass after its input
+    and output */
+    fn command(&self) -> (&'static str, Vec<String>) {
+        if let Some(compression) = self.compression {
+            return ("convert", vec!["--compression".into(), compression.into()]);
+        }
+        let mut flags = vec![];
+        if let Some(block_size) = self.block_size {
+            flags.extend(["--block-size".into(), block_size.to_string()]);
+        }
+        if self.runs {
+            flags.push("--runs".into());
+        }
+        if let Some(pack_frames) = self.pack_frames {
+            flags.extend(["--pack-frames".into(), pack_frames.into()]);
+        }
+        ("reencode", flags)
+    }
+    /* Bytes it takes to encode the sample */
+    fn measure(&self, sample: &Sample) -> Result<u64, ReplayError> {
+        let mut header = sample.header.clone();
+        if let Some(compression) = self.compression {
+            header.set_checkpoint_compression(parse_compression(compression));
+        } else {
+            header.upgrade();
+        }
+        if let Some(block_size) = self.block_size {
+            header.set_block_size(block_size);
+        }
+        if let Some(pack_frames) = self.pack_frames {
+            let packing = Some(parse_compression(pack_frames));
+            header.metadata_mut().set_frame_packing(packing);
+        }
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut enc = encode(header, &sample.initial_state, &mut out)?;
+        enc.set_run_tokens(self.runs);
+        for frame in &sample.frames {
+            enc.write_frame(frame)?;
+        }
+        enc.finish()?;
+        drop(enc);
+        Ok(out.into_inner().len() as u64)
+    }
+    /* The whole replay's size scaled from the sample's, if it encodes */
+    fn estimate(&self, sample: &Sample) -> Option<u64> {
+        let bytes = self.measure(sample).ok()?;
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        Some((sample.file_bytes as f64 * bytes as f64 / sample.bytes.max(1) as f64) as u64)
+    }
+}
+
+/* The start of a replay, up to its sample's last checkpoint */
+struct Sample {
+    header: Header,
+    initial_state: Vec<u8>,
+    frames: Vec<Frame>,
+    /* What those frames take up in the replay, header included */
+    bytes: u64,
+    /* The whole replay's size */
+    file_bytes: u64,
+}
+
+fn sample(path: &Path, checkpoints: usize) -> Result<Sample, ReplayError> {
+    let file = std::fs::File::open(path)?;
+    let file_bytes = file.metadata()?.len();
+    let mut rply = decode(std::io::BufReader::new(file))?;
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    let mut frames = vec![];
+    let mut seen = 0;
+    let mut ended = true;
+    for frame in rply.frames() {
+        let frame = frame?;
+        seen += usize::from(!frame.checkpoint_bytes.is_empty());
+        frames.push(frame);
+        if seen == checkpoints {
+            ended = false;
+            break;
+        }
+    }
+    let (header, initial_state) = (rply.header.clone(), rply.initial_state.clone());
+    let bytes = if ended {
+        file_bytes
+    } else {
+        rply.into_inner().stream_position()?
+    };
+    Ok(Sample {
+        header,
+        initial_state,
+        frames,
+        bytes,
+        file_bytes,
+    })
+}
+
+/* The replay's estimated size under each candidate worth trying, the
+candidates that combine being tried together last */
+fn estimates(sample: &Sample) -> Vec<(Candidate, u64)> {
+    let current = sample.header.checkpoint_compression();
+    let mut candidates = vec![];
+    for compression in ["zlib", "zstd"] {
+        if parse_compression(compression) != current {
+            candidates.push(Candidate {
+                compression: Some(compression),
+                ..Candidate::default()
+            });
+        }
+    }
+    for block_size in BLOCK_SIZES {
+        if block_size != sample.header.block_size() {
+            candidates.push(Candidate {
+                block_size: Some(block_size),
+                ..Candidate::default()
+            });
+        }
+    }
+    if sample
+        .header
+        .metadata()
+        .and_then(|m| m.frame_packing())
+        .is_none()
+    {
+        candidates.push(Candidate {
+            pack_frames: Some("zstd"),
+            ..Candidate::default()
+        });
+    }
+    candidates.push(Candidate {
+        runs: true,
+        ..Candidate::default()
+    });
+    // Candidates that don't encode, e.g. block sizes the state can't use, are left out
+    let mut estimates: Vec<(Candidate, u64)> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let estimate = candidate.estimate(sample)?;
+            Some((candidate, estimate))
+        })
+        .collect();
+    let helps = |estimate: u64| estimate < sample.file_bytes;
+    let mut combined = Candidate::default();
+    let mut parts = 0;
+    if let Some((best, _)) = estimates
+        .iter()
+        .filter(|(c, estimate)| c.block_size.is_some() && helps(*estimate))
+        .min_by_key(|(_, estimate)| *estimate)
+    {
+        combined.block_size = best.block_size;
+        parts += 1;
+    }
+    for (candidate, estimate) in &estimates {
+        if helps(*estimate) && (candidate.runs || candidate.pack_frames.is_some()) {
+            combined.runs |= candidate.runs;
+            combined.pack_frames = combined.pack_frames.or(candidate.pack_frames);
+            parts += 1;
+        }
+    }
+    if parts > 1
+        && let Some(estimate) = combined.estimate(sample)
+    {
+        estimates.push((combined, estimate));
+    }
+    estimates
+}
+
+/* Every replay under `dir`, in order */
+fn replays(dir: &Path, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(std::fs::DirEntry::path);
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            replays(&path, found)?;
+        } else if path.extension().is_some_and(|e| e == "replay") {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn plan_command(mut args: Vec<String>) {
+    let checkpoints =
+        parse_flag::<usize>(&mut args, "--sample").map_or(SAMPLE_CHECKPOINTS, |n| n.max(1));
+    let min_savings = parse_flag::<f64>(&mut args, "--min-savings").unwrap_or(5.0);
+    let json = take_switch(&mut args, "--json");
+    let mut paths = vec![];
+    replays(Path::new(arg(&args, 1)), &mut paths).unwrap();
+    let mut plan = vec![];
+    let mut unchanged = vec![];
+    let mut skipped = vec![];
+    let mut total = 0;
+    for path in paths {
+        let file = path.display().to_string();
+        let sample = match sample(&path, checkpoints) {
+            Ok(sample) => sample,
+            Err(e) => {
+                if !json {
+                    eprintln!("Skipping {file}: {e}");
+                }
+                skipped.push(json!({ "file": file, "error": e.to_string() }));
+                continue;
+            }
+        };
+        total += sample.file_bytes;
+        #[allow(clippy::cast_precision_loss)]
+        let threshold = sample.file_bytes as f64 * (1.0 - min_savings / 100.0);
+        #[allow(clippy::cast_precision_loss)]
+        match estimates(&sample)
+            .into_iter()
+            .min_by_key(|(_, estimate)| *estimate)
+            .filter(|(_, estimate)| (*estimate as f64) < threshold)
+        {
+            Some((candidate, estimate)) => {
+                plan.push((file, sample.file_bytes, estimate, candidate));
+            }
+            None => unchanged.push(file),
+        }
+    }
+    // Biggest savings first
+    plan.sort_by_key(|(_, bytes, estimate, _)| std::cmp::Reverse(bytes - estimate));
+    let savings: u64 = plan
+        .iter()
+        .map(|(_, bytes, estimate, _)| bytes - estimate)
+        .sum();
+    if json {
+        let plan: Vec<_> = plan
+            .iter()
+            .map(|(file, bytes, estimate, candidate)| {
+                let (command, flags) = candidate.command();
+                json!({
+                    "file": file,
+                    "bytes": bytes,
+                    "estimated_bytes": estimate,
+                    "command": command,
+                    "args": flags,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "plan": plan,
+                "unchanged": unchanged,
+                "skipped": skipped,
+                "bytes": total,
+                "estimated_savings": savings,
+            })
+        );
+        return;
+    }
+    for (i, (file, bytes, estimate, candidate)) in plan.iter().enumerate() {
+        let (command, flags) = candidate.command();
+        #[allow(clippy::cast_precision_loss)]
+        let percent = 100.0 * (bytes - estimate) as f64 / *bytes as f64;
+        println!(
+            "{}. {file}: {bytes} -> ~{estimate} bytes ({percent:.1}% smaller)\n   rply {command} {file} OUT {}",
+            i + 1,
+            flags.join(" ")
+        );
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let percent = 100.0 * savings as f64 / total.max(1) as f64;
+    println!(
+        "{} replays to re-encode, {} left as they are, {} skipped; ~{savings} of {total} bytes ({percent:.1}%) saved",
+        plan.len(),
+        unchanged.len(),
+        skipped.len()
+    );
+}
@@ -0,0 +1,422 @@
+use crate::{EXIT_CHECK_FAILED, parse_flag, take_flag, take_switch};
+use rply_codec::{Compression, Frame, Header, HeaderBase, InputData, ReplayError, decode, encode};
+use serde_json::json;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/* Checkpoints between checks of the process's memory */
+const RSS_CHECK_EVERY: usize = 1024;
+
+/* xorshift64*, so that a run's settings and states all follow from its seed */
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // splitmix64, so that neighbouring seeds start far apart
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self((z ^ (z >> 31)) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+    /* Something in lo..=hi */
+    fn range(&mut self, lo: u64, hi: u64) -> u64 {
+        lo + self.next() % (hi - lo + 1)
+    }
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[usize::try_from(self.next() % items.len() as u64).unwrap()]
+    }
+    fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/* The encoder settings of one run */
+struct Settings {
+    state_size: usize,
+    checkpoint_every: u64,
+    block_size: u32,
+    superblock_size: u32,
+    commit_interval: u8,
+    commit_threshold: u8,
+    compression: (&'static str, Compression),
+    memory_budget: usize,
+    keyframes: Option<u64>,
+}
+
+impl Settings {
+    fn draw(rng: &mut Rng) -> Self {
+        let state_size = usize::try_from(rng.range(4 << 10, 4 << 20)).unwrap();
+        let keyframe_every = rng.range(100, 5000);
+        Self {
+            state_size,
+            checkpoint_every: rng.range(1, 30),
+            block_size: rng.pick(&[16, 32, 64, 128, 256, 512]),
+            superblock_size: rng.pick(&[4, 16, 64, 256]),
+            commit_interval: u8::try_from(rng.range(0, 8)).unwrap(),
+            commit_threshold: u8::try_from(rng.range(0, 4)).unwrap(),
+            compression: rng.pick(&[
+                ("none", Compression::None),
+                ("zlib", Compression::Zlib),
+                ("zstd", Compression::Zstd),
+            ]),
+            memory_budget: rng.pick(&[state_size / 4, state_size, state_size * 4]),
+            keyframes: rng.pick(&[None, Some(keyframe_every)]),
+        }
+    }
+    /* Table bytes the budget can't evict: the initial state's objects and
+     * the last checkpoint's, each at most a padded state's blocks plus the
+     * superblocks listing them */
+    fn table_limit(&self) -> usize {
+        let (block, superblock) = (self.block_size as usize, self.superblock_size as usize);
+        let padded = self.state_size.next_multiple_of(block * superblock) + block;
+        self.memory_budget + 2 * (padded + padded / block * 4 + superblock * 4)
+    }
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "state_size": self.state_size,
+            "checkpoint_every": self.checkpoint_every,
+            "block_size": self.block_size,
+            "superblock_size": self.superblock_size,
+            "commit_interval": self.commit_interval,
+            "commit_threshold": self.commit_threshold,
+            "compression": self.compression.0,
+            "memory_budget": self.memory_budget,
+            "keyframes": self.keyframes,
+        })
+    }
+}
+
+/* A savestate that changes a little every checkpoint, mostly to fresh
+ * bytes, but sometimes back to an older state so that evicted blocks are
+ * needed again */
+struct SyntheticCore {
+    rng: Rng,
+    state: Vec<u8>,
+    saved: Vec<u8>,
+}
+
+impl SyntheticCore {
+    fn new(seed: u64, size: usize) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut state = vec![0; size];
+        // Leave some of it zero, as real cores do
+        rng.fill(&mut state[size / 2..]);
+        Self {
+            rng,
+            saved: state.clone(),
+            state,
+        }
+    }
+    fn advance(&mut self, block_size: usize) {
+        match self.rng.range(0, 15) {
+            0 => std::mem::swap(&mut self.state, &mut self.saved),
+            1 => self.saved.clone_from(&self.state),
+            _ => {}
+        }
+        let most = (block_size * 4).max(self.state.len() / 64) as u64;
+        for _ in 0..self.rng.range(1, 16) {
+            let len = usize::try_from(self.rng.range(1, most)).unwrap();
+            let len = len.min(self.state.len());
+            let at = usize::try_from(self.rng.range(0, (self.state.len() - len) as u64)).unwrap();
+            self.rng.fill(&mut self.state[at..at + len]);
+        }
+    }
+}
+
+fn state_hash(state: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+/* Peak resident memory of this process, where the OS reports it */
+fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/* Why a run failed */
+enum Failure {
+    Codec(ReplayError),
+    Check(String),
+}
+
+impl From<ReplayError> for Failure {
+    fn from(e: ReplayError) -> Self {
+        Self::Codec(e)
+    }
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(e: std::io::Error) -> Self {
+        Self::Codec(e.into())
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Codec(e) => write!(f, "{e}"),
+            Self::Check(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+fn check_rss(max_rss: u64) -> Result<Option<u64>, Failure> {
+    match peak_rss() {
+        Some(rss) if rss > max_rss => Err(Failure::Check(format!(
+            "Peak memory use {rss} bytes is over the {max_rss} byte ceiling"
+        ))),
+        rss => Ok(rss),
+    }
+}
+
+/* What one run did */
+struct Run {
+    settings: Settings,
+    frames: u64,
+    checkpoints: usize,
+    state_bytes: u64,
+    bytes: u64,
+    encoder_tables: usize,
+    decoder_tables: usize,
+    keyframe: Option<u64>,
+    peak_rss: Option<u64>,
+    secs: f64,
+}
+
+/* Encodes synthetic states from `seed` until `total` bytes of them have
+ * been written to a file in `dir`, then decodes it, checking every state
+ * and the statestream tables' size along the way.  The file is removed
+ * afterwards, even if a check fails, since the seed reproduces it. */
+fn run(seed: u64, total: u64, max_rss: u64, dir: &Path) -> Result<Run, Failure> {
+    let path = dir.join(format!("rply-soak-{seed}.replay"));
+    let run = soak(seed, total, max_rss, &path);
+    // It may not have been created
+    std::fs::remove_file(&path).ok();
+    run
+}
+
+fn soak(seed: u64, total: u64, max_rss: u64, path: &Path) -> Result<Run, Failure> {
+    let start = Instant::now();
+    let mut rng = Rng::new(seed);
+    let settings = Settings::draw(&mut rng);
+    let table_limit = settings.table_limit();
+    let mut core = SyntheticCore::new(rng.next(), settings.state_size);
+    let mut header = Header::V0V1(HeaderBase {
+        version: 2,
+        content_crc: 0,
+        initial_state_size: 0,
+        identifier: seed,
+    });
+    header.set_block_size(settings.block_size);
+    header.set_superblock_size(settings.superblock_size);
+    header.set_checkpoint_commit_settings(settings.commit_interval, settings.commit_threshold);
+    if settings.commit_interval > 0 {
+        header.metadata_mut().set_block_commits(true);
+    }
+    header.set_checkpoint_compression(settings.compression.1);
+
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    let mut enc = encode(header, &core.state, &mut out)?;
+    enc.set_memory_budget(Some(settings.memory_budget));
+    enc.set_keyframe_interval(settings.keyframes);
+    let mut frame = Frame::default();
+    frame.input_events.push(InputData::default());
+    let (mut hashes, mut state_bytes, mut encoder_tables) = (vec![], 0, 0);
+    while state_bytes < total {
+        frame.checkpoint_bytes.clear();
+        for _ in 1..settings.checkpoint_every {
+            enc.write_frame(&frame)?;
+        }
+        core.advance(settings.block_size as usize);
+        frame.checkpoint_bytes.clone_from(&core.state);
+        enc.write_frame(&frame)?;
+        hashes.push(state_hash(&core.state));
+        state_bytes += core.state.len() as u64;
+        encoder_tables = encoder_tables.max(enc.table_bytes());
+        if enc.table_bytes() > table_limit {
+            return Err(Failure::Check(format!(
+                "Encoder tables hold {} bytes after frame {}, over the {table_limit} bytes the budget allows",
+                enc.table_bytes(),
+                enc.frame_number
+            )));
+        }
+        if hashes.len() % RSS_CHECK_EVERY == 0 {
+            check_rss(max_rss)?;
+        }
+    }
+    enc.finish()?;
+    let frames = enc.frame_number;
+    drop(enc);
+    out.flush()?;
+    drop(out);
+    let bytes = std::fs::metadata(path)?.len();
+
+    let open = || -> Result<_, Failure> { Ok(decode(BufReader::new(std::fs::File::open(path)?))?) };
+    let mut rply = open()?;
+    let (mut checkpoints, mut decoder_tables) = (0, 0);
+    while !rply.at_end()? {
+        rply.read_frame(&mut frame)?;
+        if frame.checkpoint_bytes.is_empty() {
+            continue;
+        }
+        if hashes.get(checkpoints) != Some(&state_hash(&frame.checkpoint_bytes)) {
+            return Err(Failure::Check(format!(
+                "Checkpoint at frame {} doesn't match the state encoded",
+                rply.frame_number
+            )));
+        }
+        checkpoints += 1;
+        decoder_tables = decoder_tables.max(rply.table_bytes());
+        if rply.table_bytes() > table_limit {
+            return Err(Failure::Check(format!(
+                "Decoder tables hold {} bytes after frame {}, over the {table_limit} bytes the budget allows",
+                rply.table_bytes(),
+                rply.frame_number
+            )));
+        }
+        if checkpoints % RSS_CHECK_EVERY == 0 {
+            check_rss(max_rss)?;
+        }
+    }
+    if (rply.frame_number, checkpoints) != (frames, hashes.len()) {
+        return Err(Failure::Check(format!(
+            "Decoded {} frames and {checkpoints} checkpoints of the {frames} and {} encoded",
+            rply.frame_number,
+            hashes.len()
+        )));
+    }
+    drop(rply);
+
+    // Start again from a keyframe, as a player with part of the file would
+    let mut keyframe = None;
+    let mut rply = open()?;
+    let keyframes = rply.keyframes()?;
+    if !keyframes.is_empty() {
+        let from = keyframes[usize::try_from(rng.next() % keyframes.len() as u64).unwrap()];
+        rply.seek_to_keyframe(&from)?;
+        rply.read_frame(&mut frame)?;
+        let at = usize::try_from(from.frame / settings.checkpoint_every).unwrap() - 1;
+        if hashes.get(at) != Some(&state_hash(&frame.checkpoint_bytes)) {
+            return Err(Failure::Check(format!(
+                "Keyframe at frame {} doesn't match the state encoded",
+                from.frame
+            )));
+        }
+        keyframe = Some(from.frame);
+    }
+    drop(rply);
+    Ok(Run {
+        settings,
+        frames,
+        checkpoints,
+        state_bytes,
+        bytes,
+        encoder_tables,
+        decoder_tables,
+        keyframe,
+        peak_rss: check_rss(max_rss)?,
+        secs: start.elapsed().as_secs_f64(),
+    })
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn report(seed: u64, run: &Run) {
+    let settings = &run.settings;
+    let mib = |bytes: u64| bytes as f64 / f64::from(1 << 20);
+    println!(
+        "Seed {seed}: {} frames, {} checkpoints of {} bytes every {} frames; block size {}, superblock size {}, commits {}/{}, {}, budget {} bytes, {}",
+        run.frames,
+        run.checkpoints,
+        settings.state_size,
+        settings.checkpoint_every,
+        settings.block_size,
+        settings.superblock_size,
+        settings.commit_interval,
+        settings.commit_threshold,
+        settings.compression.0,
+        settings.memory_budget,
+        settings.keyframes.map_or_else(
+            || "no keyframes".to_string(),
+            |k| format!("keyframes every {k} frames")
+        ),
+    );
+    println!(
+        "  {:.0} MiB of states in {:.1} MiB ({:.1} MiB/s); tables at most {} bytes encoding, {} decoding{}{}",
+        mib(run.state_bytes),
+        mib(run.bytes),
+        mib(run.state_bytes) / run.secs,
+        run.encoder_tables,
+        run.decoder_tables,
+        run.keyframe
+            .map_or_else(String::new, |k| format!("; restarted at keyframe {k}")),
+        run.peak_rss
+            .map_or_else(String::new, |rss| format!("; peak RSS {:.1} MiB", mib(rss))),
+    );
+}
+
+pub(crate) fn soak_command(mut args: Vec<String>) {
+    let json = take_switch(&mut args, "--json");
+    let iterations = parse_flag::<u64>(&mut args, "--iterations");
+    let seed = parse_flag(&mut args, "--seed").unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    });
+    let total = parse_flag(&mut args, "--bytes").unwrap_or(4 << 30);
+    let max_rss = parse_flag(&mut args, "--max-rss").unwrap_or(1 << 30);
+    let dir = take_flag(&mut args, "--dir").map_or_else(std::env::temp_dir, PathBuf::from);
+    for i in 0..iterations.unwrap_or(u64::MAX) {
+        let seed = seed.wrapping_add(i);
+        match run(seed, total, max_rss, &dir) {
+            Ok(run) if json => println!(
+                "{}",
+                json!({
+                    "seed": seed,
+                    "settings": run.settings.to_json(),
+                    "frames": run.frames,
+                    "checkpoints": run.checkpoints,
+                    "state_bytes": run.state_bytes,
+                    "bytes": run.bytes,
+                    "encoder_table_bytes": run.encoder_tables,
+                    "decoder_table_bytes": run.decoder_tables,
+                    "keyframe": run.keyframe,
+                    "peak_rss": run.peak_rss,
+                    "secs": run.secs,
+                })
+            ),
+            Ok(run) => report(seed, &run),
+            Err(failure) => {
+                if json {
+                    println!(
+                        "{}",
+                        json!({ "seed": seed, "failure": failure.to_string() })
+                    );
+                } else {
+                    eprintln!("Seed {seed}: {failure}");
+                }
+                std::process::exit(EXIT_CHECK_FAILED);
+            }
+        }
+    }
+}
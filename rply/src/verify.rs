@@ -0,0 +1,154 @@
+use crate::{
+    EXIT_CHECK_FAILED, arg, create, emulator, fail, open, parse_flag, take_flag, take_switch, usage,
+};
+use rply_codec::{
+    CompatEntry, CompatMatrix, Verification, Verifier, VerifyProgress, encode, verify_external,
+    verify_parallel,
+};
+use std::path::Path;
+use std::process::Command;
+
+/* The file stem of `path`, naming the core in compatibility results */
+fn stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map_or(path.to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+pub(crate) fn verify_command(mut args: Vec<String>) {
+    let restart = take_switch(&mut args, "--restart");
+    let json = take_switch(&mut args, "--json");
+    let jobs = parse_flag::<usize>(&mut args, "--jobs");
+    let core_name = take_flag(&mut args, "--core-name");
+    let core_version = take_flag(&mut args, "--core-version").unwrap_or("unknown".to_string());
+    let core_options = take_flag(&mut args, "--core-options").unwrap_or_default();
+    let command = take_flag(&mut args, "--command");
+    let corefile = take_flag(&mut args, "--core");
+    let romfile = take_flag(&mut args, "--rom");
+    let regenerate = take_flag(&mut args, "--regenerate");
+    let every = parse_flag(&mut args, "--checkpoint-every").unwrap_or(60);
+    let replay = arg(&args, 1);
+    let mut rply = open(replay);
+    if !rply.header.capabilities().coreless_frames {
+        fail("Version 0 replays have no checkpoints to verify");
+    }
+    let (core_name, result) = match (command, corefile, romfile) {
+        (Some(command), _, _) => {
+            let mut words = command.split_whitespace();
+            let Some(program) = words.next() else {
+                usage();
+            };
+            let program_args: Vec<_> = words.collect();
+            let make = || {
+                let mut command = Command::new(program);
+                command.args(&program_args);
+                command
+            };
+            let result = verify_external(&mut rply, Path::new(replay), make).unwrap();
+            (core_name.unwrap_or_else(|| stem(program)), result)
+        }
+        (None, Some(corefile), Some(romfile)) => {
+            let result = match &regenerate {
+                Some(outfile) => regenerate_core(&mut rply, &corefile, &romfile, outfile, every),
+                None => verify_core(&mut rply, replay, &corefile, &romfile, jobs, restart, json),
+            };
+            (core_name.unwrap_or_else(|| stem(&corefile)), result)
+        }
+        _ => usage(),
+    };
+    let compat_file = format!("{replay}.compat");
+    let mut compat = CompatMatrix::load(&compat_file).unwrap();
+    let outcome = CompatEntry {
+        core: core_name,
+        version: core_version,
+        options: core_options,
+        result,
+    };
+    compat.record(outcome.clone());
+    compat.save(&compat_file).unwrap();
+    let result = &outcome.result;
+    if json {
+        println!("{}", serde_json::to_string(&outcome).unwrap());
+        if result.desync.is_some() {
+            std::process::exit(EXIT_CHECK_FAILED);
+        }
+        return;
+    }
+    for entry in compat.entries() {
+        println!(
+            "{} {} [{}]: {}",
+            entry.core,
+            entry.version,
+            entry.options,
+            if entry.synced() { "syncs" } else { "desyncs" }
+        );
+    }
+    println!(
+        "{} frames, {} checkpoints matched",
+        result.frames, result.checkpoints
+    );
+    if let Some(frame) = result.desync {
+        println!("Desync at frame {frame}");
+        for range in &result.differences {
+            println!("  bytes {range:?} differ");
+        }
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+/* Verifies with the core while writing the replay to `outfile` with a
+checkpoint every `every` frames */
+fn regenerate_core(
+    rply: &mut rply_codec::ReplayDecoder<crate::Source>,
+    corefile: &str,
+    romfile: &str,
+    outfile: &str,
+    every: u64,
+) -> Verification {
+    let core = emulator(Some(corefile.to_string()), Some(romfile.to_string())).unwrap();
+    let mut outfile = create(outfile);
+    let mut out = encode(rply.header.clone(), &rply.initial_state, &mut outfile).unwrap();
+    Verifier::new(core)
+        .regenerate(rply, every, &mut out)
+        .unwrap()
+}
+
+fn verify_core(
+    rply: &mut rply_codec::ReplayDecoder<crate::Source>,
+    replay: &str,
+    corefile: &str,
+    romfile: &str,
+    jobs: Option<usize>,
+    restart: bool,
+    json: bool,
+) -> Verification {
+    let sidecar = format!("{replay}.verify");
+    let load = || emulator(Some(corefile.to_string()), Some(romfile.to_string())).unwrap();
+    if let Some(jobs) = jobs {
+        return verify_parallel(rply, jobs, load).unwrap();
+    }
+    let resume = if restart {
+        None
+    } else {
+        VerifyProgress::load(&sidecar).unwrap()
+    };
+    if let Some(progress) = resume.as_ref().filter(|_| !json) {
+        println!("Resuming from frame {}", progress.frame);
+    }
+    let mut verifier = Verifier::new(load());
+    let result = verifier
+        .verify(rply, resume.as_ref(), |progress| {
+            if !json {
+                println!("Verified {} frames", progress.frame);
+            }
+            progress.save(&sidecar)
+        })
+        .unwrap();
+    // Finished verifications don't need their progress any more
+    if let Err(e) = std::fs::remove_file(&sidecar)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        eprintln!("Couldn't remove {sidecar}: {e}");
+    }
+    result
+}
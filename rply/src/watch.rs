@@ -0,0 +1,142 @@
+use crate::{arg, bad_argument, fail, parse_flag, take_switch};
+use rply_codec::{Frame, ReplayError, decode};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/* Probes averaged over for the recording rate and checkpoint cadence */
+const WINDOW: usize = 10;
+
+/* What one pass over the replay found */
+struct Probe {
+    frames: u64,
+    bytes: u64,
+    /* Frames of every checkpoint after the initial state */
+    checkpoints: Vec<u64>,
+    /* Uncompressed size of the initial state and every checkpoint */
+    state_bytes: u64,
+    finished: bool,
+}
+
+fn probe(replay: &str) -> Result<Probe, ReplayError> {
+    let file = std::fs::File::open(replay)?;
+    let bytes = file.metadata()?.len();
+    let mut rply = decode(std::io::BufReader::new(file))?;
+    if !rply.header.capabilities().coreless_frames {
+        return Err(ReplayError::NoCoreRead());
+    }
+    // The encoder writes the frame count when it finishes, and recorders
+    // flushing their headers as they go write it before then too
+    let count = rply.header.frame_count().filter(|count| *count > 0);
+    rply.set_live(true);
+    let mut state_bytes = rply.initial_state.len() as u64;
+    let mut checkpoints = vec![];
+    let mut frame = Frame::default();
+    while !rply.at_end()? {
+        match rply.read_frame_skipping_checkpoints(&mut frame) {
+            Ok(Some(size)) => {
+                checkpoints.push(rply.frame_number);
+                state_bytes += size;
+            }
+            Ok(None) => {}
+            // The recorder hasn't finished writing this frame yet
+            Err(e)
+                if matches!(e.root(), ReplayError::IO(e)
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof) =>
+            {
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Probe {
+        frames: rply.frame_number,
+        bytes,
+        checkpoints,
+        state_bytes,
+        finished: count == Some(rply.frame_number),
+    })
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn report(probe: &Probe, history: &VecDeque<(Instant, u64)>) {
+    let mut line = format!("{} frames", probe.frames);
+    if let (Some((then, old)), Some((now, new))) = (history.front(), history.back()) {
+        let secs = now.duration_since(*then).as_secs_f64();
+        if secs > 0.0 {
+            line += &format!(" ({:.1}/s)", (new - old) as f64 / secs);
+        }
+    }
+    if probe.frames > 0 {
+        line += &format!(
+            ", {:.1} bytes/frame",
+            probe.bytes as f64 / probe.frames as f64
+        );
+    }
+    let recent = &probe.checkpoints[probe.checkpoints.len().saturating_sub(WINDOW + 1)..];
+    if let [first, .., last] = recent {
+        line += &format!(
+            ", checkpoint every {:.1} frames",
+            (last - first) as f64 / (recent.len() - 1) as f64
+        );
+    }
+    if let Some(last) = probe.checkpoints.last() {
+        line += &format!(" (last at {last})");
+    }
+    if probe.bytes > 0 {
+        line += &format!(
+            ", states {:.1}x file size",
+            probe.state_bytes as f64 / probe.bytes as f64
+        );
+    }
+    println!("{line}");
+}
+
+pub(crate) fn watch_command(mut args: Vec<String>) {
+    let interval = parse_flag(&mut args, "--interval").unwrap_or(1.0);
+    let interval = Duration::try_from_secs_f64(interval)
+        .unwrap_or_else(|e| bad_argument(&format!("Bad --interval {interval}: {e}")));
+    let json = take_switch(&mut args, "--json");
+    let replay = arg(&args, 1);
+    let mut history = VecDeque::with_capacity(WINDOW + 1);
+    let mut last_bytes = None;
+    loop {
+        match probe(replay) {
+            Ok(mut probe) => {
+                // A header flushed mid-recording matches the frames too, until
+                // the next frame is written
+                probe.finished &= last_bytes.replace(probe.bytes) == Some(probe.bytes);
+                if history.len() > WINDOW {
+                    history.pop_front();
+                }
+                history.push_back((Instant::now(), probe.frames));
+                if json {
+                    println!(
+                        "{}",
+                        json!({
+                            "frames": probe.frames,
+                            "bytes": probe.bytes,
+                            "checkpoints": probe.checkpoints.len(),
+                            "last_checkpoint": probe.checkpoints.last(),
+                            "state_bytes": probe.state_bytes,
+                            "finished": probe.finished,
+                        })
+                    );
+                } else {
+                    report(&probe, &history);
+                }
+                if probe.finished {
+                    if !json {
+                        println!("Recording finished");
+                    }
+                    return;
+                }
+            }
+            // Not written yet, or the header is still being written
+            Err(ReplayError::IO(e)) if !json => println!("Waiting for {replay}: {e}"),
+            Err(ReplayError::IO(_)) => {}
+            Err(e) => fail(&format!("{replay}: {e}")),
+        }
+        std::thread::sleep(interval);
+    }
+}